@@ -0,0 +1,15 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The build time, the earliest wall-clock time the service takes as set
+/// (`time.floor`); `SOURCE_DATE_EPOCH` stands in for it in reproducible
+/// builds. Cargo only reruns this when `build.rs` or `SOURCE_DATE_EPOCH`
+/// changes, so an incremental build keeps the time of the first one.
+fn main() {
+    let secs = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|secs| secs.parse::<i64>().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs() as i64));
+    println!("cargo:rustc-env=BUILD_TIME_SECS={}", secs);
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=build.rs");
+}
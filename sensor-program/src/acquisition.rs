@@ -0,0 +1,132 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::timing::millis;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AcquisitionFormat {
+    /// `acquisition_offsets_ms`: milliseconds from the record's timestamp.
+    #[default]
+    Offset,
+    /// `acquired_at`: the time itself.
+    Absolute,
+}
+
+/// A record is timestamped when its cycle starts, but a read can complete
+/// much later: a DS18B20 conversion takes ~750 ms, more with retries. A
+/// measurement acquired `age_threshold_ms` or more away from the record's
+/// timestamp is listed with its own acquisition time; 0 lists them all.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcquisitionConfig {
+    pub age_threshold_ms: u64,
+    pub format: AcquisitionFormat,
+}
+
+impl Default for AcquisitionConfig {
+    fn default() -> Self {
+        AcquisitionConfig { age_threshold_ms: 500, format: AcquisitionFormat::default() }
+    }
+}
+
+/// `acquisition_offsets_ms` and `acquired_at` of a data record.
+#[derive(Default)]
+pub struct Late {
+    pub offsets_ms: Option<BTreeMap<String, f64>>,
+    pub acquired_at: Option<BTreeMap<String, DateTime<Utc>>>,
+}
+
+/// When each measurement of a cycle was acquired, in milliseconds from the
+/// record's timestamp. Every read reports here, whatever thread or rate it
+/// runs at, and the record's timing fields are all built from it.
+#[derive(Default)]
+pub struct Acquisitions {
+    offsets_ms: BTreeMap<String, f64>,
+}
+
+impl Acquisitions {
+    /// A read of `key` that completed `after` the record's timestamp.
+    pub fn completed(&mut self, key: impl Into<String>, after: Duration) {
+        self.offsets_ms.insert(key.into(), millis(after));
+    }
+
+    /// Every offset, for `capture_offsets_ms`, once they spread over more
+    /// than `threshold_ms`.
+    pub fn capture_offsets(&self, threshold_ms: u64) -> Option<BTreeMap<String, f64>> {
+        let offsets = self.offsets_ms.values();
+        let spread = offsets.clone().fold(f64::MIN, |a, &b| a.max(b)) - offsets.fold(f64::MAX, |a, &b| a.min(b));
+        (spread > threshold_ms as f64).then(|| self.offsets_ms.clone())
+    }
+
+    /// The measurements older than the threshold: only the field
+    /// `config.format` asks for is set, and neither when every measurement
+    /// is recent enough.
+    pub fn late(&self, config: &AcquisitionConfig, timestamp: DateTime<Utc>) -> Late {
+        let late: BTreeMap<String, f64> = self
+            .offsets_ms
+            .iter()
+            .filter(|(_, offset)| offset.abs() >= config.age_threshold_ms as f64)
+            .map(|(key, offset)| (key.clone(), *offset))
+            .collect();
+        if late.is_empty() {
+            return Late::default();
+        }
+        match config.format {
+            AcquisitionFormat::Offset => Late { offsets_ms: Some(late), acquired_at: None },
+            AcquisitionFormat::Absolute => {
+                let at = |offset: f64| timestamp + chrono::Duration::microseconds((offset * 1000.0).round() as i64);
+                let acquired_at = late.into_iter().map(|(key, offset)| (key, at(offset))).collect();
+                Late { offsets_ms: None, acquired_at: Some(acquired_at) }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sim-test"))]
+mod tests {
+    use chrono::{DateTime, Duration as Offset};
+    use std::time::Duration;
+
+    use super::{AcquisitionConfig, AcquisitionFormat};
+    use crate::config::Config;
+    use crate::sim::bench::Bench;
+
+    /// The MS5611 read with 4 conversion pairs (460 ms), then the second one
+    /// of `[voting]` with 1 (160 ms) on the same simulated bus.
+    fn run(name: &str, acquisition: AcquisitionConfig) -> Vec<serde_json::Value> {
+        let edit = |config: &mut Config| {
+            config.ms5611.samples_per_cycle = 4;
+            config.voting = Some(toml::from_str("secondary = { address = 0x76 }").unwrap());
+            config.acquisition = acquisition;
+        };
+        let mut bench = Bench::start(name, Duration::ZERO, edit);
+        bench.run(2);
+        bench.finish().0
+    }
+
+    #[test]
+    fn lists_the_measurements_acquired_late() {
+        let records = run("acquisition-offset", AcquisitionConfig { age_threshold_ms: 500, ..Default::default() });
+        assert_eq!(records.len(), 2);
+        for record in &records {
+            assert_eq!(record["acquisition_offsets_ms"], serde_json::json!({ "ms5611_secondary": 620.0 }));
+            assert!(record.get("acquired_at").is_none());
+        }
+    }
+
+    #[test]
+    fn lists_a_measurement_exactly_at_the_threshold() {
+        let acquisition = AcquisitionConfig { age_threshold_ms: 460, format: AcquisitionFormat::Absolute };
+        let records = run("acquisition-absolute", acquisition);
+        for record in &records {
+            let timestamp = DateTime::parse_from_rfc3339(record["timestamp"].as_str().unwrap()).unwrap();
+            let at = |key: &str| DateTime::parse_from_rfc3339(record["acquired_at"][key].as_str().unwrap()).unwrap();
+            assert_eq!(at("ms5611") - timestamp, Offset::milliseconds(460));
+            assert_eq!(at("ms5611_secondary") - timestamp, Offset::milliseconds(620));
+            assert!(record.get("acquisition_offsets_ms").is_none());
+        }
+    }
+}
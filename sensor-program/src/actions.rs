@@ -0,0 +1,243 @@
+use rppal::gpio::{Gpio, OutputPin};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::flight::FlightState;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Trigger {
+    FlightState { state: FlightState },
+    AltitudeAbove { altitude_m: f64 },
+    AltitudeBelow { altitude_m: f64 },
+    /// A crossing of the `[[setpoints]]` entry with this name.
+    Setpoint { name: String },
+    Signal,
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trigger::FlightState { state } => write!(f, "flight_state:{:?}", state),
+            Trigger::AltitudeAbove { altitude_m } => write!(f, "altitude_above:{}", altitude_m),
+            Trigger::AltitudeBelow { altitude_m } => write!(f, "altitude_below:{}", altitude_m),
+            Trigger::Setpoint { name } => write!(f, "setpoint:{}", name),
+            Trigger::Signal => write!(f, "signal"),
+        }
+    }
+}
+
+/// A GPIO pulse fired on `on`. Nothing is driven unless `armed` is set.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ActionConfig {
+    pub name: String,
+    /// BCM pin number.
+    pub pin: u8,
+    pub on: Vec<Trigger>,
+    #[serde(default)]
+    pub armed: bool,
+    #[serde(default)]
+    pub active_low: bool,
+    #[serde(default = "default_pulse_ms")]
+    pub pulse_ms: u64,
+    #[serde(default = "default_max_activations")]
+    pub max_activations: u32,
+    #[serde(default = "default_rearm_secs")]
+    pub rearm_secs: u64,
+}
+
+fn default_pulse_ms() -> u64 {
+    500
+}
+
+fn default_max_activations() -> u32 {
+    1
+}
+
+fn default_rearm_secs() -> u64 {
+    60
+}
+
+/// `setpoints` are the names of the configured `[[setpoints]]`.
+pub fn validate(actions: &[ActionConfig], setpoints: &[&str]) -> Vec<String> {
+    let mut errors = Vec::new();
+    for (index, action) in actions.iter().enumerate() {
+        let key = format!("actions.{}", action.name);
+        for trigger in &action.on {
+            if let Trigger::Setpoint { name } = trigger
+                && !setpoints.contains(&name.as_str())
+            {
+                errors.push(format!("{}.on: setpoint \"{}\" non definito in [[setpoints]]", key, name));
+            }
+        }
+        if action.name.is_empty() || actions[..index].iter().any(|other| other.name == action.name) {
+            errors.push(format!("actions: nome \"{}\" vuoto o duplicato", action.name));
+        }
+        if action.pin > 27 {
+            errors.push(format!("{}.pin {} non è un GPIO BCM (0-27)", key, action.pin));
+        } else if let Some(other) = actions[..index].iter().find(|other| other.pin == action.pin) {
+            errors.push(format!("{}.pin {} già assegnato ad actions.{}", key, action.pin, other.name));
+        }
+        if action.on.is_empty() {
+            errors.push(format!("{}.on non può essere vuoto", key));
+        }
+        if action.pulse_ms == 0 || action.max_activations == 0 {
+            errors.push(format!("{}.pulse_ms e {}.max_activations devono essere maggiori di zero", key, key));
+        }
+    }
+    errors
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    Fired,
+    Disarmed,
+    DryRun,
+    Exhausted,
+    Rearming { remaining_secs: u64 },
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub struct Actuation {
+    pub name: String,
+    pub pin: u8,
+    pub trigger: String,
+    pub activation: u32,
+    pub outcome: Outcome,
+}
+
+struct Action {
+    config: ActionConfig,
+    pin: Result<Arc<Mutex<OutputPin>>, String>,
+    activations: u32,
+    last_fired: Option<Instant>,
+}
+
+pub struct Actions {
+    actions: Vec<Action>,
+    dry_run: bool,
+    last_altitude: Option<f64>,
+}
+
+impl Actions {
+    /// Claims the pins of armed actions and drives them to their inactive
+    /// level. Pins that cannot be opened turn each firing into a failure.
+    pub fn new(configs: Vec<ActionConfig>, dry_run: bool) -> Self {
+        let gpio = if dry_run || !configs.iter().any(|config| config.armed) {
+            Err("GPIO non inizializzato".to_string())
+        } else {
+            Gpio::new().map_err(|e| e.to_string())
+        };
+        let actions = configs
+            .into_iter()
+            .map(|config| {
+                let pin = match &gpio {
+                    Ok(gpio) if config.armed => gpio
+                        .get(config.pin)
+                        .map(|pin| {
+                            let mut pin = pin.into_output();
+                            pin.set_reset_on_drop(false);
+                            set_active(&mut pin, &config, false);
+                            Arc::new(Mutex::new(pin))
+                        })
+                        .map_err(|e| e.to_string()),
+                    Ok(_) => Err("azione non armata".to_string()),
+                    Err(e) => Err(e.clone()),
+                };
+                Action { config, pin, activations: 0, last_fired: None }
+            })
+            .collect();
+        Actions { actions, dry_run, last_altitude: None }
+    }
+
+    pub fn on_sample(
+        &mut self,
+        now: Instant,
+        altitude: f64,
+        entered: Option<FlightState>,
+        setpoints: &[&str],
+    ) -> Vec<Actuation> {
+        let previous = self.last_altitude.replace(altitude);
+        self.fire_matching(now, |trigger| match trigger {
+            Trigger::FlightState { state } => entered == Some(*state),
+            Trigger::AltitudeAbove { altitude_m } => {
+                previous.is_some_and(|previous| previous < *altitude_m && altitude >= *altitude_m)
+            }
+            Trigger::AltitudeBelow { altitude_m } => {
+                previous.is_some_and(|previous| previous > *altitude_m && altitude <= *altitude_m)
+            }
+            Trigger::Setpoint { name } => setpoints.contains(&name.as_str()),
+            Trigger::Signal => false,
+        })
+    }
+
+    pub fn on_signal(&mut self, now: Instant) -> Vec<Actuation> {
+        self.fire_matching(now, |trigger| *trigger == Trigger::Signal)
+    }
+
+    fn fire_matching(&mut self, now: Instant, matches: impl Fn(&Trigger) -> bool) -> Vec<Actuation> {
+        let dry_run = self.dry_run;
+        let mut actuations = Vec::new();
+        for action in &mut self.actions {
+            if let Some(trigger) = action.config.on.iter().find(|trigger| matches(trigger)) {
+                let trigger = trigger.to_string();
+                actuations.push(action.fire(now, trigger, dry_run));
+            }
+        }
+        actuations
+    }
+}
+
+impl Action {
+    fn fire(&mut self, now: Instant, trigger: String, dry_run: bool) -> Actuation {
+        let rearm = Duration::from_secs(self.config.rearm_secs);
+        let outcome = if !self.config.armed {
+            Outcome::Disarmed
+        } else if self.activations >= self.config.max_activations {
+            Outcome::Exhausted
+        } else if let Some(last) = self.last_fired.filter(|last| now.duration_since(*last) < rearm) {
+            Outcome::Rearming { remaining_secs: (rearm - now.duration_since(last)).as_secs() }
+        } else if dry_run {
+            Outcome::DryRun
+        } else {
+            match &self.pin {
+                Ok(pin) => {
+                    let pin = Arc::clone(pin);
+                    let config = self.config.clone();
+                    thread::spawn(move || pulse(&pin, &config));
+                    self.activations += 1;
+                    self.last_fired = Some(now);
+                    Outcome::Fired
+                }
+                Err(e) => Outcome::Failed(e.clone()),
+            }
+        };
+        Actuation {
+            name: self.config.name.clone(),
+            pin: self.config.pin,
+            trigger,
+            activation: self.activations,
+            outcome,
+        }
+    }
+}
+
+fn set_active(pin: &mut OutputPin, config: &ActionConfig, active: bool) {
+    if active != config.active_low {
+        pin.set_high();
+    } else {
+        pin.set_low();
+    }
+}
+
+fn pulse(pin: &Mutex<OutputPin>, config: &ActionConfig) {
+    let mut pin = pin.lock().unwrap_or_else(|e| e.into_inner());
+    set_active(&mut pin, config, true);
+    thread::sleep(Duration::from_millis(config.pulse_ms));
+    set_active(&mut pin, config, false);
+}
@@ -0,0 +1,135 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::cli::AnalyzeOptions;
+use crate::flight::FlightState;
+use crate::flight_dir;
+use crate::record::{HeaderRecord, RecordLayout, SensorData};
+
+#[derive(Serialize, Debug)]
+pub struct SessionSummary {
+    /// Empty for records written before sessions existed.
+    pub session_id: String,
+    pub records: u64,
+    pub boot_ids: BTreeSet<String>,
+    pub first: DateTime<Utc>,
+    pub last: DateTime<Utc>,
+    pub duration_secs: i64,
+    pub min_pressure_hpa: f64,
+    pub max_altitude_m: f64,
+    pub min_temperature_c: f64,
+    pub max_temperature_c: f64,
+    pub min_vertical_speed_ms: Option<f64>,
+    pub max_vertical_speed_ms: Option<f64>,
+    /// In the order they were first reached.
+    pub flight_states: Vec<FlightState>,
+    /// Records with at least one DS18B20 reading missing.
+    pub incomplete_records: u64,
+    /// One per start of the service in this session.
+    pub headers: Vec<HeaderRecord>,
+    /// Records taken with the wall clock not set, left out of `first`
+    /// and `last`.
+    pub clock_invalid_records: u64,
+    #[serde(skip)]
+    dated: bool,
+}
+
+impl SessionSummary {
+    fn new(record: &SensorData) -> Self {
+        SessionSummary {
+            session_id: record.session_id.clone(),
+            records: 0,
+            boot_ids: BTreeSet::new(),
+            first: record.timestamp,
+            last: record.timestamp,
+            duration_secs: 0,
+            min_pressure_hpa: f64::INFINITY,
+            max_altitude_m: f64::NEG_INFINITY,
+            min_temperature_c: f64::INFINITY,
+            max_temperature_c: f64::NEG_INFINITY,
+            min_vertical_speed_ms: None,
+            max_vertical_speed_ms: None,
+            flight_states: Vec::new(),
+            incomplete_records: 0,
+            headers: Vec::new(),
+            clock_invalid_records: 0,
+            dated: false,
+        }
+    }
+
+    fn add(&mut self, record: &SensorData) {
+        self.records += 1;
+        self.boot_ids.insert(record.boot_id.clone());
+        if record.clock_invalid {
+            self.clock_invalid_records += 1;
+        } else if !self.dated {
+            (self.first, self.last, self.dated) = (record.timestamp, record.timestamp, true);
+        } else {
+            self.first = self.first.min(record.timestamp);
+            self.last = self.last.max(record.timestamp);
+        }
+        self.duration_secs = (self.last - self.first).num_seconds();
+        self.min_pressure_hpa = self.min_pressure_hpa.min(record.ms5611.pressure);
+        self.max_altitude_m = self.max_altitude_m.max(record.altitude_m);
+        self.min_temperature_c = self.min_temperature_c.min(record.ms5611.temperature);
+        self.max_temperature_c = self.max_temperature_c.max(record.ms5611.temperature);
+        if let Some(speed) = record.vertical_speed_ms {
+            self.min_vertical_speed_ms = Some(self.min_vertical_speed_ms.map_or(speed, |min| min.min(speed)));
+            self.max_vertical_speed_ms = Some(self.max_vertical_speed_ms.map_or(speed, |max| max.max(speed)));
+        }
+        if !self.flight_states.contains(&record.flight_state) {
+            self.flight_states.push(record.flight_state);
+        }
+        let probes = record.ds18b20_1.iter().chain(&record.ds18b20_2);
+        if probes.chain(record.ds18b20_extra.values()).any(Option::is_none) {
+            self.incomplete_records += 1;
+        }
+    }
+}
+
+/// Groups the data records of every input, a file or the data files of a
+/// flight directory, by session, ordered by their first record, with the
+/// headers of each. Records added by `replay`, those of a warm-up and lines
+/// that are neither data records nor headers are skipped.
+pub fn analyze(options: &AnalyzeOptions) -> Result<Vec<SessionSummary>, Box<dyn std::error::Error>> {
+    let mut sessions: Vec<SessionSummary> = Vec::new();
+    let mut headers = Vec::new();
+    for path in &flight_dir::expand_inputs(&options.inputs)? {
+        let input = BufReader::new(File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?);
+        for line in input.lines() {
+            let line = line?;
+            let Ok(value) = serde_json::from_str::<serde_json::Value>(&line) else {
+                continue;
+            };
+            if value.get("type").is_some() {
+                headers.extend(HeaderRecord::parse(&line));
+                continue;
+            }
+            let Ok(record) = serde_json::from_value::<SensorData>(RecordLayout::Nested.apply(value)) else {
+                continue;
+            };
+            if record.filled || record.warmup {
+                continue;
+            }
+            let index = match sessions.iter().position(|session| session.session_id == record.session_id) {
+                Some(index) => index,
+                None => {
+                    sessions.push(SessionSummary::new(&record));
+                    sessions.len() - 1
+                }
+            };
+            sessions[index].add(&record);
+        }
+    }
+    headers.sort_by_key(|header| header.timestamp);
+    for header in headers {
+        if let Some(session) = sessions.iter_mut().find(|session| session.session_id == header.session_id) {
+            session.headers.push(header);
+        }
+    }
+    sessions.sort_by_key(|session| session.first);
+    Ok(sessions)
+}
@@ -0,0 +1,44 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Consecutive failed reads after which a sensor counts as lost.
+pub const LOST_AFTER_FAILURES: u32 = 3;
+
+/// Which optional sensors have been dropped from the run, by record field
+/// (`ds18b20_1`, `ds18b20_2`, `battery`), and how many reads in a row
+/// each sensor has failed.
+#[derive(Default)]
+pub struct Availability {
+    dropped: BTreeSet<&'static str>,
+    failures: BTreeMap<&'static str, u32>,
+}
+
+impl Availability {
+    pub fn is_dropped(&self, key: &str) -> bool {
+        self.dropped.contains(key)
+    }
+
+    pub fn dropped(&self) -> Vec<&'static str> {
+        self.dropped.iter().copied().collect()
+    }
+
+    pub fn drop_sensor(&mut self, key: &'static str) {
+        self.dropped.insert(key);
+        self.failures.remove(key);
+    }
+
+    /// Returns true if the sensor had been dropped.
+    pub fn restore(&mut self, key: &str) -> bool {
+        self.dropped.remove(key)
+    }
+
+    /// Counts a failed read; true when it makes the sensor lost.
+    pub fn failed(&mut self, key: &'static str) -> bool {
+        let failures = self.failures.entry(key).or_default();
+        *failures += 1;
+        *failures == LOST_AFTER_FAILURES
+    }
+
+    pub fn succeeded(&mut self, key: &str) {
+        self.failures.remove(key);
+    }
+}
@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+
+use crate::i2c_bus::Bus;
+
+const INA219_BUS_VOLTAGE: u8 = 0x02;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Measurement {
+    /// Pack voltage divided by `cells`.
+    CellVoltage,
+    PackVoltage,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BatteryLevel {
+    /// Entered when the measurement drops below this voltage.
+    pub below_v: f64,
+    pub interval_multiplier: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatteryConfig {
+    /// Fail the startup, or stop the service, when the INA219 cannot be read.
+    pub required: bool,
+    pub bus: u8,
+    pub address: u16,
+    pub cells: u32,
+    pub measurement: Measurement,
+    pub hysteresis_v: f64,
+    pub levels: Vec<BatteryLevel>,
+}
+
+impl Default for BatteryConfig {
+    fn default() -> Self {
+        BatteryConfig {
+            required: false,
+            bus: 1,
+            address: 0x40,
+            cells: 1,
+            measurement: Measurement::CellVoltage,
+            hysteresis_v: 0.05,
+            levels: vec![
+                BatteryLevel { below_v: 3.5, interval_multiplier: 4 },
+                BatteryLevel { below_v: 3.3, interval_multiplier: 12 },
+            ],
+        }
+    }
+}
+
+impl BatteryConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if !(0x40..=0x4F).contains(&self.address) {
+            errors.push(format!("battery.address 0x{:X} non è un indirizzo INA219 (0x40-0x4F)", self.address));
+        }
+        if self.cells == 0 {
+            errors.push("battery.cells deve essere almeno 1".to_string());
+        }
+        if self.hysteresis_v < 0.0 {
+            errors.push("battery.hysteresis_v non può essere negativa".to_string());
+        }
+        for pair in self.levels.windows(2) {
+            if pair[1].below_v >= pair[0].below_v || pair[1].interval_multiplier < pair[0].interval_multiplier {
+                errors.push(
+                    "battery.levels deve avere soglie decrescenti e moltiplicatori non decrescenti".to_string(),
+                );
+                break;
+            }
+        }
+        if self.levels.iter().any(|level| level.interval_multiplier == 0) {
+            errors.push("battery.levels[].interval_multiplier deve essere maggiore di zero".to_string());
+        }
+        errors
+    }
+}
+
+/// Bus voltage of the INA219 in volts (register 0x02, 4 mV per bit).
+pub fn read_pack_voltage(bus: &Bus, config: &BatteryConfig) -> Result<f64, Box<dyn std::error::Error>> {
+    let mut buf = [0u8; 2];
+    bus.with_device(config.address, |i2c| i2c.write_read(&[INA219_BUS_VOLTAGE], &mut buf))?;
+    let raw = u16::from_be_bytes(buf);
+    Ok((raw >> 3) as f64 * 0.004)
+}
+
+#[derive(Debug)]
+pub struct LevelChange {
+    pub from_multiplier: u32,
+    pub to_multiplier: u32,
+    pub voltage: f64,
+}
+
+/// Level 0 is the normal rate; level `n` applies `levels[n - 1]`.
+pub struct BatteryMonitor {
+    config: BatteryConfig,
+    level: usize,
+    voltage: Option<f64>,
+}
+
+impl BatteryMonitor {
+    pub fn new(config: BatteryConfig) -> Self {
+        BatteryMonitor { config, level: 0, voltage: None }
+    }
+
+    pub fn set_config(&mut self, config: BatteryConfig) {
+        self.level = self.level.min(config.levels.len());
+        self.config = config;
+    }
+
+    pub fn config(&self) -> &BatteryConfig {
+        &self.config
+    }
+
+    pub fn voltage(&self) -> Option<f64> {
+        self.voltage
+    }
+
+    pub fn multiplier(&self) -> u32 {
+        match self.level {
+            0 => 1,
+            level => self.config.levels[level - 1].interval_multiplier,
+        }
+    }
+
+    /// Records a pack voltage and moves between levels, leaving one only
+    /// once the measurement is `hysteresis_v` above its threshold.
+    pub fn update(&mut self, pack_voltage: f64) -> Option<LevelChange> {
+        let voltage = match self.config.measurement {
+            Measurement::CellVoltage => pack_voltage / self.config.cells as f64,
+            Measurement::PackVoltage => pack_voltage,
+        };
+        self.voltage = Some(voltage);
+        let from_multiplier = self.multiplier();
+        let levels = &self.config.levels;
+        let mut level = self.level;
+        while level < levels.len() && voltage < levels[level].below_v {
+            level += 1;
+        }
+        while level > 0 && voltage > levels[level - 1].below_v + self.config.hysteresis_v {
+            level -= 1;
+        }
+        if level == self.level {
+            return None;
+        }
+        self.level = level;
+        Some(LevelChange { from_multiplier, to_multiplier: self.multiplier(), voltage })
+    }
+}
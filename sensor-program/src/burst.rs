@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BurstConfig {
+    pub enabled: bool,
+    pub fast_interval_ms: u64,
+    pub duration_secs: u64,
+    pub vertical_speed_threshold: f64,
+    pub on_signal: bool,
+}
+
+impl Default for BurstConfig {
+    fn default() -> Self {
+        BurstConfig {
+            enabled: false,
+            fast_interval_ms: 500,
+            duration_secs: 60,
+            vertical_speed_threshold: 8.0,
+            on_signal: false,
+        }
+    }
+}
+
+impl BurstConfig {
+    /// `ms5611_read_ms` is the time a cycle spends on the MS5611, checked only
+    /// with burst mode enabled.
+    pub fn validate(&self, ms5611_read_ms: u64) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.enabled && self.fast_interval_ms < ms5611_read_ms {
+            errors.push(format!(
+                "burst.fast_interval_ms ({}) è inferiore al tempo di conversione dell'MS5611 ({} ms)",
+                self.fast_interval_ms, ms5611_read_ms
+            ));
+        }
+        if self.vertical_speed_threshold <= 0.0 {
+            errors.push("burst.vertical_speed_threshold deve essere positiva".to_string());
+        }
+        errors
+    }
+}
+
+pub struct BurstMode {
+    config: BurstConfig,
+    until: Option<Instant>,
+}
+
+impl BurstMode {
+    pub fn new(config: BurstConfig) -> Self {
+        BurstMode { config, until: None }
+    }
+
+    pub fn set_config(&mut self, config: BurstConfig) {
+        self.config = config;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.until.is_some()
+    }
+
+    pub fn accepts_signal(&self) -> bool {
+        self.config.enabled && self.config.on_signal
+    }
+
+    pub fn exceeds_threshold(&self, vertical_speed: Option<f64>) -> bool {
+        self.config.enabled && vertical_speed.is_some_and(|v| v.abs() > self.config.vertical_speed_threshold)
+    }
+
+    /// Starts or extends burst mode; returns true if it was not already active.
+    pub fn trigger(&mut self, now: Instant) -> bool {
+        let was_active = self.is_active();
+        self.until = Some(now + Duration::from_secs(self.config.duration_secs));
+        !was_active
+    }
+
+    /// Returns true if burst mode has just ended.
+    pub fn expire(&mut self, now: Instant) -> bool {
+        match self.until {
+            Some(until) if now >= until || !self.config.enabled => {
+                self.until = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn interval(&self, normal: Duration) -> Duration {
+        if self.is_active() {
+            normal.min(Duration::from_millis(self.config.fast_interval_ms))
+        } else {
+            normal
+        }
+    }
+}
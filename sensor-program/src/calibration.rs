@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use toml_edit::{value, DocumentMut, Item, Table};
+
+use crate::config::{Config, Ms5611Config};
+use crate::ds18b20;
+use crate::i2c_bus::Buses;
+use crate::ms5611;
+
+/// Offsets added to the computed values (raw D1/D2 stay untouched).
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CalibrationConfig {
+    pub ms5611_temperature: f64,
+    pub ms5611_pressure: f64,
+    pub ds18b20_1: f64,
+    pub ds18b20_2: f64,
+    /// For the MS5611 under `[voting.secondary]`.
+    pub ms5611_secondary_temperature: f64,
+    pub ms5611_secondary_pressure: f64,
+}
+
+impl CalibrationConfig {
+    pub fn offset(&self, sensor: Sensor) -> f64 {
+        match sensor {
+            Sensor::Ms5611Temperature => self.ms5611_temperature,
+            Sensor::Ms5611Pressure => self.ms5611_pressure,
+            Sensor::Ds18b20First => self.ds18b20_1,
+            Sensor::Ds18b20Second => self.ds18b20_2,
+            Sensor::Ms5611SecondaryTemperature => self.ms5611_secondary_temperature,
+            Sensor::Ms5611SecondaryPressure => self.ms5611_secondary_pressure,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sensor {
+    Ms5611Temperature,
+    Ms5611Pressure,
+    Ds18b20First,
+    Ds18b20Second,
+    Ms5611SecondaryTemperature,
+    Ms5611SecondaryPressure,
+}
+
+impl Sensor {
+    pub const ALL: [Sensor; 6] = [
+        Sensor::Ms5611Temperature,
+        Sensor::Ms5611Pressure,
+        Sensor::Ds18b20First,
+        Sensor::Ds18b20Second,
+        Sensor::Ms5611SecondaryTemperature,
+        Sensor::Ms5611SecondaryPressure,
+    ];
+
+    /// Also the key under `[calibration]`.
+    pub fn key(self) -> &'static str {
+        match self {
+            Sensor::Ms5611Temperature => "ms5611_temperature",
+            Sensor::Ms5611Pressure => "ms5611_pressure",
+            Sensor::Ds18b20First => "ds18b20_1",
+            Sensor::Ds18b20Second => "ds18b20_2",
+            Sensor::Ms5611SecondaryTemperature => "ms5611_secondary_temperature",
+            Sensor::Ms5611SecondaryPressure => "ms5611_secondary_pressure",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Sensor> {
+        Sensor::ALL.into_iter().find(|sensor| sensor.key() == name)
+    }
+
+    /// Standard deviation above which the readings are too noisy to derive
+    /// an offset from.
+    pub fn default_max_stddev(self) -> f64 {
+        match self {
+            Sensor::Ms5611Pressure | Sensor::Ms5611SecondaryPressure => 0.2,
+            _ => 0.1,
+        }
+    }
+
+    /// The MS5611 this sensor is read from; an error for the DS18B20s.
+    fn ms5611(self, config: &Config) -> Result<&Ms5611Config, String> {
+        match self {
+            Sensor::Ms5611Temperature | Sensor::Ms5611Pressure => Ok(&config.ms5611),
+            Sensor::Ms5611SecondaryTemperature | Sensor::Ms5611SecondaryPressure => {
+                config.voting.as_ref().map(|voting| &voting.secondary).ok_or_else(|| "[voting] non configurato".into())
+            }
+            Sensor::Ds18b20First | Sensor::Ds18b20Second => Err("non è un MS5611".into()),
+        }
+    }
+
+    /// One uncalibrated reading.
+    fn read(self, config: &Config, buses: &Buses) -> Result<f64, Box<dyn std::error::Error>> {
+        match self {
+            Sensor::Ms5611Temperature
+            | Sensor::Ms5611Pressure
+            | Sensor::Ms5611SecondaryTemperature
+            | Sensor::Ms5611SecondaryPressure => {
+                let ms5611 = self.ms5611(config)?;
+                let data = ms5611::read_and_calculate(buses.get(ms5611.bus)?, ms5611)?;
+                let pressure = matches!(self, Sensor::Ms5611Pressure | Sensor::Ms5611SecondaryPressure);
+                Ok(if pressure { data.pressure } else { data.temperature })
+            }
+            Sensor::Ds18b20First => Ok(ds18b20::read_temperature(&config.ds18b20.sensor_1)? as f64),
+            Sensor::Ds18b20Second => Ok(ds18b20::read_temperature(&config.ds18b20.sensor_2)? as f64),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Calibration {
+    pub mean: f64,
+    pub stddev: f64,
+    pub offset: f64,
+}
+
+pub fn measure(
+    config: &Config,
+    sensor: Sensor,
+    reference: f64,
+    samples: usize,
+) -> Result<Calibration, Box<dyn std::error::Error>> {
+    let buses = Buses::open(sensor.ms5611(config).ok().map(|ms5611| ms5611.bus));
+    let mut readings = Vec::with_capacity(samples);
+    for index in 0..samples {
+        let reading = sensor.read(config, &buses).map_err(|e| format!("lettura {} di {}: {}", index + 1, samples, e))?;
+        readings.push(reading);
+    }
+    let mean = readings.iter().sum::<f64>() / samples as f64;
+    let variance = readings.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (samples.max(2) - 1) as f64;
+    Ok(Calibration { mean, stddev: variance.sqrt(), offset: reference - mean })
+}
+
+/// Stores the offset under `[calibration]`, keeping the rest of the file
+/// (comments and formatting included) as it is.
+pub fn write_offset(path: &Path, sensor: Sensor, offset: f64) -> Result<(), Box<dyn std::error::Error>> {
+    let content = if path.exists() { fs::read_to_string(path)? } else { String::new() };
+    let mut document: DocumentMut = content.parse()?;
+    let section = document.entry("calibration").or_insert_with(|| Item::Table(Table::new()));
+    let table = section.as_table_mut().ok_or("calibration non è una tabella")?;
+    table[sensor.key()] = value((offset * 10_000.0).round() / 10_000.0);
+
+    let tmp_path = path.with_extension("toml.tmp");
+    fs::write(&tmp_path, document.to_string())?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,734 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::calibration::Sensor;
+use crate::config::DEFAULT_CONFIG_PATH;
+use crate::control::ControlCommand;
+use crate::ms5611::Compensation;
+use crate::overrides::{self, Override};
+use crate::record::RecordLayout;
+use crate::session;
+
+pub const USAGE: &str = "\
+Uso:
+  sensor-program [--config <file>] [--dry-run] [--once] [--async] [--flight-id <id>]
+                 [--set <chiave>=<valore>]... [--print-effective-config] [--tui] [--resume]
+                 [--record-transcript <file>]
+  sensor-program healthcheck --max-age <durata> [--file <percorso>] [--config <file>]
+  sensor-program check-config [--config <file>]
+  sensor-program self-test [--config <file>] [--json]
+  sensor-program schema [--config <file>] [--raw] [--check <file>]
+  sensor-program calibrate --sensor <nome> --reference <valore> [--samples <n>] [--write]
+                 [--max-stddev <valore>] [--config <file>]
+  sensor-program convert --to <nested|flat|parquet> <ingresso|cartella> [--output <file>] [--row-group <n>]
+  sensor-program replay --step <durata> <ingresso> [--output <file>] [--recompute]
+                 [--compensation <first_order|second_order>]
+  sensor-program scan [--bus <n|all>]
+  sensor-program ctl [--config <file>] [--socket <percorso>] <comando>
+  sensor-program analyze <file|cartella>... [--json]
+  sensor-program soak [--records <n>] [--dir <cartella>] [--keep] [--unset-clock <durata>]
+                 [--config <file>] [--async]
+  sensor-program transcript [--bless] <trascrizione>...
+
+--async usa il runtime tokio (richiede la feature tokio-runtime).
+--tui (feature tui) mostra una dashboard aggiornata a ogni ciclo: valori con
+unità, minimo e massimo dall'avvio, andamento recente, stato dei sensori con
+l'ultimo errore, uscite e tempo di attività. q chiude la dashboard e la
+registrazione su file continua (con --dry-run arresta il servizio); Ctrl-C
+arresta il servizio. Non si combina con --once né con --async.
+--flight-id impone l'ID di sessione (lettere, cifre, '-', '_', '.') invece di
+generarne uno o riprendere il precedente entro session.resume_window_secs;
+l'ID sostituisce {session} in output.path ed events.path.
+Con flight_dir.template (es. /data/flights/{date}T{time}_{device}; anche
+{session}) ogni avvio crea la propria cartella, in cui vanno i percorsi
+relativi di output.path, events.path, ring_buffer.dump_dir e delle uscite su
+file, più una copia dell'intestazione (header-<boot_id>.json). Se la cartella
+esiste già o lo spazio libero è sotto flight_dir.min_free_mb il servizio non
+parte (73); --resume continua nella cartella dell'ultimo avvio.
+Ogni chiave della configurazione si può impostare con una variabile
+RUST_SENSORS__<SEZIONE>__<CHIAVE> (es. RUST_SENSORS__OUTPUT__PATH,
+RUST_SENSORS__SINKS__0__PATH per il primo elemento di [[sinks]]) e con
+--set sezione.chiave=valore; i valori sono letterali TOML, altrimenti stringhe.
+L'ordine è: valori predefiniti, file, ambiente, --set. Con SIGUSR1 le
+sostituzioni vengono riapplicate. --print-effective-config stampa ogni chiave
+con il valore risultante e la sua origine (default, file, env, cli) ed esce.
+Le sostituzioni da ambiente valgono anche per gli altri comandi.
+check-config valida la configurazione ed esce (0 se valida, 78 altrimenti).
+self-test verifica sensori, cartelle di uscita e uscite di rete prima del volo.
+schema stampa lo JSON Schema (draft 2020-12) dei record di dati scritti con la
+configurazione: layout, [mapping] (escluso con --raw, come in output.raw),
+output.compact, misure di [[exec]] e sensori abilitati, con unità (x-unit),
+nullabilità e descrizione di ogni campo e la versione dello schema
+(x-schema-version). La stessa configurazione dà sempre lo stesso testo. Con
+--check verifica invece i record di dati del file indicato rispetto allo schema.
+All'avvio e in self-test ogni MS5611 e l'INA219 vengono identificati ([identify]):
+un chip diverso (es. BME280, BMP388, INA226) è trattato come un sensore assente.
+Se all'avvio /dev/i2c-N dell'MS5611 manca o non è accessibile, il servizio
+riprova con attesa crescente fino a i2c.retry_max_secs per i2c.wait_secs
+secondi (0: esce subito) e intanto registra solo i DS18B20 (righe type
+ds18b20_only); la comparsa del bus è registrata come evento i2c_bus.
+Con ds18b20.read_mode = split una sola conversione avvia tutti i DS18B20
+(therm_bulk_read, kernel 5.10 o successivo) e l'MS5611 è letto durante
+l'attesa; senza supporto del kernel si usa la lettura bloccante (blocking,
+predefinita). La modalità in uso è nell'intestazione (ds18b20_read_mode).
+Con [warmup] (samples, secs) i primi cicli dopo l'avvio, la riapertura dei bus
+o la comparsa di un sensore sono marcati warmup (scartati con discard) ed
+esclusi da stato di volo, setpoint, azioni, burst, deadband e telemetria; la
+fine del riscaldamento è registrata come evento warmup.
+Un orologio di sistema prima di time.floor (predefinito: l'ora di compilazione)
+o dell'ultimo record del file di dati non è ancora impostato (es. 1970 senza
+RTC): con time.invalid_clock = tag (predefinito) i record sono marcati
+clock_invalid; con hold restano in memoria (al massimo time.max_held, oltre il
+più vecchio è scritto marcato) e appena l'orologio è impostato sono scritti con
+l'ora ricostruita dall'orologio monotono; off non controlla. Gli eventi
+clock_invalid e clock_correction registrano il comportamento e la correzione.
+Le [[sinks]] con fill_last_good (predefinito per tutte tranne type = file)
+ricevono i record con le misure dei DS18B20 ed exec mancanti o nulle prese
+dall'ultima lettura riuscita, affiancate da <misura>_age_s (secondi); oltre
+last_good.max_age_secs (predefinito 600) la misura resta null. Il file di dati
+non è completato; un sensore escluso o rimosso non è più completato.
+calibrate legge il sensore (ms5611_temperature, ms5611_pressure, ds18b20_1,
+ds18b20_2, ms5611_secondary_temperature, ms5611_secondary_pressure) e calcola
+l'offset rispetto al riferimento; con --write lo salva in [calibration] se la
+deviazione standard è sotto il limite.
+convert riscrive un file NDJSON nel layout indicato (output.layout); senza
+--output scrive su stdout. --to parquet (feature parquet) scrive solo i record
+di dati in --output, a gruppi di --row-group righe (predefinito 10000). Al
+posto del file si può indicare una cartella di volo con un solo file di dati.
+replay ricostruisce una serie regolare da un file filtrato con [deadband],
+ripetendo ogni record ogni --step fino al successivo (campo filled); le righe
+di intestazione (type header) sono copiate e riassunte su stderr. Con
+--recompute temperatura e pressione dell'MS5611 sono ricalcolate da d1/d2 con
+la compensazione di --compensation (predefinita second_order) e i coefficienti
+della PROM nell'intestazione precedente (più la calibrazione che vi è
+registrata), e altitude_m dalla nuova pressione; i record senza
+un'intestazione con la PROM mantengono i valori salvati. Con [voting] si usa
+la PROM del sensore indicato in voting.source. Le righe non valide o che non
+sono record di dati leggibili sono copiate senza modifiche.
+analyze riassume per sessione (session_id) i record di dati dei file indicati,
+esclusi quelli di riscaldamento, con le intestazioni scritte a ogni avvio;
+di una cartella di volo legge i file di dati che contiene.
+soak (feature sim-test) esegue il servizio con sensori simulati e un orologio
+virtuale per --records cicli (predefinito 20000), con i file in --dir (vuota o
+da creare; predefinita una cartella temporanea, rimossa se non c'è --keep o
+un controllo fallito), poi verifica: righe JSON valide, sequenza senza buchi,
+record conformi a schema, timestamp in ordine, file attesi, riepilogo di
+analyze uguale agli aggregati ricalcolati, stati di volo, code e buffer
+circolare entro i limiti, memoria che non cresce. I DS18B20, exec, [[sinks]],
+[[actions]], telemetria, deadband e warmup sono esclusi.
+Con --unset-clock l'orologio virtuale parte dal 1970 ed è impostato dopo la
+durata indicata; il controllo timestamps verifica i record non marcati.
+Con --async il servizio campiona sul runtime tokio (richiede la feature tokio-runtime).
+--record-transcript salva alla fine in <file> (JSON) la configurazione, i cicli
+eseguiti e, per dispositivo, ogni transazione I2C con la risposta ottenuta
+(anche NACK ed errori) e le attese dei driver. transcript (feature sim-test)
+riesegue il servizio su ogni trascrizione, con un orologio virtuale che parte
+da started_at e gli stessi esclusi di soak, e confronta i record di dati con
+quelli attesi (records; esclusi boot_id, time_synced e clock_offset_ms); una
+transazione diversa da quella registrata, o una rimasta inutilizzata, è un
+errore. Nelle trascrizioni si possono introdurre guasti: fault nack o
+{error: messaggio}, una lettura con meno byte di len, stall_ms prima della
+risposta. --bless salva come attesi i record riprodotti, da verificare nel diff.
+scan elenca gli indirizzi che rispondono sul bus I2C indicato (predefinito: tutti
+quelli presenti in /dev).
+Con control.socket il servizio accetta comandi su un socket Unix (permessi
+0660, gruppo control.group), una riga JSON per richiesta e per risposta; la
+richiesta è un oggetto con il solo campo command. I comandi sono health (stato
+del servizio, dei sensori e delle uscite), dump (salva il buffer circolare),
+rotate (chiude e riapre i file di uscita, es. dopo averli spostati; il file di
+dati riparte con l'intestazione), burst, rescan (cerca subito i DS18B20),
+reload (come SIGUSR1) e stop; quelli fuori da control.allow (predefinito:
+tutti) sono rifiutati, come le richieste non valide. Ogni comando accettato è
+registrato come evento control con l'esito. ctl invia un comando al socket di
+control.socket (o --socket) e stampa il risultato.
+
+Codici di uscita (servizio):
+  0   uscita regolare
+  2   --once: almeno una lettura non riuscita
+  64  argomenti non validi
+  69  bus I2C dell'MS5611 o un sensore richiesto non disponibile, all'avvio
+      (dopo i2c.wait_secs se manca /dev/i2c-N) o durante l'esecuzione, o
+      all'indirizzo risponde un altro chip ([identify])
+  73  un'uscita marcata required non può essere aperta, o la cartella di volo
+      esiste già (senza --resume), non si crea o ha poco spazio libero
+  75  un'altra istanza detiene il lock sul file di output
+  78  configurazione non valida
+
+Codici di uscita (healthcheck):
+  0   dati recenti e leggibili
+  1   ultimo record più vecchio di --max-age
+  2   file assente, vuoto o non leggibile
+
+Codici di uscita (self-test):
+  0   tutti i controlli obbligatori superati
+  1   almeno un controllo obbligatorio fallito
+  78  configurazione non valida
+
+Codici di uscita (schema):
+  0   schema stampato, o con --check tutti i record conformi
+  1   --check: almeno un record non conforme
+  64  argomenti non validi
+  74  --check: errore di lettura
+  78  configurazione non valida
+
+Codici di uscita (calibrate):
+  0   offset calcolato (e salvato con --write)
+  1   --write rifiutato: letture troppo instabili
+  2   lettura del sensore non riuscita
+  74  errore di scrittura della configurazione
+
+Codici di uscita (convert):
+  0   conversione completata
+  64  argomenti non validi
+  74  errore di lettura o scrittura
+
+Codici di uscita (replay):
+  0   serie ricostruita
+  64  argomenti non validi
+  74  errore di lettura o scrittura
+
+Codici di uscita (analyze):
+  0   riepilogo stampato
+  64  argomenti non validi
+  74  errore di lettura
+
+Codici di uscita (soak):
+  0   tutti i controlli superati
+  1   almeno un controllo fallito
+  73  cartella non utilizzabile o servizio non avviabile
+  78  configurazione non valida
+
+Codici di uscita (transcript):
+  0   record uguali agli attesi in ogni trascrizione (o salvati con --bless)
+  1   almeno una trascrizione non riprodotta o con record diversi
+  64  argomenti non validi
+
+Codici di uscita (scan):
+  0   almeno un bus analizzato
+  64  argomenti non validi
+  69  nessun bus I2C apribile
+
+Codici di uscita (ctl):
+  0   comando eseguito
+  1   comando rifiutato o non riuscito
+  64  argomenti non validi
+  69  socket non raggiungibile
+  78  configurazione non valida o control.socket non impostato";
+
+pub const EXIT_READ_FAILED: i32 = 2;
+pub const EXIT_USAGE: i32 = 64;
+pub const EXIT_UNAVAILABLE: i32 = 69;
+pub const EXIT_CANT_CREATE: i32 = 73;
+pub const EXIT_IO: i32 = 74;
+pub const EXIT_LOCKED: i32 = 75;
+pub const EXIT_CONFIG: i32 = 78;
+
+pub enum Command {
+    Run(RunOptions),
+    Healthcheck(HealthcheckOptions),
+    CheckConfig(PathBuf),
+    SelfTest(SelfTestOptions),
+    Schema(SchemaOptions),
+    Calibrate(CalibrateOptions),
+    Convert(ConvertOptions),
+    Replay(ReplayOptions),
+    Scan(ScanOptions),
+    Analyze(AnalyzeOptions),
+    Ctl(CtlOptions),
+    #[cfg(feature = "sim-test")]
+    Soak(SoakOptions),
+    #[cfg(feature = "sim-test")]
+    Transcript(TranscriptOptions),
+    Help,
+}
+
+pub struct RunOptions {
+    pub config_path: PathBuf,
+    pub dry_run: bool,
+    pub once: bool,
+    pub async_runtime: bool,
+    pub tui: bool,
+    pub flight_id: Option<String>,
+    /// Goes on in the flight directory of the last run.
+    pub resume: bool,
+    /// From `--set`, applied after the environment.
+    pub overrides: Vec<Override>,
+    pub print_effective_config: bool,
+    /// Where the transactions on the I2C buses are saved at shutdown.
+    pub record_transcript: Option<PathBuf>,
+}
+
+pub struct HealthcheckOptions {
+    pub config_path: PathBuf,
+    pub max_age: Duration,
+    pub file: Option<PathBuf>,
+}
+
+pub struct SelfTestOptions {
+    pub config_path: PathBuf,
+    pub json: bool,
+}
+
+pub struct SchemaOptions {
+    pub config_path: PathBuf,
+    /// The records without `[mapping]`.
+    pub raw: bool,
+    /// A data file to check against the schema instead of printing it.
+    pub check: Option<PathBuf>,
+}
+
+pub struct CalibrateOptions {
+    pub config_path: PathBuf,
+    pub sensor: Sensor,
+    pub reference: f64,
+    pub samples: usize,
+    pub write: bool,
+    pub max_stddev: Option<f64>,
+}
+
+pub enum ConvertTarget {
+    Layout(RecordLayout),
+    #[cfg(feature = "parquet")]
+    Parquet { row_group_rows: usize },
+}
+
+pub struct ConvertOptions {
+    pub to: ConvertTarget,
+    pub input: PathBuf,
+    pub output: Option<PathBuf>,
+}
+
+pub struct ReplayOptions {
+    pub step: Duration,
+    pub input: PathBuf,
+    pub output: Option<PathBuf>,
+    /// With `--recompute`: the compensation the MS5611 values are computed with.
+    pub recompute: Option<Compensation>,
+}
+
+pub struct AnalyzeOptions {
+    pub inputs: Vec<PathBuf>,
+    pub json: bool,
+}
+
+#[cfg(feature = "sim-test")]
+pub struct SoakOptions {
+    pub config_path: PathBuf,
+    pub records: u64,
+    pub dir: PathBuf,
+    pub keep: bool,
+    /// Samples on the tokio runtime, as `--async` does.
+    pub async_runtime: bool,
+    /// The wall clock starts in 1970 and is set after this.
+    pub unset_clock: Duration,
+}
+
+#[cfg(feature = "sim-test")]
+pub struct TranscriptOptions {
+    pub fixtures: Vec<PathBuf>,
+    /// Saves the records replayed as the expected ones.
+    pub bless: bool,
+}
+
+pub struct CtlOptions {
+    pub config_path: PathBuf,
+    /// In place of `control.socket`.
+    pub socket: Option<PathBuf>,
+    pub command: ControlCommand,
+}
+
+pub struct ScanOptions {
+    /// `None` scans every bus.
+    pub bus: Option<u8>,
+}
+
+impl Command {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+        let mut args = args.into_iter().peekable();
+        match args.peek().map(String::as_str) {
+            Some("healthcheck") => {
+                args.next();
+                parse_healthcheck(args).map(Command::Healthcheck)
+            }
+            Some("check-config") => {
+                args.next();
+                parse_check_config(args).map(Command::CheckConfig)
+            }
+            Some("self-test") => {
+                args.next();
+                parse_self_test(args).map(Command::SelfTest)
+            }
+            Some("schema") => {
+                args.next();
+                parse_schema(args).map(Command::Schema)
+            }
+            Some("calibrate") => {
+                args.next();
+                parse_calibrate(args).map(Command::Calibrate)
+            }
+            Some("convert") => {
+                args.next();
+                parse_convert(args).map(Command::Convert)
+            }
+            Some("replay") => {
+                args.next();
+                parse_replay(args).map(Command::Replay)
+            }
+            Some("scan") => {
+                args.next();
+                parse_scan(args).map(Command::Scan)
+            }
+            Some("analyze") => {
+                args.next();
+                parse_analyze(args).map(Command::Analyze)
+            }
+            Some("ctl") => {
+                args.next();
+                parse_ctl(args).map(Command::Ctl)
+            }
+            #[cfg(feature = "sim-test")]
+            Some("soak") => {
+                args.next();
+                parse_soak(args).map(Command::Soak)
+            }
+            #[cfg(not(feature = "sim-test"))]
+            Some("soak") => Err("soak richiede la compilazione con --features sim-test".to_string()),
+            #[cfg(feature = "sim-test")]
+            Some("transcript") => {
+                args.next();
+                parse_transcript(args).map(Command::Transcript)
+            }
+            #[cfg(not(feature = "sim-test"))]
+            Some("transcript") => Err("transcript richiede la compilazione con --features sim-test".to_string()),
+            Some("--help") | Some("-h") => Ok(Command::Help),
+            _ => parse_run(args).map(Command::Run),
+        }
+    }
+}
+
+fn parse_run(mut args: impl Iterator<Item = String>) -> Result<RunOptions, String> {
+    let mut options = RunOptions {
+        config_path: PathBuf::from(DEFAULT_CONFIG_PATH),
+        dry_run: false,
+        once: false,
+        async_runtime: false,
+        tui: false,
+        flight_id: None,
+        resume: false,
+        overrides: Vec::new(),
+        print_effective_config: false,
+        record_transcript: None,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => options.config_path = PathBuf::from(value(&mut args, "--config")?),
+            "--dry-run" => options.dry_run = true,
+            "--once" => options.once = true,
+            "--async" if cfg!(feature = "tokio-runtime") => options.async_runtime = true,
+            "--async" => return Err("--async richiede la compilazione con --features tokio-runtime".to_string()),
+            "--tui" if cfg!(feature = "tui") => options.tui = true,
+            "--tui" => return Err("--tui richiede la compilazione con --features tui".to_string()),
+            "--flight-id" => {
+                let id = value(&mut args, "--flight-id")?;
+                if !session::is_valid_id(&id) {
+                    return Err(format!("ID di sessione non valido: {}", id));
+                }
+                options.flight_id = Some(id);
+            }
+            "--set" => options.overrides.push(overrides::from_cli(&value(&mut args, "--set")?)?),
+            "--print-effective-config" => options.print_effective_config = true,
+            "--resume" => options.resume = true,
+            "--record-transcript" => {
+                options.record_transcript = Some(PathBuf::from(value(&mut args, "--record-transcript")?))
+            }
+            other => return Err(format!("Argomento sconosciuto: {}", other)),
+        }
+    }
+    if options.tui && (options.once || options.async_runtime) {
+        return Err("--tui non si combina con --once né con --async".to_string());
+    }
+    Ok(options)
+}
+
+fn parse_healthcheck(mut args: impl Iterator<Item = String>) -> Result<HealthcheckOptions, String> {
+    let mut config_path = PathBuf::from(DEFAULT_CONFIG_PATH);
+    let mut max_age = None;
+    let mut file = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = PathBuf::from(value(&mut args, "--config")?),
+            "--max-age" => max_age = Some(parse_duration(&value(&mut args, "--max-age")?)?),
+            "--file" => file = Some(PathBuf::from(value(&mut args, "--file")?)),
+            other => return Err(format!("Argomento sconosciuto: {}", other)),
+        }
+    }
+    Ok(HealthcheckOptions {
+        config_path,
+        max_age: max_age.ok_or("healthcheck richiede --max-age")?,
+        file,
+    })
+}
+
+fn parse_check_config(mut args: impl Iterator<Item = String>) -> Result<PathBuf, String> {
+    let mut config_path = PathBuf::from(DEFAULT_CONFIG_PATH);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = PathBuf::from(value(&mut args, "--config")?),
+            other => return Err(format!("Argomento sconosciuto: {}", other)),
+        }
+    }
+    Ok(config_path)
+}
+
+fn parse_self_test(mut args: impl Iterator<Item = String>) -> Result<SelfTestOptions, String> {
+    let mut options = SelfTestOptions { config_path: PathBuf::from(DEFAULT_CONFIG_PATH), json: false };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => options.config_path = PathBuf::from(value(&mut args, "--config")?),
+            "--json" => options.json = true,
+            other => return Err(format!("Argomento sconosciuto: {}", other)),
+        }
+    }
+    Ok(options)
+}
+
+fn parse_schema(mut args: impl Iterator<Item = String>) -> Result<SchemaOptions, String> {
+    let mut options = SchemaOptions { config_path: PathBuf::from(DEFAULT_CONFIG_PATH), raw: false, check: None };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => options.config_path = PathBuf::from(value(&mut args, "--config")?),
+            "--raw" => options.raw = true,
+            "--check" => options.check = Some(PathBuf::from(value(&mut args, "--check")?)),
+            other => return Err(format!("Argomento sconosciuto: {}", other)),
+        }
+    }
+    Ok(options)
+}
+
+fn parse_calibrate(mut args: impl Iterator<Item = String>) -> Result<CalibrateOptions, String> {
+    let mut config_path = PathBuf::from(DEFAULT_CONFIG_PATH);
+    let mut sensor = None;
+    let mut reference = None;
+    let mut samples = 50;
+    let mut write = false;
+    let mut max_stddev = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = PathBuf::from(value(&mut args, "--config")?),
+            "--sensor" => {
+                let name = value(&mut args, "--sensor")?;
+                sensor = Some(Sensor::parse(&name).ok_or_else(|| format!("Sensore sconosciuto: {}", name))?);
+            }
+            "--reference" => reference = Some(number(&value(&mut args, "--reference")?)?),
+            "--samples" => {
+                samples = match value(&mut args, "--samples")?.parse() {
+                    Ok(samples) if samples >= 2 => samples,
+                    _ => return Err("--samples richiede un intero maggiore di 1".to_string()),
+                }
+            }
+            "--write" => write = true,
+            "--max-stddev" => max_stddev = Some(number(&value(&mut args, "--max-stddev")?)?),
+            other => return Err(format!("Argomento sconosciuto: {}", other)),
+        }
+    }
+    Ok(CalibrateOptions {
+        config_path,
+        sensor: sensor.ok_or("calibrate richiede --sensor")?,
+        reference: reference.ok_or("calibrate richiede --reference")?,
+        samples,
+        write,
+        max_stddev,
+    })
+}
+
+fn number(text: &str) -> Result<f64, String> {
+    text.parse().map_err(|_| format!("Numero non valido: {}", text))
+}
+
+fn parse_convert(mut args: impl Iterator<Item = String>) -> Result<ConvertOptions, String> {
+    let mut to = None;
+    let mut input = None;
+    let mut output = None;
+    let mut row_group_rows: Option<usize> = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--to" => to = Some(value(&mut args, "--to")?),
+            "--output" => output = Some(PathBuf::from(value(&mut args, "--output")?)),
+            "--row-group" => {
+                row_group_rows = match value(&mut args, "--row-group")?.parse() {
+                    Ok(rows) if rows > 0 => Some(rows),
+                    _ => return Err("--row-group richiede un intero positivo".to_string()),
+                }
+            }
+            other if other.starts_with("--") || input.is_some() => {
+                return Err(format!("Argomento sconosciuto: {}", other));
+            }
+            other => input = Some(PathBuf::from(other)),
+        }
+    }
+    let to = match to.ok_or("convert richiede --to")?.as_str() {
+        #[cfg(not(feature = "parquet"))]
+        "parquet" => return Err("--to parquet richiede la compilazione con --features parquet".to_string()),
+        #[cfg(feature = "parquet")]
+        "parquet" if output.is_none() => return Err("--to parquet richiede --output".to_string()),
+        #[cfg(feature = "parquet")]
+        "parquet" => ConvertTarget::Parquet {
+            row_group_rows: row_group_rows.unwrap_or(crate::columnar::DEFAULT_ROW_GROUP_ROWS),
+        },
+        _ if row_group_rows.is_some() => return Err("--row-group vale solo con --to parquet".to_string()),
+        "nested" => ConvertTarget::Layout(RecordLayout::Nested),
+        "flat" => ConvertTarget::Layout(RecordLayout::Flat),
+        other => return Err(format!("Formato sconosciuto: {} (nested, flat o parquet)", other)),
+    };
+    Ok(ConvertOptions { to, input: input.ok_or("convert richiede il file di ingresso")?, output })
+}
+
+fn parse_replay(mut args: impl Iterator<Item = String>) -> Result<ReplayOptions, String> {
+    let mut step = None;
+    let mut input = None;
+    let mut output = None;
+    let mut recompute = false;
+    let mut compensation = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--step" => step = Some(parse_duration(&value(&mut args, "--step")?)?),
+            "--output" => output = Some(PathBuf::from(value(&mut args, "--output")?)),
+            "--recompute" => recompute = true,
+            "--compensation" => {
+                compensation = Some(match value(&mut args, "--compensation")?.as_str() {
+                    "first_order" => Compensation::FirstOrder,
+                    "second_order" => Compensation::SecondOrder,
+                    other => return Err(format!("Compensazione sconosciuta: {} (first_order o second_order)", other)),
+                })
+            }
+            other if other.starts_with("--") || input.is_some() => {
+                return Err(format!("Argomento sconosciuto: {}", other));
+            }
+            other => input = Some(PathBuf::from(other)),
+        }
+    }
+    let step = step.ok_or("replay richiede --step")?;
+    if step.is_zero() {
+        return Err("--step deve essere maggiore di zero".to_string());
+    }
+    let recompute = match (recompute, compensation) {
+        (true, compensation) => Some(compensation.unwrap_or(Compensation::SecondOrder)),
+        (false, None) => None,
+        (false, Some(_)) => return Err("--compensation vale solo con --recompute".to_string()),
+    };
+    Ok(ReplayOptions { step, input: input.ok_or("replay richiede il file di ingresso")?, output, recompute })
+}
+
+fn parse_analyze(args: impl Iterator<Item = String>) -> Result<AnalyzeOptions, String> {
+    let mut options = AnalyzeOptions { inputs: Vec::new(), json: false };
+    for arg in args {
+        match arg.as_str() {
+            "--json" => options.json = true,
+            other if other.starts_with("--") => return Err(format!("Argomento sconosciuto: {}", other)),
+            other => options.inputs.push(PathBuf::from(other)),
+        }
+    }
+    if options.inputs.is_empty() {
+        return Err("analyze richiede almeno un file".to_string());
+    }
+    Ok(options)
+}
+
+#[cfg(feature = "sim-test")]
+fn parse_soak(mut args: impl Iterator<Item = String>) -> Result<SoakOptions, String> {
+    let mut options = SoakOptions {
+        config_path: PathBuf::from(DEFAULT_CONFIG_PATH),
+        records: 20_000,
+        dir: std::env::temp_dir().join(format!("sensor-program-soak-{}", std::process::id())),
+        keep: false,
+        async_runtime: false,
+        unset_clock: Duration::ZERO,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => options.config_path = PathBuf::from(value(&mut args, "--config")?),
+            "--records" => {
+                options.records = match value(&mut args, "--records")?.parse() {
+                    Ok(records) if records > 0 => records,
+                    _ => return Err("--records richiede un intero positivo".to_string()),
+                }
+            }
+            "--dir" => options.dir = PathBuf::from(value(&mut args, "--dir")?),
+            "--keep" => options.keep = true,
+            "--async" if cfg!(feature = "tokio-runtime") => options.async_runtime = true,
+            "--async" => return Err("--async richiede la compilazione con --features tokio-runtime".to_string()),
+            "--unset-clock" => options.unset_clock = parse_duration(&value(&mut args, "--unset-clock")?)?,
+            other => return Err(format!("Argomento sconosciuto: {}", other)),
+        }
+    }
+    Ok(options)
+}
+
+#[cfg(feature = "sim-test")]
+fn parse_transcript(args: impl Iterator<Item = String>) -> Result<TranscriptOptions, String> {
+    let mut options = TranscriptOptions { fixtures: Vec::new(), bless: false };
+    for arg in args {
+        match arg.as_str() {
+            "--bless" => options.bless = true,
+            other if other.starts_with("--") => return Err(format!("Argomento sconosciuto: {}", other)),
+            _ => options.fixtures.push(PathBuf::from(arg)),
+        }
+    }
+    if options.fixtures.is_empty() {
+        return Err("transcript richiede almeno una trascrizione".to_string());
+    }
+    Ok(options)
+}
+
+fn parse_scan(mut args: impl Iterator<Item = String>) -> Result<ScanOptions, String> {
+    let mut options = ScanOptions { bus: None };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bus" => {
+                options.bus = match value(&mut args, "--bus")?.as_str() {
+                    "all" => None,
+                    number => Some(number.parse().map_err(|_| format!("Bus I2C non valido: {}", number))?),
+                }
+            }
+            other => return Err(format!("Argomento sconosciuto: {}", other)),
+        }
+    }
+    Ok(options)
+}
+
+fn parse_ctl(mut args: impl Iterator<Item = String>) -> Result<CtlOptions, String> {
+    let mut config_path = PathBuf::from(DEFAULT_CONFIG_PATH);
+    let mut socket = None;
+    let mut command = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = PathBuf::from(value(&mut args, "--config")?),
+            "--socket" => socket = Some(PathBuf::from(value(&mut args, "--socket")?)),
+            other if other.starts_with("--") => return Err(format!("Argomento sconosciuto: {}", other)),
+            _ if command.is_some() => return Err("ctl accetta un solo comando".to_string()),
+            name => {
+                command = Some(ControlCommand::parse(name).ok_or_else(|| {
+                    format!("Comando sconosciuto: {} (ammessi {})", name, ControlCommand::names())
+                })?)
+            }
+        }
+    }
+    let command = command.ok_or_else(|| format!("ctl richiede un comando ({})", ControlCommand::names()))?;
+    Ok(CtlOptions { config_path, socket, command })
+}
+
+fn value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} richiede un valore", flag))
+}
+
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let (number, multiplier) = match text.char_indices().last() {
+        Some((i, 's')) => (&text[..i], 1),
+        Some((i, 'm')) => (&text[..i], 60),
+        Some((i, 'h')) => (&text[..i], 3600),
+        _ => (text, 1),
+    };
+    let invalid = || format!("Durata non valida: {} (esempi: 30s, 5m, 1h)", text);
+    let seconds: u64 = number.parse().map_err(|_| invalid())?;
+    seconds.checked_mul(multiplier).map(Duration::from_secs).ok_or_else(invalid)
+}
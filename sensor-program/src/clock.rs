@@ -0,0 +1,90 @@
+use chrono::{DateTime, Utc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where the sampling loop reads the time and waits. The service runs on
+/// `SystemClock`; with the `sim-test` feature the soak harness runs it on a
+/// `VirtualClock`, so hours of sampling take seconds.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn utc(&self) -> DateTime<Utc>;
+    fn sleep(&self, duration: Duration);
+
+    fn since(&self, earlier: Instant) -> Duration {
+        self.now().saturating_duration_since(earlier)
+    }
+
+    /// How long a timer outside the clock, like the tokio runtime's, has
+    /// to run to reach `deadline`.
+    #[cfg(feature = "tokio-runtime")]
+    fn until(&self, deadline: Instant) -> Duration {
+        deadline.saturating_duration_since(self.now())
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Time that moves only when something sleeps on it: monotonic and wall
+/// clock advance together from the moment the clock is created.
+#[cfg(feature = "sim-test")]
+pub struct VirtualClock {
+    start: Instant,
+    start_utc: DateTime<Utc>,
+    elapsed: std::sync::Mutex<Duration>,
+    unset_for: Duration,
+}
+
+#[cfg(feature = "sim-test")]
+impl VirtualClock {
+    /// A wall clock that starts in 1970, as on a board without RTC, and is
+    /// set once `duration` has elapsed.
+    pub fn unset_for(duration: Duration) -> Self {
+        VirtualClock { start: Instant::now(), start_utc: Utc::now(), elapsed: Default::default(), unset_for: duration }
+    }
+
+    /// A wall clock already set, to `start_utc`.
+    pub fn starting_at(start_utc: DateTime<Utc>) -> Self {
+        VirtualClock { start: Instant::now(), start_utc, elapsed: Default::default(), unset_for: Duration::ZERO }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(feature = "sim-test")]
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        let elapsed = self.elapsed();
+        if elapsed < self.unset_for { DateTime::UNIX_EPOCH + elapsed } else { self.start_utc + elapsed }
+    }
+
+    fn sleep(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap_or_else(|e| e.into_inner()) += duration;
+    }
+
+    /// None: the clock moves to `deadline` at once.
+    #[cfg(feature = "tokio-runtime")]
+    fn until(&self, deadline: Instant) -> Duration {
+        self.sleep(deadline.saturating_duration_since(self.now()));
+        Duration::ZERO
+    }
+}
@@ -0,0 +1,231 @@
+use parquet::basic::Compression;
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DataType, DoubleType, FloatType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedColumnWriter, SerializedFileWriter};
+use parquet::schema::parser::parse_message_type;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::record::SensorData;
+
+/// Columns in the order `write_column` fills them. `ds18b20_extra`, `exec`,
+/// `capture_offsets_ms`, `acquisition_offsets_ms`, `acquired_at` and
+/// `timing` have open-ended keys and are left out;
+/// `suspect` is joined with commas.
+const SCHEMA: &str = "
+message sensor_data {
+    required int64 timestamp (TIMESTAMP(MICROS,true));
+    required binary session_id (STRING);
+    required binary boot_id (STRING);
+    required int64 sequence;
+    optional boolean time_synced;
+    optional double clock_offset_ms;
+    required int64 ms5611_d1;
+    required int64 ms5611_d2;
+    required double ms5611_temperature;
+    required double ms5611_pressure;
+    optional float ds18b20_1;
+    optional float ds18b20_2;
+    required double altitude_m;
+    optional double vertical_speed_ms;
+    required binary flight_state (STRING);
+    required boolean burst_mode;
+    optional binary suspect (STRING);
+    required boolean filled;
+    required boolean warmup;
+    required boolean clock_invalid;
+}";
+
+pub const DEFAULT_ROW_GROUP_ROWS: usize = 10_000;
+
+/// Buffers records and writes one row group every `row_group_rows`.
+/// `finish` must be called to write the last group and the footer.
+pub struct ParquetWriter {
+    writer: SerializedFileWriter<File>,
+    rows: Vec<SensorData>,
+    row_group_rows: usize,
+}
+
+impl ParquetWriter {
+    pub fn create(path: &Path, row_group_rows: usize) -> Result<Self, Box<dyn std::error::Error>> {
+        let schema = Arc::new(parse_message_type(SCHEMA)?);
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(row_group_rows)
+            .build();
+        let writer = SerializedFileWriter::new(File::create(path)?, schema, Arc::new(properties))?;
+        Ok(ParquetWriter { writer, rows: Vec::with_capacity(row_group_rows), row_group_rows })
+    }
+
+    pub fn push(&mut self, record: SensorData) -> Result<(), Box<dyn std::error::Error>> {
+        self.rows.push(record);
+        if self.rows.len() >= self.row_group_rows {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.flush()?;
+        self.writer.close()?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let rows = std::mem::take(&mut self.rows);
+        let mut group = self.writer.next_row_group()?;
+        let mut index = 0;
+        while let Some(mut column) = group.next_column()? {
+            write_column(&mut column, index, &rows)?;
+            column.close()?;
+            index += 1;
+        }
+        group.close()?;
+        Ok(())
+    }
+}
+
+fn write_column(column: &mut SerializedColumnWriter, index: usize, rows: &[SensorData]) -> parquet::errors::Result<()> {
+    let text = |value: &str| ByteArray::from(value);
+    match index {
+        0 => required::<Int64Type>(column, rows.iter().map(|r| r.timestamp.timestamp_micros()).collect()),
+        1 => required::<ByteArrayType>(column, rows.iter().map(|r| text(&r.session_id)).collect()),
+        2 => required::<ByteArrayType>(column, rows.iter().map(|r| text(&r.boot_id)).collect()),
+        3 => required::<Int64Type>(column, rows.iter().map(|r| r.sequence as i64).collect()),
+        4 => optional::<BoolType>(column, rows.iter().map(|r| r.time_synced).collect()),
+        5 => optional::<DoubleType>(column, rows.iter().map(|r| r.clock_offset_ms).collect()),
+        6 => required::<Int64Type>(column, rows.iter().map(|r| r.ms5611.d1 as i64).collect()),
+        7 => required::<Int64Type>(column, rows.iter().map(|r| r.ms5611.d2 as i64).collect()),
+        8 => required::<DoubleType>(column, rows.iter().map(|r| r.ms5611.temperature).collect()),
+        9 => required::<DoubleType>(column, rows.iter().map(|r| r.ms5611.pressure).collect()),
+        10 => optional::<FloatType>(column, rows.iter().map(|r| r.ds18b20_1.flatten()).collect()),
+        11 => optional::<FloatType>(column, rows.iter().map(|r| r.ds18b20_2.flatten()).collect()),
+        12 => required::<DoubleType>(column, rows.iter().map(|r| r.altitude_m).collect()),
+        13 => optional::<DoubleType>(column, rows.iter().map(|r| r.vertical_speed_ms).collect()),
+        14 => required::<ByteArrayType>(column, rows.iter().map(|r| text(&flight_state_name(r))).collect()),
+        15 => required::<BoolType>(column, rows.iter().map(|r| r.burst_mode).collect()),
+        16 => optional::<ByteArrayType>(
+            column,
+            rows.iter().map(|r| (!r.suspect.is_empty()).then(|| text(&r.suspect.join(",")))).collect(),
+        ),
+        17 => required::<BoolType>(column, rows.iter().map(|r| r.filled).collect()),
+        18 => required::<BoolType>(column, rows.iter().map(|r| r.warmup).collect()),
+        19 => required::<BoolType>(column, rows.iter().map(|r| r.clock_invalid).collect()),
+        _ => Err(parquet::errors::ParquetError::General(format!("colonna {} non prevista", index))),
+    }
+}
+
+/// The name used in the JSON records, e.g. "preflight".
+fn flight_state_name(record: &SensorData) -> String {
+    match serde_json::to_value(record.flight_state) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => format!("{:?}", record.flight_state),
+    }
+}
+
+fn required<T: DataType>(column: &mut SerializedColumnWriter, values: Vec<T::T>) -> parquet::errors::Result<()> {
+    column.typed::<T>().write_batch(&values, None, None)?;
+    Ok(())
+}
+
+/// Missing values become nulls through the definition levels.
+fn optional<T: DataType>(
+    column: &mut SerializedColumnWriter,
+    values: Vec<Option<T::T>>,
+) -> parquet::errors::Result<()> {
+    let levels: Vec<i16> = values.iter().map(|value| value.is_some() as i16).collect();
+    let present: Vec<T::T> = values.into_iter().flatten().collect();
+    column.typed::<T>().write_batch(&present, Some(&levels), None)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::Field;
+    use std::fs::{self, File};
+
+    use super::ParquetWriter;
+    use crate::flight::FlightState;
+    use crate::record::{MS5611Data, SensorData};
+
+    fn records() -> Vec<SensorData> {
+        (0..5)
+            .map(|index| SensorData {
+                timestamp: DateTime::UNIX_EPOCH + Duration::days(20_000) + Duration::milliseconds(5_000 * index),
+                session_id: "20240904T000000Z".to_string(),
+                boot_id: "boot-1".to_string(),
+                sequence: index as u64,
+                time_synced: (index != 1).then_some(index % 2 == 0),
+                clock_offset_ms: (index == 2).then_some(-1.25),
+                ms5611: MS5611Data {
+                    d1: 9_085_466 + index as u32,
+                    d2: 8_569_150,
+                    temperature: 21.5 + index as f64 / 100.0,
+                    pressure: 1013.25 - index as f64,
+                    ..MS5611Data::default()
+                },
+                ds18b20_1: Some((index != 3).then_some(-12.5)),
+                ds18b20_2: None,
+                altitude_m: 8.25 * index as f64,
+                vertical_speed_ms: (index > 0).then_some(1.65),
+                flight_state: if index < 3 { FlightState::Preflight } else { FlightState::Ascent },
+                burst_mode: index == 4,
+                suspect: if index == 3 { vec!["ms5611_d1".to_string(), "ds18b20_1".to_string()] } else { Vec::new() },
+                filled: index == 1,
+                warmup: index == 0,
+                clock_invalid: false,
+                ..SensorData::example()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_back_what_it_wrote() {
+        let path = std::env::temp_dir().join(format!("sensor-program-columnar-{}.parquet", std::process::id()));
+        let mut writer = ParquetWriter::create(&path, 2).unwrap();
+        for record in records() {
+            writer.push(record).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().num_row_groups(), 3);
+        let rows: Vec<_> = reader.get_row_iter(None).unwrap().map(Result::unwrap).collect();
+        fs::remove_file(&path).unwrap();
+        let records = records();
+        assert_eq!(rows.len(), records.len());
+        let text = |value: &str| Field::Str(value.to_string());
+        for (row, record) in rows.iter().zip(&records) {
+            let expected = vec![
+                Field::TimestampMicros(record.timestamp.timestamp_micros()),
+                text(&record.session_id),
+                text(&record.boot_id),
+                Field::Long(record.sequence as i64),
+                record.time_synced.map_or(Field::Null, Field::Bool),
+                record.clock_offset_ms.map_or(Field::Null, Field::Double),
+                Field::Long(record.ms5611.d1.into()),
+                Field::Long(record.ms5611.d2.into()),
+                Field::Double(record.ms5611.temperature),
+                Field::Double(record.ms5611.pressure),
+                record.ds18b20_1.flatten().map_or(Field::Null, Field::Float),
+                Field::Null,
+                Field::Double(record.altitude_m),
+                record.vertical_speed_ms.map_or(Field::Null, Field::Double),
+                text(if record.flight_state == FlightState::Preflight { "preflight" } else { "ascent" }),
+                Field::Bool(record.burst_mode),
+                if record.suspect.is_empty() { Field::Null } else { text("ms5611_d1,ds18b20_1") },
+                Field::Bool(record.filled),
+                Field::Bool(record.warmup),
+                Field::Bool(record.clock_invalid),
+            ];
+            let fields: Vec<Field> = row.get_column_iter().map(|(_, field)| field.clone()).collect();
+            assert_eq!(fields, expected, "sequence {}", record.sequence);
+        }
+    }
+}
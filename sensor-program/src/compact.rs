@@ -0,0 +1,200 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::record;
+use crate::units::{self, Unit};
+
+/// Shorter data records for tight links, applied when a record is
+/// serialized for the sinks; the values the service computes with keep
+/// their full resolution. With `enabled`, numbers are rounded to the
+/// decimals of their unit, keys are sorted at every level whatever the
+/// layout, and with `omit_nulls` null fields are left out, which makes a
+/// failed read look like a disabled sensor.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompactConfig {
+    pub enabled: bool,
+    pub omit_nulls: bool,
+    /// Decimals by unit, for the fields listed in the header's `units`;
+    /// numbers in a unit missing here keep every digit.
+    pub decimals: BTreeMap<Unit, usize>,
+    /// Decimals by flat-layout key, over the ones of the unit.
+    pub fields: BTreeMap<String, usize>,
+}
+
+impl Default for CompactConfig {
+    fn default() -> Self {
+        let decimals = [
+            (Unit::Celsius, 3),
+            (Unit::Fahrenheit, 3),
+            (Unit::Hectopascal, 2),
+            (Unit::Pascal, 0),
+            (Unit::InchOfMercury, 3),
+            (Unit::Metre, 2),
+            (Unit::Foot, 1),
+            (Unit::MetrePerSecond, 2),
+            (Unit::FootPerSecond, 2),
+            (Unit::Millisecond, 1),
+            (Unit::Volt, 3),
+        ];
+        CompactConfig { enabled: false, omit_nulls: false, decimals: decimals.into(), fields: BTreeMap::new() }
+    }
+}
+
+impl CompactConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let units = self.decimals.iter().map(|(unit, decimals)| (format!("decimals.{}", unit), decimals));
+        let fields = self.fields.iter().map(|(key, decimals)| (format!("fields.{}", key), decimals));
+        units
+            .chain(fields)
+            .filter(|(_, decimals)| **decimals > 9)
+            .map(|(key, decimals)| format!("output.compact.{}: {} decimali, al massimo 9", key, decimals))
+            .collect()
+    }
+
+    /// `record` as it has to be serialized. Lines with a `"type"` field
+    /// (events, header, status) are left as they are.
+    pub fn apply(&self, record: Value, units: &BTreeMap<String, Unit>) -> Value {
+        let Value::Object(fields) = record else {
+            return record;
+        };
+        if !self.enabled || fields.contains_key("type") {
+            return Value::Object(fields);
+        }
+        let mut flat = record::flatten(fields);
+        if self.omit_nulls {
+            flat.retain(|_, value| !value.is_null());
+        }
+        for (key, value) in flat.iter_mut() {
+            if let Some(decimals) = self.decimals_for(key, units) {
+                round(value, decimals);
+            }
+        }
+        // Back to the layout it came in; a map serializes sorted.
+        Value::Object(record::unflatten(flat))
+    }
+
+    fn decimals_for(&self, key: &str, units: &BTreeMap<String, Unit>) -> Option<usize> {
+        if let Some(decimals) = self.fields.get(key) {
+            return Some(*decimals);
+        }
+        self.decimals.get(&units::unit_for(units, key)?).copied()
+    }
+}
+
+/// Rounds a float, or the floats of an array, to `decimals`; integers
+/// and everything else are kept. With no decimals the float becomes an
+/// integer, and -0 becomes 0, both to save the bytes.
+fn round(value: &mut Value, decimals: usize) {
+    match value {
+        Value::Number(number) if number.is_f64() => {
+            let scale = 10f64.powi(decimals as i32);
+            let Some(rounded) = number.as_f64().map(|v| (v * scale).round() / scale + 0.0) else {
+                return;
+            };
+            if decimals == 0 && rounded.abs() < i64::MAX as f64 {
+                *number = (rounded as i64).into();
+            } else if let Some(rounded) = serde_json::Number::from_f64(rounded) {
+                *number = rounded;
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|value| round(value, decimals)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::CompactConfig;
+    use crate::record::{MS5611Data, RecordLayout, SensorData};
+    use crate::timing::CycleTiming;
+    use crate::units;
+    use crate::voting::{PressureSource, VotingData};
+
+    fn record() -> SensorData {
+        SensorData {
+            timestamp: "2026-06-01T10:42:10.123456789Z".parse().unwrap(),
+            session_id: "20260601T103000Z".to_string(),
+            boot_id: "boot-1".to_string(),
+            sequence: 181,
+            time_synced: None,
+            clock_offset_ms: None,
+            ms5611: MS5611Data {
+                d1: 9_085_466,
+                d2: 8_569_150,
+                temperature: 20.071234,
+                pressure: 1000.0949,
+                ..MS5611Data::default()
+            },
+            ds18b20_1: Some(Some(-12.5625)),
+            ds18b20_2: Some(None),
+            ds18b20_extra: BTreeMap::new(),
+            exec: [("co2_ppm".to_string(), Some(412.345678))].into(),
+            altitude_m: 113.45678,
+            vertical_speed_ms: Some(-0.004),
+            suspect: Vec::new(),
+            filled: false,
+            warmup: false,
+            clock_invalid: false,
+            capture_offsets_ms: None,
+            acquisition_offsets_ms: None,
+            acquired_at: None,
+            timing: Some(CycleTiming { start_lateness_ms: 0.04, ms5611_ms: 160.0512, ..CycleTiming::default() }),
+            voting: None,
+            ..SensorData::example()
+        }
+    }
+
+    #[test]
+    fn writes_the_same_bytes_for_the_same_record() {
+        let compact = CompactConfig { enabled: true, omit_nulls: true, ..CompactConfig::default() };
+        let value = compact.apply(serde_json::to_value(record()).unwrap(), &units::record_units(&[]));
+        // Sorted keys, the null DS18B20 left out, -0.004 m/s down to 0 and
+        // the exec measurement, which has no unit, kept whole.
+        let nested = concat!(
+            r#"{"altitude_m":113.46,"boot_id":"boot-1","burst_mode":false,"ds18b20_1":-12.563,"#,
+            r#""exec":{"co2_ppm":412.345678},"flight_state":"preflight","#,
+            r#""ms5611":{"d1":9085466,"d2":8569150,"pressure":1000.09,"temperature":20.071},"#,
+            r#""sequence":181,"session_id":"20260601T103000Z","timestamp":"2026-06-01T10:42:10.123456789Z","#,
+            r#""timing":{"derived_ms":0.0,"ms5611_ms":160.1,"start_lateness_ms":0.0},"vertical_speed_ms":0.0}"#,
+        );
+        assert_eq!(serde_json::to_string(&value).unwrap(), nested);
+        let flat = concat!(
+            r#"{"altitude_m":113.46,"boot_id":"boot-1","burst_mode":false,"ds18b20_1":-12.563,"#,
+            r#""exec_co2_ppm":412.345678,"flight_state":"preflight","#,
+            r#""ms5611_d1":9085466,"ms5611_d2":8569150,"ms5611_pressure":1000.09,"ms5611_temperature":20.071,"#,
+            r#""sequence":181,"session_id":"20260601T103000Z","timestamp":"2026-06-01T10:42:10.123456789Z","#,
+            r#""timing_derived_ms":0.0,"timing_ms5611_ms":160.1,"timing_start_lateness_ms":0.0,"#,
+            r#""vertical_speed_ms":0.0}"#,
+        );
+        assert_eq!(serde_json::to_string(&RecordLayout::Flat.apply(value)).unwrap(), flat);
+    }
+
+    #[test]
+    fn rounds_the_voting_readings() {
+        let reading = |pressure| MS5611Data {
+            d1: 9_085_466,
+            d2: 8_569_150,
+            temperature: 20.071234,
+            pressure,
+            ..MS5611Data::default()
+        };
+        let voting = VotingData {
+            source: PressureSource::Ms5611,
+            ms5611: Some(reading(1000.0949)),
+            ms5611_secondary: Some(reading(1000.0051)),
+            difference_hpa: Some(0.0898),
+            disagreement: false,
+        };
+        let compact = CompactConfig { enabled: true, ..CompactConfig::default() };
+        let record = SensorData { voting: Some(voting), ..record() };
+        let value = compact.apply(serde_json::to_value(record).unwrap(), &units::record_units(&[]));
+        let expected = r#"{"d1":9085466,"d2":8569150,"pressure":1000.01,"temperature":20.071}"#;
+        assert_eq!(serde_json::to_string(&value["voting"]["ms5611_secondary"]).unwrap(), expected);
+        assert_eq!(value["voting"]["ms5611"]["pressure"], 1000.09);
+        assert_eq!(value["voting"]["difference_hpa"], 0.09);
+    }
+}
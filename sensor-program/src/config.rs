@@ -0,0 +1,624 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::acquisition::AcquisitionConfig;
+use crate::actions::{self, ActionConfig};
+use crate::battery::BatteryConfig;
+use crate::burst::BurstConfig;
+use crate::calibration::CalibrationConfig;
+use crate::compact::CompactConfig;
+use crate::control::ControlConfig;
+use crate::deadband::DeadbandConfig;
+use crate::ds18b20::ReadMode;
+use crate::exec::{self, ExecConfig};
+use crate::flight::FlightConfig;
+use crate::flight_dir::{self, FlightDirConfig};
+use crate::i2c_bus::I2cConfig;
+use crate::identify::IdentifyConfig;
+use crate::last_good::LastGoodConfig;
+use crate::mapping::MappingConfig;
+use crate::ms5611::{self, Aggregation, Compensation};
+use crate::overrides::{self, Override};
+use crate::pipeline::QueueConfig;
+use crate::record::RecordLayout;
+use crate::ringbuffer::RingBufferConfig;
+use crate::session::{self, SessionConfig};
+use crate::setpoints::{self, SetpointConfig};
+use crate::sinks::{LineTemplate, SinkConfig, SinkKind};
+use crate::stuck::StuckConfig;
+use crate::telemetry::{TelemetryConfig, TelemetrySinkConfig};
+use crate::timesync::TimeConfig;
+use crate::timing::StatusConfig;
+use crate::voting::VotingConfig;
+use crate::warmup::WarmupConfig;
+use crate::watchdog::WatchdogConfig;
+
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Keys whose values are replaced by `REDACTED` in `Config::snapshot`.
+const SECRET_KEYS: [&str; 3] = ["password", "secret", "token"];
+const REDACTED: &str = "***";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub sampling: SamplingConfig,
+    pub output: OutputConfig,
+    pub ms5611: Ms5611Config,
+    pub ds18b20: Ds18b20Config,
+    pub events: EventsConfig,
+    pub flight: FlightConfig,
+    pub burst: BurstConfig,
+    pub ring_buffer: RingBufferConfig,
+    pub status: StatusConfig,
+    pub stuck: StuckConfig,
+    pub time: TimeConfig,
+    pub session: SessionConfig,
+    pub calibration: CalibrationConfig,
+    pub telemetry: Option<TelemetryConfig>,
+    pub battery: Option<BatteryConfig>,
+    pub deadband: Option<DeadbandConfig>,
+    pub mapping: MappingConfig,
+    pub sinks: Vec<SinkConfig>,
+    pub actions: Vec<ActionConfig>,
+    pub exec: Vec<ExecConfig>,
+    pub watchdog: WatchdogConfig,
+    pub setpoints: Vec<SetpointConfig>,
+    pub voting: Option<VotingConfig>,
+    pub identify: IdentifyConfig,
+    pub i2c: I2cConfig,
+    pub acquisition: AcquisitionConfig,
+    pub warmup: WarmupConfig,
+    pub flight_dir: FlightDirConfig,
+    pub last_good: LastGoodConfig,
+    pub control: ControlConfig,
+}
+
+/// A loaded configuration with what it was built from.
+pub struct Layers {
+    pub config: Config,
+    /// The file as written, before any override.
+    pub file: toml::Table,
+    pub applied: Vec<Override>,
+}
+
+impl Layers {
+    /// Every key with its effective value and origin, one per line.
+    pub fn describe(&self) -> String {
+        overrides::describe(&self.config.snapshot(), &self.file, &self.applied)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SamplingConfig {
+    pub interval_secs: u64,
+    pub gap_factor: f64,
+    pub capture_offset_threshold_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub path: String,
+    pub layout: RecordLayout,
+    /// Write data records without `[mapping]` applied.
+    pub raw: bool,
+    pub queue: QueueConfig,
+    pub compact: CompactConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    pub path: String,
+    pub inline: bool,
+    pub queue: QueueConfig,
+    /// An error repeated identically by the same sensor is logged once
+    /// per this many seconds, with a count; 0 logs every occurrence.
+    pub repeat_window_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ms5611Config {
+    pub bus: u8,
+    pub address: u16,
+    /// Conversion pairs taken back to back in every cycle and aggregated.
+    pub samples_per_cycle: u32,
+    pub aggregation: Aggregation,
+    /// Add the raw values of every conversion to the record.
+    pub include_samples: bool,
+    pub compensation: Compensation,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ds18b20Config {
+    pub sensor_1: String,
+    pub sensor_2: String,
+    /// A disabled probe is never read and has no field in the records.
+    pub sensor_1_enabled: bool,
+    pub sensor_2_enabled: bool,
+    /// A required probe missing at startup stops the service; an optional
+    /// one is dropped from the run.
+    pub sensor_1_required: bool,
+    pub sensor_2_required: bool,
+    /// Seconds between rescans of the 1-Wire bus; 0 scans only at startup
+    /// and on SIGUSR1.
+    pub scan_interval_secs: u64,
+    pub read_mode: ReadMode,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        SamplingConfig { interval_secs: 5, gap_factor: 2.0, capture_offset_threshold_ms: 250 }
+    }
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            path: "sensor_data_{session}.json".to_string(),
+            layout: RecordLayout::default(),
+            raw: false,
+            queue: QueueConfig::default(),
+            compact: CompactConfig::default(),
+        }
+    }
+}
+
+impl OutputConfig {
+    pub fn path_for(&self, session: &str) -> String {
+        session::expand(&self.path, session)
+    }
+}
+
+impl EventsConfig {
+    pub fn path_for(&self, session: &str) -> String {
+        session::expand(&self.path, session)
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        EventsConfig {
+            path: "sensor_events_{session}.json".to_string(),
+            inline: false,
+            queue: QueueConfig::default(),
+            repeat_window_secs: 3600,
+        }
+    }
+}
+
+impl Default for Ms5611Config {
+    fn default() -> Self {
+        Ms5611Config {
+            bus: 1,
+            address: 0x77,
+            samples_per_cycle: 1,
+            aggregation: Aggregation::default(),
+            include_samples: false,
+            compensation: Compensation::default(),
+        }
+    }
+}
+
+/// One of the two configured DS18B20s.
+pub struct Probe<'a> {
+    /// `sensor_1` or `sensor_2`.
+    pub key: &'static str,
+    /// The record field, `ds18b20_1` or `ds18b20_2`.
+    pub field: &'static str,
+    pub name: &'static str,
+    pub id: &'a str,
+    pub enabled: bool,
+    pub required: bool,
+}
+
+impl Ds18b20Config {
+    pub fn probes(&self) -> [Probe<'_>; 2] {
+        [
+            Probe {
+                key: "sensor_1",
+                field: "ds18b20_1",
+                name: "DS18B20 1",
+                id: &self.sensor_1,
+                enabled: self.sensor_1_enabled,
+                required: self.sensor_1_required,
+            },
+            Probe {
+                key: "sensor_2",
+                field: "ds18b20_2",
+                name: "DS18B20 2",
+                id: &self.sensor_2,
+                enabled: self.sensor_2_enabled,
+                required: self.sensor_2_required,
+            },
+        ]
+    }
+}
+
+impl Default for Ds18b20Config {
+    fn default() -> Self {
+        Ds18b20Config {
+            sensor_1: "28-277a480a6461".to_string(),
+            sensor_2: "28-7c7a480a6461".to_string(),
+            sensor_1_enabled: true,
+            sensor_2_enabled: true,
+            sensor_1_required: false,
+            sensor_2_required: false,
+            scan_interval_secs: 60,
+            read_mode: ReadMode::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, Box<dyn std::error::Error>> {
+        Ok(Config::load_layers(path, &[])?.config)
+    }
+
+    /// Reads `path` if it exists, then applies the `RUST_SENSORS__*`
+    /// variables and finally `cli`. Without a file or overrides the defaults
+    /// are used as they are.
+    pub fn load_layers(path: &Path, cli: &[Override]) -> Result<Layers, Box<dyn std::error::Error>> {
+        let mut applied = overrides::from_env();
+        applied.extend(cli.iter().cloned());
+        let (mut config, file) = if path.exists() {
+            let content = fs::read_to_string(path)?;
+            let config: Config = toml::from_str(&content)
+                .map_err(|e| format!("Configurazione non valida in {}: {}", path.display(), e))?;
+            (config, content.parse::<toml::Table>().unwrap_or_default())
+        } else if applied.is_empty() {
+            return Ok(Layers { config: Config::default(), file: toml::Table::new(), applied });
+        } else {
+            (Config::default(), toml::Table::new())
+        };
+        let mut merged = file.clone();
+        for layer in &applied {
+            config = layer.merge(&mut merged).map_err(|e| format!("Configurazione non valida: {}", e))?;
+        }
+        let mut errors = config.validate();
+        errors.extend(config.check_paths());
+        if !errors.is_empty() {
+            return Err(format!(
+                "Configurazione non valida in {}:\n  {}",
+                path.display(),
+                errors.join("\n  ")
+            )
+            .into());
+        }
+        Ok(Layers { config, file, applied })
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.sampling.interval_secs == 0 {
+            errors.push("sampling.interval_secs deve essere maggiore di zero".to_string());
+        }
+        if self.sampling.gap_factor < 1.0 {
+            errors.push("sampling.gap_factor deve essere almeno 1".to_string());
+        }
+        if self.output.path.is_empty() {
+            errors.push("output.path non può essere vuoto".to_string());
+        }
+        if self.events.path.is_empty() {
+            errors.push("events.path non può essere vuoto".to_string());
+        } else if self.events.path == self.output.path {
+            errors.push("events.path deve essere diverso da output.path (usare events.inline)".to_string());
+        }
+        if self.session.state_file.is_empty() {
+            errors.push("session.state_file non può essere vuoto".to_string());
+        }
+        for (key, queue) in [("output.queue", &self.output.queue), ("events.queue", &self.events.queue)] {
+            if queue.capacity == 0 {
+                errors.push(format!("{}.capacity deve essere maggiore di zero", key));
+            }
+        }
+        if ![0x76, 0x77].contains(&self.ms5611.address) {
+            errors.push(format!(
+                "ms5611.address 0x{:X} non valido: l'MS5611 risponde solo a 0x76 o 0x77",
+                self.ms5611.address
+            ));
+        }
+        let read_time_ms = ms5611::read_time_ms(self.ms5611.samples_per_cycle);
+        if self.ms5611.samples_per_cycle == 0 || self.ms5611.samples_per_cycle > ms5611::MAX_SAMPLES_PER_CYCLE {
+            errors.push(format!(
+                "ms5611.samples_per_cycle deve essere tra 1 e {}",
+                ms5611::MAX_SAMPLES_PER_CYCLE
+            ));
+        } else if self.sampling.interval_secs * 1000 < read_time_ms {
+            errors.push(format!(
+                "sampling.interval_secs ({} s) è inferiore al tempo di lettura dell'MS5611 con {} conversioni ({} ms)",
+                self.sampling.interval_secs, self.ms5611.samples_per_cycle, read_time_ms
+            ));
+        }
+        for probe in self.ds18b20.probes() {
+            if probe.enabled && !is_ds18b20_id(probe.id) {
+                let (key, id) = (probe.key, probe.id);
+                errors.push(format!("ds18b20.{} \"{}\" non è un ID DS18B20 (atteso 28-xxxxxxxxxxxx)", key, id));
+            }
+            if !probe.enabled && probe.required {
+                errors.push(format!("ds18b20.{}: un sensore disattivato non può essere richiesto", probe.key));
+            }
+        }
+        let ds18b20 = &self.ds18b20;
+        if ds18b20.sensor_1_enabled && ds18b20.sensor_2_enabled && ds18b20.sensor_1 == ds18b20.sensor_2 {
+            errors.push("ds18b20.sensor_1 e ds18b20.sensor_2 indicano lo stesso sensore".to_string());
+        }
+        errors.extend(self.flight.validate());
+        errors.extend(self.burst.validate(read_time_ms));
+        if self.time.sync_poll_secs == 0 {
+            errors.push("time.sync_poll_secs deve essere maggiore di zero".to_string());
+        }
+        if self.time.step_threshold_secs <= 0.0 {
+            errors.push("time.step_threshold_secs deve essere positiva".to_string());
+        }
+        if self.status.interval_secs == 0 {
+            errors.push("status.interval_secs deve essere maggiore di zero".to_string());
+        }
+        if self.ring_buffer.capacity == 0 || self.ring_buffer.max_bytes == 0 {
+            errors.push("ring_buffer.capacity e ring_buffer.max_bytes devono essere maggiori di zero".to_string());
+        }
+        if let Some(telemetry) = &self.telemetry {
+            errors.extend(telemetry.validate());
+        }
+        if let Some(battery) = &self.battery {
+            errors.extend(battery.validate());
+        }
+        if let Some(deadband) = &self.deadband {
+            errors.extend(deadband.validate());
+        }
+        errors.extend(self.mapping.validate());
+        let setpoint_names: Vec<_> = self.setpoints.iter().map(|setpoint| setpoint.name.as_str()).collect();
+        errors.extend(actions::validate(&self.actions, &setpoint_names));
+        errors.extend(setpoints::validate(&self.setpoints));
+        errors.extend(exec::validate(&self.exec, self.sampling.interval_secs * 1000));
+        errors.extend(self.watchdog.validate());
+        errors.extend(self.i2c.validate());
+        errors.extend(self.flight_dir.validate());
+        errors.extend(self.control.validate());
+        errors.extend(self.output.compact.validate());
+        if let Some(voting) = &self.voting {
+            errors.extend(voting.validate(&self.ms5611, self.sampling.interval_secs * 1000));
+        }
+        let mut names = vec!["data", "events", "telemetry"];
+        let mut files = vec![&self.output.path, &self.events.path];
+        for sink in &self.sinks {
+            if sink.name.is_empty() || names.contains(&sink.name.as_str()) {
+                errors.push(format!("sinks: nome \"{}\" vuoto o duplicato", sink.name));
+            }
+            names.push(&sink.name);
+            if sink.queue.capacity == 0 {
+                errors.push(format!("sinks.{}.queue.capacity deve essere maggiore di zero", sink.name));
+            }
+            match &sink.kind {
+                SinkKind::File { path } => {
+                    if path.is_empty() || files.contains(&path) {
+                        errors.push(format!(
+                            "sinks.{}.path \"{}\" vuoto o già usato da un'altra uscita",
+                            sink.name, path
+                        ));
+                    }
+                    files.push(path);
+                }
+                SinkKind::Udp { address } | SinkKind::Tcp { address } => {
+                    if !is_host_port(address) {
+                        let name = &sink.name;
+                        errors.push(format!("sinks.{}.address \"{}\" non è nella forma host:porta", name, address));
+                    }
+                }
+                SinkKind::Serial { device, baud, format, every, .. } => {
+                    if device.is_empty() {
+                        errors.push(format!("sinks.{}.device non può essere vuoto", sink.name));
+                    }
+                    if *baud == 0 || *every == 0 {
+                        errors.push(format!("sinks.{}: baud ed every devono essere maggiori di zero", sink.name));
+                    }
+                    if let Err(e) = LineTemplate::parse(format) {
+                        errors.push(format!("sinks.{}.format: {}", sink.name, e));
+                    }
+                    if sink.include_events {
+                        errors.push(format!("sinks.{}.include_events non vale per type = \"serial\"", sink.name));
+                    }
+                }
+            }
+        }
+        errors
+    }
+
+    /// The configuration as JSON for the header record, with the value of
+    /// every key that looks like a credential replaced.
+    pub fn snapshot(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        redact(&mut value);
+        value
+    }
+
+    /// The I2C devices in use as (config section, bus, address).
+    pub fn i2c_devices(&self) -> Vec<(&'static str, u8, u16)> {
+        let mut devices = vec![("ms5611", self.ms5611.bus, self.ms5611.address)];
+        if let Some(battery) = &self.battery {
+            devices.push(("battery", battery.bus, battery.address));
+        }
+        if let Some(voting) = &self.voting {
+            devices.push(("voting.secondary", voting.secondary.bus, voting.secondary.address));
+        }
+        devices
+    }
+
+    /// Checks that every file the service writes can be created, naming the
+    /// config key of each directory that is missing or read-only.
+    pub fn check_paths(&self) -> Vec<String> {
+        if self.flight_dir.is_enabled() {
+            // The files go in a directory created at startup, under its base.
+            let mut files = Config { flight_dir: FlightDirConfig::default(), ..self.clone() };
+            flight_dir::relocate(&mut files, &self.flight_dir.base());
+            return files.check_paths();
+        }
+        let mut targets = vec![
+            ("output.path", parent_dir(&self.output.path)),
+            ("events.path", parent_dir(&self.events.path)),
+            ("flight.state_file", parent_dir(&self.flight.state_file)),
+            ("session.state_file", parent_dir(&self.session.state_file)),
+            ("ring_buffer.dump_dir", Path::new(&self.ring_buffer.dump_dir)),
+        ];
+        if let Some(TelemetryConfig { sink: TelemetrySinkConfig::File { path }, .. }) = &self.telemetry {
+            targets.push(("telemetry.sink.path", parent_dir(path)));
+        }
+        for sink in &self.sinks {
+            if let SinkKind::File { path } = &sink.kind {
+                targets.push(("sinks[].path", parent_dir(path)));
+            }
+        }
+        targets
+            .into_iter()
+            .filter_map(|(key, dir)| {
+                let problem = match fs::metadata(dir) {
+                    Err(e) => e.to_string(),
+                    Ok(metadata) if !metadata.is_dir() => "non è una cartella".to_string(),
+                    Ok(metadata) if metadata.permissions().readonly() => "non è scrivibile".to_string(),
+                    Ok(_) => return None,
+                };
+                Some(format!("{}: cartella {} non utilizzabile: {}", key, dir.display(), problem))
+            })
+            .collect()
+    }
+
+    /// Applies the runtime-safe parts of `new` and returns the changes that
+    /// were left untouched because they need a restart.
+    pub fn apply_reload(&mut self, new: Config) -> Vec<&'static str> {
+        let mut restart_required = Vec::new();
+        if new.ms5611.bus != self.ms5611.bus || new.ms5611.address != self.ms5611.address {
+            restart_required.push("ms5611 (bus/indirizzo)");
+        }
+        let probes = Ds18b20Config { scan_interval_secs: self.ds18b20.scan_interval_secs, ..new.ds18b20.clone() };
+        if probes != self.ds18b20 {
+            restart_required.push("ds18b20 (sensori)");
+        }
+        if (OutputConfig { compact: self.output.compact.clone(), ..new.output.clone() }) != self.output {
+            restart_required.push("output (path/layout/raw/queue)");
+        }
+
+        if new.events.path != self.events.path || new.events.queue != self.events.queue {
+            restart_required.push("events.path/events.queue");
+        }
+        if new.ring_buffer.dump_dir != self.ring_buffer.dump_dir {
+            restart_required.push("ring_buffer.dump_dir");
+        }
+        if new.flight.state_file != self.flight.state_file {
+            restart_required.push("flight.state_file");
+        }
+        if new.session.state_file != self.session.state_file {
+            restart_required.push("session.state_file");
+        }
+        if new.watchdog != self.watchdog {
+            restart_required.push("watchdog");
+        }
+        if new.i2c != self.i2c {
+            restart_required.push("i2c");
+        }
+        if new.identify != self.identify {
+            restart_required.push("identify");
+        }
+        if new.flight_dir != self.flight_dir {
+            restart_required.push("flight_dir");
+        }
+        if new.control != self.control {
+            restart_required.push("control");
+        }
+
+        self.sampling = new.sampling;
+        self.output.compact = new.output.compact;
+        self.ms5611 = Ms5611Config { bus: self.ms5611.bus, address: self.ms5611.address, ..new.ms5611 };
+        self.ds18b20.scan_interval_secs = new.ds18b20.scan_interval_secs;
+        self.events.inline = new.events.inline;
+        self.events.repeat_window_secs = new.events.repeat_window_secs;
+        self.burst = new.burst;
+        self.status = new.status;
+        self.stuck = new.stuck;
+        self.time = new.time;
+        self.session.resume_window_secs = new.session.resume_window_secs;
+        self.calibration = new.calibration;
+        self.exec = new.exec;
+        self.setpoints = new.setpoints;
+        self.acquisition = new.acquisition;
+        self.warmup = new.warmup;
+        self.last_good = new.last_good;
+        self.voting = match (&self.voting, new.voting) {
+            (Some(current), Some(new)) => {
+                let secondary = &current.secondary;
+                if new.secondary.bus != secondary.bus || new.secondary.address != secondary.address {
+                    restart_required.push("voting.secondary (bus/indirizzo)");
+                }
+                let secondary = Ms5611Config { bus: secondary.bus, address: secondary.address, ..new.secondary };
+                Some(VotingConfig { secondary, ..new })
+            }
+            (None, None) => None,
+            (current, _) => {
+                restart_required.push("voting");
+                current.clone()
+            }
+        };
+        self.battery = match (&self.battery, new.battery) {
+            (Some(current), Some(new)) if new.bus != current.bus => {
+                restart_required.push("battery.bus");
+                Some(BatteryConfig { bus: current.bus, ..new })
+            }
+            (_, new) => new,
+        };
+        self.deadband = new.deadband;
+        self.ring_buffer = RingBufferConfig { dump_dir: self.ring_buffer.dump_dir.clone(), ..new.ring_buffer };
+        self.flight = FlightConfig { state_file: self.flight.state_file.clone(), ..new.flight };
+        match (&mut self.telemetry, new.telemetry) {
+            (Some(current), Some(new)) if current.sink == new.sink && current.queue == new.queue => *current = new,
+            (None, None) => {}
+            _ => restart_required.push("telemetry.sink/telemetry.queue"),
+        }
+        if new.mapping != self.mapping {
+            restart_required.push("mapping");
+        }
+        if new.actions != self.actions {
+            restart_required.push("actions");
+        }
+        if new.sinks != self.sinks {
+            restart_required.push("sinks");
+        }
+        restart_required
+    }
+}
+
+fn redact(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                if SECRET_KEYS.iter().any(|secret| key.to_lowercase().contains(secret)) {
+                    *value = serde_json::Value::from(REDACTED);
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn parent_dir(path: &str) -> &Path {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+fn is_ds18b20_id(id: &str) -> bool {
+    id.strip_prefix("28-").is_some_and(|serial| serial.len() == 12 && serial.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn is_host_port(address: &str) -> bool {
+    address
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok_and(|port| port > 0))
+}
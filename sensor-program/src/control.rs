@@ -0,0 +1,352 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::ffi::CString;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// Longest request line accepted.
+const MAX_LINE: u64 = 1024;
+/// How long a connection may stay silent before it is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a request waits for the sampling loop, which takes it between
+/// cycles.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `[control]`: commands from scripts on a Unix socket, one JSON object
+/// per line each way, e.g. `{"command": "dump"}`. The socket is only
+/// accessible to the service's user and `group` (mode 0660).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    /// Empty: no socket.
+    pub socket: String,
+    /// The commands accepted; the others are refused.
+    pub allow: Vec<ControlCommand>,
+    /// Group the socket is given; empty keeps the service's.
+    pub group: String,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig { socket: String::new(), allow: ControlCommand::ALL.to_vec(), group: String::new() }
+    }
+}
+
+impl ControlConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if !self.group.is_empty() && group_id(&self.group).is_none() {
+            errors.push(format!("control.group: gruppo {} inesistente", self.group));
+        }
+        errors
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.socket.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlCommand {
+    /// State of the service, sensors and outputs.
+    Health,
+    /// Saves the ring buffer.
+    Dump,
+    /// Closes every output file and opens it again at its path.
+    Rotate,
+    /// Starts or extends burst mode.
+    Burst,
+    /// Scans the 1-Wire bus for DS18B20s now.
+    Rescan,
+    /// Reloads the configuration, as SIGUSR1.
+    Reload,
+    Stop,
+}
+
+impl ControlCommand {
+    pub const ALL: [ControlCommand; 7] = [
+        ControlCommand::Health,
+        ControlCommand::Dump,
+        ControlCommand::Rotate,
+        ControlCommand::Burst,
+        ControlCommand::Rescan,
+        ControlCommand::Reload,
+        ControlCommand::Stop,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ControlCommand::Health => "health",
+            ControlCommand::Dump => "dump",
+            ControlCommand::Rotate => "rotate",
+            ControlCommand::Burst => "burst",
+            ControlCommand::Rescan => "rescan",
+            ControlCommand::Reload => "reload",
+            ControlCommand::Stop => "stop",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<ControlCommand> {
+        ControlCommand::ALL.into_iter().find(|command| command.name() == name)
+    }
+
+    pub fn names() -> String {
+        ControlCommand::ALL.map(ControlCommand::name).join(", ")
+    }
+}
+
+/// An accepted command, for the sampling loop to run and answer.
+pub struct Request {
+    pub command: ControlCommand,
+    reply: Sender<Result<Value, String>>,
+}
+
+impl Request {
+    pub fn reply(self, result: Result<Value, String>) {
+        // The client may have given up waiting.
+        let _ = self.reply.send(result);
+    }
+}
+
+/// The socket, served on its own threads; the requests they accept wait
+/// in `requests`. The socket file is removed on drop.
+pub struct ControlServer {
+    path: PathBuf,
+    requests: Receiver<Request>,
+}
+
+impl ControlServer {
+    pub fn try_recv(&self) -> Option<Request> {
+        self.requests.try_recv().ok()
+    }
+
+    /// `None` when nothing came within `timeout`.
+    #[cfg(feature = "tokio-runtime")]
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Request> {
+        self.requests.recv_timeout(timeout).ok()
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Binds `config.socket`, replacing a socket left by a previous run but
+/// not one still in use.
+pub fn listen(config: &ControlConfig) -> Result<ControlServer, String> {
+    let path = PathBuf::from(&config.socket);
+    let describe = |e: std::io::Error| format!("{}: {}", path.display(), e);
+    if let Ok(metadata) = fs::symlink_metadata(&path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("{} esiste e non è un socket", path.display()));
+        }
+        if UnixStream::connect(&path).is_ok() {
+            return Err(format!("{} è già in uso da un altro processo", path.display()));
+        }
+        fs::remove_file(&path).map_err(describe)?;
+    }
+    // Bound in a directory only the service can enter and moved into place
+    // once restricted, so that it is never reachable with the umask's mode.
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let private = path.with_file_name(format!(".{}.{}", name, std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&private).map_err(|e| format!("{}: {}", private.display(), e))?;
+    let staged = private.join("socket");
+    let bound = (|| {
+        let listener = UnixListener::bind(&staged)?;
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o660))?;
+        if !config.group.is_empty() {
+            let missing = || std::io::Error::other(format!("gruppo {} inesistente", config.group));
+            let gid = group_id(&config.group).ok_or_else(missing)?;
+            std::os::unix::fs::chown(&staged, None, Some(gid))?;
+        }
+        fs::rename(&staged, &path)?;
+        Ok(listener)
+    })();
+    let _ = fs::remove_dir_all(&private);
+    let listener = bound.map_err(describe)?;
+    let (sender, requests) = mpsc::channel();
+    // From here an error removes the socket file.
+    let server = ControlServer { path: path.clone(), requests };
+
+    let allow = config.allow.clone();
+    thread::Builder::new()
+        .name("control".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let (sender, allow) = (sender.clone(), allow.clone());
+                let spawned = thread::Builder::new().name("control-conn".to_string()).spawn(move || {
+                    if let Err(e) = serve(stream, &sender, &allow) {
+                        println!("Connessione di controllo chiusa: {}", e);
+                    }
+                });
+                if let Err(e) = spawned {
+                    println!("Connessione di controllo rifiutata: {}", e);
+                }
+            }
+        })
+        .map_err(|e| e.to_string())?;
+    Ok(server)
+}
+
+/// Answers every line of a connection until the client closes it.
+fn serve(stream: UnixStream, sender: &Sender<Request>, allow: &[ControlCommand]) -> Result<(), String> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT)).map_err(|e| e.to_string())?;
+    let mut writer = stream.try_clone().map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = String::new();
+        let read = reader.by_ref().take(MAX_LINE).read_line(&mut line);
+        let response = match read {
+            Ok(0) => return Ok(()),
+            Ok(_) if !line.ends_with('\n') && line.len() as u64 >= MAX_LINE => {
+                let response = refusal(None, &format!("richiesta oltre {} byte", MAX_LINE));
+                let _ = writeln!(writer, "{}", response);
+                return Err("richiesta troppo lunga".to_string());
+            }
+            Ok(_) if line.trim().is_empty() => continue,
+            Ok(_) => answer(line.trim(), sender, allow),
+            Err(e) => {
+                let _ = writeln!(writer, "{}", refusal(None, &format!("richiesta non leggibile: {}", e)));
+                return Err(e.to_string());
+            }
+        };
+        writeln!(writer, "{}", response).map_err(|e| e.to_string())?;
+    }
+}
+
+fn answer(line: &str, sender: &Sender<Request>, allow: &[ControlCommand]) -> Value {
+    let command = match parse_request(line) {
+        Ok(command) => command,
+        Err(e) => return refusal(None, &e),
+    };
+    if !allow.contains(&command) {
+        return refusal(Some(command), "comando non consentito (control.allow)");
+    }
+    let (reply, replies) = mpsc::channel();
+    if sender.send(Request { command, reply }).is_err() {
+        return refusal(Some(command), "servizio in arresto");
+    }
+    match replies.recv_timeout(REPLY_TIMEOUT) {
+        Ok(Ok(result)) => json!({ "ok": true, "command": command.name(), "result": result }),
+        Ok(Err(e)) => refusal(Some(command), &e),
+        Err(RecvTimeoutError::Timeout) => {
+            refusal(Some(command), &format!("nessuna risposta dal servizio entro {} s", REPLY_TIMEOUT.as_secs()))
+        }
+        Err(RecvTimeoutError::Disconnected) => refusal(Some(command), "servizio in arresto"),
+    }
+}
+
+fn parse_request(line: &str) -> Result<ControlCommand, String> {
+    let value: Value = serde_json::from_str(line).map_err(|e| format!("JSON non valido: {}", e))?;
+    let Value::Object(fields) = value else {
+        return Err("la richiesta deve essere un oggetto JSON".to_string());
+    };
+    if let Some(key) = fields.keys().find(|key| *key != "command") {
+        return Err(format!("campo {} sconosciuto", key));
+    }
+    let name = fields.get("command").and_then(Value::as_str).ok_or("campo command mancante o non testuale")?;
+    ControlCommand::parse(name)
+        .ok_or_else(|| format!("comando {} sconosciuto (ammessi {})", name, ControlCommand::names()))
+}
+
+fn refusal(command: Option<ControlCommand>, error: &str) -> Value {
+    json!({ "ok": false, "command": command.map(ControlCommand::name), "error": error })
+}
+
+fn group_id(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    // SAFETY: getgrnam takes a NUL-terminated string; the entry is read
+    // before any other call could overwrite it.
+    let group = unsafe { libc::getgrnam(name.as_ptr()) };
+    (!group.is_null()).then(|| unsafe { (*group).gr_gid })
+}
+
+/// The client side, for `ctl`: sends `command` and returns the response.
+pub fn send(socket: &Path, command: ControlCommand) -> Result<Value, String> {
+    let describe = |e: std::io::Error| format!("{}: {}", socket.display(), e);
+    let mut stream = UnixStream::connect(socket).map_err(describe)?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT + Duration::from_secs(5))).map_err(describe)?;
+    writeln!(stream, "{}", json!({ "command": command.name() })).map_err(describe)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).map_err(describe)?;
+    serde_json::from_str(&line).map_err(|e| format!("risposta non valida: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::{listen, parse_request, serve, ControlCommand, ControlConfig, MAX_LINE};
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert_eq!(parse_request(r#"{"command": "dump"}"#), Ok(ControlCommand::Dump));
+        assert!(parse_request("{\"command\": ").unwrap_err().starts_with("JSON non valido"));
+        assert_eq!(parse_request(r#"["dump"]"#), Err("la richiesta deve essere un oggetto JSON".to_string()));
+        assert_eq!(parse_request(r#""dump""#), Err("la richiesta deve essere un oggetto JSON".to_string()));
+        assert_eq!(parse_request(r#"{"command": "dump", "force": true}"#), Err("campo force sconosciuto".to_string()));
+        assert_eq!(parse_request("{}"), Err("campo command mancante o non testuale".to_string()));
+        assert_eq!(parse_request(r#"{"command": 1}"#), Err("campo command mancante o non testuale".to_string()));
+        let unknown = parse_request(r#"{"command": "format"}"#).unwrap_err();
+        assert!(unknown.starts_with("comando format sconosciuto"), "{}", unknown);
+    }
+
+    #[test]
+    fn refuses_a_command_not_allowed_and_closes_on_an_over_long_line() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let (sender, _requests) = mpsc::channel();
+        let served = thread::spawn(move || serve(server, &sender, &[ControlCommand::Health]));
+        let mut writer = client.try_clone().unwrap();
+        let mut reader = BufReader::new(client);
+        let mut response = || {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            serde_json::from_str::<Value>(&line).unwrap()
+        };
+        writeln!(writer, "{}", json!({ "command": "stop" })).unwrap();
+        let refused = json!({ "ok": false, "command": "stop", "error": "comando non consentito (control.allow)" });
+        assert_eq!(response(), refused);
+        writeln!(writer, "{}", "x".repeat(MAX_LINE as usize * 2)).unwrap();
+        let too_long = format!("richiesta oltre {} byte", MAX_LINE);
+        assert_eq!(response(), json!({ "ok": false, "command": null, "error": too_long }));
+        assert_eq!(served.join().unwrap(), Err("richiesta troppo lunga".to_string()));
+    }
+
+    #[test]
+    fn binds_the_socket_with_mode_0660_only() {
+        let dir = std::env::temp_dir().join(format!("sensor-program-control-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("control.sock");
+        let config = ControlConfig { socket: socket.display().to_string(), ..ControlConfig::default() };
+        let server = listen(&config).unwrap();
+        assert_eq!(fs::metadata(&socket).unwrap().permissions().mode() & 0o777, 0o660);
+        // Nothing is left of the directory it was bound in.
+        let entries: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(entries, ["control.sock"]);
+        assert!(UnixStream::connect(&socket).is_ok());
+        let in_use = listen(&config).err().unwrap_or_default();
+        assert!(in_use.ends_with("è già in uso da un altro processo"), "{}", in_use);
+        drop(server);
+        assert!(!socket.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
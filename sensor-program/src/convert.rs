@@ -0,0 +1,91 @@
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use crate::cli::{ConvertOptions, ConvertTarget};
+use crate::flight_dir;
+use crate::record::{RecordLayout, SensorData};
+
+/// Rewrites every line of `options.input`, a file or a flight directory
+/// with a single data file, in the requested layout and returns how many
+/// lines were converted. Lines that are not valid JSON are copied
+/// unchanged with a warning on stderr.
+pub fn convert(options: &ConvertOptions) -> Result<usize, Box<dyn std::error::Error>> {
+    let input_path = flight_dir::single_input(&options.input)?;
+    if options.output.as_ref() == Some(&input_path) {
+        return Err("il file di uscita deve essere diverso da quello di ingresso".into());
+    }
+    #[cfg_attr(not(feature = "parquet"), allow(clippy::infallible_destructuring_match))]
+    let layout = match options.to {
+        ConvertTarget::Layout(layout) => layout,
+        #[cfg(feature = "parquet")]
+        ConvertTarget::Parquet { row_group_rows } => return to_parquet(&input_path, options, row_group_rows),
+    };
+    let input = BufReader::new(File::open(&input_path)?);
+    let mut output: Box<dyn Write> = match &options.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    let mut converted = 0;
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        match serde_json::from_str::<Value>(&line) {
+            Ok(value) => {
+                writeln!(output, "{}", to_layout(layout, value)?)?;
+                converted += 1;
+            }
+            Err(e) => {
+                eprintln!("Riga {} non valida, copiata senza modifiche: {}", index + 1, e);
+                writeln!(output, "{}", line)?;
+            }
+        }
+    }
+    output.flush()?;
+    Ok(converted)
+}
+
+fn to_layout(layout: RecordLayout, value: Value) -> serde_json::Result<String> {
+    let value = layout.apply(value);
+    // Going back through SensorData restores the field order the service writes.
+    if layout == RecordLayout::Nested
+        && value.get("type").is_none()
+        && let Ok(record) = serde_json::from_value::<SensorData>(value.clone())
+    {
+        return serde_json::to_string(&record);
+    }
+    serde_json::to_string(&value)
+}
+
+/// Writes the data records of `input` as Parquet; status, gap and event
+/// lines have a different shape and are skipped.
+#[cfg(feature = "parquet")]
+fn to_parquet(
+    input: &std::path::Path,
+    options: &ConvertOptions,
+    row_group_rows: usize,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let output = options.output.as_ref().ok_or("--to parquet richiede --output")?;
+    let input = BufReader::new(File::open(input)?);
+    let mut writer = crate::columnar::ParquetWriter::create(output, row_group_rows)?;
+    let mut converted = 0;
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        let record = serde_json::from_str::<Value>(&line)
+            .map_err(|e| e.to_string())
+            .and_then(|value| match value.get("type") {
+                Some(kind) => Err(format!("record di tipo {}", kind)),
+                None => {
+                    serde_json::from_value::<SensorData>(RecordLayout::Nested.apply(value)).map_err(|e| e.to_string())
+                }
+            });
+        match record {
+            Ok(record) => {
+                writer.push(record)?;
+                converted += 1;
+            }
+            Err(e) => eprintln!("Riga {} ignorata: {}", index + 1, e),
+        }
+    }
+    writer.finish()?;
+    Ok(converted)
+}
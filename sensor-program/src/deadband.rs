@@ -0,0 +1,231 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::flight::FlightState;
+use crate::record::SensorData;
+
+/// A record is written once a measurement moves by more than its delta
+/// since the last written record, or after `heartbeat_secs` regardless.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeadbandConfig {
+    pub heartbeat_secs: u64,
+    pub ms5611_pressure: f64,
+    pub ms5611_temperature: f64,
+    /// Applies to every DS18B20, discovered ones included.
+    pub ds18b20: f64,
+    /// Applies to the `[[exec]]` measurements not in `exec_measurements`;
+    /// their units vary, so by default any change is written.
+    pub exec: f64,
+    /// Delta by record name of an `[[exec]]` measurement.
+    pub exec_measurements: BTreeMap<String, f64>,
+}
+
+impl Default for DeadbandConfig {
+    fn default() -> Self {
+        DeadbandConfig {
+            heartbeat_secs: 900,
+            ms5611_pressure: 0.1,
+            ms5611_temperature: 0.1,
+            ds18b20: 0.1,
+            exec: 0.0,
+            exec_measurements: BTreeMap::new(),
+        }
+    }
+}
+
+impl DeadbandConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.heartbeat_secs == 0 {
+            errors.push("deadband.heartbeat_secs deve essere maggiore di zero".to_string());
+        }
+        let mut deltas = [self.ms5611_pressure, self.ms5611_temperature, self.ds18b20, self.exec]
+            .into_iter()
+            .chain(self.exec_measurements.values().copied());
+        if deltas.any(|delta| delta < 0.0) {
+            errors.push("deadband: le soglie non possono essere negative".to_string());
+        }
+        errors
+    }
+}
+
+struct Written {
+    at: Instant,
+    pressure: f64,
+    temperature: f64,
+    ds18b20: BTreeMap<String, f32>,
+    exec: BTreeMap<String, f64>,
+    flight_state: FlightState,
+    burst_mode: bool,
+}
+
+pub struct Deadband {
+    config: DeadbandConfig,
+    last: Option<Written>,
+    suppressed: u64,
+}
+
+impl Deadband {
+    pub fn new(config: DeadbandConfig) -> Self {
+        Deadband { config, last: None, suppressed: 0 }
+    }
+
+    pub fn set_config(&mut self, config: DeadbandConfig) {
+        self.config = config;
+    }
+
+    /// Samples dropped since startup.
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+
+    /// Decides whether `data` is written. Failed or suspect readings, flight
+    /// state and burst mode changes always are.
+    pub fn admit(&mut self, now: Instant, data: &SensorData) -> bool {
+        let mut ds18b20 = BTreeMap::new();
+        let mut failed = !data.suspect.is_empty() || data.exec.values().any(Option::is_none);
+        let probes = [("ds18b20_1", &data.ds18b20_1), ("ds18b20_2", &data.ds18b20_2)];
+        let probes = probes.into_iter().filter_map(|(key, value)| Some((key, value.as_ref()?)));
+        for (key, value) in probes.chain(data.ds18b20_extra.iter().map(|(k, v)| (k.as_str(), v))) {
+            match value {
+                Some(value) => {
+                    ds18b20.insert(key.to_string(), *value);
+                }
+                None => failed = true,
+            }
+        }
+        let exec = data.exec.iter().filter_map(|(name, value)| Some((name.clone(), (*value)?))).collect();
+        let written = Written {
+            at: now,
+            pressure: data.ms5611.pressure,
+            temperature: data.ms5611.temperature,
+            ds18b20,
+            exec,
+            flight_state: data.flight_state,
+            burst_mode: data.burst_mode,
+        };
+        let admit = match &self.last {
+            None => true,
+            Some(_) if failed => true,
+            Some(last) => self.changed(now, last, &written),
+        };
+        if admit {
+            self.last = Some(written);
+        } else {
+            self.suppressed += 1;
+        }
+        admit
+    }
+
+    fn changed(&self, now: Instant, last: &Written, current: &Written) -> bool {
+        let config = &self.config;
+        now.duration_since(last.at) >= Duration::from_secs(config.heartbeat_secs)
+            || current.flight_state != last.flight_state
+            || current.burst_mode != last.burst_mode
+            || (current.pressure - last.pressure).abs() > config.ms5611_pressure
+            || (current.temperature - last.temperature).abs() > config.ms5611_temperature
+            || current.ds18b20.len() != last.ds18b20.len()
+            || current.ds18b20.iter().any(|(key, value)| {
+                last.ds18b20.get(key).is_none_or(|previous| (value - previous).abs() as f64 > config.ds18b20)
+            })
+            || current.exec.len() != last.exec.len()
+            || current.exec.iter().any(|(name, value)| {
+                let delta = config.exec_measurements.get(name).unwrap_or(&config.exec);
+                last.exec.get(name).is_none_or(|previous| (value - previous).abs() > *delta)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Deadband, DeadbandConfig};
+    use crate::flight::FlightState;
+    use crate::record::SensorData;
+
+    fn sample(pressure: f64, ds18b20_1: Option<f32>) -> SensorData {
+        let mut data = SensorData { suspect: Vec::new(), ds18b20_2: None, ..SensorData::example() };
+        data.ms5611.pressure = pressure;
+        data.ds18b20_1 = Some(ds18b20_1);
+        data
+    }
+
+    /// Whether each of `samples` is written, one a second from `start`.
+    fn admitted(deadband: &mut Deadband, start: Instant, samples: &[SensorData]) -> Vec<bool> {
+        let at = |secs: usize| start + Duration::from_secs(secs as u64);
+        samples.iter().enumerate().map(|(secs, data)| deadband.admit(at(secs), data)).collect()
+    }
+
+    #[test]
+    fn writes_a_change_over_the_delta_and_counts_the_rest() {
+        let mut deadband = Deadband::new(DeadbandConfig::default());
+        let samples = [
+            sample(1000.0, Some(20.0)),
+            sample(1000.05, Some(20.0)),
+            sample(1000.09, Some(20.05)),
+            sample(1000.2, Some(20.05)),
+            sample(1000.2, Some(20.2)),
+        ];
+        assert_eq!(admitted(&mut deadband, Instant::now(), &samples), [true, false, false, true, true]);
+        assert_eq!(deadband.suppressed(), 2);
+    }
+
+    #[test]
+    fn writes_the_heartbeat_without_changes() {
+        let mut deadband = Deadband::new(DeadbandConfig { heartbeat_secs: 3, ..DeadbandConfig::default() });
+        let samples: Vec<_> = (0..7).map(|_| sample(1000.0, Some(20.0))).collect();
+        let expected = [true, false, false, true, false, false, true];
+        assert_eq!(admitted(&mut deadband, Instant::now(), &samples), expected);
+        assert_eq!(deadband.suppressed(), 4);
+    }
+
+    #[test]
+    fn compares_exec_measurements_with_their_delta() {
+        let config = DeadbandConfig { exec: 1.0, ..DeadbandConfig::default() };
+        let mut deadband = Deadband::new(DeadbandConfig {
+            exec_measurements: [("uv".to_string(), 10.0)].into_iter().collect(),
+            ..config
+        });
+        let exec = |values: &[(&str, Option<f64>)]| SensorData {
+            exec: values.iter().map(|&(name, value)| (name.to_string(), value)).collect(),
+            ..sample(1000.0, Some(20.0))
+        };
+        let samples = [
+            exec(&[("uv", Some(5.0)), ("lux", Some(5.0))]),
+            exec(&[("uv", Some(14.0)), ("lux", Some(5.5))]),
+            exec(&[("uv", Some(500.0)), ("lux", Some(5.5))]),
+            exec(&[("uv", Some(500.0)), ("lux", Some(7.0))]),
+            exec(&[("uv", Some(500.0)), ("lux", None)]),
+            exec(&[("uv", Some(500.0))]),
+            exec(&[("uv", Some(500.0))]),
+            exec(&[("uv", Some(500.0)), ("lux", Some(7.0))]),
+        ];
+        let expected = [true, false, true, true, true, false, false, true];
+        assert_eq!(admitted(&mut deadband, Instant::now(), &samples), expected);
+    }
+
+    #[test]
+    fn always_writes_failed_and_suspect_readings_and_state_changes() {
+        let mut deadband = Deadband::new(DeadbandConfig::default());
+        let suspect = SensorData { suspect: vec!["ms5611_d1".to_string()], ..sample(1000.0, Some(20.0)) };
+        let ascent = || SensorData { flight_state: FlightState::Ascent, ..sample(1000.0, Some(20.0)) };
+        let burst = SensorData { burst_mode: true, ..ascent() };
+        let samples = [
+            sample(1000.0, Some(20.0)),
+            sample(1000.0, None),
+            suspect,
+            ascent(),
+            ascent(),
+            burst,
+            // Unchanged, then without the probe.
+            SensorData { burst_mode: true, ..ascent() },
+            SensorData { ds18b20_1: None, burst_mode: true, ..ascent() },
+        ];
+        let expected = [true, true, true, true, false, true, false, true];
+        assert_eq!(admitted(&mut deadband, Instant::now(), &samples), expected);
+        assert_eq!(deadband.suppressed(), 2);
+    }
+}
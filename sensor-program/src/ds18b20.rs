@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+pub const DEVICES_DIR: &str = "/sys/bus/w1/devices";
+
+/// How often to look for the 1-Wire subsystem while it is missing.
+pub const SUBSYSTEM_POLL: Duration = Duration::from_secs(30);
+
+/// A 12-bit conversion, for probes whose `conv_time` cannot be read.
+const CONVERSION_TIME: Duration = Duration::from_millis(750);
+
+/// How the probes are read. `blocking` reads each `w1_slave`, which
+/// starts a conversion and waits ~750 ms for it, one probe after the
+/// other since the kernel serializes the bus. `split` starts a single
+/// conversion of every probe through the bus master's `therm_bulk_read`,
+/// reads the MS5611 while it runs and then only the scratchpads; it falls
+/// back to `blocking` on kernels without it (before 5.10).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadMode {
+    #[default]
+    Blocking,
+    Split,
+}
+
+pub fn read_temperature(sensor_id: &str) -> Result<f32, Box<dyn std::error::Error>> {
+    let path = format!("{}/{}/w1_slave", DEVICES_DIR, sensor_id);
+    let mut content = String::new();
+    File::open(path)?.read_to_string(&mut content)?;
+
+    if content.contains("YES") {
+        let temp_pos = content.find("t=").ok_or("Valore t= non trovato")? + 2;
+        let temp_str = &content[temp_pos..].trim();
+        let temp_raw: f32 = temp_str.parse()?;
+        Ok(temp_raw / 1000.0)
+    } else {
+        Err("Errore nella lettura del DS18B20".into())
+    }
+}
+
+/// The temperature of a probe whose conversion `BulkConversion::trigger`
+/// started: the `temperature` attribute reads the scratchpad, which the
+/// kernel does without a new conversion once one was triggered.
+pub fn read_converted(sensor_id: &str) -> Result<f32, Box<dyn std::error::Error>> {
+    let path = format!("{}/{}/temperature", DEVICES_DIR, sensor_id);
+    let content = fs::read_to_string(path)?;
+    let temp_raw: f32 = content.trim().parse().map_err(|_| format!("valore {:?} non valido", content.trim()))?;
+    Ok(temp_raw / 1000.0)
+}
+
+/// One conversion of every probe on every bus master, for `ReadMode::Split`.
+pub struct BulkConversion {
+    masters: Vec<PathBuf>,
+    conversion_time: Duration,
+}
+
+impl BulkConversion {
+    /// The bus masters that support it, and the longest `conv_time` of the
+    /// probes present; fails when none does.
+    pub fn detect() -> Result<BulkConversion, String> {
+        let entries = fs::read_dir(DEVICES_DIR).map_err(|e| format!("{}: {}", DEVICES_DIR, e))?;
+        let masters: Vec<_> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("w1_bus_master"))
+            .map(|entry| entry.path().join("therm_bulk_read"))
+            .filter(|path| path.exists())
+            .collect();
+        if masters.is_empty() {
+            return Err("therm_bulk_read non supportato dal kernel".to_string());
+        }
+        let conversion_time = scan()
+            .unwrap_or_default()
+            .iter()
+            .map(|id| {
+                let path = format!("{}/{}/conv_time", DEVICES_DIR, id);
+                let ms = fs::read_to_string(path).ok().and_then(|ms| ms.trim().parse().ok());
+                ms.map_or(CONVERSION_TIME, Duration::from_millis)
+            })
+            .max()
+            .unwrap_or(CONVERSION_TIME);
+        Ok(BulkConversion { masters, conversion_time })
+    }
+
+    pub fn conversion_time(&self) -> Duration {
+        self.conversion_time
+    }
+
+    /// Starts the conversion on every bus master; the values can be read
+    /// with `read_converted` after `conversion_time`.
+    pub fn trigger(&self) -> std::io::Result<()> {
+        for master in &self.masters {
+            fs::write(master, "trigger\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// IDs of the DS18B20s (family code 28) currently on the 1-Wire bus.
+pub fn scan() -> std::io::Result<BTreeSet<String>> {
+    let mut ids = BTreeSet::new();
+    for entry in fs::read_dir(DEVICES_DIR)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with("28-") {
+            ids.insert(name);
+        }
+    }
+    Ok(ids)
+}
+
+/// Checks that the kernel exposes the 1-Wire bus and at least one bus
+/// master, i.e. that the w1-gpio overlay and modules are loaded.
+pub fn subsystem_status() -> Result<(), String> {
+    let entries = fs::read_dir(DEVICES_DIR).map_err(|e| format!("{}: {}", DEVICES_DIR, e))?;
+    let has_master = entries
+        .filter_map(|entry| entry.ok())
+        .any(|entry| entry.file_name().to_string_lossy().starts_with("w1_bus_master"));
+    if has_master {
+        Ok(())
+    } else {
+        Err(format!("nessun w1_bus_master in {}", DEVICES_DIR))
+    }
+}
+
+pub enum Subsystem {
+    Ready,
+    /// Found after having been reported missing.
+    Appeared,
+    /// Missing on the first check; carries the reason.
+    Missing(String),
+    StillMissing,
+}
+
+#[derive(Debug, Default)]
+pub struct ScanChanges {
+    pub appeared: Vec<String>,
+    pub disappeared: Vec<String>,
+}
+
+/// Tracks which probes are on the bus between periodic rescans. Until the
+/// first successful scan every configured probe is assumed present.
+#[derive(Default)]
+pub struct Discovery {
+    present: Option<BTreeSet<String>>,
+    last_scan: Option<Instant>,
+    subsystem_ready: bool,
+    subsystem_reported: bool,
+    last_subsystem_check: Option<Instant>,
+}
+
+impl Discovery {
+    /// Looks for the 1-Wire subsystem, rechecking at most every
+    /// `SUBSYSTEM_POLL` while it is missing. Once found it is not checked
+    /// again.
+    pub fn check_subsystem(&mut self, now: Instant) -> Subsystem {
+        if self.subsystem_ready {
+            return Subsystem::Ready;
+        }
+        if self.last_subsystem_check.is_some_and(|last| now.duration_since(last) < SUBSYSTEM_POLL) {
+            return Subsystem::StillMissing;
+        }
+        self.last_subsystem_check = Some(now);
+        match subsystem_status() {
+            Ok(()) => {
+                self.subsystem_ready = true;
+                self.force_rescan();
+                if self.subsystem_reported { Subsystem::Appeared } else { Subsystem::Ready }
+            }
+            Err(_) if self.subsystem_reported => Subsystem::StillMissing,
+            Err(reason) => {
+                self.subsystem_reported = true;
+                Subsystem::Missing(reason)
+            }
+        }
+    }
+
+    pub fn force_rescan(&mut self) {
+        self.last_scan = None;
+    }
+
+    pub fn is_due(&self, now: Instant, interval_secs: u64) -> bool {
+        self.last_scan.is_none_or(|last| {
+            interval_secs > 0 && now.duration_since(last) >= Duration::from_secs(interval_secs)
+        })
+    }
+
+    pub fn rescan(&mut self, now: Instant, configured: &[&str]) -> std::io::Result<ScanChanges> {
+        self.last_scan = Some(now);
+        let found = scan()?;
+        let previous = self
+            .present
+            .take()
+            .unwrap_or_else(|| configured.iter().map(|id| id.to_string()).collect());
+        let changes = ScanChanges {
+            appeared: found.difference(&previous).cloned().collect(),
+            disappeared: previous.difference(&found).cloned().collect(),
+        };
+        self.present = Some(found);
+        Ok(changes)
+    }
+
+    pub fn is_present(&self, id: &str) -> bool {
+        self.present.as_ref().is_none_or(|present| present.contains(id))
+    }
+
+    /// Present probes that are not among the configured ones.
+    pub fn extras(&self, configured: &[&str]) -> Vec<String> {
+        self.present
+            .iter()
+            .flatten()
+            .filter(|id| !configured.contains(&id.as_str()))
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,30 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Event {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub timestamp: DateTime<Utc>,
+    /// Filled in by the service when the event is emitted.
+    pub session_id: String,
+    pub severity: Severity,
+    pub category: &'static str,
+    pub message: String,
+    pub payload: Value,
+}
+
+impl Event {
+    pub fn new(severity: Severity, category: &'static str, message: String, payload: Value) -> Self {
+        Event { kind: "event", timestamp: Utc::now(), session_id: String::new(), severity, category, message, payload }
+    }
+}
@@ -0,0 +1,265 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::units::Unit;
+
+/// How stdout of an external command is read.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecFormat {
+    /// A single number, recorded under the name of the sensor.
+    #[default]
+    Number,
+    /// A JSON object of numbers; `fields` maps its keys to record names.
+    Json,
+}
+
+/// A sensor read by running an external command, e.g. a vendor script.
+/// Its measurements go in the `exec` object of the record.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ExecConfig {
+    pub name: String,
+    /// Program and arguments, run directly without a shell.
+    pub command: Vec<String>,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default)]
+    pub format: ExecFormat,
+    /// Key in the output -> name in the record, for `format = "json"`.
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    /// Seconds between runs; 0 runs the command every cycle.
+    #[serde(default)]
+    pub interval_secs: u64,
+    /// Unit of each measurement, by record name; listed in the header.
+    #[serde(default)]
+    pub units: BTreeMap<String, Unit>,
+}
+
+fn default_timeout_ms() -> u64 {
+    2000
+}
+
+impl ExecConfig {
+    /// Names the measurements of this sensor have in the record.
+    pub fn measurements(&self) -> Vec<&str> {
+        match self.format {
+            ExecFormat::Number => vec![self.name.as_str()],
+            ExecFormat::Json => self.fields.values().map(String::as_str).collect(),
+        }
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// `interval_ms` is the sampling interval: commands run within the cycle,
+/// so a timeout longer than that would stretch it.
+pub fn validate(sensors: &[ExecConfig], interval_ms: u64) -> Vec<String> {
+    let mut errors = Vec::new();
+    let mut measurements: Vec<&str> = Vec::new();
+    for (index, sensor) in sensors.iter().enumerate() {
+        let key = format!("exec.{}", sensor.name);
+        if !is_valid_name(&sensor.name) || sensors[..index].iter().any(|other| other.name == sensor.name) {
+            errors.push(format!("exec: nome \"{}\" vuoto, duplicato o non valido (lettere, cifre, '_')", sensor.name));
+        }
+        if sensor.command.first().is_none_or(String::is_empty) {
+            errors.push(format!("{}.command non può essere vuoto", key));
+        }
+        if sensor.timeout_ms == 0 || sensor.timeout_ms > interval_ms {
+            errors.push(format!(
+                "{}.timeout_ms {} deve essere maggiore di zero e non superare l'intervallo di campionamento ({} ms)",
+                key, sensor.timeout_ms, interval_ms
+            ));
+        }
+        match sensor.format {
+            ExecFormat::Number if !sensor.fields.is_empty() => {
+                errors.push(format!("{}.fields vale solo con format = \"json\"", key));
+            }
+            ExecFormat::Json if sensor.fields.is_empty() => {
+                errors.push(format!("{}.fields non può essere vuoto con format = \"json\"", key));
+            }
+            _ => {}
+        }
+        for name in sensor.measurements() {
+            if !is_valid_name(name) || measurements.contains(&name) {
+                errors.push(format!("{}: nome di misura \"{}\" duplicato o non valido", key, name));
+            }
+            measurements.push(name);
+        }
+        for name in sensor.units.keys().filter(|name| !sensor.measurements().contains(&name.as_str())) {
+            errors.push(format!("{}.units: \"{}\" non è una misura del sensore", key, name));
+        }
+    }
+    errors
+}
+
+/// When each sensor with its own `interval_secs` last ran.
+#[derive(Default)]
+pub struct ExecSchedule {
+    last_run: BTreeMap<String, Instant>,
+}
+
+impl ExecSchedule {
+    /// True, and the run is recorded, when `sensor` has to run in this cycle.
+    pub fn take_due(&mut self, sensor: &ExecConfig, now: Instant) -> bool {
+        let interval = Duration::from_secs(sensor.interval_secs);
+        let due = self.last_run.get(&sensor.name).is_none_or(|last| now.duration_since(*last) >= interval);
+        if due {
+            self.last_run.insert(sensor.name.clone(), now);
+        }
+        due
+    }
+}
+
+/// Runs the command and parses its output into record name -> value. The
+/// command gets its own process group, which is killed on timeout so that
+/// children it started do not linger either.
+pub fn run(sensor: &ExecConfig) -> Result<BTreeMap<String, f64>, String> {
+    let (program, args) = sensor.command.split_first().ok_or("comando vuoto")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .map_err(|e| format!("avvio di {} non riuscito: {}", program, e))?;
+    let stdout = collect(child.stdout.take());
+    let stderr = collect(child.stderr.take());
+
+    let timeout = Duration::from_millis(sensor.timeout_ms);
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() >= deadline => {
+                kill(&mut child);
+                return Err(format!("nessuna risposta entro {} ms, processo terminato", sensor.timeout_ms));
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+            Err(e) => {
+                kill(&mut child);
+                return Err(e.to_string());
+            }
+        }
+    };
+    // A grandchild may still hold the pipes open; do not wait for it.
+    let grace = deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(100));
+    let stdout = stdout.recv_timeout(grace).unwrap_or_default();
+    if !status.success() {
+        let stderr = stderr.recv_timeout(Duration::from_millis(100)).unwrap_or_default();
+        let detail = stderr.lines().rev().find(|line| !line.trim().is_empty()).unwrap_or("").trim();
+        let exit = match (status.code(), status.signal()) {
+            (Some(code), _) => format!("uscito con codice {}", code),
+            (None, Some(signal)) => format!("terminato dal segnale {}", signal),
+            (None, None) => "terminato".to_string(),
+        };
+        return Err(if detail.is_empty() { exit } else { format!("{}: {}", exit, detail) });
+    }
+    parse(sensor, &stdout)
+}
+
+fn collect(pipe: Option<impl Read + Send + 'static>) -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    if let Some(mut pipe) = pipe {
+        thread::spawn(move || {
+            let mut text = String::new();
+            let _ = pipe.read_to_string(&mut text);
+            let _ = sender.send(text);
+        });
+    }
+    receiver
+}
+
+fn kill(child: &mut Child) {
+    // SAFETY: kill(2) on the process group created for this child.
+    unsafe {
+        libc::kill(-(child.id() as i32), libc::SIGKILL);
+    }
+    let _ = child.wait();
+}
+
+fn parse(sensor: &ExecConfig, stdout: &str) -> Result<BTreeMap<String, f64>, String> {
+    let text = stdout.trim();
+    let finite = |value: f64| value.is_finite().then_some(value);
+    match sensor.format {
+        ExecFormat::Number => {
+            let value = text.parse::<f64>().ok().and_then(finite);
+            let value = value.ok_or_else(|| format!("uscita \"{}\" non è un numero", text))?;
+            Ok(BTreeMap::from([(sensor.name.clone(), value)]))
+        }
+        ExecFormat::Json => {
+            let object: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_str(text).map_err(|e| format!("uscita non è un oggetto JSON: {}", e))?;
+            sensor
+                .fields
+                .iter()
+                .map(|(key, name)| {
+                    let value = object.get(key).and_then(serde_json::Value::as_f64).and_then(finite);
+                    let value = value.ok_or_else(|| format!("\"{}\" assente o non numerico", key))?;
+                    Ok((name.clone(), value))
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::{Duration, Instant};
+
+    use super::{parse, run, ExecConfig, ExecFormat};
+
+    fn sensor(command: &[&str], timeout_ms: u64) -> ExecConfig {
+        ExecConfig {
+            name: "uv".to_string(),
+            command: command.iter().map(|arg| arg.to_string()).collect(),
+            timeout_ms,
+            format: ExecFormat::Number,
+            fields: BTreeMap::new(),
+            interval_secs: 0,
+            units: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn parses_a_number_or_the_fields_of_an_object() {
+        let number = sensor(&["true"], 100);
+        assert_eq!(parse(&number, " 21.5\n"), Ok(BTreeMap::from([("uv".to_string(), 21.5)])));
+        assert_eq!(parse(&number, "NaN"), Err("uscita \"NaN\" non è un numero".to_string()));
+        assert_eq!(parse(&number, "caldo"), Err("uscita \"caldo\" non è un numero".to_string()));
+
+        let fields = [("lux", "light"), ("uvi", "uv")].map(|(key, name)| (key.to_string(), name.to_string()));
+        let json = ExecConfig { format: ExecFormat::Json, fields: fields.into_iter().collect(), ..number };
+        let expected = BTreeMap::from([("light".to_string(), 300.0), ("uv".to_string(), 2.0)]);
+        assert_eq!(parse(&json, r#"{"lux": 300, "uvi": 2.0, "extra": "x"}"#), Ok(expected));
+        assert_eq!(parse(&json, r#"{"lux": 300}"#), Err("\"uvi\" assente o non numerico".to_string()));
+        assert_eq!(parse(&json, r#"{"lux": 300, "uvi": "2"}"#), Err("\"uvi\" assente o non numerico".to_string()));
+        assert!(parse(&json, "[1, 2]").unwrap_err().starts_with("uscita non è un oggetto JSON"));
+    }
+
+    #[test]
+    fn reports_the_exit_code_with_the_last_line_of_stderr() {
+        let failing = sensor(&["sh", "-c", "echo primo >&2; echo guasto >&2; exit 3"], 1000);
+        assert_eq!(run(&failing), Err("uscito con codice 3: guasto".to_string()));
+        assert_eq!(run(&sensor(&["sh", "-c", "echo 4.5"], 1000)), Ok(BTreeMap::from([("uv".to_string(), 4.5)])));
+    }
+
+    #[test]
+    fn kills_a_command_that_does_not_answer() {
+        let started = Instant::now();
+        let result = run(&sensor(&["sleep", "10"], 100));
+        assert_eq!(result, Err("nessuna risposta entro 100 ms, processo terminato".to_string()));
+        assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+    }
+}
@@ -0,0 +1,401 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::time::Instant;
+
+use crate::setpoints::Fired;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FlightState {
+    #[default]
+    Preflight,
+    Ascent,
+    Burst,
+    Descent,
+    Landed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlightConfig {
+    pub state_file: String,
+    pub ascent_speed: f64,
+    pub ascent_min_altitude: f64,
+    pub burst_speed: f64,
+    pub burst_hold_secs: u64,
+    pub landed_speed: f64,
+    pub landed_secs: u64,
+    pub confirm_samples: u32,
+    pub speed_smoothing: f64,
+    /// Weight of the previous value in the filtered altitude used by the
+    /// setpoints, like `speed_smoothing`; 0 disables the filter.
+    pub altitude_smoothing: f64,
+}
+
+impl Default for FlightConfig {
+    fn default() -> Self {
+        FlightConfig {
+            state_file: "flight_state.json".to_string(),
+            ascent_speed: 1.5,
+            ascent_min_altitude: 100.0,
+            burst_speed: -5.0,
+            burst_hold_secs: 30,
+            landed_speed: 0.5,
+            landed_secs: 120,
+            confirm_samples: 3,
+            speed_smoothing: 0.5,
+            altitude_smoothing: 0.5,
+        }
+    }
+}
+
+impl FlightConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.ascent_speed <= 0.0 {
+            errors.push("flight.ascent_speed deve essere positiva".to_string());
+        }
+        if self.burst_speed >= 0.0 {
+            errors.push("flight.burst_speed deve essere negativa".to_string());
+        }
+        if self.landed_speed <= 0.0 {
+            errors.push("flight.landed_speed deve essere positiva".to_string());
+        }
+        if !(0.0..1.0).contains(&self.speed_smoothing) {
+            errors.push("flight.speed_smoothing deve essere compreso tra 0 e 1 (escluso)".to_string());
+        }
+        if !(0.0..1.0).contains(&self.altitude_smoothing) {
+            errors.push("flight.altitude_smoothing deve essere compreso tra 0 e 1 (escluso)".to_string());
+        }
+        if self.confirm_samples == 0 {
+            errors.push("flight.confirm_samples deve essere almeno 1".to_string());
+        }
+        errors
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PersistedState {
+    /// The session that wrote the file; another one starts from preflight.
+    #[serde(default)]
+    session_id: String,
+    state: FlightState,
+    launch_altitude: Option<f64>,
+    #[serde(default)]
+    setpoints: BTreeMap<String, Fired>,
+}
+
+#[derive(Debug)]
+pub struct Transition {
+    pub from: FlightState,
+    pub to: FlightState,
+    pub altitude: f64,
+    pub vertical_speed: f64,
+}
+
+pub struct FlightTracker {
+    config: FlightConfig,
+    persistent: bool,
+    session_id: String,
+    state: FlightState,
+    state_since: Instant,
+    launch_altitude: Option<f64>,
+    setpoints: BTreeMap<String, Fired>,
+    last_sample: Option<(Instant, f64)>,
+    vertical_speed: Option<f64>,
+    filtered_altitude: Option<f64>,
+    pending: Option<(FlightState, u32)>,
+    calm_since: Option<Instant>,
+}
+
+impl FlightTracker {
+    /// Resumes the state saved by `session_id`, e.g. after a restart in
+    /// flight, unless the flight had landed.
+    pub fn new(config: FlightConfig, persistent: bool, session_id: &str, now: Instant) -> Self {
+        let persisted = fs::read_to_string(&config.state_file)
+            .ok()
+            .and_then(|content| serde_json::from_str::<PersistedState>(&content).ok())
+            .filter(|persisted| persisted.session_id == session_id && persisted.state != FlightState::Landed);
+        let (state, launch_altitude, setpoints) = match persisted {
+            Some(persisted) => (persisted.state, persisted.launch_altitude, persisted.setpoints),
+            None => (FlightState::Preflight, None, BTreeMap::new()),
+        };
+        FlightTracker {
+            config,
+            persistent,
+            session_id: session_id.to_string(),
+            state,
+            state_since: now,
+            launch_altitude,
+            setpoints,
+            last_sample: None,
+            vertical_speed: None,
+            filtered_altitude: None,
+            pending: None,
+            calm_since: None,
+        }
+    }
+
+    pub fn set_config(&mut self, config: FlightConfig) {
+        self.config = config;
+    }
+
+    pub fn state(&self) -> FlightState {
+        self.state
+    }
+
+    /// The setpoints fired in this flight, as last saved.
+    pub fn setpoints(&self) -> &BTreeMap<String, Fired> {
+        &self.setpoints
+    }
+
+    /// Saves the setpoints fired so far with the flight state.
+    pub fn set_setpoints(&mut self, setpoints: BTreeMap<String, Fired>) {
+        self.setpoints = setpoints;
+        self.persist();
+    }
+
+    pub fn vertical_speed(&self) -> Option<f64> {
+        self.vertical_speed
+    }
+
+    /// Altitude smoothed with `altitude_smoothing`; `None` before the first
+    /// sample.
+    pub fn filtered_altitude(&self) -> Option<f64> {
+        self.filtered_altitude
+    }
+
+    /// Forgets the rate history, e.g. after a wall-clock step, so the next
+    /// samples rebuild the vertical speed from scratch.
+    pub fn reset_rate(&mut self) {
+        self.last_sample = None;
+        self.vertical_speed = None;
+        self.pending = None;
+    }
+
+    pub fn update(&mut self, now: Instant, altitude: f64) -> Option<Transition> {
+        if let Some((last_time, last_altitude)) = self.last_sample {
+            let dt = now.duration_since(last_time).as_secs_f64();
+            if dt > 0.0 {
+                let raw = (altitude - last_altitude) / dt;
+                let alpha = self.config.speed_smoothing;
+                self.vertical_speed = Some(match self.vertical_speed {
+                    Some(previous) => alpha * previous + (1.0 - alpha) * raw,
+                    None => raw,
+                });
+            }
+        }
+        self.last_sample = Some((now, altitude));
+        let alpha = self.config.altitude_smoothing;
+        self.filtered_altitude = Some(self.filtered_altitude.map_or(altitude, |previous| {
+            alpha * previous + (1.0 - alpha) * altitude
+        }));
+        if self.state == FlightState::Preflight {
+            self.launch_altitude = Some(self.launch_altitude.map_or(altitude, |a| a.min(altitude)));
+        }
+
+        let vertical_speed = self.vertical_speed?;
+        let candidate = self.candidate(now, altitude, vertical_speed)?;
+        let confirmations = match self.pending {
+            Some((state, count)) if state == candidate => count + 1,
+            _ => 1,
+        };
+        if confirmations < self.config.confirm_samples {
+            self.pending = Some((candidate, confirmations));
+            return None;
+        }
+
+        let transition = Transition { from: self.state, to: candidate, altitude, vertical_speed };
+        self.state = candidate;
+        self.state_since = now;
+        self.pending = None;
+        self.calm_since = None;
+        self.persist();
+        Some(transition)
+    }
+
+    fn candidate(&mut self, now: Instant, altitude: f64, vertical_speed: f64) -> Option<FlightState> {
+        let next = match self.state {
+            FlightState::Preflight => {
+                let climbed = altitude - self.launch_altitude.unwrap_or(altitude);
+                (vertical_speed > self.config.ascent_speed && climbed > self.config.ascent_min_altitude)
+                    .then_some(FlightState::Ascent)
+            }
+            FlightState::Ascent => (vertical_speed < self.config.burst_speed).then_some(FlightState::Burst),
+            FlightState::Burst => (now.duration_since(self.state_since).as_secs() >= self.config.burst_hold_secs)
+                .then_some(FlightState::Descent),
+            FlightState::Descent => {
+                if vertical_speed.abs() < self.config.landed_speed {
+                    let calm_since = *self.calm_since.get_or_insert(now);
+                    (now.duration_since(calm_since).as_secs() >= self.config.landed_secs)
+                        .then_some(FlightState::Landed)
+                } else {
+                    self.calm_since = None;
+                    None
+                }
+            }
+            FlightState::Landed => None,
+        };
+        if next.is_none() {
+            self.pending = None;
+        }
+        next
+    }
+
+    fn persist(&self) {
+        if !self.persistent {
+            return;
+        }
+        let persisted = PersistedState {
+            session_id: self.session_id.clone(),
+            state: self.state,
+            launch_altitude: self.launch_altitude,
+            setpoints: self.setpoints.clone(),
+        };
+        let tmp_path = format!("{}.tmp", self.config.state_file);
+        let result = serde_json::to_string(&persisted)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(&tmp_path, json).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&tmp_path, &self.config.state_file).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            println!("Errore salvataggio stato di volo in {}: {}", self.config.state_file, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    use super::{FlightConfig, FlightState, FlightTracker};
+    use crate::setpoints::Fired;
+
+    fn config(name: &str) -> (FlightConfig, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("sensor-program-flight-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let state_file = dir.join("flight_state.json").display().to_string();
+        (FlightConfig { state_file, speed_smoothing: 0.0, altitude_smoothing: 0.0, ..FlightConfig::default() }, dir)
+    }
+
+    /// The transitions over `altitudes`, one sample a second from `start`.
+    fn feed(tracker: &mut FlightTracker, start: Instant, altitudes: &[f64]) -> Vec<(usize, FlightState)> {
+        let mut transitions = Vec::new();
+        for (secs, &altitude) in altitudes.iter().enumerate() {
+            if let Some(transition) = tracker.update(start + Duration::from_secs(secs as u64), altitude) {
+                transitions.push((secs, transition.to));
+            }
+        }
+        transitions
+    }
+
+    /// Climbs at 5 m/s for 23 s, falls at 10 m/s until 60 s, then rests.
+    fn flight() -> Vec<f64> {
+        (0..200)
+            .map(|secs| match secs {
+                0..=23 => 5.0 * secs as f64,
+                24..=60 => 115.0 - 10.0 * (secs - 23) as f64,
+                _ => -255.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn goes_through_every_state_after_confirm_samples() {
+        let (config, dir) = config("states");
+        let mut tracker = FlightTracker::new(config, false, "session", Instant::now());
+        let transitions = feed(&mut tracker, Instant::now(), &flight());
+        // Climbed past 100 m at 21 s, falling from 24 s, burst held for
+        // 30 s and calm from 61 s, each confirmed by the third sample.
+        let expected = [
+            (23, FlightState::Ascent),
+            (26, FlightState::Burst),
+            (58, FlightState::Descent),
+            (183, FlightState::Landed),
+        ];
+        assert_eq!(transitions, expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn an_interrupted_run_of_samples_does_not_confirm() {
+        // Two samples over ascent_speed, then one under, again and again.
+        let mut altitudes = vec![0.0];
+        for step in 0..60 {
+            altitudes.push(altitudes[step] + if step % 3 == 2 { 1.0 } else { 5.0 });
+        }
+        let (config, dir) = config("hysteresis");
+        let mut tracker = FlightTracker::new(config.clone(), false, "session", Instant::now());
+        assert_eq!(feed(&mut tracker, Instant::now(), &altitudes), []);
+        assert_eq!(tracker.state(), FlightState::Preflight);
+        let config = FlightConfig { confirm_samples: 1, ..config };
+        let mut tracker = FlightTracker::new(config, false, "session", Instant::now());
+        assert_eq!(feed(&mut tracker, Instant::now(), &altitudes).first(), Some(&(28, FlightState::Ascent)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restores_only_an_unfinished_flight_of_the_same_session() {
+        let (config, dir) = config("restore");
+        let start = Instant::now();
+        let mut tracker = FlightTracker::new(config.clone(), true, "session-1", start);
+        feed(&mut tracker, start, &flight()[..30]);
+        assert_eq!(tracker.state(), FlightState::Burst);
+        let restored = |session_id: &str| FlightTracker::new(config.clone(), true, session_id, start).state();
+        assert_eq!(restored("session-1"), FlightState::Burst);
+        assert_eq!(restored("session-2"), FlightState::Preflight);
+
+        let mut tracker = FlightTracker::new(config.clone(), true, "session-1", start);
+        feed(&mut tracker, start, &flight()[26..]);
+        assert_eq!(tracker.state(), FlightState::Landed);
+        assert_eq!(restored("session-1"), FlightState::Preflight);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_fired_setpoints_with_the_flight() {
+        let (config, dir) = config("setpoints");
+        let start = Instant::now();
+        let mut tracker = FlightTracker::new(config.clone(), true, "session-1", start);
+        feed(&mut tracker, start, &flight()[..24]);
+        let fired = BTreeMap::from([("cutdown".to_string(), Fired { up: true, down: false })]);
+        tracker.set_setpoints(fired.clone());
+        assert_eq!(FlightTracker::new(config.clone(), true, "session-1", start).setpoints(), &fired);
+        assert!(FlightTracker::new(config, true, "session-2", start).setpoints().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_new_session_takes_its_own_launch_altitude() {
+        let (config, dir) = config("launch");
+        let start = Instant::now();
+        let mut tracker = FlightTracker::new(config.clone(), true, "session-1", start);
+        feed(&mut tracker, start, &flight()[..30]);
+        // Launched from 0 m; the next session starts at 1000 m, where the
+        // climb only counts from 1100 m.
+        let mut tracker = FlightTracker::new(config, true, "session-2", start);
+        let altitudes: Vec<f64> = flight()[..24].iter().map(|altitude| 1000.0 + altitude).collect();
+        assert_eq!(feed(&mut tracker, start, &altitudes), [(23, FlightState::Ascent)]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn burst_hold_runs_on_the_given_clock() {
+        let (config, dir) = config("hold");
+        let start = Instant::now();
+        let mut tracker = FlightTracker::new(config.clone(), true, "session", start);
+        feed(&mut tracker, start, &flight()[..30]);
+        // Restarted an hour later on a clock of its own: the hold starts
+        // again from there, whatever the time elsewhere.
+        let later = start + Duration::from_secs(3600);
+        let mut tracker = FlightTracker::new(config, true, "session", later);
+        assert_eq!(tracker.state(), FlightState::Burst);
+        let falling: Vec<f64> = (0..40).map(|secs| -10.0 * secs as f64).collect();
+        assert_eq!(feed(&mut tracker, later, &falling), [(32, FlightState::Descent)]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::record::HeaderRecord;
+use crate::ringbuffer::DUMP_PREFIX;
+use crate::selftest;
+use crate::session;
+use crate::sinks::SinkKind;
+use crate::telemetry::{TelemetryConfig, TelemetrySinkConfig};
+
+const PLACEHOLDERS: [&str; 4] = ["{date}", "{time}", "{device}", "{session}"];
+
+/// Prefix of the copy of the header written in the directory at every start.
+pub const HEADER_PREFIX: &str = "header-";
+
+/// Gives every run its own directory, e.g.
+/// `/data/flights/{date}T{time}_{device}` (UTC, time as `12-30-00`).
+/// Relative `output.path`, `events.path`, `ring_buffer.dump_dir` and file
+/// sinks are taken inside it; the session and flight state files stay
+/// where they are, shared by the runs. The directory must not exist yet,
+/// unless `--resume` is given, which goes on in the one of the last run.
+/// An empty template writes the files where their paths say.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlightDirConfig {
+    pub template: String,
+    /// `{device}`; empty takes the hostname.
+    pub device_id: String,
+    /// Free space the filesystem needs at startup; 0 does not check.
+    pub min_free_mb: u64,
+}
+
+impl Default for FlightDirConfig {
+    fn default() -> Self {
+        FlightDirConfig { template: String::new(), device_id: String::new(), min_free_mb: 100 }
+    }
+}
+
+impl FlightDirConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').map_or(rest.len(), |end| start + end + 1);
+            if !PLACEHOLDERS.contains(&&rest[start..end]) {
+                errors.push(format!(
+                    "flight_dir.template: segnaposto {} sconosciuto (ammessi {})",
+                    &rest[start..end],
+                    PLACEHOLDERS.join(", ")
+                ));
+            }
+            rest = &rest[end..];
+        }
+        if !self.device_id.is_empty() && !session::is_valid_id(&self.device_id) {
+            errors.push(format!("flight_dir.device_id non valido: {} (lettere, cifre, '-', '_', '.')", self.device_id));
+        }
+        errors
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.template.is_empty()
+    }
+
+    fn device_id(&self) -> String {
+        if !self.device_id.is_empty() {
+            return self.device_id.clone();
+        }
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+        let hostname = hostname.trim();
+        if session::is_valid_id(hostname) { hostname.to_string() } else { "sensor".to_string() }
+    }
+
+    /// The directory of a run of `session` started at `now`.
+    pub fn expand(&self, session: &str, now: DateTime<Utc>) -> PathBuf {
+        let dir = self
+            .template
+            .replace("{date}", &now.format("%Y-%m-%d").to_string())
+            .replace("{time}", &now.format("%H-%M-%S").to_string())
+            .replace("{device}", &self.device_id())
+            .replace("{session}", session);
+        PathBuf::from(dir)
+    }
+
+    /// The deepest existing directory the flight directories go in.
+    pub fn base(&self) -> PathBuf {
+        let mut base = Path::new(&self.template);
+        while base.to_string_lossy().contains('{') || !base.is_dir() {
+            match base.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => base = parent,
+                _ => return PathBuf::from("."),
+            }
+        }
+        base.to_path_buf()
+    }
+
+    /// Creates `dir`, which with `resume` may already exist, once the
+    /// filesystem has `min_free_mb` free.
+    pub fn create(&self, dir: &Path, resume: bool) -> Result<(), String> {
+        if dir.exists() && !resume {
+            return Err(format!("{} esiste già (--resume per continuare a scrivervi)", dir.display()));
+        }
+        let base = self.base();
+        if self.min_free_mb > 0 {
+            let free = selftest::free_bytes(&base).map_err(|e| format!("{}: {}", base.display(), e))?;
+            let free_mb = free / (1024 * 1024);
+            if free_mb < self.min_free_mb {
+                return Err(format!(
+                    "{}: solo {} MB liberi, ne servono {} (flight_dir.min_free_mb)",
+                    base.display(),
+                    free_mb,
+                    self.min_free_mb
+                ));
+            }
+        }
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))
+    }
+}
+
+/// Takes the relative paths of the files a run writes inside `dir`.
+pub fn relocate(config: &mut Config, dir: &Path) {
+    let inside = |path: &mut String| {
+        if Path::new(path.as_str()).is_relative() {
+            *path = if path == "." { dir.display().to_string() } else { dir.join(&path).display().to_string() };
+        }
+    };
+    inside(&mut config.output.path);
+    inside(&mut config.events.path);
+    inside(&mut config.ring_buffer.dump_dir);
+    if let Some(TelemetryConfig { sink: TelemetrySinkConfig::File { path }, .. }) = &mut config.telemetry {
+        inside(path);
+    }
+    for sink in &mut config.sinks {
+        if let SinkKind::File { path } = &mut sink.kind {
+            inside(path);
+        }
+    }
+}
+
+/// The data files of a flight directory, those that start with a header
+/// record; ring-buffer dumps and header copies are left out.
+pub fn data_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let mut files: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            !name.starts_with(HEADER_PREFIX) && !name.starts_with(DUMP_PREFIX)
+        })
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && starts_with_header(path))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn starts_with_header(path: &Path) -> bool {
+    let Ok(file) = fs::File::open(path) else {
+        return false;
+    };
+    let mut line = String::new();
+    BufReader::new(file).read_line(&mut line).is_ok() && HeaderRecord::parse(&line).is_some()
+}
+
+/// `input`, or the data file of it when it is a directory with only one.
+pub fn single_input(input: &Path) -> Result<PathBuf, String> {
+    if !input.is_dir() {
+        return Ok(input.to_path_buf());
+    }
+    match data_files(input)?.as_slice() {
+        [file] => Ok(file.clone()),
+        [] => Err(format!("{}: nessun file di dati", input.display())),
+        files => Err(format!("{}: {} file di dati, indicarne uno", input.display(), files.len())),
+    }
+}
+
+/// `inputs` with every directory replaced by its data files.
+pub fn expand_inputs(inputs: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    for input in inputs {
+        if !input.is_dir() {
+            files.push(input.clone());
+            continue;
+        }
+        let found = data_files(input)?;
+        if found.is_empty() {
+            return Err(format!("{}: nessun file di dati", input.display()));
+        }
+        files.extend(found);
+    }
+    Ok(files)
+}
@@ -0,0 +1,89 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::writer::{is_data_record, last_line};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GapReason {
+    CleanShutdown,
+    UncleanShutdown,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GapRecord {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub timestamp: DateTime<Utc>,
+    pub session_id: String,
+    pub boot_id: String,
+    pub gap_secs: f64,
+    pub last_timestamp: DateTime<Utc>,
+    pub last_session_id: String,
+    pub last_boot_id: String,
+    pub last_sequence: u64,
+    pub reason: GapReason,
+}
+
+#[derive(Deserialize)]
+struct LastRecord {
+    timestamp: DateTime<Utc>,
+    #[serde(default)]
+    session_id: String,
+    #[serde(default)]
+    boot_id: String,
+    #[serde(default)]
+    sequence: u64,
+}
+
+#[derive(Deserialize)]
+struct LastEvent {
+    timestamp: DateTime<Utc>,
+    category: String,
+}
+
+fn is_event(line: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(line)
+        .map(|value| value.get("type").and_then(|t| t.as_str()) == Some("event"))
+        .unwrap_or(false)
+}
+
+pub fn detect(
+    data_path: &Path,
+    events_path: &Path,
+    interval_secs: u64,
+    factor: f64,
+    now: DateTime<Utc>,
+    session_id: &str,
+    boot_id: &str,
+) -> Option<GapRecord> {
+    let line = last_line(data_path, is_data_record).ok().flatten()?;
+    let last: LastRecord = serde_json::from_str(&line).ok()?;
+
+    let gap_secs = (now - last.timestamp).num_milliseconds() as f64 / 1000.0;
+    if gap_secs <= interval_secs as f64 * factor {
+        return None;
+    }
+
+    let clean = [events_path, data_path].iter().any(|path| {
+        last_line(path, is_event)
+            .ok()
+            .flatten()
+            .and_then(|line| serde_json::from_str::<LastEvent>(&line).ok())
+            .is_some_and(|event| event.category == "shutdown" && event.timestamp >= last.timestamp)
+    });
+
+    Some(GapRecord {
+        kind: "gap",
+        timestamp: now,
+        session_id: session_id.to_string(),
+        boot_id: boot_id.to_string(),
+        gap_secs,
+        last_timestamp: last.timestamp,
+        last_session_id: last.session_id,
+        last_boot_id: last.boot_id,
+        last_sequence: last.sequence,
+        reason: if clean { GapReason::CleanShutdown } else { GapReason::UncleanShutdown },
+    })
+}
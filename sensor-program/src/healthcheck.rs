@@ -0,0 +1,89 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::writer::{is_data_record, last_line};
+
+pub enum Health {
+    Healthy(String),
+    Stale(String),
+    Broken(String),
+}
+
+impl Health {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Health::Healthy(_) => 0,
+            Health::Stale(_) => 1,
+            Health::Broken(_) => 2,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Health::Healthy(message) | Health::Stale(message) | Health::Broken(message) => message,
+        }
+    }
+}
+
+/// What the healthcheck reads of a record: only `mapping::PROTECTED`
+/// fields, which every layout and `[mapping]` keep at the top level.
+#[derive(Deserialize)]
+struct Protected {
+    timestamp: DateTime<Utc>,
+}
+
+pub fn check_file(path: &Path, max_age: Duration) -> Health {
+    let line = match last_line(path, is_data_record) {
+        Ok(Some(line)) => line,
+        Ok(None) => return Health::Broken(format!("BROKEN: {} è vuoto", path.display())),
+        Err(e) => return Health::Broken(format!("BROKEN: impossibile leggere {}: {}", path.display(), e)),
+    };
+    let record = match serde_json::from_str::<Protected>(&line) {
+        Ok(record) => record,
+        Err(e) => return Health::Broken(format!("BROKEN: ultimo record non valido in {}: {}", path.display(), e)),
+    };
+
+    let age = (Utc::now() - record.timestamp).to_std().unwrap_or(Duration::ZERO);
+    if age > max_age {
+        Health::Stale(format!(
+            "STALE: ultimo record di {}s fa (limite {}s)",
+            age.as_secs(),
+            max_age.as_secs()
+        ))
+    } else {
+        Health::Healthy(format!("OK: ultimo record di {}s fa", age.as_secs()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use std::fs;
+    use std::time::Duration;
+
+    use super::{check_file, Health};
+    use crate::mapping::MappingConfig;
+    use crate::record::{RecordLayout, SensorData};
+
+    #[test]
+    fn reads_a_record_with_renamed_and_excluded_fields() {
+        let path = std::env::temp_dir().join(format!("sensor-program-healthcheck-{}.jsonl", std::process::id()));
+        let mapping: MappingConfig = toml::from_str(
+            "rename = { ms5611_pressure = \"pressure_hpa\" }\nexclude = [\"ms5611_d1\", \"ms5611_d2\"]",
+        )
+        .unwrap();
+        let record = SensorData { timestamp: Utc::now(), ..SensorData::example() };
+        let mapped = mapping.apply(serde_json::to_value(record).unwrap(), RecordLayout::Nested);
+        assert!(mapped["ms5611"].get("pressure").is_none() && mapped.get("pressure_hpa").is_some());
+        let header = r#"{"type":"header","timestamp":"2026-06-01T10:41:55Z"}"#;
+        fs::write(&path, format!("{}\n{}\n", header, mapped)).unwrap();
+        let health = check_file(&path, Duration::from_secs(60));
+        assert!(matches!(health, Health::Healthy(_)), "{}", health.message());
+
+        fs::write(&path, format!("{}\n{{\"sequence\":1}}\n", header)).unwrap();
+        assert!(matches!(check_file(&path, Duration::from_secs(60)), Health::Broken(_)));
+        fs::remove_file(path).unwrap();
+    }
+}
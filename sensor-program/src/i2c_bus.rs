@@ -0,0 +1,284 @@
+use rppal::i2c::{self, I2c};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::transcript::Recording;
+
+/// Addresses probed by `scan`, the same range as `i2cdetect`.
+pub const SCAN_RANGE: std::ops::RangeInclusive<u16> = 0x03..=0x77;
+
+/// Printed with the errors of a bus whose /dev/i2c-N is missing or not accessible.
+pub const SETUP_HINT: &str = "verificare che l'I2C sia attivo (raspi-config, dtparam=i2c_arm=on in /boot/config.txt \
+                              o /boot/firmware/config.txt) e che l'utente sia nel gruppo i2c";
+
+/// At boot the service can start before the kernel has created
+/// /dev/i2c-N. When the node of the MS5611's bus is missing or not
+/// accessible, the service keeps retrying for `wait_secs`, with a delay
+/// doubling from 1 s up to `retry_max_secs`, and in the meantime logs
+/// the DS18B20s only; 0 gives up at once, as with any other bus error.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct I2cConfig {
+    pub wait_secs: u64,
+    pub retry_max_secs: u64,
+}
+
+impl Default for I2cConfig {
+    fn default() -> Self {
+        I2cConfig { wait_secs: 300, retry_max_secs: 30 }
+    }
+}
+
+impl I2cConfig {
+    pub fn validate(&self) -> Vec<String> {
+        if self.wait_secs > 0 && self.retry_max_secs == 0 {
+            return vec!["i2c.retry_max_secs deve essere maggiore di zero".to_string()];
+        }
+        Vec::new()
+    }
+
+    /// Whether the service waits for `bus` instead of giving up on it.
+    pub fn waits_for(&self, buses: &Buses, bus: u8) -> bool {
+        self.wait_secs > 0 && buses.is_absent(bus)
+    }
+}
+
+/// What the drivers do on a bus: rppal's `I2c` on the hardware, and with
+/// the `sim-test` feature the devices of `sim::SimulatedBus` or a
+/// transcript replayed (`transcript`).
+pub trait Transport: Send {
+    fn set_slave_address(&mut self, address: u16) -> Result<(), Box<dyn Error>>;
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, Box<dyn Error>>;
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Box<dyn Error>>;
+    fn write_read(&mut self, write_buffer: &[u8], read_buffer: &mut [u8]) -> Result<(), Box<dyn Error>>;
+    fn smbus_receive_byte(&mut self) -> Result<u8, Box<dyn Error>>;
+
+    /// Waits for a conversion the device has been told to start.
+    fn delay(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+impl Transport for I2c {
+    fn set_slave_address(&mut self, address: u16) -> Result<(), Box<dyn Error>> {
+        Ok(I2c::set_slave_address(self, address)?)
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, Box<dyn Error>> {
+        Ok(I2c::write(self, buffer)?)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Box<dyn Error>> {
+        Ok(I2c::read(self, buffer)?)
+    }
+
+    fn write_read(&mut self, write_buffer: &[u8], read_buffer: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        Ok(I2c::write_read(self, write_buffer, read_buffer)?)
+    }
+
+    fn smbus_receive_byte(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(I2c::smbus_receive_byte(self)?)
+    }
+}
+
+/// An open /dev/i2c-N shared by every sensor on it. The lock keeps each
+/// sensor's address selection and transfers together.
+#[derive(Clone)]
+pub struct Bus {
+    number: u8,
+    i2c: Arc<Mutex<Box<dyn Transport>>>,
+}
+
+fn open_error(number: u8, e: &i2c::Error) -> String {
+    format!("bus I2C {} non disponibile: {}", number, e)
+}
+
+/// The device node is missing or not accessible, which at boot usually
+/// only means the kernel or udev have not got to it yet.
+fn node_unavailable(e: &i2c::Error) -> bool {
+    matches!(e, i2c::Error::Io(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::PermissionDenied))
+}
+
+impl Bus {
+    pub fn open(number: u8) -> Result<Bus, String> {
+        let i2c = I2c::with_bus(number).map_err(|e| open_error(number, &e))?;
+        Ok(Bus::with_transport(number, Box::new(i2c)))
+    }
+
+    pub fn with_transport(number: u8, transport: Box<dyn Transport>) -> Bus {
+        Bus { number, i2c: Arc::new(Mutex::new(transport)) }
+    }
+
+    /// Runs `f` with the bus locked and `address` selected; errors carry
+    /// the bus and the address.
+    pub fn with_device<T>(
+        &self,
+        address: u16,
+        f: impl FnOnce(&mut dyn Transport) -> Result<T, Box<dyn Error>>,
+    ) -> Result<T, Box<dyn Error>> {
+        let mut i2c = self.i2c.lock().unwrap_or_else(|e| e.into_inner());
+        let result = match i2c.set_slave_address(address) {
+            Ok(()) => f(i2c.as_mut()),
+            Err(e) => Err(e),
+        };
+        result.map_err(|e| format!("bus I2C {}, 0x{:02X}: {}", self.number, address, e).into())
+    }
+
+    /// Addresses that acknowledge a one-byte read.
+    pub fn scan(&self) -> Vec<u16> {
+        SCAN_RANGE
+            .filter(|&address| self.with_device(address, |i2c| i2c.smbus_receive_byte()).is_ok())
+            .collect()
+    }
+}
+
+/// The buses opened at startup, each once, and the ones that failed.
+#[derive(Clone, Default)]
+pub struct Buses {
+    open: BTreeMap<u8, Bus>,
+    failed: BTreeMap<u8, String>,
+    /// The failed ones whose device node was missing or not accessible.
+    absent: BTreeSet<u8>,
+    /// `--record-transcript`: every bus opened is wrapped to record.
+    recording: Option<Recording>,
+}
+
+impl Buses {
+    pub fn open(numbers: impl IntoIterator<Item = u8>) -> Buses {
+        Buses::recorded(numbers, None)
+    }
+
+    /// As `open`, with the transactions on every bus kept in `recording`.
+    pub fn recorded(numbers: impl IntoIterator<Item = u8>, recording: Option<Recording>) -> Buses {
+        let mut buses = Buses { recording, ..Buses::default() };
+        for number in numbers {
+            buses.ensure(number);
+        }
+        buses
+    }
+
+    /// Opens `number` unless it was already tried.
+    pub fn ensure(&mut self, number: u8) {
+        if self.open.contains_key(&number) || self.failed.contains_key(&number) {
+            return;
+        }
+        match I2c::with_bus(number) {
+            Ok(i2c) => {
+                let transport = match &self.recording {
+                    Some(recording) => recording.wrap(number, Box::new(i2c)),
+                    None => Box::new(i2c),
+                };
+                self.open.insert(number, Bus::with_transport(number, transport));
+            }
+            Err(e) => {
+                if node_unavailable(&e) {
+                    self.absent.insert(number);
+                }
+                self.failed.insert(number, open_error(number, &e));
+            }
+        }
+    }
+
+    /// Tries `number` again if it failed; true once it is open.
+    pub fn retry(&mut self, number: u8) -> bool {
+        if self.failed.remove(&number).is_some() {
+            self.absent.remove(&number);
+            self.ensure(number);
+        }
+        self.open.contains_key(&number)
+    }
+
+    /// Whether `number` failed because /dev/i2c-N is missing or not accessible.
+    pub fn is_absent(&self, number: u8) -> bool {
+        self.absent.contains(&number)
+    }
+
+    pub fn get(&self, number: u8) -> Result<&Bus, String> {
+        self.open.get(&number).ok_or_else(|| match self.failed.get(&number) {
+            Some(e) => e.clone(),
+            None => format!("bus I2C {} non aperto", number),
+        })
+    }
+
+    /// Opens every bus again, the failed ones included, e.g. after the
+    /// watchdog found the loop stuck. Handles to the old buses stay valid.
+    pub fn reopen(&mut self) {
+        let numbers: Vec<u8> = self.open.keys().chain(self.failed.keys()).copied().collect();
+        *self = Buses::recorded(numbers, self.recording.take());
+    }
+
+    /// Adds a bus opened elsewhere, e.g. a simulated one.
+    #[cfg(feature = "sim-test")]
+    pub fn insert(&mut self, bus: Bus) {
+        self.failed.remove(&bus.number);
+        self.absent.remove(&bus.number);
+        self.open.insert(bus.number, bus);
+    }
+
+    pub fn failures(&self) -> &BTreeMap<u8, String> {
+        &self.failed
+    }
+}
+
+/// The wait for the MS5611's bus described at `I2cConfig`.
+pub struct BusWait {
+    bus: u8,
+    config: I2cConfig,
+    started: Instant,
+    next_try: Instant,
+    delay: Duration,
+}
+
+impl BusWait {
+    pub fn new(bus: u8, config: I2cConfig, now: Instant) -> BusWait {
+        let delay = Duration::from_secs(1);
+        BusWait { bus, config, started: now, next_try: now + delay, delay }
+    }
+
+    pub fn bus(&self) -> u8 {
+        self.bus
+    }
+
+    /// Whether a retry is due at `now`; if so the next one is scheduled.
+    pub fn is_due(&mut self, now: Instant) -> bool {
+        if now < self.next_try {
+            return false;
+        }
+        self.delay = (self.delay * 2).min(Duration::from_secs(self.config.retry_max_secs));
+        self.next_try = now + self.delay;
+        true
+    }
+
+    pub fn expired(&self, now: Instant) -> bool {
+        self.waited(now) >= Duration::from_secs(self.config.wait_secs)
+    }
+
+    pub fn waited(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.started)
+    }
+}
+
+/// Bus numbers with a /dev/i2c-N node, in order.
+pub fn available() -> std::io::Result<Vec<u8>> {
+    let mut numbers: Vec<u8> = fs::read_dir("/dev")?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_str()?.strip_prefix("i2c-")?.parse().ok())
+        .collect();
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+/// Chips this program knows at `address`.
+pub fn known_device(address: u16) -> Option<&'static str> {
+    match address {
+        0x40..=0x4F => Some("INA219"),
+        0x76 | 0x77 => Some("MS5611"),
+        _ => None,
+    }
+}
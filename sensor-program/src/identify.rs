@@ -0,0 +1,194 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::Ms5611Config;
+use crate::i2c_bus::Bus;
+use crate::ms5611;
+
+/// Checks at startup that the chip at each configured address is the one
+/// expected, so that e.g. a BME280 wired in place of the MS5611 is not
+/// logged as hours of plausible-looking pressures. A mismatch is handled
+/// like a sensor missing at startup: a required sensor stops the service,
+/// an optional one is dropped from the run; with `strict` every mismatch
+/// stops it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentifyConfig {
+    pub enabled: bool,
+    pub strict: bool,
+}
+
+impl Default for IdentifyConfig {
+    fn default() -> Self {
+        IdentifyConfig { enabled: true, strict: false }
+    }
+}
+
+/// Bosch pressure sensors that answer at the MS5611 addresses, by chip-ID
+/// register and value.
+const BOSCH_IDS: [(u8, u8, &str); 5] = [
+    (0xD0, 0x60, "BME280"),
+    (0xD0, 0x58, "BMP280"),
+    (0xD0, 0x61, "BME680"),
+    (0x00, 0x50, "BMP388"),
+    (0x00, 0x60, "BMP390"),
+];
+
+/// The INA219 configuration register after power-on; bit 14 is unused and
+/// reads 0, while it is set in the reset value of the INA226 family.
+const INA219_CONFIG: u8 = 0x00;
+const INA219_CONFIG_RESET: u16 = 0x399F;
+const INA226_MANUFACTURER: u8 = 0xFE;
+const TI_MANUFACTURER_ID: u16 = 0x5449;
+
+fn read_u8(bus: &Bus, address: u16, register: u8) -> Result<u8, Box<dyn std::error::Error>> {
+    let mut buf = [0u8; 1];
+    bus.with_device(address, |i2c| i2c.write_read(&[register], &mut buf))?;
+    Ok(buf[0])
+}
+
+fn read_u16(bus: &Bus, address: u16, register: u8) -> Result<u16, Box<dyn std::error::Error>> {
+    let mut buf = [0u8; 2];
+    bus.with_device(address, |i2c| i2c.write_read(&[register], &mut buf))?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+/// The PROM of an MS5611 has a valid CRC-4 and C1..C6 that are neither 0
+/// nor 0xFFFF. Only when it does not are the ID registers of the Bosch
+/// sensors read, to say what was wired instead: an MS5611 takes single
+/// bytes as commands, so those reads are not sent to a chip that passed.
+pub fn ms5611(bus: &Bus, config: &Ms5611Config) -> Result<String, String> {
+    let prom = ms5611::read_prom(bus, config).map_err(|e| format!("nessuna risposta: {}", e))?;
+    let coefficients = ms5611::coefficients(&prom);
+    let problem = if coefficients.iter().any(|&c| c == 0 || c == 0xFFFF) {
+        format!("coefficienti PROM non plausibili {:04X?}", coefficients)
+    } else if !ms5611::prom_crc_ok(&prom) {
+        format!("CRC PROM non valido {:04X?}", prom)
+    } else {
+        return Ok("MS5611, CRC PROM valido".to_string());
+    };
+    let found = BOSCH_IDS.iter().find(|&&(register, id, _)| read_u8(bus, config.address, register).ok() == Some(id));
+    Err(match found {
+        Some((register, id, chip)) => {
+            format!("atteso MS5611, trovato {} (ID 0x{:02X} nel registro 0x{:02X})", chip, id, register)
+        }
+        None => format!("atteso MS5611, trovato un dispositivo sconosciuto: {}", problem),
+    })
+}
+
+/// The INA219 has no ID register: its configuration register is checked
+/// instead, and the manufacturer ID of the INA226 family is read when it
+/// does not fit.
+pub fn ina219(bus: &Bus, address: u16) -> Result<String, String> {
+    let config = read_u16(bus, address, INA219_CONFIG).map_err(|e| format!("nessuna risposta: {}", e))?;
+    if config & 0x4000 == 0 {
+        let detail = if config == INA219_CONFIG_RESET { ", configurazione di fabbrica" } else { "" };
+        return Ok(format!("INA219 (configurazione 0x{:04X}{})", config, detail));
+    }
+    Err(match read_u16(bus, address, INA226_MANUFACTURER) {
+        Ok(TI_MANUFACTURER_ID) => {
+            format!("atteso INA219, trovato un INA226/INA260 (ID produttore 0x{:04X})", TI_MANUFACTURER_ID)
+        }
+        _ => format!("atteso INA219, configurazione 0x{:04X} non compatibile", config),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::time::Duration;
+
+    use super::ms5611;
+    use crate::config::{Config, Ms5611Config};
+    use crate::i2c_bus::{Bus, Transport};
+
+    /// A BME280: registers read after a one-byte write of their address,
+    /// all 0 but the chip ID.
+    struct Bme280 {
+        register: u8,
+    }
+
+    impl Transport for Bme280 {
+        fn set_slave_address(&mut self, _: u16) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        fn write(&mut self, buffer: &[u8]) -> Result<usize, Box<dyn Error>> {
+            self.register = buffer[0];
+            Ok(buffer.len())
+        }
+
+        fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Box<dyn Error>> {
+            for (offset, byte) in buffer.iter_mut().enumerate() {
+                *byte = if usize::from(self.register) + offset == 0xD0 { 0x60 } else { 0 };
+            }
+            Ok(buffer.len())
+        }
+
+        fn write_read(&mut self, write_buffer: &[u8], read_buffer: &mut [u8]) -> Result<(), Box<dyn Error>> {
+            self.write(write_buffer)?;
+            self.read(read_buffer)?;
+            Ok(())
+        }
+
+        fn smbus_receive_byte(&mut self) -> Result<u8, Box<dyn Error>> {
+            Ok(0)
+        }
+
+        fn delay(&mut self, _: Duration) {}
+    }
+
+    fn bme280(number: u8) -> Bus {
+        Bus::with_transport(number, Box::new(Bme280 { register: 0 }))
+    }
+
+    #[test]
+    fn names_the_bosch_chip_found_instead() {
+        let error = ms5611(&bme280(1), &Ms5611Config::default()).unwrap_err();
+        assert_eq!(error, "atteso MS5611, trovato BME280 (ID 0x60 nel registro 0xD0)");
+    }
+
+    #[cfg(feature = "sim-test")]
+    fn start_with_secondary_bme280(name: &str, strict: bool) -> Result<Vec<serde_json::Value>, String> {
+        use crate::sim::{self, bench::Bench};
+
+        let edit = |config: &mut Config| {
+            config.identify.strict = strict;
+            config.voting = Some(toml::from_str("secondary = { bus = 2, address = 0x76 }").unwrap());
+        };
+        let buses = |config: &Config, clock: &_| {
+            let mut buses = sim::buses(config, clock);
+            buses.insert(bme280(2));
+            buses
+        };
+        let mut bench = Bench::with_buses(name, Duration::ZERO, edit, buses)?;
+        bench.run(2);
+        let (records, events) = bench.finish();
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|record| record.get("voting").is_none()));
+        Ok(events)
+    }
+
+    #[cfg(feature = "sim-test")]
+    #[test]
+    fn drops_a_mismatched_optional_sensor_unless_strict() {
+        let events = start_with_secondary_bme280("identify", false).unwrap();
+        let dropped: Vec<_> = events.iter().filter(|event| event["category"] == "sensor_availability").collect();
+        assert_eq!(dropped.len(), 1);
+        let payload = &dropped[0]["payload"];
+        assert_eq!(payload["sensor"], "ms5611_secondary");
+        assert_eq!(payload["required"], false);
+        assert_eq!(payload["reason"], "atteso MS5611, trovato BME280 (ID 0x60 nel registro 0xD0)");
+
+        let error = start_with_secondary_bme280("identify-strict", true).unwrap_err();
+        assert!(error.ends_with("atteso MS5611, trovato BME280 (ID 0x60 nel registro 0xD0)"), "{}", error);
+    }
+
+    #[test]
+    fn a_changed_identify_needs_a_restart() {
+        let mut config = Config::default();
+        let mut new = Config::default();
+        new.identify.strict = true;
+        assert_eq!(config.apply_reload(new), ["identify"]);
+        assert!(!config.identify.strict);
+    }
+}
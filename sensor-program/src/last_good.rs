@@ -0,0 +1,114 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+
+use crate::record::{self, SensorData};
+
+/// Suffix of the field put beside a filled one: its age in seconds.
+pub const AGE_SUFFIX: &str = "_age_s";
+
+/// The sinks with `fill_last_good` get every record with the measurements
+/// it lacks taken from the last good reading; one older than
+/// `max_age_secs` is sent as null all the same.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LastGoodConfig {
+    pub max_age_secs: u64,
+}
+
+impl Default for LastGoodConfig {
+    fn default() -> Self {
+        LastGoodConfig { max_age_secs: 600 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    Good,
+    /// In the record's `suspect` list when it was read.
+    Suspect,
+}
+
+struct Entry {
+    value: Value,
+    timestamp: DateTime<Utc>,
+    quality: Quality,
+}
+
+/// The last value read of every sensor measurement, by flat-layout key
+/// (`ds18b20_1`, `ds18b20_extra_<id>`, `exec_<name>`). A failed read
+/// leaves the previous value, which only `clear` takes away.
+#[derive(Default)]
+pub struct LastGood {
+    entries: BTreeMap<String, Entry>,
+}
+
+impl LastGood {
+    /// Takes the measurements `record` read; a warmup record is left out.
+    pub fn update(&mut self, record: &SensorData) {
+        if record.warmup {
+            return;
+        }
+        // Through the text, so an f32 keeps the digits the record shows.
+        let Ok(Value::Object(fields)) =
+            serde_json::to_string(record).and_then(|text| serde_json::from_str::<Value>(&text))
+        else {
+            return;
+        };
+        for (key, value) in record::flatten(fields) {
+            if !key.starts_with("ds18b20_") && !key.starts_with("exec_") || !value.is_number() {
+                continue;
+            }
+            let quality = if record.suspect.contains(&key) { Quality::Suspect } else { Quality::Good };
+            self.entries.insert(key, Entry { value, timestamp: record.timestamp, quality });
+        }
+    }
+
+    /// Forgets the measurement of a sensor disabled or gone.
+    pub fn clear(&mut self, key: &str) {
+        self.entries.remove(key);
+    }
+
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.entries.retain(|key, _| keep(key));
+    }
+
+    /// `record`, a data record as JSON, with every cached measurement it
+    /// lacks or has as null put back and `<key>_age_s` beside it. A value
+    /// read while suspect goes back in the `suspect` list.
+    pub fn fill(&self, record: Value, config: &LastGoodConfig) -> Value {
+        let Value::Object(fields) = record else {
+            return record;
+        };
+        let timestamp = fields.get("timestamp").and_then(Value::as_str).and_then(|text| text.parse().ok());
+        let Some(timestamp) = timestamp.filter(|_| !self.entries.is_empty()) else {
+            return Value::Object(fields);
+        };
+        let mut flat = record::flatten(fields);
+        let mut suspect = Vec::new();
+        for (key, entry) in &self.entries {
+            if flat.get(key).is_some_and(|value| !value.is_null()) {
+                continue;
+            }
+            let age = age_secs(timestamp, entry.timestamp);
+            let fresh = age <= config.max_age_secs as f64;
+            flat.insert(key.clone(), if fresh { entry.value.clone() } else { Value::Null });
+            flat.insert(format!("{}{}", key, AGE_SUFFIX), json!(age));
+            if fresh && entry.quality == Quality::Suspect {
+                suspect.push(Value::String(key.clone()));
+            }
+        }
+        if !suspect.is_empty()
+            && let Value::Array(list) = flat.entry("suspect").or_insert_with(|| Value::Array(Vec::new()))
+        {
+            list.extend(suspect);
+        }
+        Value::Object(record::unflatten(flat))
+    }
+}
+
+/// To a tenth of a second.
+fn age_secs(now: DateTime<Utc>, then: DateTime<Utc>) -> f64 {
+    ((now - then).num_milliseconds() as f64 / 100.0).round() / 10.0
+}
@@ -0,0 +1,115 @@
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::flight::FlightState;
+use crate::pipeline::SinkStats;
+use crate::units::{self, Unit};
+
+/// Values kept per measurement for the recent history.
+pub const HISTORY_LEN: usize = 60;
+
+/// What the service was doing at its last cycle, for a front-end that
+/// shows it live (`--tui`). The service updates it as it samples; every
+/// value is a copy, so a reader holding the lock never waits on a sensor.
+#[derive(Default)]
+pub struct LiveState {
+    pub session_id: String,
+    pub boot_id: String,
+    pub uptime_secs: u64,
+    pub sequence: Option<u64>,
+    pub timestamp: Option<DateTime<Utc>>,
+    pub flight_state: FlightState,
+    pub burst_mode: bool,
+    /// Set while the service waits for the MS5611's bus (`[i2c]`).
+    pub waiting_for_bus: Option<u8>,
+    /// Set when the last record was taken during the `[warmup]`; its
+    /// values are shown but left out of min, max and history.
+    pub warmup: bool,
+    /// By flat-layout key, in the order of the keys.
+    pub measurements: BTreeMap<String, Measurement>,
+    /// By the sensor names used in the events.
+    pub sensors: BTreeMap<String, SensorStatus>,
+    pub sinks: Vec<SinkStats>,
+}
+
+pub struct Measurement {
+    pub unit: Unit,
+    /// Null when the last read failed.
+    pub value: Option<f64>,
+    pub min: f64,
+    pub max: f64,
+    pub history: VecDeque<f64>,
+    pub suspect: bool,
+}
+
+#[derive(Default)]
+pub struct SensorStatus {
+    pub ok: bool,
+    pub errors: u64,
+    pub last_error: Option<String>,
+}
+
+impl LiveState {
+    /// Takes the numeric fields of a data record that have a physical
+    /// unit; raw ADC counts and the timing fields are left out.
+    pub fn record(&mut self, record: &Value, units: &BTreeMap<String, Unit>) {
+        let Some(fields) = record.as_object() else {
+            return;
+        };
+        self.warmup = fields.get("warmup").and_then(Value::as_bool).unwrap_or(false);
+        let suspect: Vec<&str> =
+            fields.get("suspect").and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str).collect();
+        for (key, value) in crate::record::flatten(fields.clone()) {
+            let offsets = ["timing_", "capture_offsets_ms_", "acquisition_offsets_ms_"];
+            if offsets.iter().any(|prefix| key.starts_with(prefix)) {
+                continue;
+            }
+            let Some(unit) = units::unit_for(units, &key).filter(|&unit| unit != Unit::Adc) else {
+                continue;
+            };
+            if value.is_null() || value.is_number() {
+                self.update(&key, unit, value.as_f64(), suspect.contains(&key.as_str()), !self.warmup);
+            }
+        }
+        self.sequence = fields.get("sequence").and_then(Value::as_u64).or(self.sequence);
+    }
+
+    pub fn measure(&mut self, key: &str, unit: Unit, value: Option<f64>, suspect: bool) {
+        self.update(key, unit, value, suspect, true);
+    }
+
+    fn update(&mut self, key: &str, unit: Unit, value: Option<f64>, suspect: bool, counted: bool) {
+        let measurement = self.measurements.entry(key.to_string()).or_insert_with(|| Measurement {
+            unit,
+            value: None,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            suspect,
+        });
+        measurement.value = value;
+        measurement.suspect = suspect;
+        if let Some(value) = value
+            && counted
+        {
+            measurement.min = measurement.min.min(value);
+            measurement.max = measurement.max.max(value);
+            if measurement.history.len() == HISTORY_LEN {
+                measurement.history.pop_front();
+            }
+            measurement.history.push_back(value);
+        }
+    }
+
+    pub fn sensor_ok(&mut self, sensor: &str) {
+        self.sensors.entry(sensor.to_string()).or_default().ok = true;
+    }
+
+    pub fn sensor_error(&mut self, sensor: &str, error: &str) {
+        let status = self.sensors.entry(sensor.to_string()).or_default();
+        status.ok = false;
+        status.errors += 1;
+        status.last_error = Some(error.to_string());
+    }
+}
@@ -1,140 +1,553 @@
-use rppal::i2c::I2c;
-use std::{thread, time};
+mod acquisition;
+mod actions;
+mod analyze;
+mod availability;
+mod battery;
+mod burst;
+mod calibration;
+mod cli;
+mod clock;
+#[cfg(feature = "parquet")]
+mod columnar;
+mod compact;
+mod config;
+mod control;
+mod convert;
+mod deadband;
+mod ds18b20;
+mod events;
+mod exec;
+mod flight;
+mod flight_dir;
+mod gap;
+mod healthcheck;
+mod i2c_bus;
+mod identify;
+mod last_good;
+#[cfg(feature = "tui")]
+mod live;
+mod mapping;
+mod ms5611;
+mod overrides;
+mod pipeline;
+mod record;
+mod repeats;
+mod replay;
+mod ringbuffer;
+#[cfg(feature = "tokio-runtime")]
+mod runtime_tokio;
+mod schema;
+mod selftest;
+mod service;
+mod session;
+mod setpoints;
+#[cfg(feature = "sim-test")]
+mod sim;
+mod sinks;
+#[cfg(feature = "sim-test")]
+mod soak;
+mod stuck;
+mod telemetry;
+mod timesync;
+mod timing;
+mod transcript;
+#[cfg(feature = "tui")]
+mod tui;
+mod units;
+mod voting;
+mod warmup;
+mod watchdog;
+mod writer;
+
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
-use serde::{Serialize, Deserialize};
+use std::path::Path;
+use std::sync::Arc;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct SensorData {
-    ms5611: MS5611Data,
-    ds18b20_1: f32,
-    ds18b20_2: f32,
-}
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
+
+use cli::{
+    AnalyzeOptions, CalibrateOptions, Command, ConvertOptions, CtlOptions, HealthcheckOptions, ReplayOptions,
+    RunOptions, ScanOptions, SchemaOptions, SelfTestOptions, EXIT_CANT_CREATE, EXIT_CONFIG, EXIT_IO, EXIT_LOCKED,
+    EXIT_READ_FAILED, EXIT_UNAVAILABLE, EXIT_USAGE, USAGE,
+};
+use clock::{Clock, SystemClock};
+use config::Config;
+use i2c_bus::{Bus, Buses, SETUP_HINT};
+use service::{Service, Signals};
+use transcript::Recording;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct MS5611Data {
-    d1: u32,
-    d2: u32,
-    temperature: f64,
-    pressure: f64,
+fn load_config(path: &Path) -> Config {
+    match Config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Errore configurazione: {}", e);
+            std::process::exit(EXIT_CONFIG);
+        }
+    }
 }
 
-fn read_calibration_word(i2c: &mut I2c, addr: u8) -> Result<u16, Box<dyn std::error::Error>> {
-    let mut buf = [0u8; 2];
-    i2c.write(&[addr])?;
-    thread::sleep(time::Duration::from_millis(10));
-    i2c.read(&mut buf)?;
-    Ok(((buf[0] as u16) << 8) | buf[1] as u16)
+fn healthcheck(options: HealthcheckOptions) -> ! {
+    let file = match options.file {
+        Some(file) => file,
+        None => {
+            let mut config = load_config(&options.config_path);
+            if let Some(dir) = session::last_dir(&config.session).filter(|_| config.flight_dir.is_enabled()) {
+                flight_dir::relocate(&mut config, &dir);
+            }
+            match session::last(&config.session) {
+                Some(id) => config.output.path_for(&id).into(),
+                None if !config.output.path.contains(session::PLACEHOLDER) => config.output.path.into(),
+                None => {
+                    println!("BROKEN: nessuna sessione registrata in {}", config.session.state_file);
+                    std::process::exit(2);
+                }
+            }
+        }
+    };
+    let health = healthcheck::check_file(&file, options.max_age);
+    println!("{}", health.message());
+    std::process::exit(health.exit_code());
 }
 
-fn read_and_calculate_ms5611() -> Result<MS5611Data, Box<dyn std::error::Error>> {
-    let mut i2c = I2c::with_bus(1)?;
-    i2c.set_slave_address(0x77)?;
+fn ctl(options: CtlOptions) -> ! {
+    let socket = options.socket.unwrap_or_else(|| {
+        let config = load_config(&options.config_path);
+        if !config.control.is_enabled() {
+            eprintln!("control.socket non impostato in {}", options.config_path.display());
+            std::process::exit(EXIT_CONFIG);
+        }
+        config.control.socket.into()
+    });
+    let response = match control::send(&socket, options.command) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Servizio non raggiungibile: {}", e);
+            std::process::exit(EXIT_UNAVAILABLE);
+        }
+    };
+    if response["ok"] != true {
+        eprintln!("Comando {} rifiutato: {}", options.command.name(), response["error"].as_str().unwrap_or("?"));
+        std::process::exit(1);
+    }
+    match serde_json::to_string_pretty(&response["result"]) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("Errore serializzazione JSON: {}", e),
+    }
+    std::process::exit(0);
+}
 
-    i2c.write(&[0x48])?;
-    thread::sleep(time::Duration::from_millis(50));
-    i2c.write(&[0x00])?;
-    let mut buf = [0u8; 3];
-    i2c.read(&mut buf)?;
-    let d1 = ((buf[0] as u32) << 16) | ((buf[1] as u32) << 8) | buf[2] as u32;
+fn check_config(path: &Path) -> ! {
+    let config = load_config(path);
+    if !path.exists() {
+        println!("{} non esiste: verranno usati i valori predefiniti", path.display());
+    }
+    for warning in config.mapping.warnings(&config.exec) {
+        println!("Attenzione: {}", warning);
+    }
+    println!("Configurazione valida: {}", path.display());
+    std::process::exit(0);
+}
 
-    i2c.write(&[0x58])?;
-    thread::sleep(time::Duration::from_millis(50));
-    i2c.write(&[0x00])?;
-    i2c.read(&mut buf)?;
-    let d2 = ((buf[0] as u32) << 16) | ((buf[1] as u32) << 8) | buf[2] as u32;
+fn schema(options: SchemaOptions) -> ! {
+    let config = load_config(&options.config_path);
+    let record_schema = schema::data_record(&config, options.raw);
+    let Some(path) = options.check else {
+        match serde_json::to_string_pretty(&record_schema) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Errore serializzazione JSON: {}", e),
+        }
+        std::process::exit(0);
+    };
+    match schema::check_file(&record_schema, &path) {
+        Ok((records, errors)) => {
+            for error in &errors {
+                println!("{}", error);
+            }
+            println!("{}: {} record di dati, {} non conformi", path.display(), records, errors.len());
+            std::process::exit(if errors.is_empty() { 0 } else { 1 });
+        }
+        Err(e) => {
+            eprintln!("Errore lettura {}: {}", path.display(), e);
+            std::process::exit(EXIT_IO);
+        }
+    }
+}
 
-    let c1 = read_calibration_word(&mut i2c, 0xA2)? as u32;
-    let c2 = read_calibration_word(&mut i2c, 0xA4)? as u32;
-    let c3 = read_calibration_word(&mut i2c, 0xA6)? as u32;
-    let c4 = read_calibration_word(&mut i2c, 0xA8)? as u32;
-    let c5 = read_calibration_word(&mut i2c, 0xAA)? as u32;
-    let c6 = read_calibration_word(&mut i2c, 0xAC)? as u32;
+fn self_test(options: SelfTestOptions) -> ! {
+    let config = load_config(&options.config_path);
+    let report = selftest::run(&config);
+    print_report(&report, options.json);
+    std::process::exit(if report.passed { 0 } else { 1 });
+}
 
-    let d_t = d2 as i64 - (c5 as i64 * 256);
-    let temp = 2000 + (d_t * c6 as i64) / (1 << 23);
-    let off = (c2 as i64) * (1 << 16) + ((c4 as i64) * d_t) / (1 << 7);
-    let sens = (c1 as i64) * (1 << 15) + ((c3 as i64) * d_t) / (1 << 8);
-    let press = (((d1 as i64 * sens) / (1 << 21)) - off) / (1 << 15);
+fn print_report(report: &selftest::Report, json: bool) {
+    if json {
+        match serde_json::to_string_pretty(report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Errore serializzazione JSON: {}", e),
+        }
+        return;
+    }
+    for check in &report.checks {
+        let outcome = match (check.passed, check.required) {
+            (true, _) => "PASS",
+            (false, true) => "FAIL",
+            (false, false) => "WARN",
+        };
+        println!("{} {}: {}", outcome, check.name, check.detail);
+    }
+}
 
-    let temperature = temp as f64 / 100.0;
-    let pressure = press as f64 / 100.0;
+#[cfg(feature = "sim-test")]
+fn soak(options: cli::SoakOptions) -> ! {
+    let config = load_config(&options.config_path);
+    let report = match soak::run(config, &options) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Errore soak: {}", e);
+            std::process::exit(EXIT_CANT_CREATE);
+        }
+    };
+    println!("Soak: {} cicli, file in {}", options.records, options.dir.display());
+    print_report(&report, false);
+    if report.passed
+        && !options.keep
+        && let Err(e) = std::fs::remove_dir_all(&options.dir)
+    {
+        eprintln!("Impossibile rimuovere {}: {}", options.dir.display(), e);
+    }
+    std::process::exit(if report.passed { 0 } else { 1 });
+}
 
-    Ok(MS5611Data { d1, d2, temperature, pressure })
+#[cfg(feature = "sim-test")]
+fn transcript(options: cli::TranscriptOptions) -> ! {
+    let mut report = selftest::Report { passed: true, checks: Vec::new() };
+    for path in &options.fixtures {
+        report.push(path.display().to_string(), true, transcript::check(path, options.bless));
+    }
+    print_report(&report, false);
+    std::process::exit(if report.passed { 0 } else { 1 });
 }
 
-fn read_temperature_ds18b20(sensor_id: &str) -> Result<f32, Box<dyn std::error::Error>> {
-    let path = format!("/sys/bus/w1/devices/{}/w1_slave", sensor_id);
-    let mut content = String::new();
-    File::open(path)?.read_to_string(&mut content)?;
+fn calibrate(options: CalibrateOptions) -> ! {
+    let config = load_config(&options.config_path);
+    let sensor = options.sensor;
+    println!("Calibrazione {}: {} letture, riferimento {}", sensor.key(), options.samples, options.reference);
+    let result = match calibration::measure(&config, sensor, options.reference, options.samples) {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("Errore calibrazione: {}", e);
+            std::process::exit(EXIT_READ_FAILED);
+        }
+    };
+    let current = config.calibration.offset(sensor);
+    println!("Media: {:.4}", result.mean);
+    println!("Deviazione standard: {:.4}", result.stddev);
+    println!("Offset: {:+.4} (attuale {:+.4})", result.offset, current);
+    if !options.write {
+        std::process::exit(0);
+    }
 
-    if content.contains("YES") {
-        let temp_pos = content.find("t=").ok_or("Valore t= non trovato")? + 2;
-        let temp_str = &content[temp_pos..].trim();
-        let temp_raw: f32 = temp_str.parse()?;
-        Ok(temp_raw / 1000.0)
-    } else {
-        Err("Errore nella lettura del DS18B20".into())
+    let max_stddev = options.max_stddev.unwrap_or(sensor.default_max_stddev());
+    if result.stddev > max_stddev {
+        eprintln!(
+            "Deviazione standard {:.4} oltre il limite {:.4}: ambiente non stabile, offset non salvato",
+            result.stddev, max_stddev
+        );
+        std::process::exit(1);
+    }
+    if let Err(e) = calibration::write_offset(&options.config_path, sensor, result.offset) {
+        eprintln!("Errore scrittura {}: {}", options.config_path.display(), e);
+        std::process::exit(EXIT_IO);
     }
+    println!(
+        "Offset salvato in [calibration] di {} (SIGUSR1 per applicarlo al servizio in esecuzione)",
+        options.config_path.display()
+    );
+    std::process::exit(0);
 }
 
-fn log_data_to_json(sensor_data: SensorData) -> Result<(), Box<dyn std::error::Error>> {
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("sensor_data.json")?;
+fn replay(options: ReplayOptions) -> ! {
+    match replay::replay(&options) {
+        Ok(summary) => {
+            eprintln!("{} record ricostruiti", summary.filled);
+            if summary.copied > 0 {
+                eprintln!("{} righe copiate senza modifiche", summary.copied);
+            }
+            if options.recompute.is_some() {
+                eprintln!(
+                    "{} record ricalcolati da d1/d2, {} senza PROM con i valori salvati",
+                    summary.recomputed, summary.kept
+                );
+            }
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("Errore replay {}: {}", options.input.display(), e);
+            std::process::exit(EXIT_IO);
+        }
+    }
+}
 
-    let json_data = serde_json::to_string(&sensor_data)?;
+fn analyze(options: AnalyzeOptions) -> ! {
+    let sessions = match analyze::analyze(&options) {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            eprintln!("Errore analisi: {}", e);
+            std::process::exit(EXIT_IO);
+        }
+    };
+    if options.json {
+        match serde_json::to_string_pretty(&sessions) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Errore serializzazione JSON: {}", e),
+        }
+        std::process::exit(0);
+    }
+    if sessions.is_empty() {
+        println!("Nessun record di dati");
+    }
+    for session in &sessions {
+        let id = if session.session_id.is_empty() { "(senza sessione)" } else { &session.session_id };
+        println!(
+            "Sessione {}: {} record, {} avvii, {} -> {} ({} s)",
+            id,
+            session.records,
+            session.boot_ids.len(),
+            session.first,
+            session.last,
+            session.duration_secs
+        );
+        println!(
+            "  altitudine max {:.1} m, pressione min {:.2} hPa, temperatura MS5611 {:.2}..{:.2} °C",
+            session.max_altitude_m, session.min_pressure_hpa, session.min_temperature_c, session.max_temperature_c
+        );
+        if let (Some(min), Some(max)) = (session.min_vertical_speed_ms, session.max_vertical_speed_ms) {
+            println!("  velocità verticale {:.1}..{:.1} m/s", min, max);
+        }
+        let states: Vec<_> = session.flight_states.iter().map(|state| format!("{:?}", state)).collect();
+        println!("  stati di volo: {}", states.join(" -> "));
+        if session.clock_invalid_records > 0 {
+            let records = session.clock_invalid_records;
+            println!("  {} record con l'orologio non impostato, esclusi dall'intervallo", records);
+        }
+        if session.incomplete_records > 0 {
+            println!("  {} record con letture DS18B20 mancanti", session.incomplete_records);
+        }
+        for header in &session.headers {
+            println!("  {}", header.describe());
+        }
+    }
+    std::process::exit(0);
+}
 
-    writeln!(file, "{}", json_data)?;
+fn scan(options: ScanOptions) -> ! {
+    let numbers = match options.bus {
+        Some(bus) => vec![bus],
+        None => i2c_bus::available().unwrap_or_default(),
+    };
+    if numbers.is_empty() {
+        eprintln!("Nessun bus I2C in /dev");
+        std::process::exit(EXIT_UNAVAILABLE);
+    }
+    let mut opened = 0;
+    for number in numbers {
+        let bus = match Bus::open(number) {
+            Ok(bus) => bus,
+            Err(e) => {
+                println!("Bus I2C {}: {}", number, e);
+                continue;
+            }
+        };
+        opened += 1;
+        let found = bus.scan();
+        println!("Bus I2C {}: {} dispositivi", number, found.len());
+        for address in found {
+            match i2c_bus::known_device(address) {
+                Some(name) => println!("  0x{:02X} {}", address, name),
+                None => println!("  0x{:02X}", address),
+            }
+        }
+    }
+    std::process::exit(if opened > 0 { 0 } else { EXIT_UNAVAILABLE });
+}
 
-    Ok(())
+fn convert(options: ConvertOptions) -> ! {
+    match convert::convert(&options) {
+        Ok(count) => {
+            eprintln!("{} righe convertite", count);
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("Errore conversione {}: {}", options.input.display(), e);
+            std::process::exit(EXIT_IO);
+        }
+    }
+}
+
+fn lock_output(path: &str) -> File {
+    let lock_path = format!("{}.lock", path);
+    let lock = match OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path) {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("Impossibile creare il file di lock {}: {}", lock_path, e);
+            std::process::exit(EXIT_LOCKED);
+        }
+    };
+    if lock.try_lock().is_err() {
+        eprintln!("Un'altra istanza sta già scrivendo su {} (lock {})", path, lock_path);
+        std::process::exit(EXIT_LOCKED);
+    }
+    lock
 }
 
 fn main() {
-    loop {
-        match read_and_calculate_ms5611() {
-            Ok(ms5611_data) => {
-                println!("Raw D1 (pressione): {}", ms5611_data.d1);
-                println!("Raw D2 (temperatura): {}", ms5611_data.d2);
-                println!("Temperatura calcolata: {:.2} °C", ms5611_data.temperature);
-                println!("Pressione calcolata: {:.2} hPa", ms5611_data.pressure);
-
-                let sensor1 = "28-277a480a6461";
-                let sensor2 = "28-7c7a480a6461";
-
-                let ds18b20_1_temp = match read_temperature_ds18b20(sensor1) {
-                    Ok(temp) => temp,
-                    Err(e) => {
-                        println!("Errore lettura DS18B20 1: {}", e);
-                        0.0
-                    }
-                };
-
-                let ds18b20_2_temp = match read_temperature_ds18b20(sensor2) {
-                    Ok(temp) => temp,
-                    Err(e) => {
-                        println!("Errore lettura DS18B20 2: {}", e);
-                        0.0
-                    }
-                };
-
-                println!("Temperatura DS18B20 1: {:.2} °C", ds18b20_1_temp);
-                println!("Temperatura DS18B20 2: {:.2} °C", ds18b20_2_temp);
-
-                let sensor_data = SensorData {
-                    ms5611: ms5611_data,
-                    ds18b20_1: ds18b20_1_temp,
-                    ds18b20_2: ds18b20_2_temp,
-                };
-
-                if let Err(e) = log_data_to_json(sensor_data) {
-                    println!("Errore nel salvataggio dei dati nel file JSON: {}", e);
-                }
-            }
-            Err(e) => println!("Errore MS5611: {}", e),
+    let options = match Command::parse(std::env::args().skip(1)) {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Healthcheck(options)) => healthcheck(options),
+        Ok(Command::CheckConfig(path)) => check_config(&path),
+        Ok(Command::SelfTest(options)) => self_test(options),
+        Ok(Command::Schema(options)) => schema(options),
+        Ok(Command::Calibrate(options)) => calibrate(options),
+        Ok(Command::Convert(options)) => convert(options),
+        Ok(Command::Replay(options)) => replay(options),
+        Ok(Command::Scan(options)) => scan(options),
+        Ok(Command::Analyze(options)) => analyze(options),
+        Ok(Command::Ctl(options)) => ctl(options),
+        #[cfg(feature = "sim-test")]
+        Ok(Command::Soak(options)) => soak(options),
+        #[cfg(feature = "sim-test")]
+        Ok(Command::Transcript(options)) => transcript(options),
+        Ok(Command::Help) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(EXIT_USAGE);
         }
+    };
+    run(options);
+}
 
-        thread::sleep(time::Duration::from_secs(5));
+fn run(options: RunOptions) {
+    let layers = match Config::load_layers(&options.config_path, &options.overrides) {
+        Ok(layers) => layers,
+        Err(e) => {
+            eprintln!("Errore configurazione: {}", e);
+            std::process::exit(EXIT_CONFIG);
+        }
+    };
+    if options.print_effective_config {
+        println!("{}", layers.describe());
+        std::process::exit(0);
+    }
+    let mut config = layers.config;
+
+    let recording = options.record_transcript.as_ref().map(|_| Recording::new(config.clone(), chrono::Utc::now()));
+    let devices = config.i2c_devices();
+    let buses = Buses::recorded(devices.iter().map(|&(_, bus, _)| bus), recording.clone());
+    // The service reports the wait for the MS5611's bus itself.
+    let awaited = config.i2c.waits_for(&buses, config.ms5611.bus).then_some(config.ms5611.bus);
+    for (number, e) in buses.failures().iter().filter(|&(number, _)| Some(*number) != awaited) {
+        let skipped: Vec<_> = devices
+            .iter()
+            .filter(|&&(_, bus, _)| bus == *number)
+            .map(|(name, _, address)| format!("{} (0x{:02X})", name, address))
+            .collect();
+        eprintln!("{}; sensori disattivati: {}", e, skipped.join(", "));
+    }
+    if let Err(e) = buses.get(config.ms5611.bus)
+        && awaited.is_none()
+    {
+        let hint = if buses.is_absent(config.ms5611.bus) { format!("; {}", SETUP_HINT) } else { String::new() };
+        eprintln!("MS5611 non utilizzabile ({}{}): impossibile avviare", e, hint);
+        std::process::exit(EXIT_UNAVAILABLE);
+    }
+
+    if options.dry_run {
+        println!("*** MODALITÀ DRY-RUN: nessun dato viene salvato su file o inviato ***");
+    }
+    let mut session = session::start(&config.session, options.flight_id.clone(), chrono::Utc::now());
+    if config.flight_dir.is_enabled() && !options.dry_run {
+        let last = options.resume.then(|| session::last_dir(&config.session)).flatten();
+        let dir = last.unwrap_or_else(|| config.flight_dir.expand(&session.id, chrono::Utc::now()));
+        if let Err(e) = config.flight_dir.create(&dir, options.resume) {
+            eprintln!("Cartella di volo non utilizzabile: {}", e);
+            std::process::exit(EXIT_CANT_CREATE);
+        }
+        println!("Cartella di volo: {}", dir.display());
+        flight_dir::relocate(&mut config, &dir);
+        session.dir = Some(dir);
+    } else if options.resume {
+        println!("Attenzione: --resume ignorato senza flight_dir.template");
+    }
+    let _lock = (!options.dry_run).then(|| lock_output(&config.output.path_for(&session.id)));
+    let clock = Arc::new(SystemClock);
+    let mut service = match Service::new(config, options.dry_run, buses, session, clock.clone()) {
+        Ok(service) => service,
+        Err(e) => {
+            eprintln!("Errore: {}", e);
+            std::process::exit(EXIT_CANT_CREATE);
+        }
+    };
+    if let (Some(recording), Some(path)) = (recording, options.record_transcript) {
+        service.record_transcript(recording, path);
+    }
+    if let Err(e) = service.start() {
+        eprintln!("{}: impossibile avviare", e);
+        service.shutdown("sensore richiesto non disponibile");
+        std::process::exit(EXIT_UNAVAILABLE);
+    }
+
+    if options.once {
+        let all_ok = service.run_cycle(clock.now());
+        let code = match service.fatal() {
+            Some(_) => EXIT_UNAVAILABLE,
+            None if all_ok => 0,
+            None => EXIT_READ_FAILED,
+        };
+        service.shutdown("once");
+        std::process::exit(code);
+    }
+
+    service.start_watchdog();
+    let control = service.open_control();
+    #[cfg(feature = "tokio-runtime")]
+    if options.async_runtime {
+        runtime_tokio::run(service, options.config_path, options.overrides, control);
+        return;
+    }
+
+    let signals = Signals { control, ..Signals::default() };
+    for (signal, flag) in [
+        (SIGUSR1, &signals.reload),
+        (SIGUSR2, &signals.dump),
+        (SIGTERM, &signals.stop),
+        (SIGINT, &signals.stop),
+    ] {
+        if let Err(e) = signal_hook::flag::register(signal, Arc::clone(flag)) {
+            println!("Impossibile registrare il segnale {}: {}", signal, e);
+        }
+    }
+    #[cfg(feature = "tui")]
+    let dashboard = options.tui.then(|| tui::spawn(service.live_state(), Arc::clone(&signals.stop), !options.dry_run));
+    service.run(&signals, &options.config_path, &options.overrides, None);
+    #[cfg(feature = "tui")]
+    if let Some(dashboard) = dashboard {
+        dashboard.close();
+    }
+    stop(service);
+}
+
+/// Shuts the service down, with `EXIT_UNAVAILABLE` after losing a required sensor.
+fn stop(mut service: Service) {
+    match service.fatal().map(str::to_string) {
+        Some(reason) => {
+            eprintln!("{}: arresto", reason);
+            service.shutdown(&reason);
+            std::process::exit(EXIT_UNAVAILABLE);
+        }
+        None => service.shutdown("segnale"),
     }
 }
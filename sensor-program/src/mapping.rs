@@ -0,0 +1,165 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+use crate::exec::ExecConfig;
+use crate::record::{flatten, unflatten, RecordLayout, SensorData};
+
+/// Keys that gap detection and the healthcheck read back from the data file.
+pub const PROTECTED: [&str; 4] = ["timestamp", "session_id", "boot_id", "sequence"];
+
+/// Field names are the flat-layout keys (`ms5611_pressure`, `ds18b20_1`,
+/// `altitude_m`, ...) regardless of `output.layout`. Renamed fields always
+/// end up at the top level; the others keep the configured layout.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MappingConfig {
+    pub rename: BTreeMap<String, String>,
+    pub exclude: Vec<String>,
+    pub include: Vec<String>,
+}
+
+impl MappingConfig {
+    pub fn is_empty(&self) -> bool {
+        self.rename.is_empty() && self.exclude.is_empty() && self.include.is_empty()
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for field in PROTECTED {
+            if self.rename.contains_key(field) || self.exclude.iter().any(|excluded| excluded == field) {
+                errors.push(format!("mapping: il campo {} non può essere rinominato o escluso", field));
+            }
+        }
+        let mut targets: Vec<&String> = self.rename.values().collect();
+        targets.sort();
+        targets.dedup();
+        if targets.len() != self.rename.len() {
+            errors.push("mapping.rename: due campi hanno lo stesso nuovo nome".to_string());
+        }
+        if let Some(target) = targets.iter().find(|target| target.is_empty() || PROTECTED.contains(&target.as_str())) {
+            errors.push(format!("mapping.rename: nome \"{}\" non utilizzabile", target));
+        }
+        errors
+    }
+
+    /// Fields named in the mapping that no data record can contain, given
+    /// the configured `exec` sensors.
+    pub fn warnings(&self, exec: &[ExecConfig]) -> Vec<String> {
+        let mut known = match serde_json::to_value(SensorData::example()) {
+            Ok(Value::Object(fields)) => flatten(fields),
+            _ => return Vec::new(),
+        };
+        for name in exec.iter().flat_map(ExecConfig::measurements) {
+            known.insert(format!("exec_{}", name), Value::Null);
+        }
+        let sections = [
+            ("rename", self.rename.keys().collect::<Vec<_>>()),
+            ("exclude", self.exclude.iter().collect()),
+            ("include", self.include.iter().collect()),
+        ];
+        let mut warnings = Vec::new();
+        for (section, fields) in sections {
+            for field in fields.into_iter().filter(|field| !known.contains_key(field.as_str())) {
+                warnings.push(format!("mapping.{}: il campo {} non esiste", section, field));
+            }
+        }
+        warnings
+    }
+
+    /// Applies allow-list, exclusions and renames, in that order, to a data
+    /// record in the nested layout. The protected keys are always kept.
+    pub fn apply(&self, record: Value, layout: RecordLayout) -> Value {
+        let Value::Object(fields) = record else {
+            return record;
+        };
+        let mut kept = Map::new();
+        let mut renamed = Map::new();
+        for (key, value) in flatten(fields) {
+            let protected = PROTECTED.contains(&key.as_str());
+            let allowed = self.include.is_empty() || self.include.contains(&key);
+            if !protected && (!allowed || self.exclude.contains(&key)) {
+                continue;
+            }
+            match self.rename.get(&key) {
+                Some(target) => renamed.insert(target.clone(), value),
+                None => kept.insert(key, value),
+            };
+        }
+        if layout == RecordLayout::Nested {
+            kept = unflatten(kept);
+        }
+        kept.extend(renamed);
+        Value::Object(kept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::MappingConfig;
+    use crate::exec::ExecConfig;
+    use crate::record::RecordLayout;
+
+    fn mapping(text: &str) -> MappingConfig {
+        toml::from_str(text).unwrap()
+    }
+
+    fn record() -> Value {
+        json!({
+            "timestamp": "2026-06-01T10:42:00Z",
+            "session_id": "s",
+            "boot_id": "b",
+            "sequence": 7,
+            "ms5611": { "d1": 1, "d2": 2, "temperature": 20.0, "pressure": 1013.0 },
+            "altitude_m": 0.0,
+        })
+    }
+
+    #[test]
+    fn renames_to_the_top_level_and_keeps_the_layout_of_the_rest() {
+        let mapping = mapping("rename = { ms5611_pressure = \"pressure_hpa\" }\nexclude = [\"ms5611_d1\"]");
+        let nested = mapping.apply(record(), RecordLayout::Nested);
+        assert_eq!(nested["pressure_hpa"], 1013.0);
+        assert_eq!(nested["ms5611"], json!({ "d2": 2, "temperature": 20.0 }));
+        let flat = mapping.apply(record(), RecordLayout::Flat);
+        assert_eq!(flat["pressure_hpa"], 1013.0);
+        assert_eq!((flat.get("ms5611_d1"), &flat["ms5611_d2"]), (None, &json!(2)));
+        assert!(flat.get("ms5611").is_none());
+    }
+
+    #[test]
+    fn an_allow_list_keeps_the_protected_fields() {
+        let mapping = mapping("include = [\"altitude_m\", \"ms5611_temperature\"]\nexclude = [\"ms5611_temperature\"]");
+        let expected = json!({
+            "timestamp": "2026-06-01T10:42:00Z",
+            "session_id": "s",
+            "boot_id": "b",
+            "sequence": 7,
+            "altitude_m": 0.0,
+        });
+        assert_eq!(mapping.apply(record(), RecordLayout::Nested), expected);
+    }
+
+    #[test]
+    fn protected_fields_cannot_be_renamed_or_excluded() {
+        let errors = mapping("rename = { timestamp = \"time\" }\nexclude = [\"sequence\"]").validate();
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert_eq!(mapping("rename = { altitude_m = \"boot_id\" }").validate().len(), 1);
+        let duplicate = mapping("rename = { altitude_m = \"alt\", ms5611_pressure = \"alt\" }");
+        assert_eq!(duplicate.validate(), ["mapping.rename: due campi hanno lo stesso nuovo nome"]);
+        assert_eq!(mapping("rename = { altitude_m = \"alt\" }").validate(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn warns_about_fields_no_record_contains() {
+        let mapping = mapping("rename = { exec_uv = \"uv\" }\nexclude = [\"ms5611_d3\"]\ninclude = [\"altitude_m\"]");
+        assert_eq!(
+            mapping.warnings(&[]),
+            ["mapping.rename: il campo exec_uv non esiste", "mapping.exclude: il campo ms5611_d3 non esiste"]
+        );
+        let uv: ExecConfig = toml::from_str("name = \"uv\"\ncommand = [\"uv-read\"]").unwrap();
+        assert_eq!(mapping.warnings(&[uv]), ["mapping.exclude: il campo ms5611_d3 non esiste"]);
+    }
+}
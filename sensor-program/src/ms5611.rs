@@ -0,0 +1,370 @@
+use serde::{Deserialize, Serialize};
+use std::time;
+
+use crate::config::Ms5611Config;
+use crate::i2c_bus::{Bus, Transport};
+use crate::record::MS5611Data;
+
+/// Most conversion pairs `samples_per_cycle` may ask for.
+pub const MAX_SAMPLES_PER_CYCLE: u32 = 32;
+
+const CMD_RESET: u8 = 0x1E;
+const CMD_CONVERT_D1: u8 = 0x48;
+const CMD_CONVERT_D2: u8 = 0x58;
+/// Well above the 9.04 ms maximum conversion time at OSR 4096.
+const CONVERSION_MS: u64 = 50;
+const ADC_SATURATED: u32 = 0xFF_FFFF;
+
+/// A conversion that read as 0 or all ones even after a retry; the values
+/// computed from it would be garbage, so the sample is dropped instead.
+#[derive(Debug)]
+pub struct InvalidConversion {
+    pub channel: &'static str,
+    pub raw: u32,
+}
+
+impl std::fmt::Display for InvalidConversion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "conversione {} non valida (ADC 0x{:06X}) anche dopo un nuovo tentativo", self.channel, self.raw)
+    }
+}
+
+impl std::error::Error for InvalidConversion {}
+
+/// How the conversions of one cycle are combined when `samples_per_cycle` > 1.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    #[default]
+    Median,
+    /// Mean of what is left after dropping the lowest and highest quarter.
+    TrimmedMean,
+}
+
+/// Which temperature compensation of the datasheet `compensate` applies.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compensation {
+    /// The first-order formulas only, as every version so far has logged.
+    #[default]
+    FirstOrder,
+    /// Adds the second-order correction below 20 °C (and the extra one
+    /// below -15 °C).
+    SecondOrder,
+}
+
+/// Time taken by one cycle: the PROM words plus `samples` conversion pairs.
+pub fn read_time_ms(samples: u32) -> u64 {
+    6 * 10 + samples.max(1) as u64 * 2 * CONVERSION_MS
+}
+
+fn read_calibration_word(i2c: &mut dyn Transport, addr: u8) -> Result<u16, Box<dyn std::error::Error>> {
+    let mut buf = [0u8; 2];
+    i2c.write(&[addr])?;
+    i2c.delay(time::Duration::from_millis(10));
+    i2c.read(&mut buf)?;
+    Ok(((buf[0] as u16) << 8) | buf[1] as u16)
+}
+
+fn convert(i2c: &mut dyn Transport, command: u8) -> Result<u32, Box<dyn std::error::Error>> {
+    i2c.write(&[command])?;
+    i2c.delay(time::Duration::from_millis(CONVERSION_MS));
+    i2c.write(&[0x00])?;
+    let mut buf = [0u8; 3];
+    i2c.read(&mut buf)?;
+    Ok(((buf[0] as u32) << 16) | ((buf[1] as u32) << 8) | buf[2] as u32)
+}
+
+/// The ADC returns 0 when read early or after an interrupted conversion;
+/// such a result is retried once, after a full conversion time.
+fn convert_checked(
+    i2c: &mut dyn Transport,
+    command: u8,
+    channel: &'static str,
+) -> Result<Result<u32, InvalidConversion>, Box<dyn std::error::Error>> {
+    let raw = convert(i2c, command)?;
+    if raw != 0 && raw != ADC_SATURATED {
+        return Ok(Ok(raw));
+    }
+    i2c.delay(time::Duration::from_millis(CONVERSION_MS));
+    let raw = convert(i2c, command)?;
+    Ok(if raw != 0 && raw != ADC_SATURATED { Ok(raw) } else { Err(InvalidConversion { channel, raw }) })
+}
+
+/// Takes `samples_per_cycle` conversion pairs and returns their aggregate.
+/// Pairs with an invalid conversion are dropped; only when all of them are
+/// does the read fail, with a boxed `InvalidConversion`.
+pub fn read_and_calculate(bus: &Bus, config: &Ms5611Config) -> Result<MS5611Data, Box<dyn std::error::Error>> {
+    let (coefficients, pairs, invalid) = bus.with_device(config.address, |i2c| {
+        let mut coefficients = [0u16; 6];
+        for (index, coefficient) in coefficients.iter_mut().enumerate() {
+            *coefficient = read_calibration_word(i2c, 0xA2 + 2 * index as u8)?;
+        }
+        let mut pairs = Vec::new();
+        let mut invalid = None;
+        for _ in 0..config.samples_per_cycle.max(1) {
+            match convert_pair(i2c)? {
+                Ok(pair) => pairs.push(pair),
+                Err(e) => invalid = Some(e),
+            }
+        }
+        Ok((coefficients, pairs, invalid))
+    })?;
+    if let Some(e) = invalid.filter(|_| pairs.is_empty()) {
+        return Err(e.into());
+    }
+
+    let d1 = aggregate(config.aggregation, pairs.iter().map(|&(d1, _)| d1 as f64).collect()).round() as u32;
+    let d2 = aggregate(config.aggregation, pairs.iter().map(|&(_, d2)| d2 as f64).collect()).round() as u32;
+    let (temperature, pressure) = compensate(&coefficients, d1, d2, config.compensation);
+    let mut data = MS5611Data { d1, d2, temperature, pressure, ..MS5611Data::default() };
+    if config.samples_per_cycle > 1 {
+        let values: Vec<_> =
+            pairs.iter().map(|&(d1, d2)| compensate(&coefficients, d1, d2, config.compensation)).collect();
+        data.conversions = Some(pairs.len() as u32);
+        data.temperature_spread = Some(spread(values.iter().map(|&(temperature, _)| temperature)));
+        data.pressure_spread = Some(spread(values.iter().map(|&(_, pressure)| pressure)));
+        if config.include_samples {
+            data.samples = Some(pairs.iter().map(|&(d1, d2)| [d1, d2]).collect());
+        }
+    }
+    Ok(data)
+}
+
+fn convert_pair(i2c: &mut dyn Transport) -> Result<Result<(u32, u32), InvalidConversion>, Box<dyn std::error::Error>> {
+    let d1 = match convert_checked(i2c, CMD_CONVERT_D1, "D1")? {
+        Ok(d1) => d1,
+        Err(e) => return Ok(Err(e)),
+    };
+    Ok(convert_checked(i2c, CMD_CONVERT_D2, "D2")?.map(|d2| (d1, d2)))
+}
+
+/// Temperature (°C) and pressure (hPa) from C1..C6 and one conversion pair,
+/// following the MS5611-01BA03 datasheet.
+///
+/// The result is bit-for-bit reproducible: every step is done on `i64`,
+/// each `/ 2^n` of the datasheet is a division truncating toward zero (not
+/// an arithmetic shift, which differs for negative operands), products are
+/// taken before the division they precede, and only the final TEMP
+/// (0.01 °C) and P (0.01 mbar) are converted to `f64` and divided by 100.
+/// With `SecondOrder`, below TEMP 2000: T2 = dT² / 2^31,
+/// OFF2 = 5 (TEMP-2000)² / 2, SENS2 = 5 (TEMP-2000)² / 4, and below -1500
+/// OFF2 += 7 (TEMP+1500)², SENS2 += 11 (TEMP+1500)² / 2; they are taken off
+/// TEMP, OFF and SENS before P is computed.
+pub fn compensate(coefficients: &[u16; 6], d1: u32, d2: u32, compensation: Compensation) -> (f64, f64) {
+    let c = coefficients.map(i64::from);
+    let d_t = d2 as i64 - c[4] * 256;
+    let mut temp = 2000 + (d_t * c[5]) / (1 << 23);
+    let mut off = c[1] * (1 << 16) + (c[3] * d_t) / (1 << 7);
+    let mut sens = c[0] * (1 << 15) + (c[2] * d_t) / (1 << 8);
+    if compensation == Compensation::SecondOrder && temp < 2000 {
+        let low = (temp - 2000) * (temp - 2000);
+        let mut off2 = 5 * low / 2;
+        let mut sens2 = 5 * low / 4;
+        if temp < -1500 {
+            let very_low = (temp + 1500) * (temp + 1500);
+            off2 += 7 * very_low;
+            sens2 += 11 * very_low / 2;
+        }
+        temp -= (d_t * d_t) / (1 << 31);
+        off -= off2;
+        sens -= sens2;
+    }
+    let press = (((d1 as i64 * sens) / (1 << 21)) - off) / (1 << 15);
+    (temp as f64 / 100.0, press as f64 / 100.0)
+}
+
+/// C1..C6 out of the eight PROM words of `read_prom`.
+pub fn coefficients(prom: &[u16; 8]) -> [u16; 6] {
+    [prom[1], prom[2], prom[3], prom[4], prom[5], prom[6]]
+}
+
+fn aggregate(aggregation: Aggregation, mut values: Vec<f64>) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let n = values.len();
+    match aggregation {
+        Aggregation::Median if n % 2 == 1 => values[n / 2],
+        Aggregation::Median => (values[n / 2 - 1] + values[n / 2]) / 2.0,
+        Aggregation::TrimmedMean => {
+            let kept = &values[n / 4..n - n / 4];
+            kept.iter().sum::<f64>() / kept.len() as f64
+        }
+    }
+}
+
+fn spread(values: impl Iterator<Item = f64>) -> f64 {
+    let (min, max) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), v| (min.min(v), max.max(v)));
+    max - min
+}
+
+pub fn reset(bus: &Bus, config: &Ms5611Config) -> Result<(), Box<dyn std::error::Error>> {
+    bus.with_device(config.address, |i2c| {
+        i2c.write(&[CMD_RESET])?;
+        i2c.delay(time::Duration::from_millis(3));
+        Ok(())
+    })
+}
+
+/// Reads the eight PROM words: factory data, C1..C6 and the CRC word.
+pub fn read_prom(bus: &Bus, config: &Ms5611Config) -> Result<[u16; 8], Box<dyn std::error::Error>> {
+    bus.with_device(config.address, |i2c| {
+        let mut prom = [0u16; 8];
+        for (index, word) in prom.iter_mut().enumerate() {
+            *word = read_calibration_word(i2c, 0xA0 + 2 * index as u8)?;
+        }
+        Ok(prom)
+    })
+}
+
+/// CRC-4 over the PROM as described in application note AN520; the low
+/// nibble of the last word holds the expected value.
+pub fn prom_crc_ok(prom: &[u16; 8]) -> bool {
+    let mut words = *prom;
+    let expected = words[7] & 0x000F;
+    words[7] &= 0xFF00;
+    let mut remainder: u16 = 0;
+    for count in 0..16 {
+        let word = words[count / 2];
+        remainder ^= if count % 2 == 1 { word & 0x00FF } else { word >> 8 };
+        for _ in 0..8 {
+            remainder = if remainder & 0x8000 != 0 { (remainder << 1) ^ 0x3000 } else { remainder << 1 };
+        }
+    }
+    (remainder >> 12) & 0x000F == expected
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::error::Error;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::{read_and_calculate, InvalidConversion, ADC_SATURATED, CMD_CONVERT_D1, CMD_CONVERT_D2};
+    use crate::config::Ms5611Config;
+    use crate::i2c_bus::{Bus, Transport};
+
+    /// The example of the datasheet: 20.07 °C and 1000.09 mbar.
+    const COEFFICIENTS: [u16; 6] = [40127, 36924, 23317, 23282, 33464, 28312];
+    const D1: u32 = 9_085_466;
+    const D2: u32 = 8_569_150;
+
+    /// An MS5611 whose ADC answers the `d1` and `d2` given, in order, and
+    /// the datasheet example once they have run out.
+    #[derive(Default)]
+    struct Adc {
+        d1: VecDeque<u32>,
+        d2: VecDeque<u32>,
+        command: Option<u8>,
+        pending: Option<u32>,
+        /// Conversions started, by command, and waits.
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Transport for Adc {
+        fn set_slave_address(&mut self, _: u16) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        fn write(&mut self, buffer: &[u8]) -> Result<usize, Box<dyn Error>> {
+            let command = buffer[0];
+            match command {
+                CMD_CONVERT_D1 => self.pending = Some(self.d1.pop_front().unwrap_or(D1)),
+                CMD_CONVERT_D2 => self.pending = Some(self.d2.pop_front().unwrap_or(D2)),
+                _ => {}
+            }
+            if matches!(command, CMD_CONVERT_D1 | CMD_CONVERT_D2) {
+                self.log.lock().unwrap().push(format!("0x{:02X}", command));
+            }
+            self.command = Some(command);
+            Ok(1)
+        }
+
+        fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Box<dyn Error>> {
+            match (self.command.take(), buffer.len()) {
+                (Some(word @ 0xA0..=0xAE), 2) => {
+                    let index = usize::from((word - 0xA0) / 2);
+                    let value = if (1..7).contains(&index) { COEFFICIENTS[index - 1] } else { 0 };
+                    buffer.copy_from_slice(&value.to_be_bytes());
+                }
+                (Some(0x00), 3) => buffer.copy_from_slice(&self.pending.take().unwrap_or(0).to_be_bytes()[1..]),
+                (_, len) => return Err(format!("lettura di {} byte non prevista", len).into()),
+            }
+            Ok(buffer.len())
+        }
+
+        fn write_read(&mut self, _: &[u8], _: &mut [u8]) -> Result<(), Box<dyn Error>> {
+            Err("nessun registro".into())
+        }
+
+        fn smbus_receive_byte(&mut self) -> Result<u8, Box<dyn Error>> {
+            Ok(0)
+        }
+
+        fn delay(&mut self, duration: Duration) {
+            if duration.as_millis() == super::CONVERSION_MS.into() {
+                self.log.lock().unwrap().push("attesa".to_string());
+            }
+        }
+    }
+
+    fn read(adc: Adc) -> (Result<crate::record::MS5611Data, Box<dyn Error>>, Vec<String>) {
+        let log = adc.log.clone();
+        let bus = Bus::with_transport(1, Box::new(adc));
+        let result = read_and_calculate(&bus, &Ms5611Config::default());
+        let log = log.lock().unwrap().clone();
+        (result, log)
+    }
+
+    #[test]
+    fn computes_the_datasheet_example() {
+        let (data, log) = read(Adc::default());
+        let data = data.unwrap();
+        assert_eq!((data.d1, data.d2, data.temperature, data.pressure), (D1, D2, 20.07, 1000.09));
+        assert_eq!(log, ["0x48", "attesa", "0x58", "attesa"]);
+    }
+
+    #[test]
+    fn retries_an_empty_conversion_once() {
+        let (data, log) = read(Adc { d2: [0].into(), ..Adc::default() });
+        assert_eq!(data.unwrap().d2, D2);
+        // The conversion time, and as much again before the retry.
+        assert_eq!(log, ["0x48", "attesa", "0x58", "attesa", "attesa", "0x58", "attesa"]);
+    }
+
+    #[test]
+    fn gives_no_values_when_the_retry_fails_too() {
+        for raw in [0, ADC_SATURATED] {
+            let (data, log) = read(Adc { d1: [raw, raw].into(), ..Adc::default() });
+            let error = data.unwrap_err();
+            let invalid = error.downcast_ref::<InvalidConversion>().unwrap();
+            assert_eq!((invalid.channel, invalid.raw), ("D1", raw));
+            // D2 is not converted for a pair that is already lost.
+            assert_eq!(log, ["0x48", "attesa", "attesa", "0x48", "attesa"]);
+        }
+    }
+    #[cfg(feature = "sim-test")]
+    #[test]
+    fn a_cycle_without_a_valid_conversion_writes_no_record() {
+        use crate::i2c_bus::Buses;
+        use crate::sim::bench::Bench;
+
+        let buses = |config: &crate::config::Config, _: &_| {
+            let mut buses = Buses::default();
+            let adc = Adc { d1: [D1, 0, 0].into(), ..Adc::default() };
+            buses.insert(Bus::with_transport(config.ms5611.bus, Box::new(adc)));
+            buses
+        };
+        let mut bench = Bench::with_buses("invalid-conversion", Duration::ZERO, |_| {}, buses).unwrap();
+        let cycles: Vec<bool> = (0..3).map(|_| bench.service.run_cycle(std::time::Instant::now())).collect();
+        bench.service.wait_for_sinks(crate::soak::DRAIN_TIMEOUT);
+        let (records, events) = bench.finish();
+        assert_eq!(cycles, [true, false, true]);
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|record| record["ms5611"]["pressure"] == 1000.09));
+        let errors: Vec<_> = events.iter().filter(|event| event["category"] == "sensor").collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["payload"]["sensor"], "MS5611");
+        assert!(errors[0]["message"].as_str().unwrap().contains("conversione D1 non valida (ADC 0x000000)"));
+    }
+}
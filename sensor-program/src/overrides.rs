@@ -0,0 +1,204 @@
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use toml::{Table, Value};
+
+/// `RUST_SENSORS__OUTPUT__PATH=/data/x.json` sets `output.path`: the rest of
+/// the name, split on `__` and lowercased, is the key. A numeric segment
+/// picks an element of an array, e.g. `RUST_SENSORS__SINKS__0__PATH`.
+pub const ENV_PREFIX: &str = "RUST_SENSORS__";
+
+/// Where the effective value of a key comes from. Later layers win:
+/// defaults, then the file, then the environment, then `--set`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    Default,
+    File,
+    Env,
+    Cli,
+}
+
+impl Origin {
+    pub fn name(self) -> &'static str {
+        match self {
+            Origin::Default => "default",
+            Origin::File => "file",
+            Origin::Env => "env",
+            Origin::Cli => "cli",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Override {
+    pub origin: Origin,
+    /// The variable or the `--set` argument, for error messages.
+    pub name: String,
+    pub key: Vec<String>,
+    pub value: String,
+}
+
+/// The `ENV_PREFIX` variables, sorted by name so that the order in which
+/// they are applied does not depend on the environment.
+pub fn from_env() -> Vec<Override> {
+    let mut overrides: Vec<_> = std::env::vars()
+        .filter_map(|(name, value)| {
+            let key = name.strip_prefix(ENV_PREFIX)?.split("__").map(str::to_lowercase).collect();
+            Some(Override { origin: Origin::Env, name: name.clone(), key, value })
+        })
+        .collect();
+    overrides.sort_by(|a, b| a.name.cmp(&b.name));
+    overrides
+}
+
+/// Parses a `--set` argument, `chiave.sotto_chiave=valore`.
+pub fn from_cli(arg: &str) -> Result<Override, String> {
+    let (key, value) = arg.split_once('=').ok_or_else(|| format!("--set {}: atteso chiave=valore", arg))?;
+    if key.is_empty() || key.split('.').any(str::is_empty) {
+        return Err(format!("--set {}: chiave non valida", arg));
+    }
+    Ok(Override {
+        origin: Origin::Cli,
+        name: format!("--set {}", arg),
+        key: key.split('.').map(str::to_string).collect(),
+        value: value.to_string(),
+    })
+}
+
+impl Override {
+    pub fn dotted_key(&self) -> String {
+        self.key.join(".")
+    }
+
+    /// The value as a TOML literal when it is one (`5`, `true`, `[1, 2]`),
+    /// otherwise as a string.
+    pub fn parsed(&self) -> Value {
+        format!("v = {}", self.value)
+            .parse::<Table>()
+            .ok()
+            .and_then(|mut table| table.remove("v"))
+            .unwrap_or_else(|| self.as_string())
+    }
+
+    pub fn as_string(&self) -> Value {
+        Value::String(self.value.clone())
+    }
+
+    /// Sets the key in `table`, creating the missing tables on the way.
+    pub fn apply(&self, table: &mut Table, value: Value) -> Result<(), String> {
+        let mut root = Value::Table(std::mem::take(table));
+        let result = self.set(&mut root, value);
+        if let Value::Table(root) = root {
+            *table = root;
+        }
+        result
+    }
+
+    fn set(&self, root: &mut Value, value: Value) -> Result<(), String> {
+        let mut current = root;
+        for (depth, segment) in self.key.iter().enumerate() {
+            let last = depth + 1 == self.key.len();
+            current = match current {
+                Value::Table(table) if last => {
+                    table.insert(segment.clone(), value);
+                    return Ok(());
+                }
+                Value::Table(table) => table.entry(segment.clone()).or_insert_with(|| Value::Table(Table::new())),
+                Value::Array(elements) if !last => self.element(elements, segment)?,
+                _ => return Err(format!("{}: {} non è una tabella", self.name, self.key[..depth].join("."))),
+            };
+        }
+        Err(format!("{}: chiave vuota", self.name))
+    }
+
+    /// Applies the override to `table` and returns the result deserialized.
+    /// A value that parses as a TOML literal but does not fit the key is
+    /// tried again as a string, so that e.g. an ID made of digits still
+    /// works. On error `table` is left untouched.
+    pub fn merge<T: DeserializeOwned>(&self, table: &mut Table) -> Result<T, String> {
+        let attempt = |value: Value| -> Result<(Table, T), String> {
+            let mut merged = table.clone();
+            self.apply(&mut merged, value)?;
+            let result = Value::Table(merged.clone()).try_into::<T>().map_err(|e| format!("{}: {}", self.name, e))?;
+            Ok((merged, result))
+        };
+        let parsed = self.parsed();
+        let (merged, result) = match attempt(parsed.clone()) {
+            Err(e) if !parsed.is_str() => attempt(self.as_string()).map_err(|_| e)?,
+            other => other?,
+        };
+        *table = merged;
+        Ok(result)
+    }
+
+    fn element<'a>(&self, elements: &'a mut [Value], segment: &str) -> Result<&'a mut Value, String> {
+        let len = elements.len();
+        segment
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| elements.get_mut(index))
+            .ok_or_else(|| format!("{}: indice {} non valido, l'elenco ha {} elementi", self.name, segment, len))
+    }
+}
+
+/// One `chiave = valore  # origine` line per key of `effective` (the
+/// configuration as JSON), for `--print-effective-config`. The origin is the
+/// last override on the key or one of its parents, else the file if it sets
+/// the key, else the default.
+pub fn describe(effective: &serde_json::Value, file: &Table, applied: &[Override]) -> String {
+    let mut leaves = BTreeMap::new();
+    collect_leaves(effective, String::new(), &mut leaves);
+    leaves
+        .into_iter()
+        .map(|(key, value)| {
+            let overridden = applied.iter().rev().find(|o| {
+                let dotted = o.dotted_key();
+                key == dotted || key.starts_with(&format!("{}.", dotted))
+            });
+            let origin = match overridden {
+                Some(o) => format!("{} ({})", o.origin.name(), o.name),
+                None if in_file(file, &key) => Origin::File.name().to_string(),
+                None => Origin::Default.name().to_string(),
+            };
+            format!("{} = {}  # {}", key, value, origin)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn collect_leaves(value: &serde_json::Value, prefix: String, leaves: &mut BTreeMap<String, serde_json::Value>) {
+    let join = |key: &str| if prefix.is_empty() { key.to_string() } else { format!("{}.{}", prefix, key) };
+    match value {
+        serde_json::Value::Object(fields) if !fields.is_empty() => {
+            for (key, value) in fields {
+                collect_leaves(value, join(key), leaves);
+            }
+        }
+        serde_json::Value::Array(elements) if elements.iter().any(serde_json::Value::is_object) => {
+            for (index, value) in elements.iter().enumerate() {
+                collect_leaves(value, join(&index.to_string()), leaves);
+            }
+        }
+        value => {
+            leaves.insert(prefix, value.clone());
+        }
+    }
+}
+
+fn in_file(file: &Table, key: &str) -> bool {
+    let mut segments = key.split('.');
+    let Some(mut current) = segments.next().and_then(|first| file.get(first)) else {
+        return false;
+    };
+    for segment in segments {
+        let next = match current {
+            Value::Table(table) => table.get(segment),
+            Value::Array(elements) => segment.parse::<usize>().ok().and_then(|index| elements.get(index)),
+            _ => None,
+        };
+        match next {
+            Some(next) => current = next,
+            None => return false,
+        }
+    }
+    true
+}
@@ -0,0 +1,426 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub enum Output {
+    Record(Arc<str>),
+    /// A data record after `[mapping]`, together with the unmapped text.
+    Mapped { record: Arc<str>, raw: Arc<str> },
+    /// A data record as written and as completed from the last good
+    /// values, for the sinks with `fill_last_good`.
+    Filled { plain: Box<Output>, filled: Box<Output> },
+    Event(Arc<str>),
+    Sentence(Arc<str>),
+}
+
+impl Output {
+    pub fn text(&self) -> &str {
+        match self {
+            Output::Record(text) | Output::Event(text) | Output::Sentence(text) => text,
+            Output::Mapped { record, .. } => record,
+            Output::Filled { plain, .. } => plain.text(),
+        }
+    }
+
+    pub fn is_record(&self) -> bool {
+        matches!(self, Output::Record(_) | Output::Mapped { .. } | Output::Filled { .. })
+    }
+
+    pub fn is_event(&self) -> bool {
+        matches!(self, Output::Event(_))
+    }
+
+    pub fn is_sentence(&self) -> bool {
+        matches!(self, Output::Sentence(_))
+    }
+}
+
+pub trait Sink: Send {
+    fn write(&mut self, output: &Output) -> Result<(), String>;
+    fn flush(&mut self) {}
+    /// Closes the file written, if any, to open it again at its path with
+    /// the next output.
+    fn reopen(&mut self) {}
+    /// Connection state of a sink that talks to a device, reported in the
+    /// status record.
+    fn link(&self) -> Option<Arc<Mutex<LinkState>>> {
+        None
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct LinkState {
+    pub connected: bool,
+    pub reconnects: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    DropOldest,
+    DropNewest,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub drop_policy: DropPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig { capacity: 256, drop_policy: DropPolicy::DropOldest }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    written: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+enum Item {
+    Output(Output),
+    /// `Pipeline::reopen`, taken in order with the outputs queued before it.
+    Reopen,
+}
+
+struct QueueState {
+    items: VecDeque<Item>,
+    closed: bool,
+}
+
+struct Queue {
+    state: Mutex<QueueState>,
+    ready: Condvar,
+    config: QueueConfig,
+    counters: Counters,
+}
+
+impl Queue {
+    fn push(&self, output: Output) {
+        self.push_item(Item::Output(output));
+    }
+
+    /// Only outputs are subject to the capacity and counted as dropped; a
+    /// `Reopen` is always queued, and never dropped to make room.
+    fn push_item(&self, item: Item) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if matches!(item, Item::Output(_)) && state.items.len() >= self.config.capacity {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            let oldest = state.items.iter().position(|item| matches!(item, Item::Output(_)));
+            match (self.config.drop_policy, oldest) {
+                (DropPolicy::DropOldest, Some(oldest)) => {
+                    state.items.remove(oldest);
+                }
+                _ => return,
+            }
+        }
+        state.items.push_back(item);
+        self.ready.notify_one();
+    }
+
+    fn pop(&self) -> Option<Item> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                return Some(item);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.ready.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).closed = true;
+        self.ready.notify_all();
+    }
+
+    fn depth(&self) -> usize {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).items.len()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SinkStats {
+    pub name: String,
+    pub depth: usize,
+    pub capacity: usize,
+    pub written: u64,
+    pub failed: u64,
+    pub dropped: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<LinkState>,
+}
+
+struct SinkHandle {
+    name: String,
+    accepts: fn(&Output) -> bool,
+    queue: Arc<Queue>,
+    link: Option<Arc<Mutex<LinkState>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+pub struct Pipeline {
+    sinks: Vec<SinkHandle>,
+}
+
+impl Pipeline {
+    pub fn add(&mut self, name: &str, config: QueueConfig, accepts: fn(&Output) -> bool, mut sink: Box<dyn Sink>) {
+        let queue = Arc::new(Queue {
+            state: Mutex::new(QueueState { items: VecDeque::new(), closed: false }),
+            ready: Condvar::new(),
+            config,
+            counters: Counters::default(),
+        });
+        let consumer = Arc::clone(&queue);
+        let link = sink.link();
+        let thread_name = name.to_string();
+        let thread = thread::Builder::new().name(format!("sink-{}", name)).spawn(move || {
+            // An unreachable device fails every write the same way; it is
+            // reported once, and again when the error changes.
+            let mut last_error: Option<String> = None;
+            while let Some(item) = consumer.pop() {
+                let output = match item {
+                    Item::Output(output) => output,
+                    Item::Reopen => {
+                        sink.reopen();
+                        continue;
+                    }
+                };
+                match sink.write(&output) {
+                    Ok(()) => {
+                        if last_error.take().is_some() {
+                            println!("Uscita {} di nuovo funzionante", thread_name);
+                        }
+                        consumer.counters.written.fetch_add(1, Ordering::Relaxed)
+                    }
+                    Err(e) => {
+                        if last_error.as_ref() != Some(&e) {
+                            println!("Errore uscita {}: {}", thread_name, e);
+                            last_error = Some(e);
+                        }
+                        consumer.counters.failed.fetch_add(1, Ordering::Relaxed)
+                    }
+                };
+            }
+            sink.flush();
+        });
+        match thread {
+            Ok(thread) => self.sinks.push(SinkHandle {
+                name: name.to_string(),
+                accepts,
+                queue,
+                link,
+                thread: Some(thread),
+            }),
+            Err(e) => println!("Impossibile avviare il thread dell'uscita {}: {}", name, e),
+        }
+    }
+
+    pub fn send(&self, output: Output) {
+        for sink in self.sinks.iter().filter(|sink| (sink.accepts)(&output)) {
+            sink.queue.push(output.clone());
+        }
+    }
+
+    /// Has every sink reopen its file once it has written what is queued.
+    pub fn reopen(&self) {
+        for sink in &self.sinks {
+            sink.queue.push_item(Item::Reopen);
+        }
+    }
+
+    /// A handle on the queues of the sinks that take events.
+    pub fn event_sender(&self) -> EventSender {
+        let probe = Output::Event(Arc::from(""));
+        let queues = self.sinks.iter().filter(|sink| (sink.accepts)(&probe)).map(|sink| Arc::clone(&sink.queue));
+        EventSender { queues: queues.collect() }
+    }
+
+    pub fn stats(&self) -> Vec<SinkStats> {
+        self.sinks
+            .iter()
+            .map(|sink| SinkStats {
+                name: sink.name.clone(),
+                depth: sink.queue.depth(),
+                capacity: sink.queue.config.capacity,
+                written: sink.queue.counters.written.load(Ordering::Relaxed),
+                failed: sink.queue.counters.failed.load(Ordering::Relaxed),
+                dropped: sink.queue.counters.dropped.load(Ordering::Relaxed),
+                link: sink.link.as_ref().map(|link| link.lock().unwrap_or_else(|e| e.into_inner()).clone()),
+            })
+            .collect()
+    }
+
+    /// Waits up to `timeout` for every queue to empty.
+    #[cfg(feature = "sim-test")]
+    pub fn wait_drained(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline && self.sinks.iter().any(|sink| sink.queue.depth() > 0) {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Closes every queue and waits for the sinks to drain and flush.
+    pub fn shutdown(&mut self) {
+        for sink in &self.sinks {
+            sink.queue.close();
+        }
+        for sink in &mut self.sinks {
+            if let Some(thread) = sink.thread.take()
+                && thread.join().is_err()
+            {
+                println!("Il thread dell'uscita {} è terminato in modo anomalo", sink.name);
+            }
+        }
+    }
+}
+
+/// Sends events from outside the service, e.g. from the watchdog thread
+/// while the loop is stuck.
+pub struct EventSender {
+    queues: Vec<Arc<Queue>>,
+}
+
+impl EventSender {
+    pub fn send(&self, output: Output) {
+        for queue in &self.queues {
+            queue.push(output.clone());
+        }
+    }
+
+    /// Waits up to `timeout` for the queues to empty.
+    pub fn wait_drained(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline && self.queues.iter().any(|queue| queue.depth() > 0) {
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+/// Hands the wrapped sink the unmapped text of mapped records.
+pub struct RawSink(pub Box<dyn Sink>);
+
+impl Sink for RawSink {
+    fn write(&mut self, output: &Output) -> Result<(), String> {
+        match output {
+            Output::Mapped { raw, .. } => self.0.write(&Output::Record(Arc::clone(raw))),
+            Output::Filled { plain, .. } => self.write(plain),
+            other => self.0.write(other),
+        }
+    }
+
+    fn flush(&mut self) {
+        self.0.flush();
+    }
+
+    fn reopen(&mut self) {
+        self.0.reopen();
+    }
+
+    fn link(&self) -> Option<Arc<Mutex<LinkState>>> {
+        self.0.link()
+    }
+}
+
+/// Hands the wrapped sink the data records completed from the last good values.
+pub struct FillSink(pub Box<dyn Sink>);
+
+impl Sink for FillSink {
+    fn write(&mut self, output: &Output) -> Result<(), String> {
+        match output {
+            Output::Filled { filled, .. } => self.0.write(filled),
+            other => self.0.write(other),
+        }
+    }
+
+    fn flush(&mut self) {
+        self.0.flush();
+    }
+
+    fn reopen(&mut self) {
+        self.0.reopen();
+    }
+
+    fn link(&self) -> Option<Arc<Mutex<LinkState>>> {
+        self.0.link()
+    }
+}
+
+pub struct ConsoleSink;
+
+impl Sink for ConsoleSink {
+    fn write(&mut self, output: &Output) -> Result<(), String> {
+        println!("[dry-run] {}", output.text());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Condvar, Mutex};
+
+    use super::{Counters, DropPolicy, Item, Output, Queue, QueueConfig, QueueState};
+
+    fn queue(drop_policy: DropPolicy) -> Queue {
+        Queue {
+            state: Mutex::new(QueueState { items: VecDeque::new(), closed: false }),
+            ready: Condvar::new(),
+            config: QueueConfig { capacity: 2, drop_policy },
+            counters: Counters::default(),
+        }
+    }
+
+    /// The queued items, outputs by their text.
+    fn items(queue: &Queue) -> Vec<String> {
+        let state = queue.state.lock().unwrap();
+        let text = |item: &Item| match item {
+            Item::Output(Output::Event(text)) => text.to_string(),
+            Item::Output(_) => "output".to_string(),
+            Item::Reopen => "reopen".to_string(),
+        };
+        state.items.iter().map(text).collect()
+    }
+
+    #[test]
+    fn a_reopen_goes_past_a_full_queue_and_drops_nothing() {
+        for policy in [DropPolicy::DropNewest, DropPolicy::DropOldest] {
+            let queue = queue(policy);
+            queue.push(Output::Event(Arc::from("1")));
+            queue.push(Output::Event(Arc::from("2")));
+            queue.push_item(Item::Reopen);
+            assert_eq!(items(&queue), ["1", "2", "reopen"]);
+            assert_eq!(queue.counters.dropped.load(Ordering::Relaxed), 0);
+        }
+    }
+
+    #[test]
+    fn a_full_queue_drops_outputs_only() {
+        let oldest = queue(DropPolicy::DropOldest);
+        oldest.push(Output::Event(Arc::from("1")));
+        oldest.push_item(Item::Reopen);
+        oldest.push(Output::Event(Arc::from("2")));
+        assert_eq!(items(&oldest), ["reopen", "2"]);
+        let newest = queue(DropPolicy::DropNewest);
+        newest.push_item(Item::Reopen);
+        newest.push(Output::Event(Arc::from("1")));
+        newest.push(Output::Event(Arc::from("2")));
+        assert_eq!(items(&newest), ["reopen", "1"]);
+        for queue in [oldest, newest] {
+            assert_eq!(queue.counters.dropped.load(Ordering::Relaxed), 1);
+        }
+    }
+}
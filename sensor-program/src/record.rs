@@ -0,0 +1,466 @@
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+
+use crate::ds18b20::ReadMode;
+use crate::flight::FlightState;
+use crate::pipeline::SinkStats;
+use crate::timing::{CycleTiming, StageSummary};
+use crate::units::Unit;
+use crate::voting::{PressureSource, VotingData};
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SensorData {
+    pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub session_id: String,
+    #[serde(default)]
+    pub boot_id: String,
+    /// Numbers the records written since startup: discarded warmup records
+    /// and samples suppressed by the deadband take none.
+    #[serde(default)]
+    pub sequence: u64,
+    /// Whether the kernel reported the clock as NTP-synchronized when the
+    /// record was taken; null when that cannot be determined.
+    #[serde(default)]
+    pub time_synced: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_offset_ms: Option<f64>,
+    pub ms5611: MS5611Data,
+    /// `None` (no field) when the probe is disabled or dropped from the run,
+    /// `Some(None)` (null) when its read failed.
+    #[serde(default, deserialize_with = "present", skip_serializing_if = "Option::is_none")]
+    pub ds18b20_1: Option<Option<f32>>,
+    #[serde(default, deserialize_with = "present", skip_serializing_if = "Option::is_none")]
+    pub ds18b20_2: Option<Option<f32>>,
+    /// Probes found on the bus besides the two configured ones, by ID.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ds18b20_extra: BTreeMap<String, Option<f32>>,
+    /// Measurements of the `[[exec]]` sensors that ran in this cycle; null
+    /// when the command failed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub exec: BTreeMap<String, Option<f64>>,
+    #[serde(default)]
+    pub altitude_m: f64,
+    #[serde(default)]
+    pub vertical_speed_ms: Option<f64>,
+    #[serde(default)]
+    pub flight_state: FlightState,
+    #[serde(default)]
+    pub burst_mode: bool,
+    /// Measurements that have repeated the exact same value for too long.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub suspect: Vec<String>,
+    /// Set by `replay` on the copies it adds between deadband records.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub filled: bool,
+    /// Taken during the `[warmup]` after startup or a reinitialization.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub warmup: bool,
+    /// Taken while the wall clock was before `time.floor`, hence not set.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub clock_invalid: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capture_offsets_ms: Option<BTreeMap<String, f64>>,
+    /// With `[acquisition]`: when the measurements acquired too far from
+    /// `timestamp` were taken, as an offset or as a time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acquisition_offsets_ms: Option<BTreeMap<String, f64>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acquired_at: Option<BTreeMap<String, DateTime<Utc>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timing: Option<CycleTiming>,
+    /// With `[voting]`: both MS5611 readings and which one is in `ms5611`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voting: Option<VotingData>,
+}
+
+/// Tells a null apart from a missing field, which `#[serde(default)]` maps to `None`.
+fn present<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Option<f32>>, D::Error> {
+    Option::<f32>::deserialize(deserializer).map(Some)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MS5611Data {
+    pub d1: u32,
+    pub d2: u32,
+    pub temperature: f64,
+    pub pressure: f64,
+    /// Conversion pairs aggregated into this record; the fields below are
+    /// only present with `ms5611.samples_per_cycle` > 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversions: Option<u32>,
+    /// Highest minus lowest value among those conversions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature_spread: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pressure_spread: Option<f64>,
+    /// Raw [D1, D2] of every conversion, with `ms5611.include_samples`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub samples: Option<Vec<[u32; 2]>>,
+}
+
+impl SensorData {
+    /// A record with every optional field present, used to list the keys a
+    /// data record can carry.
+    pub fn example() -> SensorData {
+        SensorData {
+            timestamp: DateTime::UNIX_EPOCH,
+            session_id: String::new(),
+            boot_id: String::new(),
+            sequence: 0,
+            time_synced: Some(true),
+            clock_offset_ms: Some(0.0),
+            ms5611: MS5611Data {
+                conversions: Some(0),
+                temperature_spread: Some(0.0),
+                pressure_spread: Some(0.0),
+                samples: Some(Vec::new()),
+                ..MS5611Data::default()
+            },
+            ds18b20_1: Some(Some(0.0)),
+            ds18b20_2: Some(Some(0.0)),
+            ds18b20_extra: BTreeMap::new(),
+            exec: BTreeMap::new(),
+            altitude_m: 0.0,
+            vertical_speed_ms: Some(0.0),
+            flight_state: FlightState::default(),
+            burst_mode: false,
+            suspect: vec!["ms5611_d1".to_string()],
+            filled: true,
+            warmup: true,
+            clock_invalid: true,
+            capture_offsets_ms: Some(
+                ["ms5611", "ds18b20_1", "ds18b20_2"].into_iter().map(|name| (name.to_string(), 0.0)).collect(),
+            ),
+            acquisition_offsets_ms: Some(
+                ["ms5611", "ds18b20_1", "ds18b20_2"].into_iter().map(|name| (name.to_string(), 0.0)).collect(),
+            ),
+            acquired_at: Some(
+                ["ms5611", "ds18b20_1", "ds18b20_2"]
+                    .into_iter()
+                    .map(|name| (name.to_string(), DateTime::UNIX_EPOCH))
+                    .collect(),
+            ),
+            timing: Some(CycleTiming {
+                ms5611_secondary_ms: Some(0.0),
+                ds18b20_1_ms: Some(0.0),
+                ds18b20_2_ms: Some(0.0),
+                previous_sinks_ms: Some(0.0),
+                previous_total_ms: Some(0.0),
+                ..CycleTiming::default()
+            }),
+            voting: Some(VotingData {
+                source: PressureSource::Ms5611,
+                ms5611: Some(MS5611Data::default()),
+                ms5611_secondary: None,
+                difference_hpa: Some(0.0),
+                disagreement: false,
+            }),
+        }
+    }
+}
+
+impl MS5611Data {
+    pub fn altitude(&self) -> f64 {
+        44330.0 * (1.0 - (self.pressure / 1013.25).powf(0.190295))
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct StatusRecord {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub timestamp: DateTime<Utc>,
+    pub session_id: String,
+    pub boot_id: String,
+    pub uptime_secs: u64,
+    pub records: u64,
+    pub interval_secs: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_v: Option<f64>,
+    /// Samples held back by `[deadband]`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suppressed: Option<u64>,
+    pub timing: BTreeMap<&'static str, StageSummary>,
+    pub sinks: Vec<SinkStats>,
+}
+
+/// Written instead of data records while the service waits for the I2C
+/// bus of the MS5611 (`[i2c]`): the DS18B20s only, without a sequence
+/// number, which the data records keep to themselves.
+#[derive(Serialize, Debug)]
+pub struct Ds18b20OnlyRecord {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub timestamp: DateTime<Utc>,
+    pub session_id: String,
+    pub boot_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ds18b20_1: Option<Option<f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ds18b20_2: Option<Option<f32>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub ds18b20_extra: BTreeMap<String, Option<f32>>,
+}
+
+impl Ds18b20OnlyRecord {
+    pub const KIND: &'static str = "ds18b20_only";
+}
+
+/// Bumped whenever a record changes in a way readers have to know about.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Written at the start of every output file, before any other record.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HeaderRecord {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub timestamp: DateTime<Utc>,
+    pub session_id: String,
+    pub boot_id: String,
+    pub schema_version: u32,
+    pub software_version: String,
+    /// C0..C7 as read at startup; null when the MS5611 did not answer.
+    pub ms5611_prom: Option<[u16; 8]>,
+    /// The same for the MS5611 under `[voting.secondary]`, if configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ms5611_secondary_prom: Option<[u16; 8]>,
+    /// The DS18B20s on the bus at startup; null without a 1-Wire bus.
+    pub ds18b20_ids: Option<Vec<String>>,
+    /// How the DS18B20s are read in this run, after a fallback from
+    /// `split`; missing in headers written before the modes existed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ds18b20_read_mode: Option<ReadMode>,
+    /// The effective configuration, defaults included, with secrets redacted.
+    pub config: Value,
+    /// Unit of each numeric field of the data records, by flat-layout key;
+    /// empty in headers written before units were listed.
+    #[serde(default)]
+    pub units: BTreeMap<String, Unit>,
+}
+
+impl HeaderRecord {
+    pub const KIND: &'static str = "header";
+
+    /// `line` parsed as a header, if it is one.
+    pub fn parse(line: &str) -> Option<HeaderRecord> {
+        serde_json::from_str::<HeaderRecord>(line).ok().filter(|header| header.kind == Self::KIND)
+    }
+
+    /// One line for the console, e.g. in `replay` and `analyze`.
+    pub fn describe(&self) -> String {
+        let interval = &self.config["sampling"]["interval_secs"];
+        let mut text = format!(
+            "avvio {} (sessione {}, boot_id {}): versione {}, schema {}, intervallo {} s",
+            self.timestamp, self.session_id, self.boot_id, self.software_version, self.schema_version, interval
+        );
+        if self.schema_version > SCHEMA_VERSION {
+            text.push_str(&format!(" (più recente dello schema {} supportato)", SCHEMA_VERSION));
+        }
+        text
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordLayout {
+    #[default]
+    Nested,
+    Flat,
+}
+
+/// Object-valued fields of `SensorData`. In the flat layout member `k` of
+/// one of these becomes the top-level key `<field>_<k>` (e.g.
+/// `ms5611_temperature`, `exec_co2_ppm`); every other key is kept as
+/// is. Flat records are serialized with keys in alphabetical order.
+const NESTED_FIELDS: [&str; 8] = [
+    "ms5611",
+    "ds18b20_extra",
+    "exec",
+    "capture_offsets_ms",
+    "acquisition_offsets_ms",
+    "acquired_at",
+    "timing",
+    "voting",
+];
+
+/// Object-valued members of a nested field, flattened one level further:
+/// `voting_ms5611_pressure`. A failed reading stays `voting_ms5611: null`.
+/// The longer name comes first, as it starts with the other.
+const NESTED_MEMBERS: [(&str, &str); 2] = [("voting", "ms5611_secondary"), ("voting", "ms5611")];
+
+impl RecordLayout {
+    /// Converts a record to this layout. Lines with a `"type"` field (events,
+    /// gaps, status) are never reshaped, and converting a record that is
+    /// already in the target layout leaves it unchanged.
+    pub fn apply(self, record: Value) -> Value {
+        let Value::Object(fields) = record else {
+            return record;
+        };
+        if fields.contains_key("type") {
+            return Value::Object(fields);
+        }
+        match self {
+            RecordLayout::Nested => Value::Object(unflatten(fields)),
+            RecordLayout::Flat => Value::Object(flatten(fields)),
+        }
+    }
+}
+
+pub fn flatten(fields: Map<String, Value>) -> Map<String, Value> {
+    let mut flat = Map::new();
+    for (key, value) in fields {
+        match value {
+            Value::Object(members) if NESTED_FIELDS.contains(&key.as_str()) => {
+                for (member, value) in members {
+                    match value {
+                        Value::Object(inner) if NESTED_MEMBERS.contains(&(key.as_str(), member.as_str())) => {
+                            for (name, value) in inner {
+                                flat.insert(format!("{}_{}_{}", key, member, name), value);
+                            }
+                        }
+                        value => {
+                            flat.insert(format!("{}_{}", key, member), value);
+                        }
+                    }
+                }
+            }
+            value => {
+                flat.insert(key, value);
+            }
+        }
+    }
+    flat
+}
+
+/// The object-valued field a flat-layout `key` is a member of, with the
+/// member's name.
+pub fn nested_parent(key: &str) -> Option<(&'static str, &str)> {
+    NESTED_FIELDS.iter().find_map(|field| key.strip_prefix(field)?.strip_prefix('_').map(|member| (*field, member)))
+}
+
+/// The object-valued member of `field` a flat-layout `member` is in, with
+/// its name there: `ms5611_pressure` of `voting` is `pressure` of `ms5611`.
+fn nested_member<'a>(field: &str, member: &'a str) -> Option<(&'static str, &'a str)> {
+    let (_, name) = NESTED_MEMBERS.iter().find(|(parent, name)| *parent == field && member.starts_with(name))?;
+    Some((name, member[name.len()..].strip_prefix('_')?))
+}
+
+pub fn unflatten(fields: Map<String, Value>) -> Map<String, Value> {
+    let mut nested = Map::new();
+    for (key, value) in fields {
+        match nested_parent(&key) {
+            Some((field, member)) => {
+                let Value::Object(members) = nested.entry(field).or_insert_with(|| Value::Object(Map::new())) else {
+                    continue;
+                };
+                match nested_member(field, member) {
+                    Some((name, inner)) => {
+                        if let Value::Object(inner_members) =
+                            members.entry(name).or_insert_with(|| Value::Object(Map::new()))
+                        {
+                            inner_members.insert(inner.to_string(), value);
+                        }
+                    }
+                    None => {
+                        members.insert(member.to_string(), value);
+                    }
+                }
+            }
+            None => {
+                nested.insert(key, value);
+            }
+        }
+    }
+    nested
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map, Value};
+
+    use super::{flatten, unflatten, RecordLayout, SensorData};
+
+    fn object(value: Value) -> Map<String, Value> {
+        let Value::Object(fields) = value else { panic!("{} non è un oggetto", value) };
+        fields
+    }
+
+    fn nested() -> Value {
+        json!({
+            "timestamp": "2026-06-01T10:42:10Z",
+            "session_id": "20260601T103000Z",
+            "sequence": 181,
+            "ms5611": { "d1": 9085466, "d2": 8569150, "temperature": 21.5, "pressure": 1013.25 },
+            "ds18b20_1": null,
+            "ds18b20_extra": { "28-0316a2790eff": -3.25 },
+            "exec": { "co2_ppm": 412.0, "humidity": null },
+            "altitude_m": 0.0,
+            "flight_state": "preflight",
+            "suspect": ["ms5611_d1"],
+            "acquired_at": { "ds18b20_1": "2026-06-01T10:42:08.5Z" },
+            "timing": { "start_lateness_ms": 1.5, "ms5611_ms": 160.0, "ms5611_secondary_ms": 160.0 },
+            "voting": {
+                "source": "ms5611",
+                "ms5611": { "d1": 9085466, "d2": 8569150, "temperature": 21.5, "pressure": 1013.25 },
+                "ms5611_secondary": { "d1": 9085000, "d2": 8569000, "temperature": 21.4, "pressure": 1013.13 },
+                "difference_hpa": 0.12,
+                "disagreement": false,
+            },
+        })
+    }
+
+    #[test]
+    fn flattens_nested_fields_into_prefixed_keys() {
+        let flat = flatten(object(nested()));
+        let expected = json!({
+            "timestamp": "2026-06-01T10:42:10Z",
+            "session_id": "20260601T103000Z",
+            "sequence": 181,
+            "ms5611_d1": 9085466,
+            "ms5611_d2": 8569150,
+            "ms5611_temperature": 21.5,
+            "ms5611_pressure": 1013.25,
+            "ds18b20_1": null,
+            "ds18b20_extra_28-0316a2790eff": -3.25,
+            "exec_co2_ppm": 412.0,
+            "exec_humidity": null,
+            "altitude_m": 0.0,
+            "flight_state": "preflight",
+            "suspect": ["ms5611_d1"],
+            "acquired_at_ds18b20_1": "2026-06-01T10:42:08.5Z",
+            "timing_start_lateness_ms": 1.5,
+            "timing_ms5611_ms": 160.0,
+            "timing_ms5611_secondary_ms": 160.0,
+            "voting_source": "ms5611",
+            "voting_ms5611_d1": 9085466,
+            "voting_ms5611_d2": 8569150,
+            "voting_ms5611_temperature": 21.5,
+            "voting_ms5611_pressure": 1013.25,
+            "voting_ms5611_secondary_d1": 9085000,
+            "voting_ms5611_secondary_d2": 8569000,
+            "voting_ms5611_secondary_temperature": 21.4,
+            "voting_ms5611_secondary_pressure": 1013.13,
+            "voting_difference_hpa": 0.12,
+            "voting_disagreement": false,
+        });
+        assert_eq!(Value::Object(flat.clone()), expected);
+        let keys: Vec<&str> = flat.keys().map(String::as_str).collect();
+        let mut sorted = keys.clone();
+        sorted.sort_unstable();
+        assert_eq!(keys, sorted);
+    }
+
+    #[test]
+    fn unflattens_what_it_flattened() {
+        let example = serde_json::to_value(SensorData::example()).unwrap();
+        for record in [nested(), example] {
+            assert_eq!(Value::Object(unflatten(flatten(object(record.clone())))), record);
+            let flat = RecordLayout::Flat.apply(record.clone());
+            assert_eq!(RecordLayout::Flat.apply(flat.clone()), flat);
+            assert_eq!(RecordLayout::Nested.apply(flat), record);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Identical errors of one sensor suppressed since the last one logged.
+#[derive(Debug)]
+pub struct Repeated {
+    pub sensor: String,
+    pub error: String,
+    pub count: u64,
+    pub over: Duration,
+    /// The sensor read fine again, as opposed to the window running out.
+    pub cleared: bool,
+}
+
+impl Repeated {
+    pub fn describe(&self) -> String {
+        let secs = self.over.as_secs();
+        let span = if secs >= 120 { format!("{} min", secs / 60) } else { format!("{} s", secs) };
+        if self.cleared {
+            format!(
+                "Errore {} ripetuto altre {} volte in {} prima di risolversi: {}",
+                self.sensor, self.count, span, self.error
+            )
+        } else {
+            format!("Errore {} ripetuto altre {} volte negli ultimi {}: {}", self.sensor, self.count, span, self.error)
+        }
+    }
+}
+
+struct Track {
+    error: String,
+    since: Instant,
+    count: u64,
+}
+
+/// Keeps repeated sensor errors off the console and the event log: the
+/// first occurrence is logged, identical ones within `window` are only
+/// counted, and the count is reported when the window rolls over, the
+/// error changes or the sensor recovers. A zero window logs everything.
+pub struct RepeatFilter {
+    window: Duration,
+    tracks: BTreeMap<String, Track>,
+}
+
+pub enum Verdict {
+    Log,
+    Suppress,
+    /// Log the summary instead of the error, which starts a new window.
+    Summarize(Repeated),
+}
+
+impl RepeatFilter {
+    pub fn new(window: Duration) -> Self {
+        RepeatFilter { window, tracks: BTreeMap::new() }
+    }
+
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    /// What to do with one more `error` of `sensor`. A different error
+    /// is always logged; the summary of the previous one, if any, is
+    /// returned alongside.
+    pub fn error(&mut self, sensor: &str, error: &str, now: Instant) -> (Verdict, Option<Repeated>) {
+        if self.window.is_zero() {
+            return (Verdict::Log, None);
+        }
+        let fresh = Track { error: error.to_string(), since: now, count: 0 };
+        let Some(track) = self.tracks.get_mut(sensor) else {
+            self.tracks.insert(sensor.to_string(), fresh);
+            return (Verdict::Log, None);
+        };
+        if track.error != error {
+            let previous = std::mem::replace(track, fresh);
+            return (Verdict::Log, summary(sensor, previous, now, false));
+        }
+        track.count += 1;
+        if now.duration_since(track.since) < self.window {
+            return (Verdict::Suppress, None);
+        }
+        let rolled = std::mem::replace(track, fresh);
+        (summary(sensor, rolled, now, false).map_or(Verdict::Suppress, Verdict::Summarize), None)
+    }
+
+    /// The sensor read fine: forgets its error and returns what was
+    /// suppressed since the last log line.
+    pub fn cleared(&mut self, sensor: &str, now: Instant) -> Option<Repeated> {
+        let track = self.tracks.remove(sensor)?;
+        summary(sensor, track, now, true)
+    }
+}
+
+fn summary(sensor: &str, track: Track, now: Instant, cleared: bool) -> Option<Repeated> {
+    (track.count > 0).then(|| Repeated {
+        sensor: sensor.to_string(),
+        error: track.error,
+        count: track.count,
+        over: now.duration_since(track.since),
+        cleared,
+    })
+}
@@ -0,0 +1,331 @@
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use crate::calibration::CalibrationConfig;
+use crate::cli::ReplayOptions;
+use crate::ms5611::{self, Compensation};
+use crate::record::{HeaderRecord, RecordLayout, SensorData};
+use crate::voting::PressureSource;
+
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    /// Records added between the ones in the input.
+    pub filled: usize,
+    /// With `--recompute`: records whose MS5611 values were recomputed, and
+    /// records left with the stored values for lack of a PROM.
+    pub recomputed: usize,
+    pub kept: usize,
+    /// Lines copied as they are because they could not be read as JSON or
+    /// as a data record.
+    pub copied: usize,
+}
+
+/// What `--recompute` needs from the last header: C1..C6 of each MS5611
+/// and the offsets the service added to the computed values.
+struct Recompute {
+    coefficients: Option<[u16; 6]>,
+    secondary: Option<[u16; 6]>,
+    calibration: CalibrationConfig,
+    compensation: Compensation,
+}
+
+impl Recompute {
+    fn from_header(header: &HeaderRecord, compensation: Compensation) -> Option<Recompute> {
+        let coefficients = |prom: Option<[u16; 8]>| {
+            let prom = prom?;
+            if !ms5611::prom_crc_ok(&prom) {
+                eprintln!("PROM dell'intestazione {} con CRC errato, valori salvati mantenuti", header.timestamp);
+                return None;
+            }
+            Some(ms5611::coefficients(&prom))
+        };
+        let recompute = Recompute {
+            coefficients: coefficients(header.ms5611_prom),
+            secondary: coefficients(header.ms5611_secondary_prom),
+            calibration: serde_json::from_value(header.config["calibration"].clone()).unwrap_or_default(),
+            compensation,
+        };
+        (recompute.coefficients.is_some() || recompute.secondary.is_some()).then_some(recompute)
+    }
+
+    /// False when the PROM of the sensor the record was read from is missing.
+    fn apply(&self, record: &mut SensorData) -> bool {
+        let source = record.voting.as_ref().map_or(PressureSource::Ms5611, |voting| voting.source);
+        let calibration = &self.calibration;
+        let (coefficients, temperature_offset, pressure_offset) = match source {
+            PressureSource::Ms5611 => {
+                (self.coefficients, calibration.ms5611_temperature, calibration.ms5611_pressure)
+            }
+            PressureSource::Ms5611Secondary => (
+                self.secondary,
+                calibration.ms5611_secondary_temperature,
+                calibration.ms5611_secondary_pressure,
+            ),
+        };
+        let Some(coefficients) = coefficients else {
+            return false;
+        };
+        let data = &mut record.ms5611;
+        let (temperature, pressure) = ms5611::compensate(&coefficients, data.d1, data.d2, self.compensation);
+        data.temperature = temperature + temperature_offset;
+        data.pressure = pressure + pressure_offset;
+        record.altitude_m = record.ms5611.altitude();
+        true
+    }
+}
+
+/// Expands a deadband-filtered file into a regular series of data records:
+/// each record is repeated every `options.step` until the next one, with
+/// `filled` set on the repeats and the layout of the input kept. The repeats
+/// keep the sequence number of the record they copy, since the samples the
+/// deadband suppressed took none. Filling stops at a change of boot_id so
+/// restarts stay visible as gaps. Header lines are copied and reported on
+/// stderr; status, gap and event lines are left out. Lines that are not
+/// valid JSON, or not a data record this version can read, are copied as
+/// they are.
+///
+/// With `options.recompute` the MS5611 temperature and pressure of every
+/// data record are computed again from its d1/d2 with that compensation,
+/// using the PROM of the header before it, and `altitude_m` from the new
+/// pressure; records with no such header keep their stored values. With
+/// `[voting]` the PROM of the sensor in `voting.source` is used. The other
+/// fields, vertical speed and the `voting` readings included, are left as
+/// they are.
+pub fn replay(options: &ReplayOptions) -> Result<ReplaySummary, Box<dyn std::error::Error>> {
+    if options.output.as_ref() == Some(&options.input) {
+        return Err("il file di uscita deve essere diverso da quello di ingresso".into());
+    }
+    let step = chrono::Duration::from_std(options.step)?;
+    let input = BufReader::new(File::open(&options.input)?);
+    let mut output: Box<dyn Write> = match &options.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    let mut held: Option<(SensorData, RecordLayout)> = None;
+    let mut recompute = None;
+    let mut summary = ReplaySummary::default();
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        let value = match serde_json::from_str::<Value>(&line) {
+            Ok(value) if value.get("type").is_none() => value,
+            Ok(_) => {
+                if let Some(header) = HeaderRecord::parse(&line) {
+                    eprintln!("Intestazione: {}", header.describe());
+                    writeln!(output, "{}", line)?;
+                    if let Some(compensation) = options.recompute {
+                        recompute = Recompute::from_header(&header, compensation);
+                    }
+                }
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Riga {} non valida, copiata com'è: {}", index + 1, e);
+                writeln!(output, "{}", line)?;
+                summary.copied += 1;
+                continue;
+            }
+        };
+        let layout = if value.get("ms5611").is_some_and(Value::is_object) {
+            RecordLayout::Nested
+        } else {
+            RecordLayout::Flat
+        };
+        let mut record = match serde_json::from_value::<SensorData>(RecordLayout::Nested.apply(value)) {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("Riga {} non è un record di dati, copiata com'è: {}", index + 1, e);
+                writeln!(output, "{}", line)?;
+                summary.copied += 1;
+                continue;
+            }
+        };
+        if let Some((mut copy, held_layout)) = held.take()
+            && copy.boot_id == record.boot_id
+        {
+            copy.filled = true;
+            copy.timestamp += step;
+            while copy.timestamp < record.timestamp {
+                writeln!(output, "{}", to_line(&copy, held_layout)?)?;
+                summary.filled += 1;
+                copy.timestamp += step;
+            }
+        }
+        match &recompute {
+            Some(recompute) if recompute.apply(&mut record) => {
+                writeln!(output, "{}", to_line(&record, layout)?)?;
+                summary.recomputed += 1;
+            }
+            _ => {
+                writeln!(output, "{}", line)?;
+                if options.recompute.is_some() {
+                    summary.kept += 1;
+                }
+            }
+        }
+        held = Some((record, layout));
+    }
+    output.flush()?;
+    Ok(summary)
+}
+
+fn to_line(record: &SensorData, layout: RecordLayout) -> serde_json::Result<String> {
+    match layout {
+        RecordLayout::Nested => serde_json::to_string(record),
+        RecordLayout::Flat => serde_json::to_string(&layout.apply(serde_json::to_value(record)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::time::Duration;
+
+    use super::replay;
+    use crate::cli::ReplayOptions;
+    use crate::ms5611::{self, Compensation};
+    use crate::record::{HeaderRecord, MS5611Data};
+
+    const COEFFICIENTS: [u16; 6] = [40127, 36924, 23317, 23282, 33464, 28312];
+    const D1: u32 = 9_085_466;
+
+    fn header() -> String {
+        let mut prom = [0u16; 8];
+        prom[1..7].copy_from_slice(&COEFFICIENTS);
+        while !ms5611::prom_crc_ok(&prom) {
+            prom[7] += 1;
+        }
+        let header = HeaderRecord {
+            kind: HeaderRecord::KIND.to_string(),
+            timestamp: "2026-06-01T10:41:55Z".parse().unwrap(),
+            session_id: "20260601T104155Z".to_string(),
+            boot_id: "boot-1".to_string(),
+            schema_version: 1,
+            software_version: "0.1.0".to_string(),
+            ms5611_prom: Some(prom),
+            ms5611_secondary_prom: None,
+            ds18b20_ids: None,
+            ds18b20_read_mode: None,
+            config: json!({ "sampling": { "interval_secs": 5 } }),
+            units: BTreeMap::new(),
+        };
+        serde_json::to_string(&header).unwrap()
+    }
+
+    fn record(timestamp: &str, sequence: u64, d2: u32) -> String {
+        json!({
+            "timestamp": timestamp,
+            "boot_id": "boot-1",
+            "sequence": sequence,
+            "ms5611": { "d1": D1, "d2": d2, "temperature": 0.0, "pressure": 0.0 },
+            "altitude_m": 0.0,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn recomputes_with_the_header_prom_and_copies_what_it_cannot_read() {
+        let dir = std::env::temp_dir().join(format!("sensor-program-replay-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let unreadable = ["{\"timestamp\":", r#"{"timestamp":"2026-06-01T10:42:02Z","ms5611":"rotto"}"#];
+        // 20.07 °C, the example of the datasheet, and 0.87 °C, where the
+        // second-order compensation applies.
+        let lines = [
+            header(),
+            record("2026-06-01T10:42:00Z", 0, 8_569_150),
+            unreadable[0].to_string(),
+            unreadable[1].to_string(),
+            record("2026-06-01T10:42:05Z", 1, 8_000_000),
+        ];
+        let input = dir.join("data.jsonl");
+        fs::write(&input, lines.join("\n") + "\n").unwrap();
+        let mut pressures = Vec::new();
+        for compensation in [Compensation::FirstOrder, Compensation::SecondOrder] {
+            let output = dir.join("replayed.jsonl");
+            let options = ReplayOptions {
+                step: Duration::from_secs(5),
+                input: input.clone(),
+                output: Some(output.clone()),
+                recompute: Some(compensation),
+            };
+            let summary = replay(&options).unwrap();
+            assert_eq!((summary.recomputed, summary.kept, summary.copied, summary.filled), (2, 0, 2, 0));
+            let content = fs::read_to_string(&output).unwrap();
+            let replayed: Vec<&str> = content.lines().collect();
+            assert_eq!(replayed.len(), lines.len());
+            assert_eq!(replayed[0], lines[0]);
+            assert_eq!(replayed[2..4], unreadable);
+            for (line, d2) in [(replayed[1], 8_569_150), (replayed[4], 8_000_000)] {
+                let record: Value = serde_json::from_str(line).unwrap();
+                let (temperature, pressure) = ms5611::compensate(&COEFFICIENTS, D1, d2, compensation);
+                assert_eq!(record["ms5611"]["temperature"], temperature);
+                assert_eq!(record["ms5611"]["pressure"], pressure);
+                let altitude = MS5611Data { pressure, ..MS5611Data::default() }.altitude();
+                assert_eq!(record["altitude_m"], altitude);
+                pressures.push(pressure);
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(pressures[0], pressures[2]);
+        assert_ne!(pressures[1], pressures[3]);
+    }
+
+    #[cfg(feature = "sim-test")]
+    #[test]
+    fn rebuilds_the_series_of_a_deadband_file() {
+        use chrono::DateTime;
+
+        use crate::deadband::DeadbandConfig;
+        use crate::sim::bench::Bench;
+
+        let mut bench = Bench::start("replay-deadband", Duration::ZERO, |config| {
+            config.sampling.interval_secs = 5;
+            config.deadband = Some(DeadbandConfig { heartbeat_secs: 60, ..DeadbandConfig::default() });
+        });
+        bench.run(48);
+        let sparse_path = bench.data_path.clone();
+        let output = bench.data_path.with_file_name("replayed.jsonl");
+        let (sparse, _) = bench.finish_keeping_files();
+        assert!(sparse.len() < 48, "{} record", sparse.len());
+        let sequences: Vec<u64> = sparse.iter().map(|record| record["sequence"].as_u64().unwrap()).collect();
+        assert_eq!(sequences, (0..sparse.len() as u64).collect::<Vec<_>>());
+
+        let options = ReplayOptions {
+            step: Duration::from_secs(5),
+            input: sparse_path,
+            output: Some(output.clone()),
+            recompute: None,
+        };
+        let summary = replay(&options).unwrap();
+        let content = fs::read_to_string(&output).unwrap();
+        let _ = fs::remove_dir_all(output.parent().unwrap());
+        let dense: Vec<Value> = content
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .filter(|record| record.get("type").is_none())
+            .collect();
+        let millis = |record: &Value| {
+            DateTime::parse_from_rfc3339(record["timestamp"].as_str().unwrap()).unwrap().timestamp_millis()
+        };
+        let span = millis(sparse.last().unwrap()) - millis(&sparse[0]);
+        assert_eq!(dense.len() as i64, span / 5000 + 1);
+        assert_eq!(summary.filled, dense.len() - sparse.len());
+        let mut written = sparse.iter();
+        let mut last = None;
+        for (index, record) in dense.iter().enumerate() {
+            assert_eq!(millis(record) - millis(&sparse[0]), index as i64 * 5000);
+            let filled = record.get("filled").is_some_and(|filled| filled == true);
+            if !filled {
+                last = written.next();
+                assert_eq!(Some(record), last);
+                continue;
+            }
+            let last = last.unwrap();
+            assert_eq!(record["sequence"], last["sequence"]);
+            assert_eq!(record["ms5611"], last["ms5611"]);
+        }
+        assert!(written.next().is_none());
+    }
+}
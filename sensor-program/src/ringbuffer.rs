@@ -0,0 +1,120 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use crate::flight::FlightState;
+
+/// Start of the name of every dump file.
+pub const DUMP_PREFIX: &str = "ringbuffer-";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RingBufferConfig {
+    pub capacity: usize,
+    pub max_bytes: usize,
+    pub dump_dir: String,
+    pub dump_on_states: Vec<FlightState>,
+}
+
+impl Default for RingBufferConfig {
+    fn default() -> Self {
+        RingBufferConfig {
+            capacity: 720,
+            max_bytes: 1024 * 1024,
+            dump_dir: ".".to_string(),
+            dump_on_states: vec![FlightState::Burst],
+        }
+    }
+}
+
+pub struct RingBuffer {
+    config: RingBufferConfig,
+    lines: VecDeque<String>,
+    bytes: usize,
+    dumper: Sender<Vec<String>>,
+}
+
+impl RingBuffer {
+    pub fn new(config: RingBufferConfig) -> Self {
+        let (dumper, requests) = mpsc::channel::<Vec<String>>();
+        let dump_dir = PathBuf::from(&config.dump_dir);
+        thread::spawn(move || {
+            for snapshot in requests {
+                match write_dump(&dump_dir, &snapshot) {
+                    Ok(path) => println!("Buffer circolare salvato in {} ({} record)", path.display(), snapshot.len()),
+                    Err(e) => println!("Errore salvataggio buffer circolare: {}", e),
+                }
+            }
+        });
+        RingBuffer { config, lines: VecDeque::new(), bytes: 0, dumper }
+    }
+
+    pub fn set_config(&mut self, config: RingBufferConfig) {
+        self.config = config;
+        self.trim();
+    }
+
+    pub fn triggers_on(&self, state: FlightState) -> bool {
+        self.config.dump_on_states.contains(&state)
+    }
+
+    pub fn push(&mut self, line: String) {
+        self.bytes += line.len();
+        self.lines.push_back(line);
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        while self.lines.len() > self.config.capacity || self.bytes > self.config.max_bytes {
+            match self.lines.pop_front() {
+                Some(line) => self.bytes -= line.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Lines and bytes held.
+    #[cfg(feature = "sim-test")]
+    pub fn usage(&self) -> (usize, usize) {
+        (self.lines.len(), self.bytes)
+    }
+
+    pub fn dump(&self) -> usize {
+        let snapshot: Vec<String> = self.lines.iter().cloned().collect();
+        let len = snapshot.len();
+        if self.dumper.send(snapshot).is_err() {
+            println!("Thread di salvataggio del buffer circolare non disponibile");
+        }
+        len
+    }
+}
+
+fn write_dump(dir: &Path, lines: &[String]) -> std::io::Result<PathBuf> {
+    let stamp = Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+    let mut suffix = 0;
+    loop {
+        let name = if suffix == 0 {
+            format!("{}{}.json", DUMP_PREFIX, stamp)
+        } else {
+            format!("{}{}-{}.json", DUMP_PREFIX, stamp, suffix)
+        };
+        let path = dir.join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => {
+                let mut file = BufWriter::new(file);
+                for line in lines {
+                    writeln!(file, "{}", line)?;
+                }
+                file.flush()?;
+                return Ok(path);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => suffix += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::control::{ControlServer, Request};
+use crate::overrides::Override;
+use crate::service::Service;
+
+/// How often the control thread looks whether the loop has ended.
+const CONTROL_POLL: Duration = Duration::from_millis(200);
+
+enum Control {
+    Reload,
+    Dump,
+    Command(Request),
+}
+
+/// Hands the requests of the control socket to the loop; the socket is
+/// closed once the loop is cancelled.
+fn forward_commands(server: ControlServer, token: CancellationToken, control: mpsc::UnboundedSender<Control>) {
+    let spawned = std::thread::Builder::new().name("control-forward".to_string()).spawn(move || {
+        while !token.is_cancelled() {
+            if let Some(request) = server.recv_timeout(CONTROL_POLL)
+                && control.send(Control::Command(request)).is_err()
+            {
+                break;
+            }
+        }
+    });
+    if let Err(e) = spawned {
+        println!("Socket di controllo non disponibile: {}", e);
+    }
+}
+
+async fn forward_signals(token: CancellationToken, control: mpsc::UnboundedSender<Control>) -> std::io::Result<()> {
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    let mut usr2 = signal(SignalKind::user_defined2())?;
+    loop {
+        tokio::select! {
+            _ = term.recv() => break,
+            _ = int.recv() => break,
+            _ = usr1.recv() => { let _ = control.send(Control::Reload); }
+            _ = usr2.recv() => { let _ = control.send(Control::Dump); }
+        }
+    }
+    token.cancel();
+    Ok(())
+}
+
+/// Samples on the `next_tick` schedule until the token is cancelled, a
+/// required sensor is lost or `cycles` cycles have run.
+async fn sample_loop(
+    mut service: Service,
+    config_path: PathBuf,
+    overrides: Vec<Override>,
+    token: CancellationToken,
+    mut control_rx: mpsc::UnboundedReceiver<Control>,
+    cycles: Option<u64>,
+) -> Service {
+    let mut scheduled = service.clock().now();
+    let mut done = 0;
+    while !token.is_cancelled() && cycles.is_none_or(|cycles| done < cycles) {
+        service = match tokio::task::spawn_blocking(move || {
+            service.run_cycle(scheduled);
+            service
+        })
+        .await
+        {
+            Ok(service) => service,
+            Err(e) => panic!("ciclo di campionamento interrotto: {}", e),
+        };
+        done += 1;
+        if service.fatal().is_some() {
+            break;
+        }
+
+        scheduled = service.next_tick(scheduled);
+        let deadline = tokio::time::Instant::now() + service.clock().until(scheduled);
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = tokio::time::sleep_until(deadline) => break,
+                Some(control) = control_rx.recv() => match control {
+                    Control::Reload => service.reload_config(&config_path, &overrides),
+                    Control::Dump => {
+                        service.dump_ring_buffer("SIGUSR2");
+                        service.signal_burst("SIGUSR2");
+                        service.signal_actions("SIGUSR2");
+                    }
+                    Control::Command(request) => {
+                        if service.control(request, &config_path, &overrides) {
+                            token.cancel();
+                        }
+                    }
+                },
+            }
+        }
+    }
+    service
+}
+
+pub fn run(service: Service, config_path: PathBuf, overrides: Vec<Override>, control: Option<ControlServer>) {
+    let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Impossibile avviare il runtime tokio: {}", e);
+            std::process::exit(1);
+        }
+    };
+    runtime.block_on(async move {
+        let token = CancellationToken::new();
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        if let Some(server) = control {
+            forward_commands(server, token.clone(), control_tx.clone());
+        }
+        let signal_token = token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = forward_signals(signal_token.clone(), control_tx).await {
+                println!("Impossibile registrare i segnali: {}", e);
+                signal_token.cancel();
+            }
+        });
+        let service = sample_loop(service, config_path, overrides, token, control_rx, None).await;
+        let _ = tokio::task::spawn_blocking(move || crate::stop(service)).await;
+    });
+}
+
+/// Runs `cycles` cycles of `service` on the runtime, with no signals or
+/// control socket, and hands the service back.
+#[cfg(feature = "sim-test")]
+pub fn run_cycles(service: Service, cycles: u64) -> Result<Service, String> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| format!("Impossibile avviare il runtime tokio: {}", e))?;
+    let (_control_tx, control_rx) = mpsc::unbounded_channel();
+    let token = CancellationToken::new();
+    Ok(runtime.block_on(sample_loop(service, PathBuf::new(), Vec::new(), token, control_rx, Some(cycles))))
+}
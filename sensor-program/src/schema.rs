@@ -0,0 +1,510 @@
+use chrono::DateTime;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::acquisition::AcquisitionFormat;
+use crate::config::Config;
+use crate::flight::FlightState;
+use crate::mapping::PROTECTED;
+use crate::record::{self, RecordLayout, SCHEMA_VERSION};
+use crate::timesync::InvalidClock;
+use crate::units::{self, Unit};
+use crate::voting::PressureSource;
+
+const DRAFT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// What a field holds, as JSON.
+#[derive(Clone, Copy)]
+enum Kind {
+    Integer,
+    Number,
+    Boolean,
+    Text,
+    Time,
+    State,
+    Source,
+    /// Flat-layout keys.
+    Keys,
+    /// [D1, D2] pairs.
+    Pairs,
+    /// An MS5611 reading, as the `ms5611` field of the nested layout.
+    Reading,
+    /// Only ever null.
+    Null,
+}
+
+/// A field of a data record by flat-layout key; a key ending in `_*`
+/// stands for the open-ended members of an object.
+struct Field {
+    key: String,
+    kind: Kind,
+    nullable: bool,
+    /// In every record, as opposed to only in some of them.
+    required: bool,
+    description: String,
+}
+
+fn field(key: &str, kind: Kind, nullable: bool, required: bool, description: &str) -> Field {
+    Field { key: key.to_string(), kind, nullable, required, description: description.to_string() }
+}
+
+/// The fields a data record can carry with `config`, before `[mapping]`.
+fn fields(config: &Config) -> Vec<Field> {
+    use Kind::*;
+    let mut fields = vec![
+        field("timestamp", Time, false, true, "Inizio del ciclo di lettura (UTC)"),
+        field("session_id", Text, false, true, "ID della sessione, comune ai riavvii dello stesso volo"),
+        field("boot_id", Text, false, true, "ID dell'avvio del servizio"),
+        field("sequence", Integer, false, true, "Numero progressivo del record nella sessione"),
+        field("time_synced", Boolean, true, true, "Orologio sincronizzato via NTP; null se non determinabile"),
+        field("clock_offset_ms", Number, false, false, "Scostamento dell'orologio riportato dal kernel"),
+        field("ms5611_d1", Integer, false, true, "Conversione grezza della pressione (D1)"),
+        field("ms5611_d2", Integer, false, true, "Conversione grezza della temperatura (D2)"),
+        field("ms5611_temperature", Number, false, true, "Temperatura compensata dell'MS5611"),
+        field("ms5611_pressure", Number, false, true, "Pressione compensata dell'MS5611"),
+    ];
+    if config.ms5611.samples_per_cycle > 1 {
+        fields.extend([
+            field("ms5611_conversions", Integer, false, true, "Coppie di conversioni aggregate nel record"),
+            field("ms5611_temperature_spread", Number, false, true, "Massimo meno minimo delle temperature"),
+            field("ms5611_pressure_spread", Number, false, true, "Massimo meno minimo delle pressioni"),
+        ]);
+        if config.ms5611.include_samples {
+            fields.push(field("ms5611_samples", Pairs, false, true, "[D1, D2] di ogni conversione"));
+        }
+    }
+    let probes = [("ds18b20_1", config.ds18b20.sensor_1_enabled), ("ds18b20_2", config.ds18b20.sensor_2_enabled)];
+    for (key, enabled) in probes {
+        if enabled {
+            let description = "Temperatura della sonda; null se la lettura non è riuscita, assente se esclusa";
+            fields.push(field(key, Number, true, false, description));
+        }
+    }
+    fields.push(field("ds18b20_extra_*", Number, true, false, "Sonde DS18B20 non configurate, per ID"));
+    for sensor in &config.exec {
+        for name in sensor.measurements() {
+            let description = format!("Misura di [[exec]] {}; null se il comando non è riuscito", sensor.name);
+            fields.push(Field { description, ..field(&format!("exec_{}", name), Number, true, false, "") });
+        }
+    }
+    fields.extend([
+        field("altitude_m", Number, false, true, "Quota barometrica, derivata dalla pressione"),
+        field("vertical_speed_ms", Number, true, true, "Velocità verticale derivata; null finché non calcolabile"),
+        field("flight_state", State, false, true, "Fase di volo"),
+        field("burst_mode", Boolean, false, true, "Campionamento accelerato attivo"),
+        field("suspect", Keys, false, false, "Misure ferme sullo stesso valore da troppo tempo"),
+        field("filled", Boolean, false, false, "Copia aggiunta da replay fra due record"),
+    ]);
+    if config.warmup.is_enabled() && !config.warmup.discard {
+        fields.push(field("warmup", Boolean, false, false, "Record del riscaldamento ([warmup])"));
+    }
+    if config.time.invalid_clock != InvalidClock::Off {
+        fields.push(field("clock_invalid", Boolean, false, false, "Orologio non ancora impostato (time.floor)"));
+    }
+    fields.push(field("capture_offsets_ms_*", Number, false, false, "Completamento di ogni lettura dal timestamp"));
+    match config.acquisition.format {
+        AcquisitionFormat::Offset => fields.push(field(
+            "acquisition_offsets_ms_*",
+            Number,
+            false,
+            false,
+            "Misure acquisite lontano dal timestamp: scostamento",
+        )),
+        AcquisitionFormat::Absolute => fields.push(field(
+            "acquired_at_*",
+            Time,
+            false,
+            false,
+            "Misure acquisite lontano dal timestamp: istante (UTC)",
+        )),
+    }
+    if config.status.timing_in_records {
+        fields.extend([
+            field("timing_start_lateness_ms", Number, false, true, "Ritardo dell'inizio del ciclo"),
+            field("timing_ms5611_ms", Number, false, true, "Durata della lettura dell'MS5611"),
+            field("timing_ms5611_secondary_ms", Number, false, false, "Durata della lettura del secondo MS5611"),
+            field("timing_ds18b20_1_ms", Number, false, false, "Durata della lettura di ds18b20_1"),
+            field("timing_ds18b20_2_ms", Number, false, false, "Durata della lettura di ds18b20_2"),
+            field("timing_derived_ms", Number, false, true, "Durata del calcolo dei campi derivati"),
+            field("timing_previous_sinks_ms", Number, false, false, "Scrittura del record precedente"),
+            field("timing_previous_total_ms", Number, false, false, "Durata totale del ciclo precedente"),
+        ]);
+    }
+    if config.voting.is_some() {
+        fields.extend([
+            field("voting_source", Source, false, false, "Sensore da cui vengono ms5611 e la quota"),
+            field("voting_ms5611", Reading, true, false, "Lettura dell'MS5611; null se non riuscita"),
+            field("voting_ms5611_secondary", Reading, true, false, "Lettura del secondo MS5611; null se non riuscita"),
+            field("voting_difference_hpa", Number, true, false, "Differenza assoluta delle pressioni"),
+            field("voting_disagreement", Boolean, false, false, "Differenza oltre la soglia per troppi campioni"),
+        ]);
+    }
+    fields
+}
+
+fn kind_schema(kind: Kind) -> Value {
+    let names = |values: Vec<Value>| json!({ "type": "string", "enum": values });
+    match kind {
+        Kind::Integer => json!({ "type": "integer", "minimum": 0 }),
+        Kind::Number => json!({ "type": "number" }),
+        Kind::Boolean => json!({ "type": "boolean" }),
+        Kind::Text => json!({ "type": "string" }),
+        Kind::Time => json!({ "type": "string", "format": "date-time" }),
+        Kind::State => names(
+            [FlightState::Preflight, FlightState::Ascent, FlightState::Burst, FlightState::Descent, FlightState::Landed]
+                .iter()
+                .map(|state| json!(state))
+                .collect(),
+        ),
+        Kind::Source => names(
+            [PressureSource::Ms5611, PressureSource::Ms5611Secondary].iter().map(|source| json!(source)).collect(),
+        ),
+        Kind::Keys => json!({ "type": "array", "items": { "type": "string" } }),
+        Kind::Pairs => json!({
+            "type": "array",
+            "items": { "type": "array", "items": kind_schema(Kind::Integer), "minItems": 2, "maxItems": 2 },
+        }),
+        Kind::Null => json!({ "type": "null" }),
+        Kind::Reading => {
+            let integer = ["d1", "d2"].map(|name| (name.to_string(), kind_schema(Kind::Integer)));
+            let number = ["temperature", "pressure"].map(|name| (name.to_string(), kind_schema(Kind::Number)));
+            let properties: Map<String, Value> = integer.into_iter().chain(number).collect();
+            json!({ "type": "object", "properties": properties, "required": ["d1", "d2", "pressure", "temperature"] })
+        }
+    }
+}
+
+/// One member of the schema, under its final key.
+struct Entry {
+    key: String,
+    schema: Value,
+    required: bool,
+    /// Renamed by `[mapping]`, hence at the top level whatever the layout.
+    renamed: bool,
+}
+
+/// The JSON Schema (draft 2020-12) of the data records written with
+/// `config`: layout, `[mapping]` (unless `raw`), compact output, exec
+/// measurements and optional sensors included. Units are in `x-unit`.
+/// Lines with a `type` field (header, events, status) are not covered.
+/// The same configuration always gives the same text.
+pub fn data_record(config: &Config, raw: bool) -> Value {
+    let units = units::record_units(&config.exec);
+    let mapping = if raw { Default::default() } else { config.mapping.clone() };
+    let omit_nulls = config.output.compact.enabled && config.output.compact.omit_nulls;
+    let layout = config.output.layout;
+    let fields = fields(config).into_iter().flat_map(|field| match field.kind {
+        Kind::Reading if layout == RecordLayout::Flat => flat_reading(field),
+        _ => vec![field],
+    });
+    let mut entries = Vec::new();
+    for field in fields {
+        let mut schema = kind_schema(field.kind);
+        if field.nullable
+            && let Some(kind) = schema.get("type").cloned()
+        {
+            schema["type"] = json!([kind, "null"]);
+        }
+        schema["description"] = json!(field.description);
+        if let Some(unit) = units::unit_for(&units, &field.key).filter(|&unit| unit != Unit::Other) {
+            schema["x-unit"] = json!(unit);
+        }
+        let required = field.required && !(field.nullable && omit_nulls);
+        // An allow-list, exclusion or rename names single keys, also the
+        // members of an open-ended object.
+        let keys: Vec<String> = match field.key.strip_suffix('*') {
+            Some(prefix) if !mapping.include.is_empty() => {
+                mapping.include.iter().filter(|key| key.starts_with(prefix)).cloned().collect()
+            }
+            Some(prefix) => {
+                let renamed = mapping.rename.keys().filter(|key| key.starts_with(prefix)).cloned();
+                std::iter::once(field.key.clone()).chain(renamed).collect()
+            }
+            None => vec![field.key.clone()],
+        };
+        for key in keys {
+            let protected = PROTECTED.contains(&key.as_str());
+            let allowed = mapping.include.is_empty() || mapping.include.contains(&key);
+            if !protected && (!allowed || mapping.exclude.contains(&key)) {
+                continue;
+            }
+            let required = required && !key.ends_with('*') && (key == field.key);
+            match mapping.rename.get(&key) {
+                Some(target) => {
+                    entries.push(Entry { key: target.clone(), schema: schema.clone(), required, renamed: true })
+                }
+                None => entries.push(Entry { key, schema: schema.clone(), required, renamed: false }),
+            }
+        }
+    }
+
+    let mut top = Vec::new();
+    let mut nested: BTreeMap<&str, Vec<Entry>> = BTreeMap::new();
+    for entry in entries {
+        let parent = record::nested_parent(&entry.key).map(|(parent, member)| (parent, member.to_string()));
+        match parent {
+            Some((parent, member)) if layout == RecordLayout::Nested && !entry.renamed => {
+                nested.entry(parent).or_default().push(Entry { key: member, ..entry });
+            }
+            _ => top.push(entry),
+        }
+    }
+    for (parent, members) in nested {
+        let required = members.iter().any(|member| member.required);
+        let mut schema = object(members, false);
+        schema["description"] = json!(format!("Campi {}_* del layout flat", parent));
+        top.push(Entry { key: parent.to_string(), schema, required, renamed: false });
+    }
+
+    let mut schema = object(top, layout == RecordLayout::Flat);
+    let title = format!("sensor-program: record di dati, schema {}", SCHEMA_VERSION);
+    let description = "Righe senza il campo type; le altre (header, eventi, stato) hanno un formato proprio";
+    for (key, value) in [
+        ("$schema", json!(DRAFT)),
+        ("title", json!(title)),
+        ("description", json!(description)),
+        ("x-schema-version", json!(SCHEMA_VERSION)),
+        ("x-layout", json!(layout)),
+        ("x-mapping", json!(!raw && !config.mapping.is_empty())),
+    ] {
+        schema[key] = value;
+    }
+    schema
+}
+
+/// A reading in the flat layout: a key for each member or, when it
+/// failed, the key of the reading with null.
+fn flat_reading(reading: Field) -> Vec<Field> {
+    use Kind::*;
+    let members = [("d1", Integer), ("d2", Integer), ("temperature", Number), ("pressure", Number)];
+    let mut fields: Vec<Field> = members
+        .into_iter()
+        .map(|(member, kind)| Field {
+            key: format!("{}_{}", reading.key, member),
+            kind,
+            nullable: false,
+            required: false,
+            description: format!("Campo {} di {}", member, reading.key),
+        })
+        .collect();
+    fields.insert(0, Field { kind: Null, nullable: false, ..reading });
+    fields
+}
+
+/// An object with `entries` as members. Keys ending in `*` match every
+/// key with that prefix: as `patternProperties` with `patterns`, as
+/// `additionalProperties` otherwise (a nested object has one at most).
+fn object(entries: Vec<Entry>, patterns: bool) -> Value {
+    let mut properties = Map::new();
+    let mut pattern_properties = Map::new();
+    let mut additional = json!(false);
+    let mut required = Vec::new();
+    for entry in entries {
+        match entry.key.strip_suffix('*') {
+            Some(prefix) if patterns => {
+                pattern_properties.insert(format!("^{}", prefix), entry.schema);
+            }
+            Some(_) => additional = entry.schema,
+            None => {
+                if entry.required {
+                    required.push(entry.key.clone());
+                }
+                properties.insert(entry.key, entry.schema);
+            }
+        }
+    }
+    required.sort();
+    let mut schema = json!({ "type": "object", "properties": properties, "additionalProperties": additional });
+    if !pattern_properties.is_empty() {
+        schema["patternProperties"] = Value::Object(pattern_properties);
+    }
+    if !required.is_empty() {
+        schema["required"] = json!(required);
+    }
+    schema
+}
+
+/// Checks the data records of `path` against `schema`: how many there
+/// are and a message for each one that does not conform.
+pub fn check_file(schema: &Value, path: &Path) -> Result<(u64, Vec<String>), String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let (mut records, mut errors) = (0, Vec::new());
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        let value = match serde_json::from_str::<Value>(&line) {
+            Ok(value) if value.get("type").is_some() => continue,
+            Ok(value) => value,
+            Err(e) => {
+                errors.push(format!("riga {}: {}", index + 1, e));
+                continue;
+            }
+        };
+        records += 1;
+        if let Err(e) = validate(schema, &value) {
+            errors.push(format!("riga {}: {}", index + 1, e));
+        }
+    }
+    Ok((records, errors))
+}
+
+/// Checks `value` against `schema`, for the keywords `data_record` uses;
+/// patterns are only ever `^` and a prefix.
+pub fn validate(schema: &Value, value: &Value) -> Result<(), String> {
+    check(schema, value, "")
+}
+
+fn check(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let at = if path.is_empty() { "record".to_string() } else { path.to_string() };
+    let types: Vec<&str> = match &schema["type"] {
+        Value::String(kind) => vec![kind.as_str()],
+        Value::Array(kinds) => kinds.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    let matches = |kind: &str| match kind {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => false,
+    };
+    if !types.is_empty() && !types.iter().any(|kind| matches(kind)) {
+        return Err(format!("{}: atteso {}, trovato {}", at, types.join(" o "), value));
+    }
+    if let Some(allowed) = schema["enum"].as_array()
+        && !value.is_null()
+        && !allowed.contains(value)
+    {
+        return Err(format!("{}: valore {} non ammesso", at, value));
+    }
+    if let (Some(minimum), Some(number)) = (schema["minimum"].as_f64(), value.as_f64())
+        && number < minimum
+    {
+        return Err(format!("{}: {} sotto il minimo {}", at, number, minimum));
+    }
+    if schema["format"] == "date-time"
+        && let Some(text) = value.as_str()
+        && DateTime::parse_from_rfc3339(text).is_err()
+    {
+        return Err(format!("{}: {} non è una data RFC 3339", at, text));
+    }
+    if let Value::Array(items) = value {
+        let length = items.len() as u64;
+        if schema["minItems"].as_u64().is_some_and(|min| length < min)
+            || schema["maxItems"].as_u64().is_some_and(|max| length > max)
+        {
+            return Err(format!("{}: {} elementi", at, length));
+        }
+        for (index, item) in items.iter().enumerate() {
+            if schema.get("items").is_some() {
+                check(&schema["items"], item, &format!("{}[{}]", at, index))?;
+            }
+        }
+    }
+    if let Value::Object(members) = value {
+        for key in schema["required"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+            if !members.contains_key(key) {
+                return Err(format!("{}: manca il campo {}", at, key));
+            }
+        }
+        let properties = schema["properties"].as_object();
+        let patterns = schema["patternProperties"].as_object();
+        for (key, member) in members {
+            let path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+            let pattern = patterns.and_then(|patterns| {
+                patterns.iter().find(|(pattern, _)| pattern.strip_prefix('^').is_some_and(|p| key.starts_with(p)))
+            });
+            match (properties.and_then(|properties| properties.get(key)), pattern) {
+                (Some(schema), _) | (None, Some((_, schema))) => check(schema, member, &path)?,
+                (None, None) => match &schema["additionalProperties"] {
+                    Value::Bool(false) => return Err(format!("{}: campo non previsto", path)),
+                    Value::Object(_) => check(&schema["additionalProperties"], member, &path)?,
+                    _ => {}
+                },
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    use super::{data_record, validate};
+    use crate::config::Config;
+    use crate::record::HeaderRecord;
+
+    #[test]
+    fn a_header_is_not_a_data_record() {
+        let header = HeaderRecord {
+            kind: HeaderRecord::KIND.to_string(),
+            timestamp: "2026-06-01T10:41:55Z".parse().unwrap(),
+            session_id: "20260601T104155Z".to_string(),
+            boot_id: "boot-1".to_string(),
+            schema_version: 1,
+            software_version: "0.1.0".to_string(),
+            ms5611_prom: None,
+            ms5611_secondary_prom: None,
+            ds18b20_ids: None,
+            ds18b20_read_mode: None,
+            config: json!({}),
+            units: BTreeMap::new(),
+        };
+        let schema = data_record(&Config::default(), false);
+        assert!(validate(&schema, &serde_json::to_value(&header).unwrap()).is_err());
+    }
+
+    #[cfg(feature = "sim-test")]
+    #[test]
+    fn the_written_records_conform() {
+        use std::fs;
+        use std::time::Duration;
+
+        use super::check_file;
+        use crate::record::RecordLayout;
+        use crate::sim::bench::Bench;
+        use crate::soak;
+
+        type Edit = fn(&mut Config);
+        let configurations: [(&str, Edit); 4] = [
+            ("default", |_| {}),
+            ("flat", |config| config.output.layout = RecordLayout::Flat),
+            ("full", |config| {
+                config.ms5611.samples_per_cycle = 4;
+                config.ms5611.include_samples = true;
+                config.status.timing_in_records = true;
+                config.voting = Some(toml::from_str("secondary = { address = 0x76 }").unwrap());
+            }),
+            ("flat-voting", |config| {
+                config.output.layout = RecordLayout::Flat;
+                config.voting = Some(toml::from_str("secondary = { address = 0x76 }").unwrap());
+            }),
+        ];
+        for (name, edit) in configurations {
+            let mut bench = Bench::start(&format!("schema-{}", name), Duration::ZERO, edit);
+            bench.run(5);
+            let mut config = soak::soak_config(Config::default(), &bench.dir);
+            edit(&mut config);
+            let schema = data_record(&config, false);
+            let (dir, data_path) = (bench.dir.clone(), bench.data_path.clone());
+            let (records, _) = bench.finish_keeping_files();
+            assert_eq!(records.len(), 5, "{}", name);
+            if name == "flat-voting" {
+                assert!(records.iter().all(|record| record["voting_ms5611_secondary_pressure"].is_number()));
+            }
+            // The header line is in the file, and skipped.
+            let content = fs::read_to_string(&data_path).unwrap();
+            let first: serde_json::Value = serde_json::from_str(content.lines().next().unwrap()).unwrap();
+            assert_eq!(first["type"], HeaderRecord::KIND);
+            assert_eq!(check_file(&schema, &data_path).unwrap(), (5, Vec::new()), "{}", name);
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use serde::Serialize;
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+
+use crate::config::Config;
+use crate::ds18b20;
+use crate::exec;
+use crate::flight_dir;
+use crate::i2c_bus::{Bus, Buses};
+use crate::identify;
+use crate::ms5611;
+use crate::telemetry::TelemetrySink;
+
+/// Free space required in the output directories.
+const MIN_FREE_BYTES: u64 = 50 * 1024 * 1024;
+
+const GROUND_PRESSURE_HPA: (f64, f64) = (850.0, 1100.0);
+const GROUND_TEMPERATURE_C: (f64, f64) = (-30.0, 60.0);
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+    pub name: String,
+    pub required: bool,
+    pub passed: bool,
+    pub detail: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub passed: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn push(&mut self, name: impl Into<String>, required: bool, result: Result<String, String>) {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        if required && !passed {
+            self.passed = false;
+        }
+        self.checks.push(Check { name: name.into(), required, passed, detail });
+    }
+}
+
+pub fn run(config: &Config) -> Report {
+    let mut report = Report { passed: true, checks: Vec::new() };
+
+    let devices = config.i2c_devices();
+    let buses = Buses::open(devices.iter().map(|&(_, bus, _)| bus));
+    for &(name, number, address) in &devices {
+        let required = name == "ms5611" || (name == "battery" && config.battery.as_ref().is_some_and(|b| b.required));
+        let result = buses.get(number).and_then(|bus| {
+            bus.with_device(address, |i2c| i2c.smbus_receive_byte())
+                .map(|_| format!("bus {}, 0x{:02X} risponde", number, address))
+                .map_err(|e| e.to_string())
+        });
+        report.push(format!("i2c.{}", name), required, result);
+    }
+    if let Ok(bus) = buses.get(config.ms5611.bus) {
+        let reset = ms5611::reset(bus, &config.ms5611).map(|_| "reset inviato".to_string()).map_err(|e| e.to_string());
+        report.push("ms5611_reset", true, reset);
+        report.push("ms5611_identity", true, identify::ms5611(bus, &config.ms5611));
+        report.push("ms5611_reading", true, check_reading(bus, config));
+    }
+    if let Some(voting) = &config.voting {
+        let result = buses.get(voting.secondary.bus).and_then(|bus| identify::ms5611(bus, &voting.secondary));
+        report.push("ms5611_secondary_identity", config.identify.strict, result);
+    }
+    if let Some(battery) = &config.battery {
+        let result = buses.get(battery.bus).and_then(|bus| identify::ina219(bus, battery.address));
+        report.push("battery_identity", battery.required || config.identify.strict, result);
+    }
+
+    let probes = config.ds18b20.probes();
+    report.push("ds18b20_bus", probes.iter().any(|probe| probe.required), enumerate_ds18b20());
+    for probe in probes.iter().filter(|probe| probe.enabled) {
+        let id = probe.id;
+        let result = ds18b20::read_temperature(id)
+            .map_err(|e| format!("{}: {}", id, e))
+            .and_then(|temperature| {
+                if (-55.0..=125.0).contains(&temperature) {
+                    Ok(format!("{}: {:.2} °C, CRC valido", id, temperature))
+                } else {
+                    Err(format!("{}: {:.2} °C fuori dal campo del sensore", id, temperature))
+                }
+            });
+        report.push(format!("ds18b20.{}", probe.key), probe.required, result);
+    }
+    for sensor in &config.exec {
+        let result = exec::run(sensor).map(|values| {
+            values.iter().map(|(name, value)| format!("{} = {}", name, value)).collect::<Vec<_>>().join(", ")
+        });
+        report.push(format!("exec.{}", sensor.name), false, result);
+    }
+
+    // The flight directory does not exist yet: its files are checked in its base.
+    let mut files = config.clone();
+    if config.flight_dir.is_enabled() {
+        flight_dir::relocate(&mut files, &config.flight_dir.base());
+    }
+    for (key, path) in [("output.path", &files.output.path), ("events.path", &files.events.path)] {
+        report.push(key, true, check_directory(path));
+    }
+
+    for sink in &config.sinks {
+        let result = sink.open().map(|_| "uscita aperta".to_string());
+        report.push(format!("sinks.{}", sink.name), sink.required, result);
+    }
+    if let Some(telemetry) = &config.telemetry {
+        let result = TelemetrySink::open(&telemetry.sink)
+            .map(|_| "uscita aperta".to_string())
+            .map_err(|e| e.to_string());
+        report.push("telemetry.sink", false, result);
+    }
+    report
+}
+
+fn check_reading(bus: &Bus, config: &Config) -> Result<String, String> {
+    let data = ms5611::read_and_calculate(bus, &config.ms5611).map_err(|e| e.to_string())?;
+    let detail = format!("{:.2} hPa, {:.2} °C", data.pressure, data.temperature);
+    let pressure_ok = (GROUND_PRESSURE_HPA.0..=GROUND_PRESSURE_HPA.1).contains(&data.pressure);
+    let temperature_ok = (GROUND_TEMPERATURE_C.0..=GROUND_TEMPERATURE_C.1).contains(&data.temperature);
+    if pressure_ok && temperature_ok {
+        Ok(detail)
+    } else {
+        Err(format!("{} fuori dai valori plausibili a terra", detail))
+    }
+}
+
+fn enumerate_ds18b20() -> Result<String, String> {
+    let ids = ds18b20::scan().map_err(|e| format!("{}: {}", ds18b20::DEVICES_DIR, e))?;
+    if ids.is_empty() {
+        Err("nessun DS18B20 sul bus 1-Wire".to_string())
+    } else {
+        Ok(format!("trovati: {}", ids.into_iter().collect::<Vec<_>>().join(", ")))
+    }
+}
+
+fn check_directory(path: &str) -> Result<String, String> {
+    let dir = match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let probe = dir.join(format!(".sensor-program-selftest-{}", std::process::id()));
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|e| format!("{} non scrivibile: {}", dir.display(), e))?;
+    let free = free_bytes(dir).map_err(|e| format!("{}: spazio libero non determinabile: {}", dir.display(), e))?;
+    let free_mb = free / (1024 * 1024);
+    if free < MIN_FREE_BYTES {
+        let min_mb = MIN_FREE_BYTES / (1024 * 1024);
+        return Err(format!("{}: solo {} MB liberi (minimo {} MB)", dir.display(), free_mb, min_mb));
+    }
+    Ok(format!("{} scrivibile, {} MB liberi", dir.display(), free_mb))
+}
+
+pub fn free_bytes(dir: &Path) -> std::io::Result<u64> {
+    let path = CString::new(dir.as_os_str().as_encoded_bytes()).map_err(std::io::Error::other)?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid NUL-terminated string and `stats` is only
+    // read after statvfs reports success.
+    let stats = unsafe {
+        if libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stats.assume_init()
+    };
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
@@ -0,0 +1,2180 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::acquisition::Acquisitions;
+use crate::actions::{Actions, Actuation, Outcome};
+use crate::availability::{Availability, LOST_AFTER_FAILURES};
+use crate::battery::{self, BatteryMonitor};
+use crate::burst::BurstMode;
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::control::{self, ControlCommand, ControlServer, Request};
+use crate::deadband::Deadband;
+use crate::ds18b20::{self, BulkConversion, Discovery, ReadMode, Subsystem};
+use crate::events::{Event, Severity};
+use crate::exec::{self, ExecSchedule};
+use crate::flight::{FlightTracker, Transition};
+use crate::flight_dir::{self, HEADER_PREFIX};
+use crate::gap;
+use crate::i2c_bus::{BusWait, Buses, SETUP_HINT};
+use crate::identify;
+use crate::last_good::LastGood;
+use crate::ms5611;
+use crate::overrides::Override;
+use crate::pipeline::{ConsoleSink, Output, Pipeline, RawSink};
+use crate::record::{
+    Ds18b20OnlyRecord, HeaderRecord, MS5611Data, RecordLayout, SensorData, StatusRecord, SCHEMA_VERSION,
+};
+use crate::repeats::{RepeatFilter, Repeated, Verdict};
+use crate::stuck::{StuckChange, StuckDetector};
+use crate::ringbuffer::RingBuffer;
+use crate::session::{self, Session};
+use crate::setpoints::{Crossing, Direction, Setpoints};
+use crate::sinks::SinkConfig;
+use crate::telemetry::{SentenceBuilder, TelemetrySink};
+use crate::timesync::{ClockGuard, InvalidClock, Released, TimeSync};
+use crate::timing::{millis, CycleTiming, TimingStats};
+use crate::transcript::Recording;
+use crate::units::{self, Unit};
+use crate::voting::{Change, PressureSource, Voter, VotingData};
+use crate::warmup::{Phase, Warmup};
+use crate::watchdog::{self, Heartbeat, Stage};
+use crate::writer::{self, JsonlWriter};
+
+pub struct Service {
+    config: Config,
+    dry_run: bool,
+    clock: Arc<dyn Clock>,
+    pipeline: Pipeline,
+    telemetry: Option<SentenceBuilder>,
+    flight: FlightTracker,
+    ring: RingBuffer,
+    burst: BurstMode,
+    actions: Actions,
+    setpoints: Setpoints,
+    voter: Option<Voter>,
+    /// Unit of every record field, for `[output.compact]`.
+    record_units: BTreeMap<String, Unit>,
+    buses: Buses,
+    #[cfg(feature = "tui")]
+    live: Option<Arc<std::sync::Mutex<crate::live::LiveState>>>,
+    /// Set while the MS5611's bus is awaited, see `I2cConfig`.
+    bus_wait: Option<BusWait>,
+    ds18b20_only_records: u64,
+    availability: Availability,
+    /// Set when a required sensor is lost; the service must stop.
+    fatal: Option<String>,
+    repeats: RepeatFilter,
+    exec_schedule: ExecSchedule,
+    heartbeat: Arc<Heartbeat>,
+    battery: Option<BatteryMonitor>,
+    deadband: Option<Deadband>,
+    stuck: StuckDetector,
+    warmup: Warmup,
+    time_sync: TimeSync,
+    last_clock: Option<(Instant, chrono::DateTime<chrono::Utc>)>,
+    clock_guard: ClockGuard,
+    last_good: LastGood,
+    last_ds18b20_read: Option<Instant>,
+    /// Set with `ds18b20.read_mode = "split"` once the kernel supports it.
+    ds18b20_bulk: Option<BulkConversion>,
+    probes: Discovery,
+    session: Session,
+    boot_id: String,
+    sequence: u64,
+    started: Instant,
+    last_status: Instant,
+    timing_stats: TimingStats,
+    previous_sinks_ms: Option<f64>,
+    previous_total_ms: Option<f64>,
+    /// `--record-transcript`: saved at shutdown.
+    transcript: Option<(Recording, PathBuf)>,
+}
+
+/// How often the wait between cycles looks at the signal flags.
+const SIGNAL_POLL: Duration = Duration::from_millis(20);
+
+/// Flags set by the signal handlers and polled between cycles, with the
+/// commands from the control socket.
+#[derive(Default)]
+pub struct Signals {
+    pub reload: Arc<AtomicBool>,
+    pub dump: Arc<AtomicBool>,
+    pub stop: Arc<AtomicBool>,
+    pub control: Option<ControlServer>,
+}
+
+fn new_boot_id() -> String {
+    let nanos = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    format!("{:x}-{:x}", nanos, std::process::id())
+}
+
+impl Service {
+    /// Fails only when a sink marked `required` cannot be opened.
+    pub fn new(
+        config: Config,
+        dry_run: bool,
+        buses: Buses,
+        session: Session,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self, String> {
+        let mut pipeline = Pipeline::default();
+        let mut telemetry = None;
+        if dry_run {
+            pipeline.add(
+                "console",
+                config.output.queue.clone(),
+                |output| output.is_record() || output.is_sentence(),
+                Box::new(ConsoleSink),
+            );
+            telemetry = config.telemetry.clone().map(SentenceBuilder::new);
+        } else {
+            pipeline.add(
+                "data",
+                config.output.queue.clone(),
+                Output::is_record,
+                if config.output.raw {
+                    Box::new(RawSink(Box::new(JsonlWriter::new(&config.output.path_for(&session.id)))))
+                } else {
+                    Box::new(JsonlWriter::new(&config.output.path_for(&session.id)))
+                },
+            );
+            pipeline.add(
+                "events",
+                config.events.queue.clone(),
+                Output::is_event,
+                Box::new(JsonlWriter::new(&config.events.path_for(&session.id))),
+            );
+            if let Some(telemetry_config) = &config.telemetry {
+                match TelemetrySink::open(&telemetry_config.sink) {
+                    Ok(sink) => {
+                        pipeline.add("telemetry", telemetry_config.queue.clone(), Output::is_sentence, Box::new(sink));
+                        telemetry = Some(SentenceBuilder::new(telemetry_config.clone()));
+                    }
+                    Err(e) => println!("Errore apertura uscita telemetria: {}", e),
+                }
+            }
+            for sink_config in &config.sinks {
+                match sink_config.open() {
+                    Ok(sink) => pipeline.add(&sink_config.name, sink_config.queue.clone(), sink_config.accepts(), sink),
+                    Err(e) if sink_config.required => {
+                        pipeline.shutdown();
+                        return Err(format!("uscita richiesta {} non disponibile: {}", sink_config.name, e));
+                    }
+                    Err(e) => println!("Attenzione: uscita {} ignorata: {}", sink_config.name, e),
+                }
+            }
+        }
+        let flight = FlightTracker::new(config.flight.clone(), !dry_run, &session.id, clock.now());
+        let ring = RingBuffer::new(config.ring_buffer.clone());
+        let burst = BurstMode::new(config.burst.clone());
+        let actions = Actions::new(config.actions.clone(), dry_run);
+        let setpoints = Setpoints::new(config.setpoints.clone(), flight.setpoints());
+        let voter = config.voting.clone().map(Voter::new);
+        let record_units = units::record_units(&config.exec);
+        let battery = config.battery.clone().map(BatteryMonitor::new);
+        let deadband = config.deadband.clone().map(Deadband::new);
+        let repeats = RepeatFilter::new(Duration::from_secs(config.events.repeat_window_secs));
+        let heartbeat = Heartbeat::new(Duration::from_secs(config.sampling.interval_secs));
+        let started = clock.now();
+        let ms5611_bus = config.ms5611.bus;
+        let bus_wait = config
+            .i2c
+            .waits_for(&buses, ms5611_bus)
+            .then(|| BusWait::new(ms5611_bus, config.i2c.clone(), started));
+        Ok(Service {
+            pipeline,
+            config,
+            dry_run,
+            clock,
+            telemetry,
+            flight,
+            ring,
+            burst,
+            actions,
+            setpoints,
+            voter,
+            record_units,
+            buses,
+            #[cfg(feature = "tui")]
+            live: None,
+            bus_wait,
+            ds18b20_only_records: 0,
+            availability: Availability::default(),
+            fatal: None,
+            repeats,
+            exec_schedule: ExecSchedule::default(),
+            heartbeat,
+            battery,
+            deadband,
+            stuck: StuckDetector::default(),
+            warmup: Warmup::new(started),
+            time_sync: TimeSync::new(),
+            last_clock: None,
+            clock_guard: ClockGuard::new(None),
+            last_good: LastGood::default(),
+            last_ds18b20_read: None,
+            ds18b20_bulk: None,
+            probes: Discovery::default(),
+            session,
+            boot_id: new_boot_id(),
+            sequence: 0,
+            started,
+            last_status: started,
+            timing_stats: TimingStats::default(),
+            previous_sinks_ms: None,
+            previous_total_ms: None,
+            transcript: None,
+        })
+    }
+
+    /// Fails when a required sensor cannot be used.
+    pub fn start(&mut self) -> Result<(), String> {
+        // With a deadband the file may legitimately go quiet for a whole heartbeat.
+        let interval_secs = match &self.config.deadband {
+            Some(deadband) => deadband.heartbeat_secs.max(self.config.sampling.interval_secs),
+            None => self.config.sampling.interval_secs,
+        };
+        // A new session writes to new files; the gap is measured on the previous one.
+        let previous = self.session.previous.as_deref().unwrap_or(&self.session.id);
+        let written = writer::last_line(Path::new(&self.config.output.path_for(previous)), writer::is_data_record)
+            .ok()
+            .flatten()
+            .and_then(|line| serde_json::from_str::<serde_json::Value>(&line).ok())
+            .and_then(|record| record["timestamp"].as_str()?.parse().ok());
+        self.clock_guard = ClockGuard::new(written);
+        let gap = gap::detect(
+            Path::new(&self.config.output.path_for(previous)),
+            Path::new(&self.config.events.path_for(previous)),
+            interval_secs,
+            self.config.sampling.gap_factor,
+            self.clock.utc(),
+            &self.session.id,
+            &self.boot_id,
+        );
+        self.touch_session();
+
+        let state = self.flight.state();
+        let split_unavailable = self.setup_split_read();
+        self.write_header();
+        self.emit(Event::new(
+            Severity::Info,
+            "startup",
+            format!("Avvio, sessione {}, stato di volo iniziale: {:?}", self.session.id, state),
+            json!({
+                "version": env!("CARGO_PKG_VERSION"),
+                "boot_id": self.boot_id,
+                "session_origin": self.session.origin,
+                "previous_session_id": self.session.previous,
+                "flight_state": state,
+            }),
+        ));
+        if let Some(reason) = split_unavailable {
+            self.emit(Event::new(
+                Severity::Warning,
+                "ds18b20",
+                format!("Lettura separata dei DS18B20 non disponibile ({}), si usa la lettura bloccante", reason),
+                json!({ "read_mode": ReadMode::Blocking, "reason": reason }),
+            ));
+        }
+        for warning in self.config.mapping.warnings(&self.config.exec) {
+            self.emit(Event::new(Severity::Warning, "config", warning, json!({})));
+        }
+
+        if let Some(gap) = gap {
+            println!(
+                "*** INTERRUZIONE DATI: {:.0}s senza record dall'ultimo campione ({}, boot_id {}, sequenza {}), {:?} ***",
+                gap.gap_secs, gap.last_timestamp, gap.last_boot_id, gap.last_sequence, gap.reason
+            );
+            self.write(&gap);
+            self.emit(Event::new(
+                Severity::Warning,
+                "gap",
+                format!("Interruzione di {:.0}s nei dati", gap.gap_secs),
+                json!({ "gap_secs": gap.gap_secs, "last_boot_id": gap.last_boot_id, "reason": gap.reason }),
+            ));
+        }
+        self.check_sensors()?;
+        if let Some(wait) = &self.bus_wait {
+            let bus = wait.bus();
+            let reason = self.buses.get(bus).err().unwrap_or_default();
+            let meanwhile = if self.probes_in_use().is_empty() {
+                "nessun DS18B20 in uso, nessun dato registrato"
+            } else {
+                "registrazione dei soli DS18B20"
+            };
+            self.emit(Event::new(
+                Severity::Warning,
+                "i2c_bus",
+                format!(
+                    "{}: {}. Nuovi tentativi per {} s, nel frattempo {}",
+                    reason, SETUP_HINT, self.config.i2c.wait_secs, meanwhile
+                ),
+                json!({ "bus": bus, "available": false, "reason": reason, "wait_secs": self.config.i2c.wait_secs }),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Enables `ds18b20.read_mode = "split"` if the kernel has the bulk
+    /// conversion; otherwise returns why not.
+    fn setup_split_read(&mut self) -> Option<String> {
+        if self.config.ds18b20.read_mode != ReadMode::Split {
+            return None;
+        }
+        match BulkConversion::detect() {
+            Ok(bulk) => {
+                self.ds18b20_bulk = Some(bulk);
+                None
+            }
+            Err(reason) => Some(reason),
+        }
+    }
+
+    fn ds18b20_read_mode(&self) -> ReadMode {
+        if self.ds18b20_bulk.is_some() { ReadMode::Split } else { ReadMode::Blocking }
+    }
+
+    /// Checks the enabled DS18B20s and the I2C sensors once at startup,
+    /// those on an awaited bus once it appears.
+    fn check_sensors(&mut self) -> Result<(), String> {
+        let awaited = self.bus_wait.as_ref().map(BusWait::bus);
+        self.check_i2c(|bus| Some(bus) != awaited)?;
+        let present = ds18b20::scan();
+        let probes: Vec<_> = self
+            .config
+            .ds18b20
+            .probes()
+            .into_iter()
+            .filter(|probe| probe.enabled)
+            .map(|probe| (probe.field, probe.name, probe.id.to_string(), probe.required))
+            .collect();
+        for (field, name, id, required) in probes {
+            let problem = match &present {
+                Err(e) => format!("bus 1-Wire non disponibile: {}", e),
+                Ok(ids) if !ids.contains(&id) => format!("{} non presente sul bus", id),
+                Ok(_) => continue,
+            };
+            self.sensor_unavailable(field, name, required, &problem)?;
+        }
+        Ok(())
+    }
+
+    /// The INA219 and, with `[identify]`, what answers at each I2C address,
+    /// for the devices on the buses `checked` accepts.
+    fn check_i2c(&mut self, checked: impl Fn(u8) -> bool) -> Result<(), String> {
+        if self.config.identify.enabled {
+            self.identify_i2c(&checked)?;
+        }
+        if let Some(battery_config) = self.config.battery.clone().filter(|battery| checked(battery.bus)) {
+            let reading = self
+                .buses
+                .get(battery_config.bus)
+                .and_then(|bus| battery::read_pack_voltage(bus, &battery_config).map_err(|e| e.to_string()));
+            if let Err(e) = reading {
+                self.sensor_unavailable("battery", "INA219", battery_config.required, &e)?;
+            } else if self.config.identify.enabled {
+                let identity =
+                    self.buses.get(battery_config.bus).and_then(|bus| identify::ina219(bus, battery_config.address));
+                if let Err(e) = identity {
+                    let required = battery_config.required || self.config.identify.strict;
+                    self.sensor_unavailable("battery", "INA219", required, &e)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The MS5611s are always required, except the second one of `[voting]`,
+    /// which is dropped from the run unless `identify.strict` is set.
+    fn identify_i2c(&mut self, checked: &impl Fn(u8) -> bool) -> Result<(), String> {
+        let mut sensors = vec![("ms5611", "MS5611", self.config.ms5611.clone(), true)];
+        if let Some(voting) = &self.config.voting {
+            let secondary = voting.secondary.clone();
+            sensors.push(("ms5611_secondary", "MS5611 secondario", secondary, self.config.identify.strict));
+        }
+        sensors.retain(|(.., config, _)| checked(config.bus));
+        for (key, name, config, required) in sensors {
+            match self.buses.get(config.bus).and_then(|bus| identify::ms5611(bus, &config)) {
+                Ok(detail) => println!("  {} bus {} 0x{:02X}: {}", name, config.bus, config.address, detail),
+                Err(problem) => self.sensor_unavailable(key, name, required, &problem)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// A sensor unusable at startup or lost during the run. A required one
+    /// is an error the service has to stop on; an optional one is dropped
+    /// from the run, its field left out of the records.
+    fn sensor_unavailable(
+        &mut self,
+        key: &'static str,
+        name: &str,
+        required: bool,
+        problem: &str,
+    ) -> Result<(), String> {
+        let (severity, message) = if required {
+            (Severity::Error, format!("Sensore richiesto {} non disponibile: {}", name, problem))
+        } else {
+            (Severity::Warning, format!("Sensore {} non disponibile, escluso dalla sessione: {}", name, problem))
+        };
+        self.emit(Event::new(
+            severity,
+            "sensor_availability",
+            message.clone(),
+            json!({ "sensor": key, "required": required, "available": false, "reason": problem }),
+        ));
+        if required {
+            return Err(message);
+        }
+        self.availability.drop_sensor(key);
+        self.last_good.clear(key);
+        match key {
+            "battery" => self.battery = None,
+            "ms5611_secondary" => {
+                self.config.voting = None;
+                self.voter = None;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Mid-run counterpart of `check_sensors`: records why the service has to stop.
+    fn sensor_lost(&mut self, key: &'static str, name: &str, required: bool, problem: &str) {
+        if let Err(e) = self.sensor_unavailable(key, name, required, problem) {
+            self.fatal = Some(e);
+        }
+    }
+
+    /// Starts the thread that reports, and per `[watchdog]` aborts, a
+    /// sampling loop that has stopped completing cycles.
+    pub fn start_watchdog(&self) {
+        if !self.config.watchdog.enabled {
+            return;
+        }
+        let events = (!self.dry_run).then(|| self.pipeline.event_sender());
+        let heartbeat = Arc::clone(&self.heartbeat);
+        watchdog::spawn(heartbeat, self.config.watchdog.clone(), events, self.session.id.clone());
+    }
+
+    /// Reopens the I2C buses on behalf of the watchdog, once the loop has
+    /// come back from whatever kept it stuck.
+    fn reinit_buses(&mut self) {
+        self.buses.reopen();
+        let reset = self
+            .buses
+            .get(self.config.ms5611.bus)
+            .and_then(|bus| ms5611::reset(bus, &self.config.ms5611).map_err(|e| e.to_string()));
+        let secondary_reset = self.config.voting.as_ref().map(|voting| {
+            let bus = self.buses.get(voting.secondary.bus);
+            bus.and_then(|bus| ms5611::reset(bus, &voting.secondary).map_err(|e| e.to_string())).err()
+        });
+        let failures: Vec<_> = self.buses.failures().values().cloned().collect();
+        let mut payload = json!({ "ms5611_reset": reset.err(), "failed_buses": failures });
+        if let Some(secondary_reset) = secondary_reset {
+            payload["ms5611_secondary_reset"] = json!(secondary_reset);
+        }
+        self.emit(Event::new(
+            Severity::Warning,
+            "watchdog",
+            "Bus I2C riaperti su richiesta del watchdog".to_string(),
+            payload,
+        ));
+        self.restart_warmup("watchdog");
+    }
+
+    /// Starts the `[warmup]` over once sensors have been set up anew; the
+    /// vertical speed is rebuilt from the samples after it.
+    fn restart_warmup(&mut self, reason: &'static str) {
+        if self.config.warmup.is_enabled() {
+            self.warmup.restart(self.clock.now(), reason);
+            self.flight.reset_rate();
+        }
+    }
+
+    /// Saves the transactions in `recording` to `path` at shutdown.
+    pub fn record_transcript(&mut self, recording: Recording, path: PathBuf) {
+        self.transcript = Some((recording, path));
+    }
+
+    /// Why the service has to stop, once a required sensor is lost.
+    pub fn fatal(&self) -> Option<&str> {
+        self.fatal.as_deref()
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    /// Writes the header record and prints the same information as a banner.
+    fn write_header(&mut self) {
+        let prom = self.buses.get(self.config.ms5611.bus).ok().and_then(|bus| {
+            ms5611::read_prom(bus, &self.config.ms5611).map_err(|e| println!("PROM MS5611 non letta: {}", e)).ok()
+        });
+        let ds18b20_ids = ds18b20::scan().ok().map(|ids| ids.into_iter().collect::<Vec<_>>());
+        let header = HeaderRecord {
+            kind: HeaderRecord::KIND.to_string(),
+            timestamp: self.clock.utc(),
+            session_id: self.session.id.clone(),
+            boot_id: self.boot_id.clone(),
+            schema_version: SCHEMA_VERSION,
+            software_version: env!("CARGO_PKG_VERSION").to_string(),
+            ms5611_prom: prom,
+            ms5611_secondary_prom: self.config.voting.as_ref().and_then(|voting| {
+                let bus = self.buses.get(voting.secondary.bus).ok()?;
+                let prom = ms5611::read_prom(bus, &voting.secondary);
+                prom.map_err(|e| println!("PROM MS5611 secondario non letta: {}", e)).ok()
+            }),
+            ds18b20_ids,
+            ds18b20_read_mode: Some(self.ds18b20_read_mode()),
+            config: self.config.snapshot(),
+            units: self.record_units.clone(),
+        };
+
+        let config = &self.config;
+        println!(
+            "sensor-program {} avviato, sessione {} ({:?}), boot_id {}, schema dati {}",
+            header.software_version, header.session_id, self.session.origin, header.boot_id, header.schema_version
+        );
+        println!(
+            "  campionamento ogni {} s, uscita {} ({:?}{})",
+            config.sampling.interval_secs,
+            config.output.path_for(&self.session.id),
+            config.output.layout,
+            if config.output.raw { ", raw" } else { "" }
+        );
+        match &header.ms5611_prom {
+            Some(prom) => println!(
+                "  MS5611 bus {} 0x{:02X}, PROM {:04X?}",
+                config.ms5611.bus, config.ms5611.address, prom
+            ),
+            None => println!("  MS5611 bus {} 0x{:02X}, PROM non letta", config.ms5611.bus, config.ms5611.address),
+        }
+        if let Some(voting) = &config.voting {
+            let secondary = &voting.secondary;
+            println!(
+                "  MS5611 secondario bus {} 0x{:02X}, PROM {}, soglia di disaccordo {} hPa",
+                secondary.bus,
+                secondary.address,
+                if header.ms5611_secondary_prom.is_some() { "letta" } else { "non letta" },
+                voting.threshold_hpa
+            );
+        }
+        let found = match &header.ds18b20_ids {
+            Some(ids) if ids.is_empty() => "nessuno".to_string(),
+            Some(ids) => ids.join(", "),
+            None => "bus 1-Wire non disponibile".to_string(),
+        };
+        let read_mode = match &self.ds18b20_bulk {
+            Some(bulk) => format!("separata, conversione di {} ms", bulk.conversion_time().as_millis()),
+            None => "bloccante".to_string(),
+        };
+        println!(
+            "  DS18B20 configurati {} e {}, presenti: {}, lettura {}",
+            config.ds18b20.sensor_1, config.ds18b20.sensor_2, found, read_mode
+        );
+        let calibration = &config.calibration;
+        println!(
+            "  calibrazione: MS5611 {:+} °C, {:+} hPa, DS18B20 {:+} °C e {:+} °C",
+            calibration.ms5611_temperature, calibration.ms5611_pressure, calibration.ds18b20_1, calibration.ds18b20_2
+        );
+        if let Some(dir) = self.session.dir.as_ref().filter(|_| !self.dry_run) {
+            let path = dir.join(format!("{}{}.json", HEADER_PREFIX, self.boot_id));
+            let result = serde_json::to_string_pretty(&header).map_err(|e| e.to_string());
+            if let Err(e) = result.and_then(|json| std::fs::write(&path, json).map_err(|e| e.to_string())) {
+                println!("Copia dell'intestazione non scritta in {}: {}", path.display(), e);
+            }
+        }
+        self.write(&header);
+    }
+
+    pub fn shutdown(&mut self, reason: &str) {
+        if self.clock_guard.held() > 0 {
+            self.release_held(self.clock.now(), None);
+        }
+        self.emit(Event::new(
+            Severity::Info,
+            "shutdown",
+            format!("Arresto ({})", reason),
+            json!({ "reason": reason, "records": self.sequence }),
+        ));
+        self.touch_session();
+        self.pipeline.shutdown();
+        if let Some((recording, path)) = self.transcript.take() {
+            let cycles = self.heartbeat.cycles();
+            match recording.save(&path, cycles) {
+                Ok(steps) => {
+                    println!("Trascrizione dei bus in {}: {} cicli, {} transazioni", path.display(), cycles, steps)
+                }
+                Err(e) => println!("Trascrizione dei bus non salvata: {}", e),
+            }
+        }
+    }
+
+    fn touch_session(&self) {
+        if !self.dry_run {
+            session::touch(&self.config.session, &self.session.id, self.session.dir.as_deref(), self.clock.utc());
+        }
+    }
+
+    /// Reloads from `path` with the environment and the `--set` overrides
+    /// applied again on top.
+    pub fn reload_config(&mut self, path: &Path, overrides: &[Override]) {
+        self.heartbeat.enter(Stage::Reloading);
+        // A failure is logged as an event.
+        let _ = self.reload(path, overrides);
+        self.heartbeat.enter(Stage::Idle);
+    }
+
+    /// The keys left as they were because they need a restart.
+    fn reload(&mut self, path: &Path, overrides: &[Override]) -> Result<Vec<&'static str>, String> {
+        let mut new_config = match Config::load_layers(path, overrides) {
+            Ok(layers) => layers.config,
+            Err(e) => {
+                self.emit(Event::new(
+                    Severity::Error,
+                    "config",
+                    format!("Ricaricamento configurazione fallito, resta attiva la precedente: {}", e),
+                    json!({ "path": path.display().to_string() }),
+                ));
+                return Err(e.to_string());
+            }
+        };
+
+        if let Some(dir) = &self.session.dir {
+            flight_dir::relocate(&mut new_config, dir);
+        }
+        let restart_required = self.config.apply_reload(new_config);
+        if let (Some(builder), Some(telemetry_config)) = (self.telemetry.as_mut(), &self.config.telemetry) {
+            builder.set_config(telemetry_config.clone());
+        }
+        self.flight.set_config(self.config.flight.clone());
+        self.ring.set_config(self.config.ring_buffer.clone());
+        self.burst.set_config(self.config.burst.clone());
+        self.setpoints.set_config(self.config.setpoints.clone());
+        self.record_units = units::record_units(&self.config.exec);
+        let measurements: Vec<String> = self
+            .config
+            .exec
+            .iter()
+            .flat_map(|sensor| sensor.measurements())
+            .map(|measurement| format!("exec_{}", measurement))
+            .collect();
+        self.last_good.retain(|key| !key.starts_with("exec_") || measurements.iter().any(|kept| kept == key));
+        if let (Some(voter), Some(voting)) = (self.voter.as_mut(), &self.config.voting) {
+            voter.set_config(voting.clone());
+        }
+        self.repeats.set_window(Duration::from_secs(self.config.events.repeat_window_secs));
+        self.probes.force_rescan();
+        self.battery = match (self.battery.take(), &self.config.battery) {
+            (Some(mut monitor), Some(battery_config)) => {
+                monitor.set_config(battery_config.clone());
+                Some(monitor)
+            }
+            (None, Some(battery_config)) => {
+                self.buses.ensure(battery_config.bus);
+                if let Err(e) = self.buses.get(battery_config.bus) {
+                    println!("Attenzione: battery disattivato: {}", e);
+                }
+                Some(BatteryMonitor::new(battery_config.clone()))
+            }
+            (_, None) => None,
+        };
+        self.deadband = match (self.deadband.take(), &self.config.deadband) {
+            (Some(mut deadband), Some(deadband_config)) => {
+                deadband.set_config(deadband_config.clone());
+                Some(deadband)
+            }
+            (None, Some(deadband_config)) => Some(Deadband::new(deadband_config.clone())),
+            (_, None) => None,
+        };
+        let mut message = format!("Configurazione ricaricata da {}", path.display());
+        for key in &restart_required {
+            message.push_str(&format!("; modifica a {} ignorata: richiede il riavvio", key));
+        }
+        let severity = if restart_required.is_empty() { Severity::Info } else { Severity::Warning };
+        self.emit(Event::new(
+            severity,
+            "config",
+            message,
+            json!({ "path": path.display().to_string(), "restart_required": &restart_required }),
+        ));
+        Ok(restart_required)
+    }
+
+    fn write(&mut self, record: &impl Serialize) {
+        let output = match self.serialize(record) {
+            Ok(output) => output,
+            Err(e) => {
+                println!("Errore serializzazione JSON: {}", e);
+                return;
+            }
+        };
+        self.ring.push(output.text().to_string());
+        self.pipeline.send(output);
+    }
+
+    fn serialize(&self, record: &impl Serialize) -> serde_json::Result<Output> {
+        let value = serde_json::to_value(record)?;
+        let is_data = value.get("type").is_none();
+        let plain = self.encode(value, || serde_json::to_string(record))?;
+        if !is_data || !self.config.sinks.iter().any(SinkConfig::fills) {
+            return Ok(plain);
+        }
+        // From the text, so an f32 keeps the digits the record shows.
+        let value = serde_json::from_str(&serde_json::to_string(record)?)?;
+        let value = self.last_good.fill(value, &self.config.last_good);
+        let filled = self.encode(value.clone(), || serde_json::to_string(&value))?;
+        Ok(Output::Filled { plain: Box::new(plain), filled: Box::new(filled) })
+    }
+
+    /// `value`, the record as JSON, with `[output]` and `[mapping]` applied;
+    /// `text` keeps the order of the fields in the nested layout.
+    fn encode(&self, value: Value, text: impl FnOnce() -> serde_json::Result<String>) -> serde_json::Result<Output> {
+        let layout = self.config.output.layout;
+        let compact = &self.config.output.compact;
+        let value = compact.apply(value, &self.record_units);
+        let raw = match layout {
+            RecordLayout::Nested if compact.enabled => serde_json::to_string(&value)?,
+            RecordLayout::Nested => text()?,
+            RecordLayout::Flat => serde_json::to_string(&layout.apply(value.clone()))?,
+        };
+        if self.config.mapping.is_empty() || value.get("type").is_some() {
+            return Ok(Output::Record(Arc::from(raw)));
+        }
+        let mapped = serde_json::to_string(&self.config.mapping.apply(value, layout))?;
+        Ok(Output::Mapped { record: Arc::from(mapped), raw: Arc::from(raw) })
+    }
+
+    /// The number of records saved.
+    pub fn dump_ring_buffer(&mut self, trigger: &str) -> usize {
+        if self.dry_run {
+            println!("[dry-run] Salvataggio del buffer circolare ({}) saltato", trigger);
+            return 0;
+        }
+        let records = self.ring.dump();
+        self.emit(Event::new(
+            Severity::Info,
+            "ring_buffer",
+            format!("Salvataggio del buffer circolare richiesto ({}, {} record)", trigger, records),
+            json!({ "trigger": trigger, "records": records }),
+        ));
+        records
+    }
+
+    /// Binds `[control]`'s socket; a failure is logged and the service
+    /// goes on without it.
+    pub fn open_control(&mut self) -> Option<ControlServer> {
+        if !self.config.control.is_enabled() {
+            return None;
+        }
+        let socket = self.config.control.socket.clone();
+        match control::listen(&self.config.control) {
+            Ok(server) => {
+                println!("Socket di controllo in ascolto su {}", socket);
+                Some(server)
+            }
+            Err(e) => {
+                self.emit(Event::new(
+                    Severity::Error,
+                    "control",
+                    format!("Socket di controllo non disponibile: {}", e),
+                    json!({ "socket": socket, "error": e }),
+                ));
+                None
+            }
+        }
+    }
+
+    /// Runs a command from the control socket, answers it and logs it with
+    /// its outcome; true when it asks the service to stop.
+    pub fn control(&mut self, request: Request, config_path: &Path, overrides: &[Override]) -> bool {
+        let command = request.command;
+        let now = self.clock.now();
+        let result = match command {
+            ControlCommand::Health => Ok(self.health(now)),
+            ControlCommand::Dump if self.dry_run => Err("dry-run: nessun salvataggio".to_string()),
+            ControlCommand::Dump => Ok(json!({ "records": self.dump_ring_buffer("ctl") })),
+            ControlCommand::Rotate if self.dry_run => Err("dry-run: nessun file aperto".to_string()),
+            ControlCommand::Rotate => {
+                self.pipeline.reopen();
+                self.write_header();
+                Ok(json!({ "path": self.config.output.path_for(&self.session.id) }))
+            }
+            ControlCommand::Burst if !self.config.burst.enabled => {
+                Err("modalità burst disattivata (burst.enabled)".to_string())
+            }
+            ControlCommand::Burst => {
+                self.start_burst("ctl", json!({ "trigger": "ctl" }));
+                Ok(json!({ "duration_secs": self.config.burst.duration_secs }))
+            }
+            ControlCommand::Rescan if !self.w1_ready(now) => Err("sottosistema 1-Wire non disponibile".to_string()),
+            ControlCommand::Rescan => {
+                self.rescan_probes(now);
+                let configured = [&self.config.ds18b20.sensor_1, &self.config.ds18b20.sensor_2];
+                Ok(json!({ "extra": self.probes.extras(&configured.map(String::as_str)) }))
+            }
+            ControlCommand::Reload => {
+                self.heartbeat.enter(Stage::Reloading);
+                let result = self.reload(config_path, overrides);
+                self.heartbeat.enter(Stage::Idle);
+                result.map(|restart_required| json!({ "restart_required": restart_required }))
+            }
+            ControlCommand::Stop => Ok(json!({})),
+        };
+        let (severity, message) = match &result {
+            Ok(_) => (Severity::Info, format!("Comando {} dal socket di controllo eseguito", command.name())),
+            Err(e) => {
+                (Severity::Warning, format!("Comando {} dal socket di controllo non riuscito: {}", command.name(), e))
+            }
+        };
+        self.emit(Event::new(
+            severity,
+            "control",
+            message,
+            json!({ "command": command.name(), "ok": result.is_ok(), "error": result.as_ref().err() }),
+        ));
+        request.reply(result);
+        command == ControlCommand::Stop
+    }
+
+    /// The answer to `health`.
+    fn health(&self, now: Instant) -> serde_json::Value {
+        json!({
+            "session_id": self.session.id,
+            "boot_id": self.boot_id,
+            "uptime_secs": now.duration_since(self.started).as_secs(),
+            "records": self.sequence,
+            "interval_secs": self.next_interval().as_secs_f64(),
+            "flight_state": self.flight.state(),
+            "burst_mode": self.burst.is_active(),
+            "waiting_for_bus": self.bus_wait.as_ref().map(BusWait::bus),
+            "clock_invalid": self.clock_guard.is_invalid(),
+            "dropped_sensors": self.availability.dropped(),
+            "fatal": self.fatal,
+            "sinks": self.pipeline.stats(),
+        })
+    }
+
+    pub fn emit(&mut self, mut event: Event) {
+        event.session_id = self.session.id.clone();
+        event.timestamp = self.clock.utc();
+        println!("[{:?}] {}", event.severity, event.message);
+        if self.dry_run {
+            return;
+        }
+        match serde_json::to_string(&event) {
+            Ok(json_data) => self.pipeline.send(Output::Event(Arc::from(json_data))),
+            Err(e) => println!("Errore serializzazione evento: {}", e),
+        }
+        if self.config.events.inline {
+            self.write(&event);
+        }
+    }
+
+    pub fn next_interval(&self) -> Duration {
+        let multiplier = self.battery.as_ref().map_or(1, BatteryMonitor::multiplier);
+        self.burst.interval(Duration::from_secs(self.config.sampling.interval_secs) * multiplier)
+    }
+
+    /// When the cycle after the one `scheduled` for should start: one
+    /// interval later on a fixed rate, so the period does not drift with the
+    /// time each cycle takes. After an overrun the missed ticks are dropped
+    /// rather than sampled back to back, and the next cycle starts at once.
+    pub fn next_tick(&self, scheduled: Instant) -> Instant {
+        let interval = self.next_interval();
+        let next = scheduled + interval;
+        let now = self.clock.now();
+        if next >= now || interval.is_zero() {
+            return next;
+        }
+        let missed = now.duration_since(next).as_nanos() / interval.as_nanos();
+        next + interval * u32::try_from(missed).unwrap_or(u32::MAX)
+    }
+
+    fn update_battery(&mut self) {
+        let Some(monitor) = self.battery.as_mut() else {
+            return;
+        };
+        let reading = self
+            .buses
+            .get(monitor.config().bus)
+            .and_then(|bus| battery::read_pack_voltage(bus, monitor.config()).map_err(|e| e.to_string()));
+        let change = match reading {
+            Ok(pack_voltage) => {
+                self.availability.succeeded("battery");
+                let change = monitor.update(pack_voltage);
+                self.sensor_ok("INA219");
+                change
+            }
+            Err(e) => {
+                let required = monitor.config().required;
+                self.sensor_error("INA219", &e);
+                if self.availability.failed("battery") {
+                    let problem = format!("{} letture consecutive fallite, l'ultima: {}", LOST_AFTER_FAILURES, e);
+                    self.sensor_lost("battery", "INA219", required, &problem);
+                }
+                return;
+            }
+        };
+        if let Some(change) = change {
+            let normal = self.config.sampling.interval_secs;
+            let slower = change.to_multiplier > change.from_multiplier;
+            let severity = if slower { Severity::Warning } else { Severity::Info };
+            self.emit(Event::new(
+                severity,
+                "battery",
+                format!(
+                    "Batteria a {:.2} V: intervallo di campionamento da {} s a {} s",
+                    change.voltage,
+                    normal * change.from_multiplier as u64,
+                    normal * change.to_multiplier as u64
+                ),
+                json!({
+                    "voltage": change.voltage,
+                    "from_multiplier": change.from_multiplier,
+                    "to_multiplier": change.to_multiplier,
+                    "interval_secs": normal * change.to_multiplier as u64,
+                }),
+            ));
+        }
+    }
+
+    pub fn signal_burst(&mut self, trigger: &str) {
+        if self.burst.accepts_signal() {
+            self.start_burst(trigger, json!({ "trigger": trigger }));
+        }
+    }
+
+    pub fn signal_actions(&mut self, trigger: &str) {
+        let actuations = self.actions.on_signal(self.clock.now());
+        for actuation in actuations {
+            self.log_actuation(actuation, json!({ "signal": trigger }));
+        }
+    }
+
+    /// Every trigger is logged, including the ones that did not drive the pin.
+    fn log_actuation(&mut self, actuation: Actuation, values: serde_json::Value) {
+        let (severity, outcome, detail) = match &actuation.outcome {
+            Outcome::Fired => (Severity::Warning, "fired", format!("impulso n. {}", actuation.activation)),
+            Outcome::Disarmed => (Severity::Info, "disarmed", "azione non armata, nessun impulso".to_string()),
+            Outcome::DryRun => (Severity::Info, "dry_run", "dry-run, nessun impulso".to_string()),
+            Outcome::Exhausted => (Severity::Info, "exhausted", "numero massimo di attivazioni raggiunto".to_string()),
+            Outcome::Rearming { remaining_secs } => {
+                (Severity::Info, "rearming", format!("in riarmo, ancora {} s", remaining_secs))
+            }
+            Outcome::Failed(e) => (Severity::Error, "failed", format!("GPIO non disponibile: {}", e)),
+        };
+        self.emit(Event::new(
+            severity,
+            "action",
+            format!("Azione {} (GPIO {}) su {}: {}", actuation.name, actuation.pin, actuation.trigger, detail),
+            json!({
+                "name": actuation.name,
+                "pin": actuation.pin,
+                "trigger": actuation.trigger,
+                "outcome": outcome,
+                "activation": actuation.activation,
+                "values": values,
+            }),
+        ));
+    }
+
+    fn start_burst(&mut self, trigger: &str, payload: serde_json::Value) {
+        if self.burst.trigger(self.clock.now()) {
+            self.emit(Event::new(
+                Severity::Info,
+                "burst_mode",
+                format!(
+                    "Modalità burst attivata ({}), intervallo MS5611 {} ms",
+                    trigger, self.config.burst.fast_interval_ms
+                ),
+                payload,
+            ));
+        }
+    }
+
+    fn log_transition(&mut self, transition: &Transition) {
+        let event = Event::new(
+            Severity::Info,
+            "flight_state",
+            format!("Transizione {:?} -> {:?}", transition.from, transition.to),
+            json!({
+                "from": transition.from,
+                "to": transition.to,
+                "altitude_m": transition.altitude,
+                "vertical_speed_ms": transition.vertical_speed,
+            }),
+        );
+        self.emit(event);
+    }
+
+    fn log_crossing(
+        &mut self,
+        crossing: &Crossing,
+        timestamp: chrono::DateTime<chrono::Utc>,
+        values: serde_json::Value,
+    ) {
+        let direction = match crossing.direction {
+            Direction::Descending => "in discesa",
+            _ => "in salita",
+        };
+        let mut payload = json!({
+            "setpoint": crossing.name,
+            "setpoint_altitude_m": crossing.altitude_m,
+            "direction": crossing.direction,
+            "timestamp": timestamp,
+        });
+        if let (Some(payload), serde_json::Value::Object(values)) = (payload.as_object_mut(), values) {
+            payload.extend(values);
+        }
+        self.emit(Event::new(
+            Severity::Info,
+            "setpoint",
+            format!("Quota {} ({} m) superata {}", crossing.name, crossing.altitude_m, direction),
+            payload,
+        ));
+    }
+
+    /// Compares the two MS5611 readings and returns the one selected for the
+    /// record, or `None` when neither can be used.
+    fn vote(
+        &mut self,
+        primary: Result<MS5611Data, String>,
+        secondary: Result<MS5611Data, String>,
+    ) -> Option<(MS5611Data, VotingData)> {
+        for (source, result) in [(PressureSource::Ms5611, &primary), (PressureSource::Ms5611Secondary, &secondary)] {
+            match result {
+                Ok(_) => self.sensor_ok(source.sensor_name()),
+                Err(e) => self.sensor_error(source.sensor_name(), e),
+            }
+        }
+        let (primary, secondary) = (primary.ok(), secondary.ok());
+        let voter = self.voter.as_mut()?;
+        let vote = voter.vote(primary.as_ref(), secondary.as_ref(), &self.stuck.suspect());
+        for change in vote.changes {
+            self.log_vote(change, primary.as_ref(), secondary.as_ref());
+        }
+        let Some(source) = vote.source else {
+            // Failed reads were reported above; what is left is out of range.
+            if primary.is_some() || secondary.is_some() {
+                self.sensor_error("MS5611", &"nessuna pressione nell'intervallo dello strumento");
+            }
+            return None;
+        };
+        let selected = match source {
+            PressureSource::Ms5611 => primary.clone(),
+            PressureSource::Ms5611Secondary => secondary.clone(),
+        }?;
+        let voting = VotingData {
+            source,
+            ms5611: primary,
+            ms5611_secondary: secondary,
+            difference_hpa: vote.difference_hpa,
+            disagreement: vote.disagreement,
+        };
+        Some((selected, voting))
+    }
+
+    fn log_vote(&mut self, change: Change, primary: Option<&MS5611Data>, secondary: Option<&MS5611Data>) {
+        let pressures = json!({
+            "ms5611_pressure": primary.map(|data| data.pressure),
+            "ms5611_secondary_pressure": secondary.map(|data| data.pressure),
+        });
+        let (severity, message, mut payload) = match change {
+            Change::Failover { from, to, reason } => (
+                Severity::Warning,
+                format!("Pressione: passaggio da {} a {} ({})", from.sensor_name(), to.sensor_name(), reason),
+                json!({ "change": "failover", "from": from, "to": to, "reason": reason }),
+            ),
+            Change::Recovered { from, to } => (
+                Severity::Info,
+                format!("Pressione: di nuovo da {} invece di {}", to.sensor_name(), from.sensor_name()),
+                json!({ "change": "recovered", "from": from, "to": to }),
+            ),
+            Change::Disagreement { difference_hpa, samples } => (
+                Severity::Warning,
+                format!(
+                    "MS5611 in disaccordo: {:.2} hPa di differenza per {} campioni (soglia {} hPa)",
+                    difference_hpa,
+                    samples,
+                    self.config.voting.as_ref().map_or(0.0, |voting| voting.threshold_hpa)
+                ),
+                json!({ "change": "disagreement", "difference_hpa": difference_hpa, "samples": samples }),
+            ),
+            Change::Agreement { difference_hpa } => (
+                Severity::Info,
+                "MS5611 di nuovo concordi".to_string(),
+                json!({ "change": "agreement", "difference_hpa": difference_hpa }),
+            ),
+        };
+        if let (Some(payload), serde_json::Value::Object(pressures)) = (payload.as_object_mut(), pressures) {
+            payload.extend(pressures);
+        }
+        self.emit(Event::new(severity, "voting", message, payload));
+    }
+
+    /// Logs a failed read, unless it repeats the last error of the sensor;
+    /// see `RepeatFilter`.
+    fn sensor_error(&mut self, sensor: &str, error: &dyn std::fmt::Display) {
+        let error = error.to_string();
+        #[cfg(feature = "tui")]
+        self.update_live(|live| live.sensor_error(sensor, &error));
+        let (verdict, previous) = self.repeats.error(sensor, &error, self.clock.now());
+        if let Some(previous) = previous {
+            self.log_repeated(previous);
+        }
+        match verdict {
+            Verdict::Log => self.emit(Event::new(
+                Severity::Error,
+                "sensor",
+                format!("Errore {}: {}", sensor, error),
+                json!({ "sensor": sensor, "error": error }),
+            )),
+            Verdict::Suppress => {}
+            Verdict::Summarize(repeated) => self.log_repeated(repeated),
+        }
+    }
+
+    fn sensor_ok(&mut self, sensor: &str) {
+        self.heartbeat.sensor_ok(sensor);
+        #[cfg(feature = "tui")]
+        self.update_live(|live| live.sensor_ok(sensor));
+        if let Some(repeated) = self.repeats.cleared(sensor, self.clock.now()) {
+            self.log_repeated(repeated);
+        }
+    }
+
+    fn log_repeated(&mut self, repeated: Repeated) {
+        let severity = if repeated.cleared { Severity::Info } else { Severity::Error };
+        self.emit(Event::new(
+            severity,
+            "sensor",
+            repeated.describe(),
+            json!({
+                "sensor": repeated.sensor,
+                "error": repeated.error,
+                "repeated": repeated.count,
+                "over_secs": repeated.over.as_secs(),
+                "cleared": repeated.cleared,
+            }),
+        ));
+    }
+
+    fn write_status_if_due(&mut self, now: Instant) {
+        if now.duration_since(self.last_status) < Duration::from_secs(self.config.status.interval_secs) {
+            return;
+        }
+        self.last_status = now;
+        let status = StatusRecord {
+            kind: "status",
+            timestamp: self.clock.utc(),
+            session_id: self.session.id.clone(),
+            boot_id: self.boot_id.clone(),
+            uptime_secs: now.duration_since(self.started).as_secs(),
+            records: self.sequence,
+            interval_secs: self.next_interval().as_secs_f64(),
+            battery_v: self.battery.as_ref().and_then(BatteryMonitor::voltage),
+            suppressed: self.deadband.as_ref().map(Deadband::suppressed),
+            timing: self.timing_stats.summary(),
+            sinks: self.pipeline.stats(),
+        };
+        self.write(&status);
+        self.touch_session();
+    }
+
+    /// Reports a missing 1-Wire subsystem once instead of failing every
+    /// probe read each cycle; the MS5611 is read regardless.
+    fn w1_ready(&mut self, now: Instant) -> bool {
+        match self.probes.check_subsystem(now) {
+            Subsystem::Ready => true,
+            Subsystem::Appeared => {
+                if self.config.ds18b20.read_mode == ReadMode::Split && self.ds18b20_bulk.is_none() {
+                    self.ds18b20_bulk = BulkConversion::detect().ok();
+                }
+                self.emit(Event::new(
+                    Severity::Info,
+                    "ds18b20",
+                    "Sottosistema 1-Wire disponibile, letture DS18B20 riprese".to_string(),
+                    json!({ "w1_available": true, "read_mode": self.ds18b20_read_mode() }),
+                ));
+                self.restart_warmup("w1_subsystem");
+                true
+            }
+            Subsystem::Missing(reason) => {
+                self.emit(Event::new(
+                    Severity::Error,
+                    "ds18b20",
+                    format!(
+                        "Sottosistema 1-Wire non disponibile ({}). Aggiungere dtoverlay=w1-gpio a /boot/config.txt \
+                         (o /boot/firmware/config.txt), riavviare e verificare che i moduli w1-gpio e w1-therm \
+                         siano caricati (lsmod, modprobe w1-gpio w1-therm). DS18B20 sospesi, nuovo controllo \
+                         ogni {} s; l'MS5611 continua a funzionare",
+                        reason,
+                        ds18b20::SUBSYSTEM_POLL.as_secs()
+                    ),
+                    json!({ "w1_available": false, "reason": reason }),
+                ));
+                false
+            }
+            Subsystem::StillMissing => false,
+        }
+    }
+
+    fn rescan_probes(&mut self, now: Instant) {
+        let configured = [self.config.ds18b20.sensor_1.clone(), self.config.ds18b20.sensor_2.clone()];
+        let enabled: Vec<_> = self
+            .config
+            .ds18b20
+            .probes()
+            .into_iter()
+            .filter(|probe| probe.enabled)
+            .map(|probe| (probe.id.to_string(), probe.field, probe.name, probe.required))
+            .collect();
+        let changes = match self.probes.rescan(now, &[&configured[0], &configured[1]]) {
+            Ok(changes) => changes,
+            Err(e) => {
+                println!("Scansione del bus 1-Wire fallita: {}", e);
+                return;
+            }
+        };
+        for id in changes.appeared {
+            let configured = configured.contains(&id);
+            if configured && !enabled.iter().any(|(enabled_id, ..)| *enabled_id == id) {
+                continue;
+            }
+            if let Some((_, field, ..)) = enabled.iter().find(|(enabled_id, ..)| *enabled_id == id) {
+                self.availability.restore(field);
+                self.restart_warmup("ds18b20");
+            }
+            self.emit(Event::new(
+                Severity::Info,
+                "ds18b20",
+                format!("Sensore DS18B20 {} rilevato sul bus", id),
+                json!({ "id": id, "present": true, "configured": configured }),
+            ));
+        }
+        for id in changes.disappeared {
+            let configured = configured.contains(&id);
+            if let Some(&(_, field, name, required)) = enabled.iter().find(|(enabled_id, ..)| *enabled_id == id) {
+                if !self.availability.is_dropped(field) {
+                    self.sensor_lost(field, name, required, &format!("{} assente dal bus", id));
+                }
+                continue;
+            }
+            if configured {
+                continue;
+            }
+            self.last_good.clear(&format!("ds18b20_extra_{}", id));
+            self.emit(Event::new(
+                Severity::Warning,
+                "ds18b20",
+                format!("Sensore DS18B20 {} assente dal bus, lettura sospesa", id),
+                json!({ "id": id, "present": false, "configured": configured }),
+            ));
+        }
+    }
+
+    fn update_time_sync(&mut self, now: Instant) {
+        let Some(previous) = self.time_sync.poll(now, &self.config.time) else {
+            return;
+        };
+        let status = self.time_sync.status();
+        let (severity, message) = match status.synced {
+            Some(true) => (Severity::Info, "Orologio di sistema sincronizzato (NTP)"),
+            Some(false) => (Severity::Warning, "Orologio di sistema non sincronizzato: timestamp non affidabili"),
+            None => (Severity::Warning, "Stato di sincronizzazione dell'orologio non determinabile"),
+        };
+        self.emit(Event::new(
+            severity,
+            "time",
+            message.to_string(),
+            json!({
+                "time_synced": status.synced,
+                "previous": previous,
+                "offset_ms": status.offset_ms,
+                "max_error_ms": status.max_error_ms,
+            }),
+        ));
+    }
+
+    /// Scheduling and rates use monotonic time; this only detects the wall
+    /// clock jumping relative to it between two samples.
+    fn check_clock_step(&mut self, now: Instant, timestamp: chrono::DateTime<chrono::Utc>) {
+        let Some((last_now, last_timestamp)) = self.last_clock.replace((now, timestamp)) else {
+            return;
+        };
+        let monotonic_secs = now.duration_since(last_now).as_secs_f64();
+        let wall_secs = (timestamp - last_timestamp).num_microseconds().unwrap_or(i64::MAX) as f64 / 1e6;
+        let step_secs = wall_secs - monotonic_secs;
+        if step_secs.abs() < self.config.time.step_threshold_secs {
+            return;
+        }
+        self.flight.reset_rate();
+        self.emit(Event::new(
+            Severity::Warning,
+            "clock_step",
+            format!(
+                "Salto dell'orologio di sistema di {:+.1} s tra due campioni; velocità verticale azzerata",
+                step_secs
+            ),
+            json!({
+                "step_secs": step_secs,
+                "wall_secs": wall_secs,
+                "monotonic_secs": monotonic_secs,
+                "previous_timestamp": last_timestamp,
+            }),
+        ));
+    }
+
+    /// A wall clock before `time.floor` has not been set yet; the records
+    /// held meanwhile are written once it is, or can no longer wait.
+    fn check_clock_floor(&mut self, now: Instant, timestamp: chrono::DateTime<chrono::Utc>) {
+        let policy = self.config.time.invalid_clock;
+        if let Some(change) = self.clock_guard.check(&self.config.time, timestamp) {
+            let values = json!({
+                "invalid": change.invalid,
+                "timestamp": timestamp,
+                "floor": change.floor,
+                "floor_source": change.source,
+                "policy": policy,
+            });
+            let event = if change.invalid {
+                let meanwhile = match policy {
+                    InvalidClock::Hold => {
+                        format!("record trattenuti in memoria (al massimo {})", self.config.time.max_held)
+                    }
+                    _ => "record marcati clock_invalid".to_string(),
+                };
+                let message = format!(
+                    "Orologio di sistema non impostato: {} è prima di {} ({}); {}",
+                    timestamp,
+                    change.floor,
+                    change.source.describe(),
+                    meanwhile
+                );
+                Event::new(Severity::Warning, "clock_invalid", message, values)
+            } else {
+                let message = format!("Orologio di sistema impostato: {}", timestamp);
+                Event::new(Severity::Info, "clock_invalid", message, values)
+            };
+            self.emit(event);
+        }
+        if self.clock_guard.held() > 0 && !(self.clock_guard.is_invalid() && policy == InvalidClock::Hold) {
+            let timestamp = (!self.clock_guard.is_invalid()).then_some(timestamp);
+            self.release_held(now, timestamp);
+        }
+    }
+
+    /// Writes the held records, timestamped back from `timestamp` at `now`
+    /// or, without it, tagged.
+    fn release_held(&mut self, now: Instant, timestamp: Option<chrono::DateTime<chrono::Utc>>) {
+        let Released { records, spilled, corrected } = self.clock_guard.release(now, timestamp);
+        for record in &records {
+            self.write(record);
+        }
+        let message = match corrected {
+            Some((first, last)) => format!(
+                "{} record trattenuti scritti con l'ora ricostruita dall'orologio monotono ({} -> {})",
+                records.len(),
+                first,
+                last
+            ),
+            None => format!("{} record trattenuti scritti con clock_invalid: orologio non impostato", records.len()),
+        };
+        self.emit(Event::new(
+            Severity::Info,
+            "clock_correction",
+            message,
+            json!({
+                "records": records.len(),
+                "spilled": spilled,
+                "corrected": corrected.is_some(),
+                "first_timestamp": corrected.map(|(first, _)| first),
+                "last_timestamp": corrected.map(|(_, last)| last),
+            }),
+        ));
+    }
+
+    /// A data record, held back while the wall clock is not set with
+    /// `time.invalid_clock = "hold"`.
+    fn write_data(&mut self, now: Instant, record: &SensorData) {
+        if !self.clock_guard.is_invalid() || self.config.time.invalid_clock != InvalidClock::Hold {
+            return self.write(record);
+        }
+        match serde_json::to_value(record) {
+            Ok(value) => {
+                if let Some(spilled) = self.clock_guard.hold(&self.config.time, now, value) {
+                    self.write(&spilled);
+                }
+            }
+            Err(e) => println!("Errore serializzazione JSON: {}", e),
+        }
+    }
+
+    /// With `[voting]` each reading is also tracked on its own, for the
+    /// voter to leave a stuck sensor.
+    fn check_stuck(
+        &mut self,
+        ms5611: &MS5611Data,
+        voting: Option<&VotingData>,
+        temperatures: &BTreeMap<String, Option<f32>>,
+    ) {
+        let thresholds = &self.config.stuck;
+        let readings = voting.into_iter().flat_map(|voting| {
+            [(PressureSource::Ms5611, &voting.ms5611), (PressureSource::Ms5611Secondary, &voting.ms5611_secondary)]
+        });
+        let readings = readings.filter_map(|(source, reading)| Some((source.keys(), reading.as_ref()?)));
+        let keys = ["ms5611_d1", "ms5611_d2", "ms5611_temperature", "ms5611_pressure"].map(String::from);
+        let mut samples = Vec::new();
+        for (keys, reading) in std::iter::once((keys, ms5611)).chain(readings) {
+            let [d1, d2, temperature, pressure] = keys;
+            samples.extend([
+                (d1, reading.d1 as u64, thresholds.ms5611_raw_samples),
+                (d2, reading.d2 as u64, thresholds.ms5611_raw_samples),
+                (temperature, reading.temperature.to_bits(), thresholds.ms5611_samples),
+                (pressure, reading.pressure.to_bits(), thresholds.ms5611_samples),
+            ]);
+        }
+        let mut changes: Vec<_> =
+            samples.iter().filter_map(|(key, bits, threshold)| self.stuck.observe(key, *bits, *threshold)).collect();
+        for (key, temp) in temperatures {
+            if let Some(temp) = temp {
+                changes.extend(self.stuck.observe(key, temp.to_bits() as u64, thresholds.ds18b20_samples));
+            }
+        }
+        for change in changes {
+            match change {
+                StuckChange::Stuck { key, samples } => self.emit(Event::new(
+                    Severity::Warning,
+                    "stuck",
+                    format!("{} identico da {} campioni consecutivi, letture sospette", key, samples),
+                    json!({ "measurement": key, "samples": samples, "suspect": true }),
+                )),
+                StuckChange::Recovered { key, samples } => self.emit(Event::new(
+                    Severity::Info,
+                    "stuck",
+                    format!("{} di nuovo variabile dopo {} campioni identici", key, samples),
+                    json!({ "measurement": key, "samples": samples, "suspect": false }),
+                )),
+            }
+        }
+    }
+
+    /// Samples on the `next_tick` schedule until a stop is requested, a
+    /// required sensor is lost or `cycles` cycles have run, reading the time
+    /// and waiting on the service's clock.
+    pub fn run(&mut self, signals: &Signals, config_path: &Path, overrides: &[Override], cycles: Option<u64>) {
+        let mut scheduled = self.clock.now();
+        let mut done = 0;
+        while !signals.stop.load(Ordering::Relaxed) && cycles.is_none_or(|cycles| done < cycles) {
+            self.run_cycle(scheduled);
+            done += 1;
+            if self.fatal.is_some() {
+                break;
+            }
+
+            scheduled = self.next_tick(scheduled);
+            let deadline = scheduled;
+            while self.clock.now() < deadline && !signals.stop.load(Ordering::Relaxed) {
+                if signals.reload.swap(false, Ordering::Relaxed) {
+                    self.reload_config(config_path, overrides);
+                }
+                if signals.dump.swap(false, Ordering::Relaxed) {
+                    self.dump_ring_buffer("SIGUSR2");
+                    self.signal_burst("SIGUSR2");
+                    self.signal_actions("SIGUSR2");
+                }
+                while let Some(request) = signals.control.as_ref().and_then(ControlServer::try_recv) {
+                    if self.control(request, config_path, overrides) {
+                        signals.stop.store(true, Ordering::Relaxed);
+                    }
+                }
+                self.clock.sleep(SIGNAL_POLL.min(deadline.saturating_duration_since(self.clock.now())));
+            }
+        }
+    }
+
+    pub fn run_cycle(&mut self, scheduled: Instant) -> bool {
+        if self.heartbeat.take_reinit() {
+            self.reinit_buses();
+        }
+        let all_ok = self.sample(scheduled);
+        #[cfg(feature = "tui")]
+        self.refresh_live();
+        self.heartbeat.cycle_done(self.next_interval());
+        all_ok
+    }
+
+    /// The enabled DS18B20s not dropped from the run, as (field, name, ID, required).
+    fn probes_in_use(&self) -> Vec<(&'static str, &'static str, String, bool)> {
+        self.config
+            .ds18b20
+            .probes()
+            .into_iter()
+            .filter(|probe| probe.enabled && !self.availability.is_dropped(probe.field))
+            .map(|probe| (probe.field, probe.name, probe.id.to_string(), probe.required))
+            .collect()
+    }
+
+    /// A DS18B20 read with its calibration applied, counted against the
+    /// probe's availability when it is one of the configured ones.
+    fn ds18b20_reading(
+        &mut self,
+        key: &str,
+        name: &str,
+        policy: Option<(&'static str, bool)>,
+        result: Result<f32, String>,
+    ) -> Option<f32> {
+        match result {
+            Ok(temp) => {
+                let temp = temp
+                    + match key {
+                        "ds18b20_1" => self.config.calibration.ds18b20_1 as f32,
+                        "ds18b20_2" => self.config.calibration.ds18b20_2 as f32,
+                        _ => 0.0,
+                    };
+                println!("Temperatura {}: {:.2} °C", name, temp);
+                self.sensor_ok(name);
+                if let Some((field, _)) = policy {
+                    self.availability.succeeded(field);
+                }
+                Some(temp)
+            }
+            Err(e) => {
+                self.sensor_error(name, &e);
+                if let Some((field, required)) = policy
+                    && self.availability.failed(field)
+                {
+                    let problem = format!("{} letture consecutive fallite, l'ultima: {}", LOST_AFTER_FAILURES, e);
+                    self.sensor_lost(field, name, required, &problem);
+                }
+                None
+            }
+        }
+    }
+
+    /// Retries the MS5611's bus when due; true while it is still awaited.
+    /// Once the wait runs out the MS5611 is lost, which stops the service.
+    fn waiting_for_bus(&mut self, now: Instant) -> bool {
+        let Some(wait) = self.bus_wait.as_mut() else {
+            return false;
+        };
+        let bus = wait.bus();
+        if !wait.is_due(now) {
+            return true;
+        }
+        if self.buses.retry(bus) {
+            self.bus_appeared(now);
+            return false;
+        }
+        if wait.expired(now) {
+            let waited = wait.waited(now).as_secs();
+            self.bus_wait = None;
+            let reason = self.buses.get(bus).err().unwrap_or_default();
+            let problem = format!("{} dopo {} s di attesa; {}", reason, waited, SETUP_HINT);
+            self.sensor_lost("ms5611", "MS5611", true, &problem);
+        }
+        true
+    }
+
+    /// Ends the wait: the devices on the bus are checked as they would
+    /// have been at startup, and sampling goes back to every sensor.
+    fn bus_appeared(&mut self, now: Instant) {
+        let Some(wait) = self.bus_wait.take() else {
+            return;
+        };
+        let (bus, waited) = (wait.bus(), wait.waited(now).as_secs());
+        let prom = self.buses.get(bus).ok().and_then(|bus| ms5611::read_prom(bus, &self.config.ms5611).ok());
+        self.emit(Event::new(
+            Severity::Info,
+            "i2c_bus",
+            format!(
+                "Bus I2C {} disponibile dopo {} s: fine della modalità ridotta ({} record dei soli DS18B20)",
+                bus, waited, self.ds18b20_only_records
+            ),
+            json!({
+                "bus": bus,
+                "available": true,
+                "waited_secs": waited,
+                "ds18b20_only_records": self.ds18b20_only_records,
+                "ms5611_prom": prom,
+            }),
+        ));
+        if let Err(e) = self.check_i2c(|number| number == bus) {
+            self.fatal = Some(e);
+        }
+        self.restart_warmup("i2c_bus");
+    }
+
+    /// A cycle while the MS5611's bus is awaited: nothing on I2C is read,
+    /// and the DS18B20s in use, if any, are written on their own.
+    fn sample_ds18b20_only(&mut self, now: Instant, timestamp: chrono::DateTime<chrono::Utc>) {
+        self.update_time_sync(now);
+        let w1_ready = self.w1_ready(now);
+        if w1_ready && self.probes.is_due(now, self.config.ds18b20.scan_interval_secs) {
+            self.rescan_probes(now);
+        }
+        let in_use = self.probes_in_use();
+        if in_use.is_empty() {
+            return;
+        }
+        self.heartbeat.enter(Stage::Reading);
+        let mut temperatures = BTreeMap::new();
+        let mut extras = BTreeMap::new();
+        if w1_ready {
+            for (field, name, id, required) in &in_use {
+                if self.probes.is_present(id) {
+                    let result = ds18b20::read_temperature(id).map_err(|e| e.to_string());
+                    let temp = self.ds18b20_reading(field, name, Some((field, *required)), result);
+                    temperatures.insert(*field, temp);
+                }
+            }
+            for id in self.probes.extras(&[&self.config.ds18b20.sensor_1, &self.config.ds18b20.sensor_2]) {
+                let result = ds18b20::read_temperature(&id).map_err(|e| e.to_string());
+                let temp = self.ds18b20_reading(&id, &format!("DS18B20 {}", id), None, result);
+                extras.insert(id, temp);
+            }
+        }
+        let mut configured_temp = |field: &str| {
+            let used = in_use.iter().any(|(in_use, ..)| *in_use == field) && !self.availability.is_dropped(field);
+            used.then(|| temperatures.remove(field).flatten())
+        };
+        let record = Ds18b20OnlyRecord {
+            kind: Ds18b20OnlyRecord::KIND,
+            timestamp,
+            session_id: self.session.id.clone(),
+            boot_id: self.boot_id.clone(),
+            ds18b20_1: configured_temp("ds18b20_1"),
+            ds18b20_2: configured_temp("ds18b20_2"),
+            ds18b20_extra: extras,
+        };
+        self.heartbeat.enter(Stage::Writing);
+        self.write(&record);
+        #[cfg(feature = "tui")]
+        self.publish_live(&record);
+        self.ds18b20_only_records += 1;
+        self.write_status_if_due(self.clock.now());
+    }
+
+    /// Feeds a sample to the flight tracker, setpoints, actions and burst
+    /// mode, for the transition it caused and the vertical speed.
+    fn derive(
+        &mut self,
+        now: Instant,
+        timestamp: chrono::DateTime<chrono::Utc>,
+        pressure: f64,
+        altitude_m: f64,
+    ) -> (Option<Transition>, Option<f64>) {
+        let transition = self.flight.update(now, altitude_m);
+        if let Some(transition) = &transition {
+            self.log_transition(transition);
+        }
+        let vertical_speed = self.flight.vertical_speed();
+        let filtered_altitude = self.flight.filtered_altitude().unwrap_or(altitude_m);
+        let crossings = self.setpoints.on_sample(now, filtered_altitude, self.flight.state());
+        if !crossings.is_empty() {
+            self.flight.set_setpoints(self.setpoints.fired());
+        }
+        for crossing in &crossings {
+            let values = json!({
+                "pressure_hpa": pressure,
+                "filtered_altitude_m": filtered_altitude,
+                "altitude_m": altitude_m,
+                "vertical_speed_ms": vertical_speed,
+                "flight_state": self.flight.state(),
+            });
+            self.log_crossing(crossing, timestamp, values);
+        }
+        let crossed: Vec<_> = crossings.iter().map(|crossing| crossing.name.as_str()).collect();
+        let entered = transition.as_ref().map(|t| t.to);
+        let actuations = self.actions.on_sample(now, altitude_m, entered, &crossed);
+        for actuation in actuations {
+            let values = json!({
+                "altitude_m": altitude_m,
+                "vertical_speed_ms": vertical_speed,
+                "flight_state": self.flight.state(),
+            });
+            self.log_actuation(actuation, values);
+        }
+        if self.burst.exceeds_threshold(vertical_speed) {
+            self.start_burst(
+                "vertical_speed",
+                json!({
+                    "trigger": "vertical_speed",
+                    "vertical_speed_ms": vertical_speed,
+                    "threshold": self.config.burst.vertical_speed_threshold,
+                }),
+            );
+        }
+        (transition, vertical_speed)
+    }
+
+    fn sample(&mut self, scheduled: Instant) -> bool {
+        self.heartbeat.enter(Stage::Preparing);
+        let now = self.clock.now();
+        let timestamp = self.clock.utc();
+        if self.waiting_for_bus(now) {
+            if self.fatal.is_none() {
+                self.sample_ds18b20_only(now, timestamp);
+            }
+            return false;
+        }
+        let mut timing = CycleTiming {
+            start_lateness_ms: millis(now.saturating_duration_since(scheduled)),
+            ..CycleTiming::default()
+        };
+        self.update_battery();
+        self.update_time_sync(now);
+        self.check_clock_step(now, timestamp);
+        self.check_clock_floor(now, timestamp);
+        if self.burst.expire(now) {
+            self.emit(Event::new(
+                Severity::Info,
+                "burst_mode",
+                "Modalità burst terminata, ritorno all'intervallo normale".to_string(),
+                json!({ "active": false }),
+            ));
+        }
+
+        let normal_interval = Duration::from_secs(self.config.sampling.interval_secs);
+        let ds18b20_due = self
+            .last_ds18b20_read
+            .is_none_or(|last| now.duration_since(last) >= normal_interval);
+        let w1_ready = self.w1_ready(now);
+        if w1_ready && self.probes.is_due(now, self.config.ds18b20.scan_interval_secs) {
+            self.rescan_probes(now);
+        }
+        // (record key, name, ID, (field, required) for the configured probes)
+        let mut ds18b20_sensors = Vec::new();
+        let in_use = self.probes_in_use();
+        let mut probes_absent = !w1_ready && !in_use.is_empty();
+        if ds18b20_due && w1_ready {
+            self.last_ds18b20_read = Some(now);
+            for (field, name, id, required) in &in_use {
+                if self.probes.is_present(id) {
+                    ds18b20_sensors.push((field.to_string(), name.to_string(), id.clone(), Some((*field, *required))));
+                } else {
+                    probes_absent = true;
+                }
+            }
+            for id in self.probes.extras(&[&self.config.ds18b20.sensor_1, &self.config.ds18b20.sensor_2]) {
+                ds18b20_sensors.push((id.clone(), format!("DS18B20 {}", id), id, None));
+            }
+        }
+
+        self.heartbeat.enter(Stage::Reading);
+        let exec_due: Vec<_> =
+            self.config.exec.iter().filter(|sensor| self.exec_schedule.take_due(sensor, now)).cloned().collect();
+
+        let ms5611_config = &self.config.ms5611;
+        let ms5611_bus = self.buses.get(ms5611_config.bus);
+        let secondary = self.config.voting.as_ref().map(|voting| &voting.secondary);
+        let secondary = secondary.map(|config| (config, self.buses.get(config.bus)));
+        let clock = self.clock.as_ref();
+        let bulk = self.ds18b20_bulk.as_ref().filter(|_| !ds18b20_sensors.is_empty());
+        let (ms5611_result, ms5611_elapsed, secondary_result, ds18b20_results, exec_results) = thread::scope(|scope| {
+            let runners: Vec<_> =
+                exec_due.iter().map(|sensor| scope.spawn(move || (exec::run(sensor), clock.since(now)))).collect();
+            // With the split read the MS5611 is read while the probes convert.
+            let triggered = bulk.and_then(|bulk| match bulk.trigger() {
+                Ok(()) => Some((clock.now(), bulk.conversion_time())),
+                Err(e) => {
+                    println!("Conversione DS18B20 non avviata ({}), lettura bloccante", e);
+                    None
+                }
+            });
+            let readers: Vec<_> = ds18b20_sensors
+                .iter()
+                .filter(|_| triggered.is_none())
+                .map(|(_, _, sensor_id, _)| {
+                    scope.spawn(move || {
+                        let stage = clock.now();
+                        let result = ds18b20::read_temperature(sensor_id).map_err(|e| e.to_string());
+                        (result, clock.since(stage), clock.since(now))
+                    })
+                })
+                .collect();
+            let ms5611_result = match ms5611_bus {
+                Ok(bus) => ms5611::read_and_calculate(bus, ms5611_config),
+                Err(e) => Err(e.into()),
+            };
+            let ms5611_elapsed = clock.since(now);
+            let secondary_result = secondary.map(|(config, bus)| {
+                let result = bus.map_err(Into::into).and_then(|bus| ms5611::read_and_calculate(bus, config));
+                (result.map_err(|e| e.to_string()), clock.since(now))
+            });
+            let ds18b20_results: Vec<_> = match triggered {
+                Some((stage, conversion_time)) => {
+                    clock.sleep(conversion_time.saturating_sub(clock.since(stage)));
+                    ds18b20_sensors
+                        .iter()
+                        .map(|(_, _, sensor_id, _)| {
+                            let result = ds18b20::read_converted(sensor_id).map_err(|e| e.to_string());
+                            (result, clock.since(stage), clock.since(now))
+                        })
+                        .collect()
+                }
+                None => readers
+                    .into_iter()
+                    .map(|reader| {
+                        reader.join().unwrap_or_else(|_| {
+                            (Err("thread di lettura interrotto".to_string()), Duration::ZERO, clock.since(now))
+                        })
+                    })
+                    .collect(),
+            };
+            let exec_results: Vec<_> = runners
+                .into_iter()
+                .map(|runner| {
+                    let interrupted = || (Err("thread di lettura interrotto".to_string()), clock.since(now));
+                    runner.join().unwrap_or_else(|_| interrupted())
+                })
+                .collect();
+            (ms5611_result, ms5611_elapsed, secondary_result, ds18b20_results, exec_results)
+        });
+
+        self.heartbeat.enter(Stage::Processing);
+        let calibration = &self.config.calibration;
+        let ms5611_result = ms5611_result.map(|ms5611_data| MS5611Data {
+            temperature: ms5611_data.temperature + calibration.ms5611_temperature,
+            pressure: ms5611_data.pressure + calibration.ms5611_pressure,
+            ..ms5611_data
+        });
+        let mut all_ok = !probes_absent;
+        let mut acquisitions = Acquisitions::default();
+        acquisitions.completed("ms5611", ms5611_elapsed);
+        let (ms5611_data, voting) = match (ms5611_result, secondary_result) {
+            (Ok(ms5611_data), None) => {
+                self.sensor_ok("MS5611");
+                (ms5611_data, None)
+            }
+            (Err(e), None) => {
+                self.sensor_error("MS5611", &e);
+                return false;
+            }
+            (primary, Some((secondary, secondary_elapsed))) => {
+                let secondary = secondary.map(|data| MS5611Data {
+                    temperature: data.temperature + calibration.ms5611_secondary_temperature,
+                    pressure: data.pressure + calibration.ms5611_secondary_pressure,
+                    ..data
+                });
+                timing.ms5611_secondary_ms = Some(millis(secondary_elapsed.saturating_sub(ms5611_elapsed)));
+                acquisitions.completed("ms5611_secondary", secondary_elapsed);
+                let Some((selected, voting)) = self.vote(primary.map_err(|e| e.to_string()), secondary) else {
+                    return false;
+                };
+                all_ok &= voting.ms5611.is_some() && voting.ms5611_secondary.is_some();
+                (selected, Some(voting))
+            }
+        };
+        timing.ms5611_ms = millis(ms5611_elapsed);
+
+        println!("Raw D1 (pressione): {}", ms5611_data.d1);
+        println!("Raw D2 (temperatura): {}", ms5611_data.d2);
+        println!("Temperatura calcolata: {:.2} °C", ms5611_data.temperature);
+        println!("Pressione calcolata: {:.2} hPa", ms5611_data.pressure);
+
+        let mut temperatures = BTreeMap::new();
+        for ((key, name, _, policy), (result, duration, offset)) in ds18b20_sensors.into_iter().zip(ds18b20_results) {
+            match key.as_str() {
+                "ds18b20_1" => timing.ds18b20_1_ms = Some(millis(duration)),
+                "ds18b20_2" => timing.ds18b20_2_ms = Some(millis(duration)),
+                _ => {}
+            }
+            acquisitions.completed(key.clone(), offset);
+            let temp = self.ds18b20_reading(&key, &name, policy, result);
+            all_ok &= temp.is_some();
+            temperatures.insert(key, temp);
+        }
+        let mut exec_values = BTreeMap::new();
+        for (sensor, (result, elapsed)) in exec_due.iter().zip(exec_results) {
+            let name = format!("exec {}", sensor.name);
+            match result {
+                Ok(values) => {
+                    self.sensor_ok(&name);
+                    for (measurement, value) in values {
+                        println!("Misura {}: {}", measurement, value);
+                        acquisitions.completed(format!("exec_{}", measurement), elapsed);
+                        exec_values.insert(measurement, Some(value));
+                    }
+                }
+                Err(e) => {
+                    self.sensor_error(&name, &e);
+                    all_ok = false;
+                    exec_values.extend(sensor.measurements().into_iter().map(|name| (name.to_string(), None)));
+                }
+            }
+        }
+        let warmup = match self.warmup.cycle(&self.config.warmup, now) {
+            Phase::WarmingUp => true,
+            Phase::Completed { samples, waited, reason } => {
+                self.emit(Event::new(
+                    Severity::Info,
+                    "warmup",
+                    format!("Riscaldamento concluso dopo {} cicli in {} s", samples, waited.as_secs()),
+                    json!({
+                        "samples": samples,
+                        "waited_secs": waited.as_secs_f64(),
+                        "reason": reason,
+                        "discard": self.config.warmup.discard,
+                    }),
+                ));
+                false
+            }
+            Phase::Done => false,
+        };
+        if !warmup {
+            self.check_stuck(&ms5611_data, voting.as_ref(), &temperatures);
+        }
+        let mut configured_temp = |field: &str| {
+            let used = in_use.iter().any(|(in_use, ..)| *in_use == field) && !self.availability.is_dropped(field);
+            used.then(|| temperatures.remove(field).flatten())
+        };
+        let ds18b20_1_temp = configured_temp("ds18b20_1");
+        let ds18b20_2_temp = configured_temp("ds18b20_2");
+
+        let capture_offsets_ms = acquisitions.capture_offsets(self.config.sampling.capture_offset_threshold_ms);
+        let late = acquisitions.late(&self.config.acquisition, timestamp);
+
+        let stage = self.clock.now();
+        let altitude_m = ms5611_data.altitude();
+        let (transition, vertical_speed) =
+            if warmup { (None, None) } else { self.derive(now, timestamp, ms5611_data.pressure, altitude_m) };
+
+        timing.derived_ms = millis(self.clock.since(stage));
+        timing.previous_sinks_ms = self.previous_sinks_ms;
+        timing.previous_total_ms = self.previous_total_ms;
+        self.timing_stats.record_cycle(&timing);
+
+        let sensor_data = SensorData {
+            timestamp,
+            session_id: self.session.id.clone(),
+            boot_id: self.boot_id.clone(),
+            sequence: self.sequence,
+            time_synced: self.time_sync.status().synced,
+            clock_offset_ms: self.time_sync.status().offset_ms,
+            ms5611: ms5611_data,
+            ds18b20_1: ds18b20_1_temp,
+            ds18b20_2: ds18b20_2_temp,
+            ds18b20_extra: temperatures,
+            exec: exec_values,
+            altitude_m,
+            vertical_speed_ms: vertical_speed,
+            flight_state: self.flight.state(),
+            burst_mode: self.burst.is_active(),
+            suspect: self.stuck.suspect(),
+            filled: false,
+            warmup,
+            clock_invalid: self.clock_guard.is_invalid() && self.config.time.invalid_clock == InvalidClock::Tag,
+            capture_offsets_ms,
+            acquisition_offsets_ms: late.offsets_ms,
+            acquired_at: late.acquired_at,
+            timing: self.config.status.timing_in_records.then_some(timing),
+            voting,
+        };
+
+        if !self.clock_guard.is_invalid() {
+            self.last_good.update(&sensor_data);
+        }
+
+        self.heartbeat.enter(Stage::Writing);
+        let stage = self.clock.now();
+        // Only written records take a sequence number, so the file shows no
+        // gap for discarded warmup records or samples the deadband suppressed.
+        let written = if warmup && self.config.warmup.discard {
+            println!("Record di riscaldamento scartato");
+            false
+        } else if warmup || self.deadband.as_mut().is_none_or(|deadband| deadband.admit(now, &sensor_data)) {
+            self.write_data(now, &sensor_data);
+            true
+        } else {
+            // The ring buffer keeps every sample so a dump stays dense; a
+            // suppressed one carries the number of the next written record.
+            if let Ok(output) = self.serialize(&sensor_data) {
+                self.ring.push(output.text().to_string());
+            }
+            false
+        };
+        if let Some(transition) = &transition
+            && self.ring.triggers_on(transition.to)
+        {
+            self.dump_ring_buffer(&format!("flight_state:{:?}", transition.to));
+        }
+
+        if let Some(builder) = &self.telemetry
+            && written
+            && !warmup
+            && builder.is_due(self.sequence)
+        {
+            let sentence = builder.build(self.sequence, timestamp, &sensor_data);
+            self.pipeline.send(Output::Sentence(Arc::from(sentence)));
+        }
+        #[cfg(feature = "tui")]
+        self.publish_live(&sensor_data);
+        let sinks_ms = millis(self.clock.since(stage));
+        let total_ms = millis(self.clock.since(now));
+        self.timing_stats.record("sinks", sinks_ms);
+        self.timing_stats.record("total", total_ms);
+        self.previous_sinks_ms = Some(sinks_ms);
+        self.previous_total_ms = Some(total_ms);
+
+        if written {
+            self.sequence += 1;
+        }
+        self.write_status_if_due(self.clock.now());
+        all_ok && self.fatal.is_none()
+    }
+}
+
+/// What the soak harness looks at while the service runs.
+#[cfg(feature = "sim-test")]
+impl Service {
+    pub fn sink_stats(&self) -> Vec<crate::pipeline::SinkStats> {
+        self.pipeline.stats()
+    }
+
+    pub fn wait_for_sinks(&self, timeout: Duration) {
+        self.pipeline.wait_drained(timeout);
+    }
+
+    pub fn ring_usage(&self) -> (usize, usize) {
+        self.ring.usage()
+    }
+}
+
+/// The state `--tui` draws, kept only once a front-end has asked for it.
+#[cfg(feature = "tui")]
+impl Service {
+    pub fn live_state(&mut self) -> Arc<std::sync::Mutex<crate::live::LiveState>> {
+        let (session_id, boot_id) = (self.session.id.clone(), self.boot_id.clone());
+        let live = self.live.get_or_insert_with(|| {
+            Arc::new(std::sync::Mutex::new(crate::live::LiveState { session_id, boot_id, ..Default::default() }))
+        });
+        Arc::clone(live)
+    }
+
+    fn update_live(&self, f: impl FnOnce(&mut crate::live::LiveState)) {
+        if let Some(live) = &self.live {
+            f(&mut live.lock().unwrap_or_else(|e| e.into_inner()));
+        }
+    }
+
+    /// Copies the values of a record just written into the live state.
+    fn publish_live(&self, record: &impl Serialize) {
+        if let Ok(value) = serde_json::to_value(record) {
+            let battery_v = self.battery.as_ref().and_then(BatteryMonitor::voltage);
+            self.update_live(|live| {
+                live.record(&value, &self.record_units);
+                if let Some(voltage) = battery_v {
+                    live.measure("battery_v", Unit::Volt, Some(voltage), false);
+                }
+            });
+        }
+    }
+
+    /// What goes with the values, after every cycle whether or not it wrote a record.
+    fn refresh_live(&self) {
+        let sinks = self.pipeline.stats();
+        self.update_live(|live| {
+            live.uptime_secs = self.clock.since(self.started).as_secs();
+            live.timestamp = Some(self.clock.utc());
+            live.flight_state = self.flight.state();
+            live.burst_mode = self.burst.is_active();
+            live.waiting_for_bus = self.bus_wait.as_ref().map(BusWait::bus);
+            live.sinks = sinks;
+        });
+    }
+}
+
+#[cfg(all(test, feature = "sim-test"))]
+mod tests {
+    use chrono::DateTime;
+    use std::fs;
+    use std::time::Duration;
+
+    use crate::sim::bench::Bench;
+    use crate::timesync::InvalidClock;
+
+    fn millis(record: &serde_json::Value, key: &str) -> i64 {
+        DateTime::parse_from_rfc3339(record[key].as_str().unwrap()).unwrap().timestamp_millis()
+    }
+
+    #[test]
+    fn samples_on_a_fixed_rate() {
+        let mut bench = Bench::start("fixed-rate", Duration::ZERO, |config| config.sampling.interval_secs = 5);
+        bench.run(6);
+        let (records, _) = bench.finish();
+        assert_eq!(records.len(), 6);
+        for pair in records.windows(2) {
+            assert_eq!(millis(&pair[1], "timestamp") - millis(&pair[0], "timestamp"), 5000);
+        }
+    }
+
+    #[test]
+    fn overrun_shows_as_lateness_without_a_burst() {
+        let mut bench = Bench::start("overrun", Duration::ZERO, |config| {
+            config.sampling.interval_secs = 1;
+            config.ms5611.samples_per_cycle = 12;
+            config.status.timing_in_records = true;
+        });
+        bench.run(4);
+        let (records, _) = bench.finish();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0]["timing"]["start_lateness_ms"], 0.0);
+        for record in &records[1..] {
+            assert!(record["timing"]["start_lateness_ms"].as_f64().unwrap() > 0.0, "{}", record["timing"]);
+        }
+        // 1260 ms per cycle against 1 s ticks: each cycle starts as soon as
+        // the previous one ends, not twice to catch up.
+        for pair in records.windows(2) {
+            assert_eq!(millis(&pair[1], "timestamp") - millis(&pair[0], "timestamp"), 1260);
+        }
+    }
+
+    #[test]
+    fn a_wall_clock_step_is_reported_once_and_resets_the_rate() {
+        // Set between the third and the fourth cycle.
+        let unset_for = Duration::from_secs(12);
+        let mut bench = Bench::start("clock-step", unset_for, |config| config.sampling.interval_secs = 5);
+        bench.run(6);
+        let (records, events) = bench.finish();
+        let steps: Vec<_> = events.iter().filter(|event| event["category"] == "clock_step").collect();
+        assert_eq!(steps.len(), 1, "{:?}", steps);
+        assert!(steps[0]["payload"]["step_secs"].as_f64().unwrap() > 1e9);
+        let speeds: Vec<bool> = records.iter().map(|record| record["vertical_speed_ms"].is_null()).collect();
+        assert_eq!(speeds, [true, false, false, true, false, false]);
+    }
+
+    #[test]
+    fn held_records_are_written_backdated_once_the_clock_is_set() {
+        // Set between the third and the fourth cycle, as in the test above.
+        let mut bench = Bench::start("clock-hold", Duration::from_secs(12), |config| {
+            config.sampling.interval_secs = 5;
+            config.time.invalid_clock = InvalidClock::Hold;
+        });
+        bench.run(3);
+        assert!(fs::read_to_string(&bench.data_path).unwrap().lines().all(|line| line.contains("\"type\"")));
+        bench.run(3);
+        let (records, events) = bench.finish();
+        let sequences: Vec<u64> = records.iter().map(|record| record["sequence"].as_u64().unwrap()).collect();
+        assert_eq!(sequences, [0, 1, 2, 3, 4, 5]);
+        let set = millis(&records[3], "timestamp");
+        for (index, record) in records.iter().enumerate() {
+            assert_eq!(record.get("clock_invalid"), None, "{}", record);
+            assert_eq!(millis(record, "timestamp"), set + (index as i64 - 3) * 5000);
+        }
+        let corrections: Vec<_> = events.iter().filter(|event| event["category"] == "clock_correction").collect();
+        assert_eq!(corrections.len(), 1);
+        assert_eq!(corrections[0]["payload"]["records"], 3);
+        assert_eq!(corrections[0]["payload"]["corrected"], true);
+    }
+}
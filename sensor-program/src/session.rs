@@ -0,0 +1,103 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Replaced by the session ID in `output.path` and `events.path`.
+pub const PLACEHOLDER: &str = "{session}";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    pub state_file: String,
+    /// A restart within this many seconds of the last sign of life keeps
+    /// the previous session; 0 always starts a new one.
+    pub resume_window_secs: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig { state_file: "session.json".to_string(), resume_window_secs: 0 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PersistedSession {
+    id: String,
+    last_seen: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dir: Option<PathBuf>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Origin {
+    FlightId,
+    Resumed,
+    New,
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: String,
+    pub origin: Origin,
+    /// The session recorded in the state file before this start.
+    pub previous: Option<String>,
+    /// The directory of this run, with `[flight_dir]`.
+    pub dir: Option<PathBuf>,
+}
+
+/// Session IDs end up in file names, so they are kept to a safe alphabet.
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        && !id.starts_with('.')
+}
+
+pub fn expand(template: &str, id: &str) -> String {
+    template.replace(PLACEHOLDER, id)
+}
+
+/// The session most recently recorded in the state file.
+pub fn last(config: &SessionConfig) -> Option<String> {
+    read(config).map(|persisted| persisted.id)
+}
+
+/// The flight directory of the last run, if it had one.
+pub fn last_dir(config: &SessionConfig) -> Option<PathBuf> {
+    read(config).and_then(|persisted| persisted.dir)
+}
+
+fn read(config: &SessionConfig) -> Option<PersistedSession> {
+    let content = fs::read_to_string(&config.state_file).ok()?;
+    serde_json::from_str::<PersistedSession>(&content).ok().filter(|persisted| is_valid_id(&persisted.id))
+}
+
+pub fn start(config: &SessionConfig, flight_id: Option<String>, now: DateTime<Utc>) -> Session {
+    let persisted = read(config);
+    let previous = persisted.as_ref().map(|persisted| persisted.id.clone());
+    if let Some(id) = flight_id {
+        return Session { id, origin: Origin::FlightId, previous, dir: None };
+    }
+    if let Some(persisted) = persisted
+        && config.resume_window_secs > 0
+        && (now - persisted.last_seen).num_seconds() <= config.resume_window_secs as i64
+    {
+        return Session { id: persisted.id, origin: Origin::Resumed, previous, dir: None };
+    }
+    Session { id: now.format("%Y%m%dT%H%M%SZ").to_string(), origin: Origin::New, previous, dir: None }
+}
+
+/// Records `id` as alive at `now`, for a later start to resume.
+pub fn touch(config: &SessionConfig, id: &str, dir: Option<&Path>, now: DateTime<Utc>) {
+    let persisted = PersistedSession { id: id.to_string(), last_seen: now, dir: dir.map(Path::to_path_buf) };
+    let tmp_path = format!("{}.tmp", config.state_file);
+    let result = serde_json::to_string(&persisted)
+        .map_err(|e| e.to_string())
+        .and_then(|json| fs::write(&tmp_path, json).map_err(|e| e.to_string()))
+        .and_then(|_| fs::rename(&tmp_path, &config.state_file).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        println!("Errore salvataggio sessione in {}: {}", config.state_file, e);
+    }
+}
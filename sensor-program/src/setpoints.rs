@@ -0,0 +1,192 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::flight::FlightState;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Ascending,
+    Descending,
+    #[default]
+    Both,
+}
+
+/// An altitude whose crossing is reported with a `setpoint` event and can
+/// trigger `[[actions]]`. Each direction fires at most once per flight.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SetpointConfig {
+    pub name: String,
+    pub altitude_m: f64,
+    #[serde(default)]
+    pub direction: Direction,
+    /// Seconds the filtered altitude has to stay past the setpoint before
+    /// the crossing counts.
+    #[serde(default = "default_dwell_secs")]
+    pub dwell_secs: u64,
+}
+
+fn default_dwell_secs() -> u64 {
+    10
+}
+
+pub fn validate(setpoints: &[SetpointConfig]) -> Vec<String> {
+    let mut errors = Vec::new();
+    for (index, setpoint) in setpoints.iter().enumerate() {
+        if setpoint.name.is_empty() || setpoints[..index].iter().any(|other| other.name == setpoint.name) {
+            errors.push(format!("setpoints: nome \"{}\" vuoto o duplicato", setpoint.name));
+        }
+        if !setpoint.altitude_m.is_finite() {
+            errors.push(format!("setpoints.{}.altitude_m non valida", setpoint.name));
+        }
+    }
+    errors
+}
+
+#[derive(Debug)]
+pub struct Crossing {
+    pub name: String,
+    pub altitude_m: f64,
+    /// `Ascending` or `Descending`, never `Both`.
+    pub direction: Direction,
+}
+
+/// The directions of a setpoint that have fired, saved with the flight
+/// state so that a restart in flight does not fire them again.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Fired {
+    pub up: bool,
+    pub down: bool,
+}
+
+#[derive(Default)]
+struct Track {
+    above: Option<bool>,
+    /// Since when the altitude has been on the other side of the setpoint.
+    crossing_since: Option<Instant>,
+    fired: Fired,
+}
+
+/// Ascending crossings are armed before burst (preflight and ascent),
+/// descending ones only from burst on, so that noise around a setpoint
+/// during the climb cannot fire a descent notification.
+pub struct Setpoints {
+    configs: Vec<SetpointConfig>,
+    tracks: BTreeMap<String, Track>,
+}
+
+impl Setpoints {
+    pub fn new(configs: Vec<SetpointConfig>, fired: &BTreeMap<String, Fired>) -> Self {
+        let tracks = fired.iter().map(|(name, &fired)| (name.clone(), Track { fired, ..Track::default() })).collect();
+        let mut setpoints = Setpoints { configs: Vec::new(), tracks };
+        setpoints.set_config(configs);
+        setpoints
+    }
+
+    pub fn fired(&self) -> BTreeMap<String, Fired> {
+        self.tracks.iter().map(|(name, track)| (name.clone(), track.fired)).collect()
+    }
+
+    /// Keeps what has fired for the setpoints that are still configured.
+    pub fn set_config(&mut self, configs: Vec<SetpointConfig>) {
+        self.tracks.retain(|name, _| configs.iter().any(|config| config.name == *name));
+        self.configs = configs;
+    }
+
+    pub fn on_sample(&mut self, now: Instant, filtered_altitude: f64, state: FlightState) -> Vec<Crossing> {
+        let mut crossings = Vec::new();
+        for config in &self.configs {
+            let track = self.tracks.entry(config.name.clone()).or_default();
+            let above = filtered_altitude >= config.altitude_m;
+            let Some(was_above) = track.above else {
+                track.above = Some(above);
+                continue;
+            };
+            if above == was_above {
+                track.crossing_since = None;
+                continue;
+            }
+            let since = *track.crossing_since.get_or_insert(now);
+            if now.duration_since(since) < Duration::from_secs(config.dwell_secs) {
+                continue;
+            }
+            track.above = Some(above);
+            track.crossing_since = None;
+            let (direction, armed, fired) = if above {
+                let armed = matches!(state, FlightState::Preflight | FlightState::Ascent);
+                (Direction::Ascending, armed, &mut track.fired.up)
+            } else {
+                let armed = matches!(state, FlightState::Burst | FlightState::Descent);
+                (Direction::Descending, armed, &mut track.fired.down)
+            };
+            let wanted = config.direction == Direction::Both || config.direction == direction;
+            if wanted && armed && !*fired {
+                *fired = true;
+                crossings.push(Crossing { name: config.name.clone(), altitude_m: config.altitude_m, direction });
+            }
+        }
+        crossings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::{Duration, Instant};
+
+    use super::{Direction, Fired, SetpointConfig, Setpoints};
+    use crate::flight::FlightState;
+
+    fn setpoint(direction: Direction, dwell_secs: u64) -> SetpointConfig {
+        SetpointConfig { name: "cutdown".to_string(), altitude_m: 1000.0, direction, dwell_secs }
+    }
+
+    /// The crossings over `samples`, one a second.
+    fn feed(setpoints: &mut Setpoints, samples: &[(f64, FlightState)]) -> Vec<(usize, Direction)> {
+        let start = Instant::now();
+        let mut crossings = Vec::new();
+        for (secs, &(altitude, state)) in samples.iter().enumerate() {
+            let at = start + Duration::from_secs(secs as u64);
+            crossings.extend(setpoints.on_sample(at, altitude, state).into_iter().map(|c| (secs, c.direction)));
+        }
+        crossings
+    }
+
+    #[test]
+    fn a_crossing_counts_after_dwell_secs_past_the_setpoint() {
+        let mut setpoints = Setpoints::new(vec![setpoint(Direction::Both, 10)], &BTreeMap::new());
+        // Above for 5 s, back below, then above from 7 s on.
+        let samples: Vec<_> = (0..25)
+            .map(|secs| (if secs == 0 || secs == 6 { 900.0 } else { 1100.0 }, FlightState::Ascent))
+            .collect();
+        assert_eq!(feed(&mut setpoints, &samples), [(17, Direction::Ascending)]);
+    }
+
+    #[test]
+    fn each_direction_is_armed_in_its_phase_and_fires_once() {
+        use FlightState::*;
+        let samples = [(1100.0, Ascent), (900.0, Ascent), (1100.0, Ascent), (900.0, Burst), (1100.0, Descent)];
+        let samples = [&samples[..], &[(900.0, Descent), (1100.0, Ascent), (900.0, Descent)]].concat();
+        let mut both = Setpoints::new(vec![setpoint(Direction::Both, 0)], &BTreeMap::new());
+        assert_eq!(feed(&mut both, &samples), [(2, Direction::Ascending), (3, Direction::Descending)]);
+        let mut descending = Setpoints::new(vec![setpoint(Direction::Descending, 0)], &BTreeMap::new());
+        assert_eq!(feed(&mut descending, &samples), [(3, Direction::Descending)]);
+    }
+
+    #[test]
+    fn what_has_fired_is_kept_across_a_restart() {
+        let mut setpoints = Setpoints::new(vec![setpoint(Direction::Both, 0)], &BTreeMap::new());
+        let climb = [(900.0, FlightState::Ascent), (1100.0, FlightState::Ascent)];
+        assert_eq!(feed(&mut setpoints, &climb), [(1, Direction::Ascending)]);
+        let fired = setpoints.fired();
+        assert_eq!(fired["cutdown"], Fired { up: true, down: false });
+
+        let mut restarted = Setpoints::new(vec![setpoint(Direction::Both, 0)], &fired);
+        let samples = [&climb[..], &[(900.0, FlightState::Burst)]].concat();
+        assert_eq!(feed(&mut restarted, &samples), [(2, Direction::Descending)]);
+        // A setpoint no longer configured is forgotten.
+        assert_eq!(Setpoints::new(Vec::new(), &fired).fired(), BTreeMap::new());
+    }
+}
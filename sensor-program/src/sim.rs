@@ -0,0 +1,291 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::{Clock, VirtualClock};
+use crate::config::Config;
+use crate::i2c_bus::{Bus, Buses, Transport};
+use crate::ms5611;
+
+/// The flight every simulated sensor follows, in seconds of virtual time:
+/// on the ground, a climb to burst, the descent, and on the ground again.
+const GROUND_SECS: f64 = 600.0;
+const ASCENT_SPEED: f64 = 5.0;
+const BURST_ALTITUDE: f64 = 25_000.0;
+const DESCENT_SPEED: f64 = 8.0;
+/// When the descent reaches the ground.
+pub const LANDING_SECS: f64 = GROUND_SECS + BURST_ALTITUDE / ASCENT_SPEED + BURST_ALTITUDE / DESCENT_SPEED;
+
+/// C1..C6 from the datasheet example.
+const COEFFICIENTS: [u16; 6] = [40127, 36924, 23317, 23282, 33464, 28312];
+
+pub fn altitude_at(secs: f64) -> f64 {
+    let ascent_secs = BURST_ALTITUDE / ASCENT_SPEED;
+    let climbing = secs - GROUND_SECS;
+    if climbing <= 0.0 {
+        0.0
+    } else if climbing <= ascent_secs {
+        climbing * ASCENT_SPEED
+    } else {
+        (BURST_ALTITUDE - (climbing - ascent_secs) * DESCENT_SPEED).max(0.0)
+    }
+}
+
+/// The inverse of `MS5611Data::altitude`.
+fn pressure_at(altitude: f64) -> f64 {
+    1013.25 * (1.0 - altitude / 44330.0).powf(1.0 / 0.190295)
+}
+
+/// Inside the payload box: warm on the ground, cooling with altitude.
+fn temperature_at(altitude: f64) -> f64 {
+    25.0 - 45.0 * altitude / BURST_ALTITUDE
+}
+
+/// A PROM with `COEFFICIENTS` and the CRC nibble that makes it valid.
+fn prom() -> [u16; 8] {
+    let mut prom = [0u16; 8];
+    prom[1..7].copy_from_slice(&COEFFICIENTS);
+    for crc in 0..16 {
+        prom[7] = crc;
+        if ms5611::prom_crc_ok(&prom) {
+            break;
+        }
+    }
+    prom
+}
+
+/// D1 and D2 that the first-order compensation turns into `temperature`
+/// and `pressure`.
+fn conversion(temperature: f64, pressure: f64) -> (u32, u32) {
+    let c = COEFFICIENTS.map(f64::from);
+    let d2 = c[4] * 256.0 + (temperature * 100.0 - 2000.0) * 8_388_608.0 / c[5];
+    let d_t = d2 - c[4] * 256.0;
+    let off = c[1] * 65536.0 + c[3] * d_t / 128.0;
+    let sens = c[0] * 32768.0 + c[2] * d_t / 256.0;
+    let d1 = (pressure * 100.0 * 32768.0 + off) * 2_097_152.0 / sens;
+    (d1.round() as u32, d2.round() as u32)
+}
+
+enum Device {
+    Ms5611 { prom: [u16; 8], pending: Option<u32> },
+    Ina219 { volts: f64 },
+}
+
+/// The devices found in `config` on every bus, answering the commands the
+/// drivers send. Conversion waits advance `clock` instead of sleeping.
+pub struct SimulatedBus {
+    clock: Arc<VirtualClock>,
+    devices: BTreeMap<u16, Device>,
+    selected: u16,
+    /// A command written and waiting for its read.
+    command: Option<u8>,
+    noise: u64,
+}
+
+impl SimulatedBus {
+    /// Small deterministic noise, so that no reading looks stuck.
+    fn noise(&mut self, amplitude: f64) -> f64 {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 7;
+        self.noise ^= self.noise << 17;
+        ((self.noise % 2001) as f64 / 1000.0 - 1.0) * amplitude
+    }
+
+    fn device(&mut self) -> Result<&mut Device, Box<dyn Error>> {
+        let address = self.selected;
+        self.devices.get_mut(&address).ok_or_else(|| format!("nessun dispositivo simulato a 0x{:02X}", address).into())
+    }
+}
+
+impl Transport for SimulatedBus {
+    fn set_slave_address(&mut self, address: u16) -> Result<(), Box<dyn Error>> {
+        self.selected = address;
+        self.command = None;
+        Ok(())
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, Box<dyn Error>> {
+        let &[command] = buffer else {
+            return Err(format!("scrittura di {} byte non prevista", buffer.len()).into());
+        };
+        let altitude = altitude_at(self.clock.elapsed().as_secs_f64());
+        let temperature = temperature_at(altitude) + self.noise(0.01);
+        let pressure = pressure_at(altitude) + self.noise(0.01);
+        let Device::Ms5611 { pending, .. } = self.device()? else {
+            return Err("comando MS5611 inviato all'INA219".into());
+        };
+        let (d1, d2) = conversion(temperature, pressure);
+        match command {
+            0x40..=0x48 => *pending = Some(d1),
+            0x50..=0x58 => *pending = Some(d2),
+            0x00 | 0x1E | 0xA0..=0xAE => {}
+            other => return Err(format!("comando MS5611 0x{:02X} sconosciuto", other).into()),
+        }
+        self.command = Some(command);
+        Ok(1)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Box<dyn Error>> {
+        let command = self.command.take();
+        let Device::Ms5611 { prom, pending } = self.device()? else {
+            return Err("lettura MS5611 dall'INA219".into());
+        };
+        match (command, buffer.len()) {
+            (Some(word @ 0xA0..=0xAE), 2) => {
+                buffer.copy_from_slice(&prom[((word - 0xA0) / 2) as usize].to_be_bytes());
+            }
+            // Read without a finished conversion, the ADC returns 0.
+            (Some(0x00), 3) => buffer.copy_from_slice(&pending.take().unwrap_or(0).to_be_bytes()[1..]),
+            (_, len) => return Err(format!("lettura di {} byte non prevista", len).into()),
+        }
+        Ok(buffer.len())
+    }
+
+    fn write_read(&mut self, write_buffer: &[u8], read_buffer: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        let noise = self.noise(0.002);
+        let Device::Ina219 { volts } = self.device()? else {
+            return Err("lettura di registro dall'MS5611".into());
+        };
+        let value: u16 = match write_buffer {
+            [0x00] => 0x399F,
+            // 4 mV per bit from bit 3, with the conversion-ready bit set.
+            [0x02] => ((((*volts + noise) / 0.004) as u16) << 3) | 0x02,
+            _ => 0,
+        };
+        match read_buffer {
+            [high, low] => [*high, *low] = value.to_be_bytes(),
+            _ => return Err(format!("lettura di {} byte non prevista", read_buffer.len()).into()),
+        }
+        Ok(())
+    }
+
+    fn smbus_receive_byte(&mut self) -> Result<u8, Box<dyn Error>> {
+        self.device().map(|_| 0)
+    }
+
+    fn delay(&mut self, duration: Duration) {
+        self.clock.sleep(duration);
+    }
+}
+
+/// One simulated bus for each bus number in `config`, with the MS5611s
+/// and the INA219 it configures.
+pub fn buses(config: &Config, clock: &Arc<VirtualClock>) -> Buses {
+    let mut devices: BTreeMap<u8, BTreeMap<u16, Device>> = BTreeMap::new();
+    let mut ms5611s = vec![&config.ms5611];
+    ms5611s.extend(config.voting.as_ref().map(|voting| &voting.secondary));
+    for ms5611 in ms5611s {
+        let device = Device::Ms5611 { prom: prom(), pending: None };
+        devices.entry(ms5611.bus).or_default().insert(ms5611.address, device);
+    }
+    if let Some(battery) = &config.battery {
+        let volts = 3.9 * battery.cells as f64;
+        devices.entry(battery.bus).or_default().insert(battery.address, Device::Ina219 { volts });
+    }
+    let mut buses = Buses::default();
+    for (number, devices) in devices {
+        let transport = SimulatedBus {
+            clock: Arc::clone(clock),
+            devices,
+            selected: 0,
+            command: None,
+            noise: 0x9E37_79B9_7F4A_7C15 ^ number as u64,
+        };
+        buses.insert(Bus::with_transport(number, Box::new(transport)));
+    }
+    buses
+}
+
+/// The service on simulated sensors and a virtual clock, for the tests.
+#[cfg(test)]
+pub mod bench {
+    use serde_json::Value;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::clock::{Clock, VirtualClock};
+    use crate::config::Config;
+    use crate::i2c_bus::Buses;
+    use crate::service::{Service, Signals};
+    use crate::session;
+    use crate::soak;
+
+    pub struct Bench {
+        pub service: Service,
+        pub dir: PathBuf,
+        pub data_path: PathBuf,
+        pub events_path: PathBuf,
+    }
+
+    /// An empty directory of its own for the test `name`.
+    pub fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sensor-program-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    impl Bench {
+        /// Started on the default configuration, with what `soak` leaves out
+        /// removed and then `edit` applied, and the wall clock unset for
+        /// `unset_for`.
+        pub fn start(name: &str, unset_for: Duration, edit: impl FnOnce(&mut Config)) -> Bench {
+            Bench::with_buses(name, unset_for, edit, super::buses).unwrap()
+        }
+
+        /// As `start`, on the buses that `buses` sets up instead of the
+        /// simulated ones; fails when the service does not start.
+        pub fn with_buses(
+            name: &str,
+            unset_for: Duration,
+            edit: impl FnOnce(&mut Config),
+            buses: impl FnOnce(&Config, &Arc<VirtualClock>) -> Buses,
+        ) -> Result<Bench, String> {
+            let dir = dir(name);
+            let mut config = soak::soak_config(Config::default(), &dir);
+            edit(&mut config);
+            let clock = Arc::new(VirtualClock::unset_for(unset_for));
+            let buses = buses(&config, &clock);
+            let session = session::start(&config.session, None, clock.utc());
+            let data_path = config.output.path_for(&session.id).into();
+            let events_path = config.events.path_for(&session.id).into();
+            let service = Service::new(config.clone(), false, buses, session, clock.clone()).unwrap();
+            let mut bench = Bench { service, dir, data_path, events_path };
+            match bench.service.start() {
+                Ok(()) => Ok(bench),
+                Err(e) => {
+                    bench.finish();
+                    Err(e)
+                }
+            }
+        }
+
+        pub fn run(&mut self, cycles: u64) {
+            self.service.run(&Signals::default(), Path::new(""), &[], Some(cycles));
+            self.service.wait_for_sinks(soak::DRAIN_TIMEOUT);
+        }
+
+        /// Shuts the service down and returns the data records and events.
+        pub fn finish(self) -> (Vec<Value>, Vec<Value>) {
+            let dir = self.dir.clone();
+            let written = self.finish_keeping_files();
+            let _ = fs::remove_dir_all(dir);
+            written
+        }
+
+        /// As `finish`, leaving the directory for the test to remove.
+        pub fn finish_keeping_files(mut self) -> (Vec<Value>, Vec<Value>) {
+            self.service.shutdown("test");
+            let records = lines(&self.data_path).into_iter().filter(|line| line.get("type").is_none()).collect();
+            (records, lines(&self.events_path))
+        }
+    }
+
+    fn lines(path: &Path) -> Vec<Value> {
+        let content = fs::read_to_string(path).unwrap_or_default();
+        content.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+}
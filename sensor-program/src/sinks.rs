@@ -0,0 +1,394 @@
+use rppal::uart::{Parity, Uart};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::pipeline::{FillSink, LinkState, Output, QueueConfig, RawSink, Sink};
+use crate::record;
+use crate::writer::JsonlWriter;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SinkKind {
+    File { path: String },
+    Udp { address: String },
+    Tcp { address: String },
+    /// One text line per record for equipment on a plain serial line.
+    Serial {
+        device: String,
+        #[serde(default = "default_baud")]
+        baud: u32,
+        /// e.g. `"{timestamp},{ms5611_pressure:.2},{ds18b20_1:.1}"`; see
+        /// `LineTemplate`.
+        format: String,
+        #[serde(default)]
+        line_ending: LineEnding,
+        /// Send every Nth record.
+        #[serde(default = "default_every")]
+        every: u64,
+    },
+}
+
+fn default_baud() -> u32 {
+    9600
+}
+
+fn default_every() -> u64 {
+    1
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+    Cr,
+}
+
+impl LineEnding {
+    fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+}
+
+/// One entry of `[[sinks]]`. Unknown keys are rejected by `SinkKind`, since
+/// serde cannot combine `deny_unknown_fields` with `flatten`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SinkConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub include_events: bool,
+    /// Receive data records without `[mapping]` applied.
+    #[serde(default)]
+    pub raw: bool,
+    /// Receive data records with the measurements they lack taken from the
+    /// last good values (`[last_good]`); by default every sink but a file,
+    /// which keeps the record as it was taken.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fill_last_good: Option<bool>,
+    #[serde(default)]
+    pub queue: QueueConfig,
+}
+
+impl SinkConfig {
+    pub fn accepts(&self) -> fn(&Output) -> bool {
+        if self.include_events {
+            |output| output.is_record() || output.is_event()
+        } else {
+            Output::is_record
+        }
+    }
+
+    pub fn fills(&self) -> bool {
+        self.fill_last_good.unwrap_or(!matches!(self.kind, SinkKind::File { .. }))
+    }
+
+    pub fn open(&self) -> Result<Box<dyn Sink>, String> {
+        let sink = self.open_kind()?;
+        let sink = if self.raw { Box::new(RawSink(sink)) } else { sink };
+        Ok(if self.fills() { Box::new(FillSink(sink)) } else { sink })
+    }
+
+    fn open_kind(&self) -> Result<Box<dyn Sink>, String> {
+        match &self.kind {
+            SinkKind::File { path } => Ok(Box::new(JsonlWriter::new(path))),
+            SinkKind::Udp { address } => {
+                UdpSink::open(address).map(|sink| Box::new(sink) as Box<dyn Sink>)
+            }
+            SinkKind::Tcp { address } => {
+                TcpSink::open(address).map(|sink| Box::new(sink) as Box<dyn Sink>)
+            }
+            SinkKind::Serial { device, baud, format, line_ending, every } => {
+                let template = LineTemplate::parse(format)?;
+                let mut sink = SerialSink::new(device, *baud, template, *line_ending, *every);
+                // Only a required sink has to find the device at start-up;
+                // the others keep retrying until it shows up.
+                match sink.connect() {
+                    Err(e) if self.required => Err(e),
+                    _ => Ok(Box::new(sink) as Box<dyn Sink>),
+                }
+            }
+        }
+    }
+}
+
+fn resolve(address: &str) -> Result<SocketAddr, String> {
+    address
+        .to_socket_addrs()
+        .map_err(|e| format!("indirizzo {} non valido: {}", address, e))?
+        .next()
+        .ok_or_else(|| format!("indirizzo {} non risolto", address))
+}
+
+pub struct UdpSink {
+    socket: UdpSocket,
+    target: SocketAddr,
+}
+
+impl UdpSink {
+    fn open(address: &str) -> Result<UdpSink, String> {
+        let target = resolve(address)?;
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+        socket.set_broadcast(true).map_err(|e| e.to_string())?;
+        Ok(UdpSink { socket, target })
+    }
+}
+
+impl Sink for UdpSink {
+    fn write(&mut self, output: &Output) -> Result<(), String> {
+        self.socket
+            .send_to(output.text().as_bytes(), self.target)
+            .map(|_| ())
+            .map_err(|e| format!("{}: {}", self.target, e))
+    }
+}
+
+pub struct TcpSink {
+    target: SocketAddr,
+    stream: Option<TcpStream>,
+}
+
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl TcpSink {
+    fn open(address: &str) -> Result<TcpSink, String> {
+        let target = resolve(address)?;
+        let mut sink = TcpSink {
+            target,
+            stream: None,
+        };
+        sink.connect()?;
+        Ok(sink)
+    }
+
+    fn connect(&mut self) -> Result<&mut TcpStream, String> {
+        if self.stream.is_none() {
+            let stream = TcpStream::connect_timeout(&self.target, TCP_TIMEOUT)
+                .map_err(|e| format!("connessione a {} fallita: {}", self.target, e))?;
+            stream
+                .set_write_timeout(Some(TCP_TIMEOUT))
+                .map_err(|e| e.to_string())?;
+            self.stream = Some(stream);
+        }
+        self.stream
+            .as_mut()
+            .ok_or_else(|| "connessione non disponibile".to_string())
+    }
+}
+
+impl Sink for TcpSink {
+    fn write(&mut self, output: &Output) -> Result<(), String> {
+        let target = self.target;
+        let stream = self.connect()?;
+        let result =
+            writeln!(stream, "{}", output.text()).map_err(|e| format!("{}: {}", target, e));
+        if result.is_err() {
+            self.stream = None;
+        }
+        result
+    }
+}
+
+/// A line with `{key}` placeholders, filled from the record in the flat
+/// layout whatever layout the sink receives. `{key:.N}` prints a number
+/// with N decimals; a missing or null value prints nothing. `{{` and `}}`
+/// are literal braces.
+#[derive(Debug)]
+pub struct LineTemplate {
+    pieces: Vec<Piece>,
+}
+
+#[derive(Debug)]
+enum Piece {
+    Text(String),
+    Value { key: String, decimals: Option<usize> },
+}
+
+impl LineTemplate {
+    pub fn parse(format: &str) -> Result<LineTemplate, String> {
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or_else(|| format!("formato \"{}\": '{{' non chiusa", format))?;
+                    let placeholder = &rest[..end];
+                    chars = rest[end + 1..].chars();
+                    let (key, decimals) = match placeholder.split_once(":.") {
+                        Some((key, decimals)) => {
+                            let invalid =
+                                || format!("formato \"{}\": decimali non validi in {{{}}}", format, placeholder);
+                            (key, Some(decimals.parse().map_err(|_| invalid())?))
+                        }
+                        None => (placeholder, None),
+                    };
+                    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                        return Err(format!("formato \"{}\": campo {{{}}} non valido", format, placeholder));
+                    }
+                    pieces.push(Piece::Text(std::mem::take(&mut text)));
+                    pieces.push(Piece::Value { key: key.to_string(), decimals });
+                }
+                '}' => return Err(format!("formato \"{}\": '}}' senza '{{'", format)),
+                c => text.push(c),
+            }
+        }
+        pieces.push(Piece::Text(text));
+        pieces.retain(|piece| !matches!(piece, Piece::Text(text) if text.is_empty()));
+        if !pieces.iter().any(|piece| matches!(piece, Piece::Value { .. })) {
+            return Err(format!("formato \"{}\": nessun campo", format));
+        }
+        Ok(LineTemplate { pieces })
+    }
+
+    pub fn render(&self, fields: &serde_json::Map<String, Value>) -> String {
+        let mut line = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => line.push_str(text),
+                Piece::Value { key, decimals } => match (fields.get(key), decimals) {
+                    (None | Some(Value::Null), _) => {}
+                    (Some(Value::String(text)), _) => line.push_str(text),
+                    (Some(Value::Number(number)), Some(decimals)) => match number.as_f64() {
+                        Some(value) => line.push_str(&format!("{:.*}", decimals, value)),
+                        None => line.push_str(&number.to_string()),
+                    },
+                    (Some(value), _) => line.push_str(&value.to_string()),
+                },
+            }
+        }
+        line
+    }
+}
+
+const SERIAL_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Writes from the sink thread and reopens the device when a write fails,
+/// e.g. because a USB adapter was unplugged, waiting twice as long after
+/// each failed attempt up to a minute. Records are dropped, and counted as
+/// failed, while the device is away.
+pub struct SerialSink {
+    device: String,
+    baud: u32,
+    template: LineTemplate,
+    line_ending: LineEnding,
+    every: u64,
+    records: u64,
+    uart: Option<Uart>,
+    retry_at: Option<Instant>,
+    backoff: Duration,
+    link: Arc<Mutex<LinkState>>,
+}
+
+impl SerialSink {
+    fn new(device: &str, baud: u32, template: LineTemplate, line_ending: LineEnding, every: u64) -> SerialSink {
+        SerialSink {
+            device: device.to_string(),
+            baud,
+            template,
+            line_ending,
+            every,
+            records: 0,
+            uart: None,
+            retry_at: None,
+            backoff: Duration::from_secs(1),
+            link: Arc::default(),
+        }
+    }
+
+    fn set_link(&self, connected: bool, error: Option<String>) {
+        let mut link = self.link.lock().unwrap_or_else(|e| e.into_inner());
+        if connected && !link.connected && link.last_error.is_some() {
+            link.reconnects += 1;
+        }
+        link.connected = connected;
+        if error.is_some() {
+            link.last_error = error;
+        }
+    }
+
+    fn connect(&mut self) -> Result<&mut Uart, String> {
+        if self.uart.is_none() {
+            if let Some(retry_at) = self.retry_at
+                && Instant::now() < retry_at
+            {
+                let link = self.link.lock().unwrap_or_else(|e| e.into_inner());
+                return Err(link.last_error.clone().unwrap_or_else(|| format!("{} non disponibile", self.device)));
+            }
+            let opened = Uart::with_path(&self.device, self.baud, Parity::None, 8, 1).and_then(|mut uart| {
+                uart.set_write_mode(true)?;
+                Ok(uart)
+            });
+            match opened {
+                Ok(uart) => {
+                    self.uart = Some(uart);
+                    self.retry_at = None;
+                    self.backoff = Duration::from_secs(1);
+                    self.set_link(true, None);
+                }
+                Err(e) => {
+                    let error = format!("apertura di {} fallita: {}", self.device, e);
+                    self.disconnected(error.clone());
+                    return Err(error);
+                }
+            }
+        }
+        self.uart.as_mut().ok_or_else(|| format!("{} non disponibile", self.device))
+    }
+
+    fn disconnected(&mut self, error: String) {
+        self.uart = None;
+        self.retry_at = Some(Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(SERIAL_MAX_BACKOFF);
+        self.set_link(false, Some(error));
+    }
+}
+
+impl Sink for SerialSink {
+    fn write(&mut self, output: &Output) -> Result<(), String> {
+        let Ok(Value::Object(fields)) = serde_json::from_str(output.text()) else {
+            return Err("record non valido".to_string());
+        };
+        // Header, gap and status lines do not fit the template.
+        if fields.contains_key("type") {
+            return Ok(());
+        }
+        self.records += 1;
+        if !(self.records - 1).is_multiple_of(self.every.max(1)) {
+            return Ok(());
+        }
+        let line = format!("{}{}", self.template.render(&record::flatten(fields)), self.line_ending.as_str());
+        let device = self.device.clone();
+        let result = self.connect()?.write(line.as_bytes()).map(|_| ());
+        result.map_err(|e| {
+            let error = format!("{}: {}", device, e);
+            self.disconnected(error.clone());
+            error
+        })
+    }
+
+    fn link(&self) -> Option<Arc<Mutex<LinkState>>> {
+        Some(Arc::clone(&self.link))
+    }
+}
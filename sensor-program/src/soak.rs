@@ -0,0 +1,358 @@
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::analyze::{self, SessionSummary};
+use crate::cli::{AnalyzeOptions, SoakOptions};
+use crate::clock::{Clock, VirtualClock};
+use crate::config::Config;
+use crate::flight::FlightState;
+use crate::flight_dir::FlightDirConfig;
+use crate::mapping::MappingConfig;
+use crate::record::{RecordLayout, SensorData};
+use crate::ringbuffer::DUMP_PREFIX;
+use crate::schema;
+use crate::selftest::Report;
+use crate::service::{Service, Signals};
+use crate::session;
+use crate::sim;
+use crate::timesync;
+use crate::warmup::WarmupConfig;
+
+/// The part of the run after which the memory in use is taken as the
+/// baseline, once the buffers have had time to fill.
+const WARMUP_DIVISOR: u64 = 10;
+/// Growth of the resident set over the baseline still counted as bounded.
+const MAX_RSS_GROWTH_BYTES: u64 = 16 * 1024 * 1024;
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What was seen of the buffers while the service ran.
+#[derive(Default)]
+struct Usage {
+    /// Deepest queue seen, against its capacity, by sink.
+    queues: Vec<(String, usize, usize)>,
+    ring_lines: usize,
+    ring_bytes: usize,
+    baseline_rss: Option<u64>,
+    final_rss: Option<u64>,
+}
+
+impl Usage {
+    fn observe(&mut self, service: &Service) {
+        for stats in service.sink_stats() {
+            match self.queues.iter_mut().find(|(name, ..)| *name == stats.name) {
+                Some((_, depth, _)) => *depth = (*depth).max(stats.depth),
+                None => self.queues.push((stats.name, stats.depth, stats.capacity)),
+            }
+        }
+        let (lines, bytes) = service.ring_usage();
+        self.ring_lines = self.ring_lines.max(lines);
+        self.ring_bytes = self.ring_bytes.max(bytes);
+    }
+}
+
+/// Runs the service on simulated sensors and a virtual clock for
+/// `options.records` cycles, with its files in `options.dir`, then checks
+/// what it wrote. Fails when the directory or the service cannot be set up.
+pub fn run(config: Config, options: &SoakOptions) -> Result<Report, String> {
+    let dir = &options.dir;
+    if fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(format!("{} non è vuota", dir.display()));
+    }
+    fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let config = soak_config(config, dir);
+    let clock = Arc::new(VirtualClock::unset_for(options.unset_clock));
+    let buses = sim::buses(&config, &clock);
+    let session = session::start(&config.session, None, clock.utc());
+    let data_path = config.output.path_for(&session.id);
+    let events_path = config.events.path_for(&session.id);
+    let mut service = Service::new(config.clone(), false, buses, session, clock.clone())?;
+    if let Err(e) = service.start() {
+        service.shutdown("soak");
+        return Err(e);
+    }
+
+    // Chunks small enough for the queues, drained in real time after each:
+    // on the virtual clock the loop outruns any sink.
+    let chunk = (config.output.queue.capacity.min(config.events.queue.capacity) / 4).max(1) as u64;
+    let mut usage = Usage::default();
+    let mut cycles = 0;
+    while cycles < options.records && service.fatal().is_none() {
+        let count = chunk.min(options.records - cycles);
+        if options.async_runtime {
+            service = run_async(service, count)?;
+        } else {
+            service.run(&Signals::default(), Path::new(""), &[], Some(count));
+        }
+        cycles += count;
+        usage.observe(&service);
+        service.wait_for_sinks(DRAIN_TIMEOUT);
+        if usage.baseline_rss.is_none() && cycles >= options.records / WARMUP_DIVISOR {
+            usage.baseline_rss = rss_bytes();
+        }
+    }
+    usage.final_rss = rss_bytes();
+    let sinks = service.sink_stats();
+    let elapsed = clock.elapsed();
+    service.shutdown("soak");
+
+    let mut report = Report { passed: true, checks: Vec::new() };
+    report.push("files_parse", true, check_parse(dir));
+    let records = read_records(Path::new(&data_path));
+    report.push("sequence", true, records.as_ref().map_err(Clone::clone).and_then(|r| check_sequence(r, cycles)));
+    let transitions = flight_transitions(Path::new(&events_path));
+    report.push("schema", true, check_schema(&config, Path::new(&data_path)));
+    let result = records.as_ref().map_err(Clone::clone).and_then(|r| check_timestamps(r, &config));
+    report.push("timestamps", true, result);
+    report.push("file_set", true, check_files(dir, &config, &data_path, &events_path, &transitions));
+    let inputs = vec![data_path.into()];
+    let summary = analyze::analyze(&AnalyzeOptions { inputs, json: false }).map_err(|e| e.to_string());
+    let result = records.as_ref().map_err(Clone::clone).and_then(|r| check_summary(r, summary?));
+    report.push("summary", true, result);
+    let reached: Vec<FlightState> = [FlightState::Preflight].into_iter().chain(transitions).collect();
+    let landed = elapsed.as_secs_f64() > sim::LANDING_SECS + config.flight.landed_secs as f64 + 600.0;
+    report.push("flight_states", landed, check_flight(&reached));
+    report.push("queues", true, check_queues(&usage, &sinks));
+    report.push("ring_buffer", true, check_ring(&usage, &config));
+    report.push("memory", true, check_memory(&usage));
+    Ok(report)
+}
+
+/// Everything that needs hardware the simulation does not have, or that
+/// would change what the checks expect, is left out.
+pub fn soak_config(mut config: Config, dir: &Path) -> Config {
+    let path = |name: &str| dir.join(name).display().to_string();
+    config.output.path = path("data_{session}.jsonl");
+    config.events.path = path("events_{session}.jsonl");
+    config.session.state_file = path("session.json");
+    config.flight.state_file = path("flight_state.json");
+    config.ring_buffer.dump_dir = dir.display().to_string();
+    config.ds18b20.sensor_1_enabled = false;
+    config.ds18b20.sensor_2_enabled = false;
+    config.exec.clear();
+    config.sinks.clear();
+    config.actions.clear();
+    config.telemetry = None;
+    config.deadband = None;
+    config.warmup = WarmupConfig::default();
+    config.flight_dir = FlightDirConfig::default();
+    config.mapping = MappingConfig::default();
+    config
+}
+
+#[cfg(feature = "tokio-runtime")]
+fn run_async(service: Service, cycles: u64) -> Result<Service, String> {
+    crate::runtime_tokio::run_cycles(service, cycles)
+}
+
+#[cfg(not(feature = "tokio-runtime"))]
+fn run_async(_: Service, _: u64) -> Result<Service, String> {
+    unreachable!("--async richiede la feature tokio-runtime")
+}
+
+fn rss_bytes() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf has no preconditions.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page_size).ok()?)
+}
+
+fn check_parse(dir: &Path) -> Result<String, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let (mut files, mut lines) = (0, 0);
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        let content = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        files += 1;
+        for (index, line) in content.lines().enumerate() {
+            serde_json::from_str::<Value>(line)
+                .map_err(|e| format!("{} riga {}: {}", path.display(), index + 1, e))?;
+            lines += 1;
+        }
+    }
+    Ok(format!("{} file, {} righe JSON valide", files, lines))
+}
+
+/// Every data record as `schema` describes it for this configuration.
+fn check_schema(config: &Config, path: &Path) -> Result<String, String> {
+    let (records, errors) = schema::check_file(&schema::data_record(config, false), path)?;
+    match errors.first() {
+        Some(error) => Err(format!("{} record su {} non conformi, il primo: {}", errors.len(), records, error)),
+        None => Ok(format!("{} record conformi allo schema", records)),
+    }
+}
+
+/// Timestamps in order and past the floor, but for the records tagged
+/// `clock_invalid`.
+fn check_timestamps(records: &[SensorData], config: &Config) -> Result<String, String> {
+    let floor = config.time.floor.unwrap_or_else(timesync::build_time);
+    let dated: Vec<_> = records.iter().filter(|record| !record.clock_invalid).collect();
+    if let Some(record) = dated.iter().find(|record| record.timestamp < floor) {
+        return Err(format!("record {} del {}, prima di {}", record.sequence, record.timestamp, floor));
+    }
+    if let Some(pair) = dated.windows(2).find(|pair| pair[1].timestamp <= pair[0].timestamp) {
+        return Err(format!("record {} non successivo al {}", pair[1].sequence, pair[0].sequence));
+    }
+    Ok(format!("{} record in ordine, {} con clock_invalid", dated.len(), records.len() - dated.len()))
+}
+
+fn read_records(path: &Path) -> Result<Vec<SensorData>, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut records = Vec::new();
+    for line in content.lines() {
+        let value: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+        if value.get("type").is_none() {
+            let record = serde_json::from_value(RecordLayout::Nested.apply(value)).map_err(|e| e.to_string())?;
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+fn check_sequence(records: &[SensorData], cycles: u64) -> Result<String, String> {
+    if let Some((expected, record)) = records.iter().enumerate().find(|(i, r)| r.sequence != *i as u64) {
+        return Err(format!("sequenza {} al posto di {}", record.sequence, expected));
+    }
+    let boot_ids: BTreeSet<_> = records.iter().map(|record| &record.boot_id).collect();
+    if boot_ids.len() > 1 {
+        return Err(format!("{} boot_id in una sola esecuzione", boot_ids.len()));
+    }
+    if records.len() as u64 != cycles {
+        return Err(format!("{} record per {} cicli", records.len(), cycles));
+    }
+    Ok(format!("{} record, sequenza 0..{} senza buchi", records.len(), records.len()))
+}
+
+/// The states entered, in order, from the `flight_state` events.
+fn flight_transitions(path: &Path) -> Vec<FlightState> {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    content
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|event| event["category"] == "flight_state")
+        .filter_map(|event| serde_json::from_value(event["payload"]["to"].clone()).ok())
+        .collect()
+}
+
+/// There is no rotation or compression: the run leaves its two files, the
+/// session and flight state, and a ring-buffer dump per triggering state.
+fn check_files(
+    dir: &Path,
+    config: &Config,
+    data_path: &str,
+    events_path: &str,
+    transitions: &[FlightState],
+) -> Result<String, String> {
+    let name = |path: &str| Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned());
+    let mut expected: BTreeSet<_> =
+        [data_path, events_path, &config.session.state_file].into_iter().flat_map(name).collect();
+    if !transitions.is_empty() {
+        expected.extend(name(&config.flight.state_file));
+    }
+    let dumps = transitions.iter().filter(|state| config.ring_buffer.dump_on_states.contains(state)).count();
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let found: BTreeSet<String> =
+        entries.filter_map(|entry| entry.ok()).map(|entry| entry.file_name().to_string_lossy().into_owned()).collect();
+    let found_dumps = found.iter().filter(|name| name.starts_with(DUMP_PREFIX)).count();
+    let others: BTreeSet<_> = found.iter().filter(|name| !name.starts_with(DUMP_PREFIX)).cloned().collect();
+    if others != expected {
+        let missing: Vec<_> = expected.difference(&others).collect();
+        let unexpected: Vec<_> = others.difference(&expected).collect();
+        return Err(format!("mancanti {:?}, inattesi {:?}", missing, unexpected));
+    }
+    if found_dumps != dumps {
+        return Err(format!("{} salvataggi del buffer circolare, attesi {}", found_dumps, dumps));
+    }
+    Ok(format!("{} file e {} salvataggi del buffer circolare", expected.len(), dumps))
+}
+
+/// `analyze` against the same aggregates taken here from the records.
+fn check_summary(records: &[SensorData], summaries: Vec<SessionSummary>) -> Result<String, String> {
+    let [summary] = summaries.as_slice() else {
+        return Err(format!("{} sessioni invece di una", summaries.len()));
+    };
+    let fold = |value: fn(&SensorData) -> f64, pick: fn(f64, f64) -> f64, start: f64| {
+        records.iter().map(value).fold(start, pick)
+    };
+    let speeds = || records.iter().filter_map(|record| record.vertical_speed_ms);
+    let mut states = Vec::new();
+    for record in records {
+        if !states.contains(&record.flight_state) {
+            states.push(record.flight_state);
+        }
+    }
+    let comparisons = [
+        ("records", summary.records as f64, records.len() as f64),
+        ("min_pressure_hpa", summary.min_pressure_hpa, fold(|r| r.ms5611.pressure, f64::min, f64::INFINITY)),
+        ("max_altitude_m", summary.max_altitude_m, fold(|r| r.altitude_m, f64::max, f64::NEG_INFINITY)),
+        ("min_temperature_c", summary.min_temperature_c, fold(|r| r.ms5611.temperature, f64::min, f64::INFINITY)),
+        ("max_temperature_c", summary.max_temperature_c, fold(|r| r.ms5611.temperature, f64::max, f64::NEG_INFINITY)),
+        ("min_vertical_speed_ms", summary.min_vertical_speed_ms.unwrap_or(0.0), speeds().fold(0.0, f64::min)),
+        ("max_vertical_speed_ms", summary.max_vertical_speed_ms.unwrap_or(0.0), speeds().fold(0.0, f64::max)),
+    ];
+    let differing: Vec<_> = comparisons
+        .iter()
+        .filter(|(_, summarized, computed)| (summarized - computed).abs() > 1e-9)
+        .map(|(key, summarized, computed)| format!("{} {} invece di {}", key, summarized, computed))
+        .collect();
+    if !differing.is_empty() {
+        return Err(differing.join(", "));
+    }
+    if summary.flight_states != states {
+        return Err(format!("stati di volo {:?} invece di {:?}", summary.flight_states, states));
+    }
+    Ok(format!("riepilogo coerente: {} record, altitudine max {:.0} m", summary.records, summary.max_altitude_m))
+}
+
+fn check_flight(reached: &[FlightState]) -> Result<String, String> {
+    let whole_flight = [
+        FlightState::Preflight,
+        FlightState::Ascent,
+        FlightState::Burst,
+        FlightState::Descent,
+        FlightState::Landed,
+    ];
+    if reached == whole_flight {
+        Ok(format!("{:?}", reached))
+    } else {
+        Err(format!("{:?} invece di {:?}", reached, whole_flight))
+    }
+}
+
+fn check_queues(usage: &Usage, sinks: &[crate::pipeline::SinkStats]) -> Result<String, String> {
+    let dropped: Vec<_> = sinks.iter().filter(|stats| stats.dropped > 0 || stats.failed > 0).collect();
+    if let Some(stats) = dropped.first() {
+        return Err(format!("{}: {} scartati, {} non scritti", stats.name, stats.dropped, stats.failed));
+    }
+    if let Some((name, depth, capacity)) = usage.queues.iter().find(|(_, depth, capacity)| depth >= capacity) {
+        return Err(format!("coda {} piena ({} di {})", name, depth, capacity));
+    }
+    let depths: Vec<_> =
+        usage.queues.iter().map(|(name, depth, capacity)| format!("{} {}/{}", name, depth, capacity)).collect();
+    Ok(format!("profondità massime {}", depths.join(", ")))
+}
+
+fn check_ring(usage: &Usage, config: &Config) -> Result<String, String> {
+    let ring = &config.ring_buffer;
+    let detail = format!("{} righe, {} byte al massimo", usage.ring_lines, usage.ring_bytes);
+    if usage.ring_lines > ring.capacity || usage.ring_bytes > ring.max_bytes {
+        return Err(format!("{} (limiti {} righe, {} byte)", detail, ring.capacity, ring.max_bytes));
+    }
+    Ok(detail)
+}
+
+fn check_memory(usage: &Usage) -> Result<String, String> {
+    let (Some(baseline), Some(last)) = (usage.baseline_rss, usage.final_rss) else {
+        return Err("memoria residente non leggibile da /proc/self/statm".to_string());
+    };
+    let growth = last.saturating_sub(baseline);
+    let detail = format!("memoria residente da {} KiB a {} KiB", baseline / 1024, last / 1024);
+    if growth > MAX_RSS_GROWTH_BYTES {
+        return Err(format!("{}, oltre {} MiB di crescita", detail, MAX_RSS_GROWTH_BYTES / (1024 * 1024)));
+    }
+    Ok(detail)
+}
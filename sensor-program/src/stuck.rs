@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Consecutive identical samples after which a measurement is flagged as
+/// stuck, per sensor type; 0 disables the check.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StuckConfig {
+    pub ms5611_samples: u32,
+    /// Raw D1/D2 ADC output repeating is a much stronger sign of a wedged
+    /// conversion, so it gets its own, usually lower, threshold.
+    pub ms5611_raw_samples: u32,
+    pub ds18b20_samples: u32,
+}
+
+impl Default for StuckConfig {
+    fn default() -> Self {
+        StuckConfig { ms5611_samples: 60, ms5611_raw_samples: 10, ds18b20_samples: 720 }
+    }
+}
+
+#[derive(Debug)]
+pub enum StuckChange {
+    Stuck { key: String, samples: u32 },
+    Recovered { key: String, samples: u32 },
+}
+
+struct Track {
+    bits: u64,
+    samples: u32,
+    suspect: bool,
+}
+
+#[derive(Default)]
+pub struct StuckDetector {
+    tracks: BTreeMap<String, Track>,
+}
+
+impl StuckDetector {
+    /// Feeds one sample, compared bit for bit with the previous one.
+    pub fn observe(&mut self, key: &str, bits: u64, threshold: u32) -> Option<StuckChange> {
+        let Some(track) = self.tracks.get_mut(key) else {
+            self.tracks.insert(key.to_string(), Track { bits, samples: 1, suspect: false });
+            return None;
+        };
+        if track.bits != bits {
+            let samples = track.samples;
+            let was_suspect = track.suspect;
+            *track = Track { bits, samples: 1, suspect: false };
+            return was_suspect.then(|| StuckChange::Recovered { key: key.to_string(), samples });
+        }
+        track.samples = track.samples.saturating_add(1);
+        if threshold > 0 && !track.suspect && track.samples >= threshold {
+            track.suspect = true;
+            return Some(StuckChange::Stuck { key: key.to_string(), samples: track.samples });
+        }
+        None
+    }
+
+    pub fn suspect(&self) -> Vec<String> {
+        self.tracks.iter().filter(|(_, track)| track.suspect).map(|(key, _)| key.clone()).collect()
+    }
+}
@@ -0,0 +1,294 @@
+use chrono::{DateTime, Utc};
+use rppal::uart::{Parity, Uart};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+use crate::pipeline::{Output, QueueConfig, Sink};
+use crate::record::SensorData;
+use crate::units::Unit;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    Sequence,
+    Time,
+    Latitude,
+    Longitude,
+    Altitude,
+    Temperature,
+    Pressure,
+    D1,
+    D2,
+    #[serde(rename = "ds18b20_1")]
+    Ds18b20First,
+    #[serde(rename = "ds18b20_2")]
+    Ds18b20Second,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum FieldSpec {
+    Name(Field),
+    Formatted {
+        field: Field,
+        decimals: Option<usize>,
+        missing: Option<String>,
+        /// Sent in this unit instead of the one the service records.
+        unit: Option<Unit>,
+    },
+}
+
+impl Field {
+    /// The unit the value is recorded in; `None` for the fields that are
+    /// not measurements.
+    pub fn unit(self) -> Option<Unit> {
+        match self {
+            Field::Sequence | Field::Time | Field::Latitude | Field::Longitude => None,
+            Field::Altitude => Some(Unit::Metre),
+            Field::Temperature | Field::Ds18b20First | Field::Ds18b20Second => Some(Unit::Celsius),
+            Field::Pressure => Some(Unit::Hectopascal),
+            Field::D1 | Field::D2 => Some(Unit::Adc),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TelemetrySinkConfig {
+    File { path: String },
+    Serial { device: String, baud: u32 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TelemetryConfig {
+    pub callsign: String,
+    pub fields: Vec<FieldSpec>,
+    pub sink: TelemetrySinkConfig,
+    /// A sentence every `every` written records.
+    #[serde(default = "default_every")]
+    pub every: u64,
+    #[serde(default = "default_decimals")]
+    pub decimals: usize,
+    #[serde(default)]
+    pub missing: String,
+    #[serde(default)]
+    pub queue: QueueConfig,
+}
+
+impl TelemetryConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.callsign.is_empty()
+            || !self.callsign.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            errors.push(format!("telemetry.callsign \"{}\" non valido", self.callsign));
+        }
+        if self.every == 0 {
+            errors.push("telemetry.every deve essere maggiore di zero".to_string());
+        }
+        if self.fields.is_empty() {
+            errors.push("telemetry.fields non può essere vuoto".to_string());
+        }
+        if self.queue.capacity == 0 {
+            errors.push("telemetry.queue.capacity deve essere maggiore di zero".to_string());
+        }
+        if self.missing.contains([',', '*', '$']) {
+            errors.push("telemetry.missing non può contenere ',', '*' o '$'".to_string());
+        }
+        for spec in &self.fields {
+            let FieldSpec::Formatted { field, unit: Some(unit), .. } = spec else {
+                continue;
+            };
+            let name = serde_json::to_value(field).ok().and_then(|name| name.as_str().map(str::to_string));
+            let name = name.unwrap_or_default();
+            match field.unit() {
+                None => errors.push(format!("telemetry.fields: {} non ha unità, {} non applicabile", name, unit)),
+                Some(recorded) if recorded.convert(0.0, *unit).is_none() => errors.push(format!(
+                    "telemetry.fields: {} è in {}, non convertibile in {}",
+                    name, recorded, unit
+                )),
+                Some(_) => {}
+            }
+        }
+        errors
+    }
+}
+
+fn default_every() -> u64 {
+    1
+}
+
+fn default_decimals() -> usize {
+    2
+}
+
+pub fn crc16_ccitt(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+pub struct SentenceBuilder {
+    config: TelemetryConfig,
+}
+
+impl SentenceBuilder {
+    pub fn new(config: TelemetryConfig) -> Self {
+        SentenceBuilder { config }
+    }
+
+    pub fn set_config(&mut self, config: TelemetryConfig) {
+        self.config = config;
+    }
+
+    pub fn is_due(&self, sequence: u64) -> bool {
+        sequence.is_multiple_of(self.config.every.max(1))
+    }
+
+    pub fn build(&self, sequence: u64, time: DateTime<Utc>, data: &SensorData) -> String {
+        let mut body = self.config.callsign.clone();
+        for spec in &self.config.fields {
+            let (field, decimals, missing, unit) = match spec {
+                FieldSpec::Name(field) => (*field, self.config.decimals, &self.config.missing, None),
+                FieldSpec::Formatted { field, decimals, missing, unit } => (
+                    *field,
+                    decimals.unwrap_or(self.config.decimals),
+                    missing.as_ref().unwrap_or(&self.config.missing),
+                    *unit,
+                ),
+            };
+            body.push(',');
+            body.push_str(&format_field(field, decimals, missing, unit, sequence, time, data));
+        }
+        format!("$${}*{:04X}", body, crc16_ccitt(body.as_bytes()))
+    }
+}
+
+fn format_field(
+    field: Field,
+    decimals: usize,
+    missing: &str,
+    unit: Option<Unit>,
+    sequence: u64,
+    time: DateTime<Utc>,
+    data: &SensorData,
+) -> String {
+    let value = match field {
+        Field::Sequence => return sequence.to_string(),
+        Field::Time => return time.format("%H:%M:%S").to_string(),
+        Field::D1 => return data.ms5611.d1.to_string(),
+        Field::D2 => return data.ms5611.d2.to_string(),
+        Field::Latitude | Field::Longitude => None,
+        Field::Altitude => Some(data.ms5611.altitude()),
+        Field::Temperature => Some(data.ms5611.temperature),
+        Field::Pressure => Some(data.ms5611.pressure),
+        Field::Ds18b20First => data.ds18b20_1.flatten().map(f64::from),
+        Field::Ds18b20Second => data.ds18b20_2.flatten().map(f64::from),
+    };
+    let value = match (value, field.unit(), unit) {
+        (Some(v), Some(recorded), Some(unit)) => recorded.convert(v, unit),
+        (value, ..) => value,
+    };
+    match value {
+        Some(v) if v.is_finite() => format!("{:.*}", decimals, v),
+        _ => missing.to_string(),
+    }
+}
+
+pub enum TelemetrySink {
+    File(File, String),
+    Serial(Uart),
+}
+
+impl TelemetrySink {
+    pub fn open(config: &TelemetrySinkConfig) -> Result<TelemetrySink, Box<dyn std::error::Error>> {
+        match config {
+            TelemetrySinkConfig::File { path } => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Ok(TelemetrySink::File(file, path.clone()))
+            }
+            TelemetrySinkConfig::Serial { device, baud } => {
+                let mut uart = Uart::with_path(device, *baud, Parity::None, 8, 1)?;
+                uart.set_write_mode(true)?;
+                Ok(TelemetrySink::Serial(uart))
+            }
+        }
+    }
+
+    pub fn send(&mut self, sentence: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let line = format!("{}\n", sentence);
+        match self {
+            TelemetrySink::File(file, _) => file.write_all(line.as_bytes())?,
+            TelemetrySink::Serial(uart) => {
+                uart.write(line.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for TelemetrySink {
+    fn write(&mut self, output: &Output) -> Result<(), String> {
+        self.send(output.text()).map_err(|e| e.to_string())
+    }
+
+    fn reopen(&mut self) {
+        if let TelemetrySink::File(file, path) = self {
+            match OpenOptions::new().create(true).append(true).open(&*path) {
+                Ok(reopened) => *file = reopened,
+                Err(e) => println!("Telemetria: {} non riaperto: {}", path, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::MS5611Data;
+
+    /// Checksums cross-checked with Python's `binascii.crc_hqx(body, 0xFFFF)`,
+    /// the CRC16-CCITT (0x1021, initial 0xFFFF) habhub expects.
+    #[test]
+    fn crc_of_ukhas_sentences() {
+        // The check value of CRC-16/CCITT-FALSE.
+        assert_eq!(crc16_ccitt(b"123456789"), 0x29B1);
+        assert_eq!(crc16_ccitt(b"hadie,181,10:42:10,54.422829,-2.062070,403,0,0,0"), 0xA6B7);
+        assert_eq!(crc16_ccitt(b"icarus,1,12:00:00,51.50000,-0.12000,1000,1013.25"), 0x4A6A);
+        assert_eq!(crc16_ccitt(b""), 0xFFFF);
+    }
+
+    #[test]
+    fn builds_sentence_byte_for_byte() {
+        let config: TelemetryConfig = toml::from_str(
+            r#"
+            callsign = "hadie"
+            fields = ["sequence", "time", "altitude", "temperature", "pressure", "d1", "d2", "ds18b20_1",
+                      { field = "ds18b20_2", decimals = 1 }, { field = "temperature", decimals = 1, unit = "degF" }]
+            sink = { type = "file", path = "/dev/null" }
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_empty());
+        let data = SensorData {
+            ms5611: MS5611Data { d1: 9085466, d2: 8569150, temperature: 21.5, pressure: 1013.25, ..Default::default() },
+            ds18b20_1: Some(None),
+            ds18b20_2: Some(Some(-12.5)),
+            ..SensorData::example()
+        };
+        let time = "2026-06-01T10:42:10Z".parse().unwrap();
+        let sentence = SentenceBuilder::new(config).build(181, time, &data);
+        assert_eq!(sentence, "$$hadie,181,10:42:10,0.00,21.50,1013.25,9085466,8569150,,-12.5,70.7*4948");
+    }
+}
@@ -0,0 +1,262 @@
+use chrono::{DateTime, Utc};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidClock {
+    /// Records are written as usual, with `clock_invalid: true`.
+    #[default]
+    Tag,
+    /// Records are held in memory until the clock is set, then written
+    /// with timestamps taken back from the monotonic clock.
+    Hold,
+    /// The wall clock is not checked.
+    Off,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeConfig {
+    pub sync_poll_secs: u64,
+    /// Difference between wall-clock and monotonic time elapsed between two
+    /// samples above which the wall clock is considered stepped.
+    pub step_threshold_secs: f64,
+    /// A wall clock before this, or before the last record of the output
+    /// file, has not been set yet, e.g. a board without RTC booted in
+    /// 1970; unset, the build time.
+    #[serde(deserialize_with = "date_time", skip_serializing_if = "Option::is_none")]
+    pub floor: Option<DateTime<Utc>>,
+    pub invalid_clock: InvalidClock,
+    /// With `hold`: records held at most; beyond, the oldest is written
+    /// with `clock_invalid: true`.
+    pub max_held: usize,
+}
+
+impl Default for TimeConfig {
+    fn default() -> Self {
+        TimeConfig {
+            sync_poll_secs: 60,
+            step_threshold_secs: 2.0,
+            floor: None,
+            invalid_clock: InvalidClock::default(),
+            max_held: 720,
+        }
+    }
+}
+
+/// A TOML date-time, `floor = 2026-01-01T00:00:00Z`, or the same as a string.
+fn date_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Written {
+        Toml(toml::value::Datetime),
+        Text(String),
+    }
+    let text = match Option::<Written>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(Written::Toml(date_time)) => date_time.to_string(),
+        Some(Written::Text(text)) => text,
+    };
+    match text.parse() {
+        Ok(date_time) => Ok(Some(date_time)),
+        Err(e) => Err(D::Error::custom(format!("{}: {} (es. 2026-01-01T00:00:00Z)", text, e))),
+    }
+}
+
+pub fn build_time() -> DateTime<Utc> {
+    DateTime::from_timestamp(env!("BUILD_TIME_SECS").parse().unwrap_or(0), 0).unwrap_or_default()
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FloorSource {
+    Build,
+    Config,
+    /// The last record of the output file.
+    Output,
+}
+
+impl FloorSource {
+    pub fn describe(self) -> &'static str {
+        match self {
+            FloorSource::Build => "ora di compilazione",
+            FloorSource::Config => "time.floor",
+            FloorSource::Output => "ultimo record del file di dati",
+        }
+    }
+}
+
+/// Whether the wall clock has been set, by the floor in `TimeConfig`, and
+/// the records held back while it has not.
+pub struct ClockGuard {
+    /// The last record of the output file at startup.
+    written: Option<DateTime<Utc>>,
+    invalid: bool,
+    held: VecDeque<(Instant, Value)>,
+    /// Held records written early, tagged, to stay within `max_held`.
+    spilled: u64,
+}
+
+/// The clock has gone below or back over the floor.
+pub struct FloorChange {
+    pub invalid: bool,
+    pub floor: DateTime<Utc>,
+    pub source: FloorSource,
+}
+
+/// The held records, once the clock is set or they can wait no longer.
+pub struct Released {
+    pub records: Vec<Value>,
+    pub spilled: u64,
+    /// First and last corrected timestamp; none when tagged instead.
+    pub corrected: Option<(DateTime<Utc>, DateTime<Utc>)>,
+}
+
+impl ClockGuard {
+    pub fn new(written: Option<DateTime<Utc>>) -> Self {
+        ClockGuard { written, invalid: false, held: VecDeque::new(), spilled: 0 }
+    }
+
+    pub fn floor(&self, config: &TimeConfig) -> (DateTime<Utc>, FloorSource) {
+        let configured = match config.floor {
+            Some(floor) => (floor, FloorSource::Config),
+            None => (build_time(), FloorSource::Build),
+        };
+        match self.written {
+            Some(written) if written > configured.0 => (written, FloorSource::Output),
+            _ => configured,
+        }
+    }
+
+    pub fn is_invalid(&self) -> bool {
+        self.invalid
+    }
+
+    /// Takes `timestamp` as the wall clock of this cycle; the change, if
+    /// it crossed the floor.
+    pub fn check(&mut self, config: &TimeConfig, timestamp: DateTime<Utc>) -> Option<FloorChange> {
+        let (floor, source) = self.floor(config);
+        let invalid = config.invalid_clock != InvalidClock::Off && timestamp < floor;
+        if invalid == self.invalid {
+            return None;
+        }
+        self.invalid = invalid;
+        Some(FloorChange { invalid, floor, source })
+    }
+
+    /// Holds a serialized data record taken at `now`; the oldest, tagged,
+    /// if more than `max_held` are waiting.
+    pub fn hold(&mut self, config: &TimeConfig, now: Instant, record: Value) -> Option<Value> {
+        self.held.push_back((now, record));
+        if self.held.len() <= config.max_held.max(1) {
+            return None;
+        }
+        self.spilled += 1;
+        self.held.pop_front().map(|(_, record)| tagged(record))
+    }
+
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    /// Every held record, timestamped from `timestamp` at `now` back along
+    /// the monotonic clock; with `timestamp` none, tagged instead.
+    pub fn release(&mut self, now: Instant, timestamp: Option<DateTime<Utc>>) -> Released {
+        let spilled = std::mem::take(&mut self.spilled);
+        let mut corrected = None::<(DateTime<Utc>, DateTime<Utc>)>;
+        let mut records = Vec::new();
+        for (taken, mut record) in self.held.drain(..) {
+            let Some(timestamp) = timestamp else {
+                records.push(tagged(record));
+                continue;
+            };
+            let age = chrono::Duration::from_std(now.saturating_duration_since(taken)).unwrap_or_default();
+            let at = timestamp - age;
+            shift(&mut record, at);
+            corrected = Some((corrected.map_or(at, |(first, _)| first), at));
+            records.push(record);
+        }
+        Released { records, spilled, corrected }
+    }
+}
+
+fn tagged(mut record: Value) -> Value {
+    record["clock_invalid"] = json!(true);
+    record
+}
+
+/// Moves the `timestamp` of `record` to `at`, and every `acquired_at` with it.
+fn shift(record: &mut Value, at: DateTime<Utc>) {
+    let Some(original) = record["timestamp"].as_str().and_then(|text| text.parse::<DateTime<Utc>>().ok()) else {
+        return;
+    };
+    let offset = at - original;
+    record["timestamp"] = json!(at);
+    if let Some(acquired) = record.get_mut("acquired_at").and_then(Value::as_object_mut) {
+        for value in acquired.values_mut() {
+            if let Some(time) = value.as_str().and_then(|text| text.parse::<DateTime<Utc>>().ok()) {
+                *value = json!(time + offset);
+            }
+        }
+    }
+}
+
+/// Kernel NTP state; `synced` is None when it cannot be queried.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct SyncStatus {
+    pub synced: Option<bool>,
+    pub offset_ms: Option<f64>,
+    pub max_error_ms: Option<f64>,
+}
+
+/// Reads the kernel clock discipline state with a read-only adjtimex call.
+pub fn query() -> SyncStatus {
+    // SAFETY: timex is plain data; modes = 0 only reads the current state.
+    let (state, timex) = unsafe {
+        let mut timex: libc::timex = std::mem::zeroed();
+        let state = libc::adjtimex(&mut timex);
+        (state, timex)
+    };
+    if state < 0 {
+        return SyncStatus::default();
+    }
+    let unsynced = state == libc::TIME_ERROR || timex.status & libc::STA_UNSYNC != 0;
+    let offset_unit_ms = if timex.status & libc::STA_NANO != 0 { 1e-6 } else { 1e-3 };
+    SyncStatus {
+        synced: Some(!unsynced),
+        offset_ms: (!unsynced).then_some(timex.offset as f64 * offset_unit_ms),
+        max_error_ms: (!unsynced).then_some(timex.maxerror as f64 / 1000.0),
+    }
+}
+
+pub struct TimeSync {
+    status: SyncStatus,
+    last_poll: Option<Instant>,
+}
+
+impl TimeSync {
+    pub fn new() -> Self {
+        TimeSync { status: SyncStatus::default(), last_poll: None }
+    }
+
+    pub fn status(&self) -> SyncStatus {
+        self.status
+    }
+
+    /// Re-queries the kernel when due and returns the previous `synced`
+    /// value if it changed.
+    pub fn poll(&mut self, now: Instant, config: &TimeConfig) -> Option<Option<bool>> {
+        if self.last_poll.is_some_and(|last| now.duration_since(last) < Duration::from_secs(config.sync_poll_secs)) {
+            return None;
+        }
+        let first = self.last_poll.is_none();
+        self.last_poll = Some(now);
+        let previous = self.status.synced;
+        self.status = query();
+        (first || previous != self.status.synced).then_some(previous)
+    }
+}
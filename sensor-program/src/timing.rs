@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatusConfig {
+    pub interval_secs: u64,
+    pub timing_in_records: bool,
+}
+
+impl Default for StatusConfig {
+    fn default() -> Self {
+        StatusConfig { interval_secs: 300, timing_in_records: false }
+    }
+}
+
+/// Stage durations of one cycle. Sink writes and the total happen after the
+/// record is serialized, so the record carries those of the previous cycle.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CycleTiming {
+    pub start_lateness_ms: f64,
+    pub ms5611_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ms5611_secondary_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ds18b20_1_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ds18b20_2_ms: Option<f64>,
+    pub derived_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_sinks_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_total_ms: Option<f64>,
+}
+
+pub fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct StageStats {
+    count: u64,
+    sum_ms: f64,
+    max_ms: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StageSummary {
+    pub count: u64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+}
+
+#[derive(Default)]
+pub struct TimingStats {
+    stages: BTreeMap<&'static str, StageStats>,
+}
+
+impl TimingStats {
+    pub fn record(&mut self, stage: &'static str, ms: f64) {
+        let stats = self.stages.entry(stage).or_default();
+        stats.count += 1;
+        stats.sum_ms += ms;
+        stats.max_ms = stats.max_ms.max(ms);
+    }
+
+    pub fn record_cycle(&mut self, timing: &CycleTiming) {
+        self.record("start_lateness", timing.start_lateness_ms);
+        self.record("ms5611", timing.ms5611_ms);
+        if let Some(ms) = timing.ms5611_secondary_ms {
+            self.record("ms5611_secondary", ms);
+        }
+        if let Some(ms) = timing.ds18b20_1_ms {
+            self.record("ds18b20_1", ms);
+        }
+        if let Some(ms) = timing.ds18b20_2_ms {
+            self.record("ds18b20_2", ms);
+        }
+        self.record("derived", timing.derived_ms);
+    }
+
+    pub fn summary(&self) -> BTreeMap<&'static str, StageSummary> {
+        self.stages
+            .iter()
+            .map(|(stage, stats)| {
+                let summary = StageSummary {
+                    count: stats.count,
+                    mean_ms: stats.sum_ms / stats.count.max(1) as f64,
+                    max_ms: stats.max_ms,
+                };
+                (*stage, summary)
+            })
+            .collect()
+    }
+}
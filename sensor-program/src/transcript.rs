@@ -0,0 +1,523 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::i2c_bus::Transport;
+#[cfg(feature = "sim-test")]
+use crate::{clock::Clock, clock::VirtualClock, i2c_bus::Bus, i2c_bus::Buses, record};
+#[cfg(feature = "sim-test")]
+use crate::{service::Service, service::Signals, session, soak};
+#[cfg(feature = "sim-test")]
+use std::collections::VecDeque;
+
+/// Fields of a data record that depend on the host the replay runs on,
+/// not on the bus; they are neither saved nor compared.
+#[cfg(feature = "sim-test")]
+const HOST_FIELDS: [&str; 3] = ["boot_id", "time_synced", "clock_offset_ms"];
+
+/// A session on the I2C buses, as `--record-transcript` saves it: the
+/// configuration it ran with and, by device, every transaction in order
+/// with the answer it got. `transcript` runs the service on it again and
+/// compares the data records with `records`.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transcript {
+    /// The wall-clock time the replay starts at.
+    pub started_at: DateTime<Utc>,
+    pub cycles: u64,
+    pub config: Config,
+    pub devices: Vec<DeviceSteps>,
+    /// From a replay found right, saved with `transcript --bless`.
+    #[serde(default)]
+    pub records: Vec<Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceSteps {
+    pub bus: u8,
+    pub address: u16,
+    pub steps: Vec<Step>,
+}
+
+/// One transaction with a device: what the driver sent and what it got
+/// back, or the fault it got instead. `stall_ms` is how long the device
+/// held the bus before answering.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Step {
+    Write {
+        bytes: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fault: Option<Fault>,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        stall_ms: u64,
+    },
+    /// Fewer `bytes` than `len` is a short read.
+    Read {
+        len: usize,
+        bytes: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fault: Option<Fault>,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        stall_ms: u64,
+    },
+    WriteRead {
+        write: Vec<u8>,
+        len: usize,
+        bytes: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fault: Option<Fault>,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        stall_ms: u64,
+    },
+    ReceiveByte {
+        byte: u8,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fault: Option<Fault>,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        stall_ms: u64,
+    },
+    /// The driver waiting for a conversion.
+    Delay { ms: u64 },
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+#[cfg(feature = "sim-test")]
+impl Step {
+    /// What the driver asked, which a replay must ask the same.
+    fn request(&self) -> String {
+        match self {
+            Step::Write { bytes, .. } => format!("write {:02X?}", bytes),
+            Step::Read { len, .. } => format!("read di {} byte", len),
+            Step::WriteRead { write, len, .. } => format!("write_read {:02X?} di {} byte", write, len),
+            Step::ReceiveByte { .. } => "receive_byte".to_string(),
+            Step::Delay { ms } => format!("attesa di {} ms", ms),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    /// No acknowledge, which the kernel reports as EREMOTEIO.
+    Nack,
+    /// Any other error, by its message.
+    Error(String),
+}
+
+impl Fault {
+    fn of(error: &(dyn Error + 'static)) -> Fault {
+        let io = error.downcast_ref::<io::Error>().or_else(|| match error.downcast_ref::<rppal::i2c::Error>() {
+            Some(rppal::i2c::Error::Io(e)) => Some(e),
+            _ => None,
+        });
+        match io.and_then(io::Error::raw_os_error) {
+            Some(libc::EREMOTEIO) => Fault::Nack,
+            _ => Fault::Error(error.to_string()),
+        }
+    }
+
+    #[cfg(feature = "sim-test")]
+    fn error(&self) -> Box<dyn Error> {
+        match self {
+            Fault::Nack => Box::new(io::Error::from_raw_os_error(libc::EREMOTEIO)),
+            Fault::Error(message) => message.clone().into(),
+        }
+    }
+}
+
+impl Transcript {
+    #[cfg(feature = "sim-test")]
+    pub fn load(path: &Path) -> Result<Transcript, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// As JSON with one step or record per line, so that a diff of two
+    /// fixtures shows the transactions that changed.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let config = serde_json::to_string_pretty(&self.config).map_err(|e| e.to_string())?;
+        let mut text = format!(
+            "{{\n  \"started_at\": {},\n  \"cycles\": {},\n  \"config\": {},\n  \"devices\": [",
+            json!(self.started_at),
+            self.cycles,
+            config.replace('\n', "\n  ")
+        );
+        for (index, device) in self.devices.iter().enumerate() {
+            let separator = if index == 0 { "" } else { "," };
+            let (bus, address) = (device.bus, device.address);
+            text += &format!("{}\n    {{\"bus\": {}, \"address\": {}, \"steps\": [", separator, bus, address);
+            text += &lines(&device.steps, 6)?;
+            text += "]}";
+        }
+        text += "\n  ],\n  \"records\": [";
+        text += &lines(&self.records, 4)?;
+        text += "]\n}\n";
+        fs::write(path, text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+fn lines<T: Serialize>(items: &[T], indent: usize) -> Result<String, String> {
+    if items.is_empty() {
+        return Ok(String::new());
+    }
+    let items: Result<Vec<String>, _> = items.iter().map(serde_json::to_string).collect();
+    let items = items.map_err(|e| e.to_string())?;
+    let pad = " ".repeat(indent);
+    Ok(format!("\n{}{}\n{}", pad, items.join(&format!(",\n{}", pad)), &pad[2..]))
+}
+
+struct Recorded {
+    started_at: DateTime<Utc>,
+    config: Config,
+    devices: BTreeMap<(u8, u16), Vec<Step>>,
+}
+
+/// The transactions of a live session, kept by the transports `wrap`
+/// returns until `save`.
+#[derive(Clone)]
+pub struct Recording {
+    recorded: Arc<Mutex<Recorded>>,
+}
+
+impl Recording {
+    pub fn new(config: Config, started_at: DateTime<Utc>) -> Recording {
+        let recorded = Recorded { started_at, config, devices: BTreeMap::new() };
+        Recording { recorded: Arc::new(Mutex::new(recorded)) }
+    }
+
+    pub fn wrap(&self, bus: u8, transport: Box<dyn Transport>) -> Box<dyn Transport> {
+        Box::new(Recorder { bus, inner: transport, selected: 0, recording: self.clone() })
+    }
+
+    /// Writes the transcript of the `cycles` cycles run so far; returns
+    /// the number of steps.
+    pub fn save(&self, path: &Path, cycles: u64) -> Result<usize, String> {
+        let recorded = self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        let devices: Vec<DeviceSteps> = recorded
+            .devices
+            .iter()
+            .map(|(&(bus, address), steps)| DeviceSteps { bus, address, steps: steps.clone() })
+            .collect();
+        let steps = devices.iter().map(|device| device.steps.len()).sum();
+        let transcript = Transcript {
+            started_at: recorded.started_at,
+            cycles,
+            config: recorded.config.clone(),
+            devices,
+            records: Vec::new(),
+        };
+        transcript.save(path)?;
+        Ok(steps)
+    }
+}
+
+/// A transport that passes everything on and notes it in `recording`.
+struct Recorder {
+    bus: u8,
+    inner: Box<dyn Transport>,
+    selected: u16,
+    recording: Recording,
+}
+
+impl Recorder {
+    fn push(&self, step: Step) {
+        let mut recorded = self.recording.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        recorded.devices.entry((self.bus, self.selected)).or_default().push(step);
+    }
+}
+
+fn fault<T>(result: &Result<T, Box<dyn Error>>) -> Option<Fault> {
+    result.as_ref().err().map(|e| Fault::of(e.as_ref()))
+}
+
+fn stall_since(started: Instant) -> u64 {
+    started.elapsed().as_millis() as u64
+}
+
+impl Transport for Recorder {
+    fn set_slave_address(&mut self, address: u16) -> Result<(), Box<dyn Error>> {
+        self.selected = address;
+        self.inner.set_slave_address(address)
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, Box<dyn Error>> {
+        let started = Instant::now();
+        let result = self.inner.write(buffer);
+        self.push(Step::Write { bytes: buffer.to_vec(), fault: fault(&result), stall_ms: stall_since(started) });
+        result
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Box<dyn Error>> {
+        let started = Instant::now();
+        let result = self.inner.read(buffer);
+        let read = result.as_ref().map_or(0, |&read| read.min(buffer.len()));
+        let stall_ms = stall_since(started);
+        self.push(Step::Read { len: buffer.len(), bytes: buffer[..read].to_vec(), fault: fault(&result), stall_ms });
+        result
+    }
+
+    fn write_read(&mut self, write_buffer: &[u8], read_buffer: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        let started = Instant::now();
+        let result = self.inner.write_read(write_buffer, read_buffer);
+        self.push(Step::WriteRead {
+            write: write_buffer.to_vec(),
+            len: read_buffer.len(),
+            bytes: if result.is_ok() { read_buffer.to_vec() } else { Vec::new() },
+            fault: fault(&result),
+            stall_ms: stall_since(started),
+        });
+        result
+    }
+
+    fn smbus_receive_byte(&mut self) -> Result<u8, Box<dyn Error>> {
+        let started = Instant::now();
+        let result = self.inner.smbus_receive_byte();
+        let byte = *result.as_ref().unwrap_or(&0);
+        self.push(Step::ReceiveByte { byte, fault: fault(&result), stall_ms: stall_since(started) });
+        result
+    }
+
+    fn delay(&mut self, duration: Duration) {
+        self.inner.delay(duration);
+        self.push(Step::Delay { ms: duration.as_millis() as u64 });
+    }
+}
+
+/// Where a replay stands: the steps left by device, and the transactions
+/// that were not the ones recorded.
+#[cfg(feature = "sim-test")]
+struct Progress {
+    steps: BTreeMap<(u8, u16), VecDeque<Step>>,
+    mismatches: Vec<String>,
+}
+
+/// A bus answering from a transcript, with the stalls and the waits
+/// advancing `clock`. A transaction other than the next one recorded for
+/// the device fails as a bus error would, and is noted.
+#[cfg(feature = "sim-test")]
+struct ReplayBus {
+    bus: u8,
+    selected: u16,
+    progress: Arc<Mutex<Progress>>,
+    clock: Arc<VirtualClock>,
+}
+
+#[cfg(feature = "sim-test")]
+impl ReplayBus {
+    /// The next step of the selected device, if `request` is what it asked.
+    fn take(&mut self, request: String) -> Result<Step, Box<dyn Error>> {
+        let mut progress = self.progress.lock().unwrap_or_else(|e| e.into_inner());
+        let device = format!("bus {}, 0x{:02X}", self.bus, self.selected);
+        let key = (self.bus, self.selected);
+        let mismatch = match progress.steps.get(&key).and_then(VecDeque::front) {
+            Some(step) if step.request() == request => None,
+            Some(step) => Some(format!("{}: {} al posto di {}", device, request, step.request())),
+            None => Some(format!("{}: {} oltre la fine della trascrizione", device, request)),
+        };
+        if let Some(mismatch) = mismatch {
+            progress.mismatches.push(mismatch.clone());
+            return Err(mismatch.into());
+        }
+        let step = progress.steps.get_mut(&key).and_then(VecDeque::pop_front).ok_or("trascrizione esaurita")?;
+        if let Step::Write { stall_ms, .. }
+        | Step::Read { stall_ms, .. }
+        | Step::WriteRead { stall_ms, .. }
+        | Step::ReceiveByte { stall_ms, .. } = &step
+        {
+            self.clock.sleep(Duration::from_millis(*stall_ms));
+        }
+        Ok(step)
+    }
+}
+
+#[cfg(feature = "sim-test")]
+impl Transport for ReplayBus {
+    fn set_slave_address(&mut self, address: u16) -> Result<(), Box<dyn Error>> {
+        self.selected = address;
+        Ok(())
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, Box<dyn Error>> {
+        let request = Step::Write { bytes: buffer.to_vec(), fault: None, stall_ms: 0 }.request();
+        match self.take(request)? {
+            Step::Write { fault: Some(fault), .. } => Err(fault.error()),
+            _ => Ok(buffer.len()),
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Box<dyn Error>> {
+        let request = Step::Read { len: buffer.len(), bytes: Vec::new(), fault: None, stall_ms: 0 }.request();
+        match self.take(request)? {
+            Step::Read { fault: Some(fault), .. } => Err(fault.error()),
+            Step::Read { bytes, .. } => {
+                let read = bytes.len().min(buffer.len());
+                buffer[..read].copy_from_slice(&bytes[..read]);
+                Ok(read)
+            }
+            _ => unreachable!("take matches the request"),
+        }
+    }
+
+    fn write_read(&mut self, write_buffer: &[u8], read_buffer: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        let (write, len) = (write_buffer.to_vec(), read_buffer.len());
+        let request = Step::WriteRead { write, len, bytes: Vec::new(), fault: None, stall_ms: 0 }.request();
+        match self.take(request)? {
+            Step::WriteRead { fault: Some(fault), .. } => Err(fault.error()),
+            Step::WriteRead { bytes, .. } => {
+                let read = bytes.len().min(read_buffer.len());
+                read_buffer[..read].copy_from_slice(&bytes[..read]);
+                Ok(())
+            }
+            _ => unreachable!("take matches the request"),
+        }
+    }
+
+    fn smbus_receive_byte(&mut self) -> Result<u8, Box<dyn Error>> {
+        let request = Step::ReceiveByte { byte: 0, fault: None, stall_ms: 0 }.request();
+        match self.take(request)? {
+            Step::ReceiveByte { fault: Some(fault), .. } => Err(fault.error()),
+            Step::ReceiveByte { byte, .. } => Ok(byte),
+            _ => unreachable!("take matches the request"),
+        }
+    }
+
+    fn delay(&mut self, duration: Duration) {
+        // A wait other than the recorded one is noted by take; the
+        // driver waits all the same.
+        let _ = self.take(Step::Delay { ms: duration.as_millis() as u64 }.request());
+        self.clock.sleep(duration);
+    }
+}
+
+/// Replays the fixture at `path`, with the service's files in a temporary
+/// directory, and compares the data records with those it expects; with
+/// `bless` saves them in the fixture instead.
+#[cfg(feature = "sim-test")]
+pub fn check(path: &Path, bless: bool) -> Result<String, String> {
+    let mut transcript = Transcript::load(path)?;
+    let dir = std::env::temp_dir().join(format!("sensor-program-transcript-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let records = replay(&transcript, &dir);
+    let _ = fs::remove_dir_all(&dir);
+    let records = records?;
+    if bless {
+        let count = records.len();
+        transcript.records = records.iter().map(|record| Value::Object(host_free(record))).collect();
+        transcript.save(path)?;
+        return Ok(format!("{} cicli, {} record salvati come attesi", transcript.cycles, count));
+    }
+    if transcript.records.is_empty() {
+        return Err(format!("nessun record atteso, {} riprodotti: verificarli e salvarli con --bless", records.len()));
+    }
+    compare(&transcript.records, &records)?;
+    Ok(format!("{} cicli, {} record uguali agli attesi", transcript.cycles, records.len()))
+}
+
+/// Runs the service on the buses of `transcript` for its cycles, with the
+/// sensors and outputs `soak` leaves out removed, and returns the data
+/// records it wrote. Fails on any transaction the transcript does not have
+/// or left unused.
+#[cfg(feature = "sim-test")]
+fn replay(transcript: &Transcript, dir: &Path) -> Result<Vec<Value>, String> {
+    let mut config = soak::soak_config(transcript.config.clone(), dir);
+    // Not the build time, which would make the records depend on the build.
+    config.time.floor.get_or_insert(transcript.started_at);
+    let errors = config.validate();
+    if !errors.is_empty() {
+        return Err(format!("configurazione non valida: {}", errors.join("; ")));
+    }
+
+    let clock = Arc::new(VirtualClock::starting_at(transcript.started_at));
+    let steps = transcript
+        .devices
+        .iter()
+        .map(|device| ((device.bus, device.address), device.steps.iter().cloned().collect()))
+        .collect();
+    let progress = Arc::new(Mutex::new(Progress { steps, mismatches: Vec::new() }));
+    let mut numbers: Vec<u8> = config.i2c_devices().iter().map(|&(_, bus, _)| bus).collect();
+    numbers.extend(transcript.devices.iter().map(|device| device.bus));
+    numbers.sort();
+    numbers.dedup();
+    let mut buses = Buses::default();
+    for bus in numbers {
+        let transport = ReplayBus { bus, selected: 0, progress: Arc::clone(&progress), clock: Arc::clone(&clock) };
+        buses.insert(Bus::with_transport(bus, Box::new(transport)));
+    }
+
+    let session = session::start(&config.session, None, clock.utc());
+    let data_path = config.output.path_for(&session.id);
+    let mut service = Service::new(config.clone(), false, buses, session, clock.clone())?;
+    let started = service.start();
+    let chunk = (config.output.queue.capacity.min(config.events.queue.capacity) / 4).max(1) as u64;
+    let mut cycles = 0;
+    while started.is_ok() && cycles < transcript.cycles && service.fatal().is_none() {
+        let count = chunk.min(transcript.cycles - cycles);
+        service.run(&Signals::default(), Path::new(""), &[], Some(count));
+        cycles += count;
+        service.wait_for_sinks(soak::DRAIN_TIMEOUT);
+    }
+    service.shutdown("transcript");
+
+    let progress = progress.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(first) = progress.mismatches.first() {
+        let count = progress.mismatches.len();
+        return Err(format!("{} transazioni diverse dalla trascrizione, la prima: {}", count, first));
+    }
+    started?;
+    let left: usize = progress.steps.values().map(VecDeque::len).sum();
+    if left > 0 {
+        return Err(format!("{} transazioni della trascrizione non eseguite in {} cicli", left, cycles));
+    }
+    let content = fs::read_to_string(&data_path).map_err(|e| format!("{}: {}", data_path, e))?;
+    let mut records = Vec::new();
+    for line in content.lines() {
+        let value: Value = serde_json::from_str(line).map_err(|e| format!("{}: {}", data_path, e))?;
+        if value.get("type").is_none() {
+            records.push(value);
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(feature = "sim-test")]
+fn host_free(record: &Value) -> serde_json::Map<String, Value> {
+    let mut fields = record.as_object().cloned().unwrap_or_default();
+    fields.retain(|key, _| !HOST_FIELDS.contains(&key.as_str()));
+    fields
+}
+
+/// The first difference between the records of a replay and the expected
+/// ones, by flat-layout key.
+#[cfg(feature = "sim-test")]
+fn compare(expected: &[Value], actual: &[Value]) -> Result<(), String> {
+    let flat = |record: &Value| record::flatten(host_free(record));
+    for (index, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+        let (expected, actual) = (flat(expected), flat(actual));
+        let keys: std::collections::BTreeSet<&String> = expected.keys().chain(actual.keys()).collect();
+        for key in keys {
+            let (want, got) = (expected.get(key), actual.get(key));
+            if want != got {
+                let show = |value: Option<&Value>| value.map_or("assente".to_string(), Value::to_string);
+                return Err(format!("record {}: {} è {} invece di {}", index, key, show(got), show(want)));
+            }
+        }
+    }
+    if expected.len() != actual.len() {
+        return Err(format!("{} record invece di {}", actual.len(), expected.len()));
+    }
+    Ok(())
+}
@@ -0,0 +1,237 @@
+use crossterm::cursor::{Hide, MoveTo, MoveToNextLine, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::style::Print;
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::compact::CompactConfig;
+use crate::live::{LiveState, Measurement};
+
+/// How often the dashboard is drawn and the keyboard looked at.
+const REFRESH: Duration = Duration::from_millis(250);
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// The dashboard thread of `--tui`.
+pub struct Dashboard {
+    closing: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Dashboard {
+    /// Puts the terminal back, e.g. before the service exits.
+    pub fn close(self) {
+        self.closing.store(true, Ordering::Relaxed);
+        let _ = self.thread.join();
+    }
+}
+
+/// Draws `live` until `q` is pressed, `close` is called or `stop` is set.
+/// Ctrl-C, which the terminal no longer turns into SIGINT, sets `stop`;
+/// so does `q` unless `keeps_logging`, i.e. when nothing but the console
+/// would have shown the data.
+pub fn spawn(live: Arc<Mutex<LiveState>>, stop: Arc<AtomicBool>, keeps_logging: bool) -> Dashboard {
+    let closing = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&closing);
+    let thread = thread::spawn(move || match show(&live, &stop, &flag) {
+        Ok(Exit::Quit) if keeps_logging => {
+            println!("Dashboard chiusa, la registrazione continua (SIGINT/SIGTERM per arrestare)");
+        }
+        Ok(Exit::Quit | Exit::Stop) => stop.store(true, Ordering::Relaxed),
+        Ok(Exit::Closed) => {}
+        Err(e) => eprintln!("Dashboard non disponibile: {}", e),
+    });
+    Dashboard { closing, thread }
+}
+
+enum Exit {
+    /// `q`: the dashboard only.
+    Quit,
+    /// Ctrl-C: the service too.
+    Stop,
+    /// The service is stopping.
+    Closed,
+}
+
+fn show(live: &Mutex<LiveState>, stop: &AtomicBool, closing: &AtomicBool) -> io::Result<Exit> {
+    let mut screen = Screen::open()?;
+    terminal::enable_raw_mode()?;
+    execute!(screen.terminal, EnterAlternateScreen, Hide)?;
+    let result = (|| {
+        while !stop.load(Ordering::Relaxed) && !closing.load(Ordering::Relaxed) {
+            let (width, height) = terminal::size()?;
+            let lines = render(&live.lock().unwrap_or_else(|e| e.into_inner()), width as usize);
+            draw(&mut screen.terminal, &lines, height as usize)?;
+            if !event::poll(REFRESH)? {
+                continue;
+            }
+            if let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+            {
+                match key.code {
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(Exit::Stop),
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(Exit::Quit),
+                    _ => {}
+                }
+            }
+        }
+        Ok(Exit::Closed)
+    })();
+    let _ = execute!(screen.terminal, LeaveAlternateScreen, Show);
+    let _ = terminal::disable_raw_mode();
+    result
+}
+
+/// Every line in place of the previous frame's, so the terminal never scrolls.
+fn draw(out: &mut File, lines: &[String], height: usize) -> io::Result<()> {
+    queue!(out, MoveTo(0, 0))?;
+    for line in lines.iter().take(height.saturating_sub(1)) {
+        queue!(out, Print(line), Clear(ClearType::UntilNewLine), MoveToNextLine(1))?;
+    }
+    queue!(out, Clear(ClearType::FromCursorDown))?;
+    out.flush()
+}
+
+fn render(live: &LiveState, width: usize) -> Vec<String> {
+    let mut lines = vec![
+        format!(
+            "sensor-program {}  sessione {}  boot {}  attivo da {}",
+            env!("CARGO_PKG_VERSION"),
+            live.session_id,
+            live.boot_id,
+            uptime(live.uptime_secs)
+        ),
+        format!(
+            "record {}  {}  stato di volo {:?}{}{}",
+            live.sequence.map_or("-".to_string(), |sequence| sequence.to_string()),
+            live.timestamp.map_or("-".to_string(), |timestamp| timestamp.format("%Y-%m-%d %H:%M:%S UTC").to_string()),
+            live.flight_state,
+            if live.burst_mode { "  BURST" } else { "" },
+            if live.warmup { "  RISCALDAMENTO" } else { "" }
+        ),
+    ];
+    if let Some(bus) = live.waiting_for_bus {
+        lines.push(format!("In attesa del bus I2C {}: modalità ridotta, nessuna lettura I2C", bus));
+    }
+
+    lines.push(String::new());
+    lines.push(format!("{:<28} {:>12} {:>12} {:>12}  andamento", "misura", "valore", "min", "max"));
+    let decimals = CompactConfig::default().decimals;
+    let spark_width = width.saturating_sub(28 + 3 * 13 + 2);
+    for (key, measurement) in &live.measurements {
+        let decimals = decimals.get(&measurement.unit).copied().unwrap_or(2);
+        let number = |value: f64| format!("{:.*} {}", decimals, value, measurement.unit);
+        let value = measurement.value.map_or("errore".to_string(), number);
+        let (min, max) = if measurement.min <= measurement.max {
+            (number(measurement.min), number(measurement.max))
+        } else {
+            ("-".to_string(), "-".to_string())
+        };
+        let history = sparkline(measurement, spark_width);
+        let mut line = format!("{:<28} {:>12} {:>12} {:>12}  {}", key, value, min, max, history);
+        if measurement.suspect {
+            line.push_str("  SOSPETTO");
+        }
+        lines.push(line);
+    }
+
+    lines.push(String::new());
+    lines.push(format!("{:<24} {:<7} {:>7}  ultimo errore", "sensore", "stato", "errori"));
+    for (name, status) in &live.sensors {
+        let state = if status.ok { "OK" } else { "ERRORE" };
+        let error = status.last_error.as_deref().unwrap_or("");
+        lines.push(format!("{:<24} {:<7} {:>7}  {}", name, state, status.errors, error));
+    }
+
+    lines.push(String::new());
+    lines.push(format!(
+        "{:<16} {:>11} {:>10} {:>8} {:>8}  collegamento",
+        "uscita", "coda", "scritti", "falliti", "persi"
+    ));
+    for sink in &live.sinks {
+        let link = match &sink.link {
+            Some(link) if link.connected => "connesso".to_string(),
+            Some(link) => format!("scollegato: {}", link.last_error.as_deref().unwrap_or("")),
+            None => String::new(),
+        };
+        let queue = format!("{}/{}", sink.depth, sink.capacity);
+        lines.push(format!(
+            "{:<16} {:>11} {:>10} {:>8} {:>8}  {}",
+            sink.name, queue, sink.written, sink.failed, sink.dropped, link
+        ));
+    }
+
+    lines.push(String::new());
+    lines.push("q: chiude la dashboard   Ctrl-C: arresta il servizio".to_string());
+    lines.into_iter().map(|line| line.chars().take(width).collect()).collect()
+}
+
+/// The last `width` values, each scaled between the lowest and highest of them.
+fn sparkline(measurement: &Measurement, width: usize) -> String {
+    let values: Vec<f64> = measurement.history.iter().rev().take(width).rev().copied().collect();
+    let low = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let high = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    values
+        .iter()
+        .map(|value| {
+            let level = if high > low { (value - low) / (high - low) * (SPARKS.len() - 1) as f64 } else { 0.0 };
+            SPARKS[level.round() as usize]
+        })
+        .collect()
+}
+
+fn uptime(secs: u64) -> String {
+    format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// While the dashboard is up, whatever the service prints would scroll it
+/// away: stdout and stderr go to /dev/null and the dashboard draws on a
+/// copy of the original stdout. Both are put back on drop.
+struct Screen {
+    terminal: File,
+    saved: Vec<(i32, i32)>,
+}
+
+impl Screen {
+    fn open() -> io::Result<Screen> {
+        let null = OpenOptions::new().write(true).open("/dev/null")?;
+        io::stdout().flush()?;
+        // SAFETY: dup takes any descriptor and the copy is owned by the File.
+        let copy = unsafe { libc::dup(libc::STDOUT_FILENO) };
+        if copy < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `copy` is a fresh descriptor nothing else owns.
+        let mut screen = Screen { terminal: unsafe { File::from_raw_fd(copy) }, saved: Vec::new() };
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            // SAFETY: dup and dup2 only take descriptors; the copies are closed on drop.
+            let saved = unsafe { libc::dup(fd) };
+            if saved < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            screen.saved.push((fd, saved));
+            if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        for &(fd, saved) in &self.saved {
+            // SAFETY: `saved` is the copy made in `open`, closed only here.
+            unsafe {
+                libc::dup2(saved, fd);
+                libc::close(saved);
+            }
+        }
+    }
+}
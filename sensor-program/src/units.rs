@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::exec::ExecConfig;
+
+/// Units of the values the service records or sends, named as in the
+/// configuration and in the header record.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Unit {
+    #[serde(rename = "degC")]
+    Celsius,
+    #[serde(rename = "degF")]
+    Fahrenheit,
+    #[serde(rename = "hPa")]
+    Hectopascal,
+    #[serde(rename = "Pa")]
+    Pascal,
+    #[serde(rename = "inHg")]
+    InchOfMercury,
+    #[serde(rename = "m")]
+    Metre,
+    #[serde(rename = "ft")]
+    Foot,
+    #[serde(rename = "m/s")]
+    MetrePerSecond,
+    #[serde(rename = "ft/s")]
+    FootPerSecond,
+    #[serde(rename = "ms")]
+    Millisecond,
+    #[serde(rename = "V")]
+    Volt,
+    /// Raw ADC output, with no physical unit.
+    #[serde(rename = "adc")]
+    Adc,
+    /// For `[[exec]]` measurements the service knows nothing about.
+    #[serde(rename = "other")]
+    Other,
+}
+
+/// What a unit measures; only units of the same quantity convert.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantity {
+    Temperature,
+    Pressure,
+    Length,
+    Speed,
+    Time,
+    Voltage,
+    Raw,
+    Unknown,
+}
+
+impl Unit {
+    pub fn quantity(self) -> Quantity {
+        match self {
+            Unit::Celsius | Unit::Fahrenheit => Quantity::Temperature,
+            Unit::Hectopascal | Unit::Pascal | Unit::InchOfMercury => Quantity::Pressure,
+            Unit::Metre | Unit::Foot => Quantity::Length,
+            Unit::MetrePerSecond | Unit::FootPerSecond => Quantity::Speed,
+            Unit::Millisecond => Quantity::Time,
+            Unit::Volt => Quantity::Voltage,
+            Unit::Adc => Quantity::Raw,
+            Unit::Other => Quantity::Unknown,
+        }
+    }
+
+    /// (scale, offset) to the reference unit of the quantity: degC, hPa,
+    /// m, m/s; the others are their own reference.
+    fn to_reference(self) -> (f64, f64) {
+        match self {
+            Unit::Fahrenheit => (5.0 / 9.0, -32.0 * 5.0 / 9.0),
+            Unit::Pascal => (0.01, 0.0),
+            Unit::InchOfMercury => (33.863_886_666_7, 0.0),
+            Unit::Foot | Unit::FootPerSecond => (0.3048, 0.0),
+            _ => (1.0, 0.0),
+        }
+    }
+
+    /// Converts an absolute value (not a difference) between units of the
+    /// same known quantity.
+    pub fn convert(self, value: f64, to: Unit) -> Option<f64> {
+        if self.quantity() != to.quantity() || self.quantity() == Quantity::Unknown {
+            return None;
+        }
+        let (scale, offset) = self.to_reference();
+        let (to_scale, to_offset) = to.to_reference();
+        Some((value * scale + offset - to_offset) / to_scale)
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(name)) => f.write_str(&name),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// The unit of the flat-layout `key` in `units` from `record_units`,
+/// matching the `_*` patterns too.
+pub fn unit_for(units: &BTreeMap<String, Unit>, key: &str) -> Option<Unit> {
+    let unit = units.get(key).or_else(|| {
+        units.iter().find_map(|(pattern, unit)| key.starts_with(pattern.strip_suffix('*')?).then_some(unit))
+    })?;
+    Some(*unit)
+}
+
+/// The unit of every numeric field of a data record, by flat-layout key.
+/// Keys ending in `_*` stand for the open-ended members of an object.
+pub fn record_units(exec: &[ExecConfig]) -> BTreeMap<String, Unit> {
+    let mut units: BTreeMap<String, Unit> = [
+        ("clock_offset_ms", Unit::Millisecond),
+        ("ms5611_d1", Unit::Adc),
+        ("ms5611_d2", Unit::Adc),
+        ("ms5611_samples", Unit::Adc),
+        ("ms5611_temperature", Unit::Celsius),
+        ("ms5611_temperature_spread", Unit::Celsius),
+        ("ms5611_pressure", Unit::Hectopascal),
+        ("ms5611_pressure_spread", Unit::Hectopascal),
+        ("ds18b20_1", Unit::Celsius),
+        ("ds18b20_2", Unit::Celsius),
+        ("ds18b20_extra_*", Unit::Celsius),
+        ("altitude_m", Unit::Metre),
+        ("vertical_speed_ms", Unit::MetrePerSecond),
+        ("capture_offsets_ms_*", Unit::Millisecond),
+        ("acquisition_offsets_ms_*", Unit::Millisecond),
+        ("timing_*", Unit::Millisecond),
+        ("voting_ms5611_d1", Unit::Adc),
+        ("voting_ms5611_d2", Unit::Adc),
+        ("voting_ms5611_temperature", Unit::Celsius),
+        ("voting_ms5611_pressure", Unit::Hectopascal),
+        ("voting_ms5611_secondary_d1", Unit::Adc),
+        ("voting_ms5611_secondary_d2", Unit::Adc),
+        ("voting_ms5611_secondary_temperature", Unit::Celsius),
+        ("voting_ms5611_secondary_pressure", Unit::Hectopascal),
+        ("voting_difference_hpa", Unit::Hectopascal),
+    ]
+    .into_iter()
+    .map(|(key, unit)| (key.to_string(), unit))
+    .collect();
+    for sensor in exec {
+        for name in sensor.measurements() {
+            units.insert(format!("exec_{}", name), sensor.units.get(name).copied().unwrap_or(Unit::Other));
+        }
+    }
+    units
+}
@@ -0,0 +1,310 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::config::Ms5611Config;
+use crate::ms5611;
+use crate::record::MS5611Data;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum PressureSource {
+    /// The sensor under `[ms5611]`.
+    Ms5611,
+    /// The sensor under `[voting.secondary]`.
+    Ms5611Secondary,
+}
+
+impl PressureSource {
+    /// The name used in sensor error messages.
+    pub fn sensor_name(self) -> &'static str {
+        match self {
+            PressureSource::Ms5611 => "MS5611",
+            PressureSource::Ms5611Secondary => "MS5611 secondario",
+        }
+    }
+
+    /// The flat-layout keys of its reading in the `voting` field, as the
+    /// stuck detector tracks them.
+    pub fn keys(self) -> [String; 4] {
+        let prefix = match self {
+            PressureSource::Ms5611 => "voting_ms5611",
+            PressureSource::Ms5611Secondary => "voting_ms5611_secondary",
+        };
+        ["d1", "d2", "temperature", "pressure"].map(|member| format!("{}_{}", prefix, member))
+    }
+}
+
+/// A second MS5611 read in every cycle next to the first. The two are
+/// compared, and the altitude and everything derived from it come from the
+/// first source in `preference` that read fine, within the range of the
+/// instrument and without measurements flagged as stuck; a flagged source
+/// is only kept when no other can be used.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct VotingConfig {
+    pub secondary: Ms5611Config,
+    /// Pressure difference above which the sensors disagree.
+    #[serde(default = "default_threshold_hpa")]
+    pub threshold_hpa: f64,
+    /// Samples in a row over the threshold before a record is flagged, and
+    /// good reads in a row before a preferred source is selected again.
+    #[serde(default = "default_consecutive")]
+    pub consecutive: u32,
+    #[serde(default = "default_preference")]
+    pub preference: Vec<PressureSource>,
+}
+
+fn default_threshold_hpa() -> f64 {
+    2.0
+}
+
+fn default_consecutive() -> u32 {
+    3
+}
+
+fn default_preference() -> Vec<PressureSource> {
+    vec![PressureSource::Ms5611, PressureSource::Ms5611Secondary]
+}
+
+/// The range the MS5611 is specified for; a reading outside it counts as
+/// a fault of the sensor.
+const PLAUSIBLE_HPA: std::ops::RangeInclusive<f64> = 10.0..=1200.0;
+
+impl VotingConfig {
+    pub fn validate(&self, primary: &Ms5611Config, interval_ms: u64) -> Vec<String> {
+        let mut errors = Vec::new();
+        let secondary = &self.secondary;
+        if ![0x76, 0x77].contains(&secondary.address) {
+            errors.push(format!("voting.secondary.address 0x{:X} non valido (0x76 o 0x77)", secondary.address));
+        }
+        if secondary.bus == primary.bus && secondary.address == primary.address {
+            errors.push("voting.secondary indica lo stesso sensore di [ms5611]".to_string());
+        }
+        if secondary.samples_per_cycle == 0 || secondary.samples_per_cycle > ms5611::MAX_SAMPLES_PER_CYCLE {
+            errors.push(format!(
+                "voting.secondary.samples_per_cycle deve essere tra 1 e {}",
+                ms5611::MAX_SAMPLES_PER_CYCLE
+            ));
+        } else {
+            let read_time_ms = ms5611::read_time_ms(primary.samples_per_cycle)
+                + ms5611::read_time_ms(secondary.samples_per_cycle);
+            if interval_ms < read_time_ms {
+                errors.push(format!(
+                    "sampling.interval_secs è inferiore al tempo di lettura dei due MS5611 ({} ms)",
+                    read_time_ms
+                ));
+            }
+        }
+        if !self.threshold_hpa.is_finite() || self.threshold_hpa <= 0.0 {
+            errors.push("voting.threshold_hpa deve essere maggiore di zero".to_string());
+        }
+        if self.consecutive == 0 {
+            errors.push("voting.consecutive deve essere maggiore di zero".to_string());
+        }
+        let mut sources = self.preference.clone();
+        sources.sort();
+        if sources != default_preference() {
+            errors.push("voting.preference deve elencare ms5611 e ms5611_secondary una volta ciascuno".to_string());
+        }
+        errors
+    }
+}
+
+/// The comparison as recorded in the `voting` field of a data record.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VotingData {
+    /// Where `ms5611`, and so the altitude, of this record come from.
+    pub source: PressureSource,
+    /// Each reading, null when it failed.
+    pub ms5611: Option<MS5611Data>,
+    pub ms5611_secondary: Option<MS5611Data>,
+    /// Absolute difference of the pressures, when both were read.
+    pub difference_hpa: Option<f64>,
+    /// The difference has been over the threshold for `consecutive` samples.
+    pub disagreement: bool,
+}
+
+#[derive(Debug)]
+pub enum Change {
+    Failover { from: PressureSource, to: PressureSource, reason: String },
+    Recovered { from: PressureSource, to: PressureSource },
+    Disagreement { difference_hpa: f64, samples: u32 },
+    Agreement { difference_hpa: Option<f64> },
+}
+
+pub struct Vote {
+    /// `None` when no source can be used.
+    pub source: Option<PressureSource>,
+    pub difference_hpa: Option<f64>,
+    pub disagreement: bool,
+    pub changes: Vec<Change>,
+}
+
+pub struct Voter {
+    config: VotingConfig,
+    selected: PressureSource,
+    /// Samples in a row with the difference over the threshold.
+    over: u32,
+    /// Good reads in a row, by source.
+    good: BTreeMap<PressureSource, u32>,
+}
+
+impl Voter {
+    pub fn new(config: VotingConfig) -> Self {
+        let selected = config.preference.first().copied().unwrap_or(PressureSource::Ms5611);
+        Voter { config, selected, over: 0, good: BTreeMap::new() }
+    }
+
+    /// Keeps the selected source and the counts.
+    pub fn set_config(&mut self, config: VotingConfig) {
+        self.config = config;
+    }
+
+    /// `suspect` are the keys the stuck detector flags, as in the record.
+    pub fn vote(&mut self, primary: Option<&MS5611Data>, secondary: Option<&MS5611Data>, suspect: &[String]) -> Vote {
+        let readings = [(PressureSource::Ms5611, primary), (PressureSource::Ms5611Secondary, secondary)];
+        let reading = |source: PressureSource| readings.iter().find(|(s, _)| *s == source).and_then(|(_, r)| *r);
+        let usable = |source: PressureSource| reading(source).is_some_and(|r| PLAUSIBLE_HPA.contains(&r.pressure));
+        let stuck = |source: PressureSource| source.keys().iter().any(|key| suspect.contains(key));
+        let healthy = |source: PressureSource| usable(source) && !stuck(source);
+        let mut changes = Vec::new();
+
+        let difference_hpa = primary.zip(secondary).map(|(a, b)| (a.pressure - b.pressure).abs());
+        let was_disagreeing = self.over >= self.config.consecutive;
+        match difference_hpa {
+            Some(difference) if difference > self.config.threshold_hpa => self.over += 1,
+            _ => self.over = 0,
+        }
+        let disagreement = self.over >= self.config.consecutive;
+        match (was_disagreeing, disagreement, difference_hpa) {
+            (false, true, Some(difference_hpa)) => {
+                changes.push(Change::Disagreement { difference_hpa, samples: self.over });
+            }
+            (true, false, difference_hpa) => changes.push(Change::Agreement { difference_hpa }),
+            _ => {}
+        }
+
+        for (source, _) in readings {
+            let good = self.good.entry(source).or_default();
+            *good = if healthy(source) { good.saturating_add(1) } else { 0 };
+        }
+        let first = |test: &dyn Fn(PressureSource) -> bool| self.config.preference.iter().copied().find(|&s| test(s));
+        if !healthy(self.selected) {
+            let to = first(&healthy).or_else(|| if usable(self.selected) { None } else { first(&usable) });
+            if let Some(to) = to {
+                let reason = match reading(self.selected) {
+                    Some(r) if usable(self.selected) => {
+                        format!("misure ferme sullo stesso valore (pressione {:.2} hPa)", r.pressure)
+                    }
+                    Some(r) => format!("pressione {:.2} hPa fuori dall'intervallo dello strumento", r.pressure),
+                    None => "lettura non riuscita".to_string(),
+                };
+                changes.push(Change::Failover { from: self.selected, to, reason });
+                self.selected = to;
+            }
+        } else if let Some(&preferred) = self.config.preference.iter().find(|&&source| {
+            self.good.get(&source).is_some_and(|&good| good >= self.config.consecutive)
+        }) && preferred != self.selected
+            && self.rank(preferred) < self.rank(self.selected)
+        {
+            changes.push(Change::Recovered { from: self.selected, to: preferred });
+            self.selected = preferred;
+        }
+
+        Vote {
+            source: usable(self.selected).then_some(self.selected),
+            difference_hpa,
+            disagreement,
+            changes,
+        }
+    }
+
+    fn rank(&self, source: PressureSource) -> usize {
+        self.config.preference.iter().position(|&s| s == source).unwrap_or(usize::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, PressureSource, Voter, VotingConfig};
+    use crate::record::MS5611Data;
+
+    use PressureSource::{Ms5611, Ms5611Secondary};
+
+    fn voter() -> Voter {
+        Voter::new(toml::from_str("secondary = { address = 0x76 }").unwrap())
+    }
+
+    fn reading(pressure: f64) -> MS5611Data {
+        MS5611Data { pressure, ..MS5611Data::default() }
+    }
+
+    #[test]
+    fn fails_over_and_recovers_after_consecutive_good_reads() {
+        let mut voter = voter();
+        let (good, other) = (reading(1000.0), reading(1000.5));
+        let vote = voter.vote(Some(&good), Some(&other), &[]);
+        assert_eq!(vote.source, Some(Ms5611));
+        assert!(vote.changes.is_empty());
+
+        let vote = voter.vote(None, Some(&other), &[]);
+        assert_eq!(vote.source, Some(Ms5611Secondary));
+        assert!(matches!(
+            &vote.changes[..],
+            [Change::Failover { from: Ms5611, to: Ms5611Secondary, reason }] if reason == "lettura non riuscita"
+        ));
+        for _ in 0..2 {
+            let vote = voter.vote(Some(&good), Some(&other), &[]);
+            assert_eq!((vote.source, vote.changes.len()), (Some(Ms5611Secondary), 0));
+        }
+        let vote = voter.vote(Some(&good), Some(&other), &[]);
+        assert_eq!(vote.source, Some(Ms5611));
+        assert!(matches!(&vote.changes[..], [Change::Recovered { from: Ms5611Secondary, to: Ms5611 }]));
+    }
+
+    #[test]
+    fn leaves_a_reading_out_of_range() {
+        let mut voter = voter();
+        let vote = voter.vote(Some(&reading(5.0)), Some(&reading(1000.0)), &[]);
+        assert_eq!(vote.source, Some(Ms5611Secondary));
+        assert!(matches!(&vote.changes[..], [Change::Failover { reason, .. }] if reason.contains("fuori")));
+        assert_eq!(voter.vote(Some(&reading(5.0)), Some(&reading(1300.0)), &[]).source, None);
+    }
+
+    #[test]
+    fn leaves_a_stuck_source_unless_no_other_is_left() {
+        let mut voter = voter();
+        let (a, b) = (reading(1000.0), reading(1000.5));
+        let stuck = Ms5611.keys()[3..].to_vec();
+        let vote = voter.vote(Some(&a), Some(&b), &stuck);
+        assert_eq!(vote.source, Some(Ms5611Secondary));
+        assert!(matches!(&vote.changes[..], [Change::Failover { reason, .. }] if reason.starts_with("misure ferme")));
+
+        // Both flagged: the secondary is kept rather than no source.
+        let both: Vec<String> = [Ms5611, Ms5611Secondary].iter().flat_map(|source| source.keys()).collect();
+        let vote = voter.vote(Some(&a), Some(&b), &both);
+        assert_eq!((vote.source, vote.changes.len()), (Some(Ms5611Secondary), 0));
+        // The secondary failing, the stuck primary is better than nothing.
+        let vote = voter.vote(Some(&a), None, &stuck);
+        assert_eq!(vote.source, Some(Ms5611));
+    }
+
+    #[test]
+    fn flags_a_disagreement_after_consecutive_samples_and_clears_it() {
+        let config: VotingConfig = toml::from_str("secondary = { address = 0x76 }\nthreshold_hpa = 2.0").unwrap();
+        let mut voter = Voter::new(config);
+        let (a, apart, close) = (reading(1000.0), reading(1003.0), reading(1000.5));
+        for _ in 0..2 {
+            let vote = voter.vote(Some(&a), Some(&apart), &[]);
+            assert!(!vote.disagreement && vote.changes.is_empty());
+        }
+        let vote = voter.vote(Some(&a), Some(&apart), &[]);
+        assert!(vote.disagreement);
+        assert!(matches!(&vote.changes[..], [Change::Disagreement { samples: 3, .. }]));
+        assert_eq!(vote.difference_hpa, Some(3.0));
+
+        let vote = voter.vote(Some(&a), Some(&close), &[]);
+        assert!(!vote.disagreement);
+        assert!(matches!(&vote.changes[..], [Change::Agreement { difference_hpa: Some(0.5) }]));
+    }
+}
@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// The first readings after power-on are off: the MS5611 self-heats and
+/// the first DS18B20 conversion is often the 85 °C reset value. For the
+/// first `samples` cycles and `secs` seconds, whichever ends later, every
+/// sensor is read as usual but the data records carry `warmup: true`, or
+/// with `discard` are not written at all, and are kept out of the flight
+/// tracker, setpoints, actions, burst mode, stuck detection, deadband,
+/// telemetry and `analyze`. Both 0 disables it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct WarmupConfig {
+    pub samples: u32,
+    pub secs: u64,
+    pub discard: bool,
+}
+
+impl WarmupConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.samples > 0 || self.secs > 0
+    }
+}
+
+/// Where a cycle stands in the warm-up.
+pub enum Phase {
+    WarmingUp,
+    /// The first cycle after the warm-up, which it is not part of.
+    Completed { samples: u32, waited: Duration, reason: &'static str },
+    Done,
+}
+
+/// Starts with the service and again whenever the sensors are set up anew,
+/// e.g. after the watchdog reopened the buses.
+pub struct Warmup {
+    since: Instant,
+    samples: u32,
+    reason: &'static str,
+    active: bool,
+}
+
+impl Warmup {
+    pub fn new(now: Instant) -> Self {
+        Warmup { since: now, samples: 0, reason: "startup", active: true }
+    }
+
+    pub fn restart(&mut self, now: Instant, reason: &'static str) {
+        *self = Warmup { reason, ..Warmup::new(now) };
+    }
+
+    /// Counts the cycle at `now` against the warm-up.
+    pub fn cycle(&mut self, config: &WarmupConfig, now: Instant) -> Phase {
+        if !self.active {
+            return Phase::Done;
+        }
+        if !config.is_enabled() {
+            self.active = false;
+            return Phase::Done;
+        }
+        let waited = now.saturating_duration_since(self.since);
+        if self.samples < config.samples || waited < Duration::from_secs(config.secs) {
+            self.samples += 1;
+            return Phase::WarmingUp;
+        }
+        self.active = false;
+        Phase::Completed { samples: self.samples, waited, reason: self.reason }
+    }
+}
@@ -0,0 +1,250 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::events::{Event, Severity};
+use crate::pipeline::{EventSender, Output};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchdogAction {
+    /// Abort the process so that the service manager restarts it.
+    #[default]
+    Abort,
+    /// Ask the loop to reopen the I2C buses; abort if it is still stuck
+    /// one timeout later.
+    Reinit,
+}
+
+/// The loop counts as stuck when no cycle has completed for
+/// `timeout_factor` times the current sampling interval, and never before
+/// `min_timeout_secs`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchdogConfig {
+    pub enabled: bool,
+    pub timeout_factor: f64,
+    pub min_timeout_secs: u64,
+    pub action: WatchdogAction,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig { enabled: true, timeout_factor: 10.0, min_timeout_secs: 60, action: WatchdogAction::default() }
+    }
+}
+
+impl WatchdogConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if !self.timeout_factor.is_finite() || self.timeout_factor < 2.0 {
+            errors.push("watchdog.timeout_factor deve essere almeno 2".to_string());
+        }
+        errors
+    }
+}
+
+/// Where the sampling loop is, for the diagnostics of a stuck loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Stage {
+    Idle,
+    Preparing,
+    Reading,
+    Processing,
+    Writing,
+    Reloading,
+}
+
+impl Stage {
+    const ALL: [Stage; 6] =
+        [Stage::Idle, Stage::Preparing, Stage::Reading, Stage::Processing, Stage::Writing, Stage::Reloading];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Idle => "attesa del ciclo successivo",
+            Stage::Preparing => "preparazione (batteria, orologio, scansione 1-Wire)",
+            Stage::Reading => "lettura sensori",
+            Stage::Processing => "calcolo dei valori derivati",
+            Stage::Writing => "scrittura dei record",
+            Stage::Reloading => "ricaricamento configurazione",
+        }
+    }
+}
+
+/// Sensors whose last good read is reported, by the name used in events.
+const SENSORS: [&str; 4] = ["MS5611", "DS18B20 1", "DS18B20 2", "INA219"];
+
+/// Published by the loop and read by the supervisor thread, with atomics
+/// only: a loop stuck in a read must not be able to block the supervisor.
+/// Times are milliseconds since `base`, 0 meaning never.
+pub struct Heartbeat {
+    base: Instant,
+    last_cycle_ms: AtomicU64,
+    interval_ms: AtomicU64,
+    cycles: AtomicU64,
+    stage: AtomicU8,
+    last_success_ms: [AtomicU64; SENSORS.len()],
+    reinit_requested: AtomicBool,
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration) -> Arc<Heartbeat> {
+        let heartbeat = Heartbeat {
+            base: Instant::now(),
+            last_cycle_ms: AtomicU64::new(0),
+            interval_ms: AtomicU64::new(interval.as_millis() as u64),
+            cycles: AtomicU64::new(0),
+            stage: AtomicU8::new(Stage::Idle as u8),
+            last_success_ms: Default::default(),
+            reinit_requested: AtomicBool::new(false),
+        };
+        heartbeat.last_cycle_ms.store(heartbeat.now_ms(), Ordering::Relaxed);
+        Arc::new(heartbeat)
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.base.elapsed().as_millis() as u64 + 1
+    }
+
+    pub fn enter(&self, stage: Stage) {
+        self.stage.store(stage as u8, Ordering::Relaxed);
+    }
+
+    /// A cycle completed; the next one is due after `interval`.
+    pub fn cycle_done(&self, interval: Duration) {
+        self.interval_ms.store(interval.as_millis() as u64, Ordering::Relaxed);
+        self.cycles.fetch_add(1, Ordering::Relaxed);
+        self.enter(Stage::Idle);
+        self.last_cycle_ms.store(self.now_ms(), Ordering::Relaxed);
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles.load(Ordering::Relaxed)
+    }
+
+    pub fn sensor_ok(&self, sensor: &str) {
+        if let Some(index) = SENSORS.iter().position(|name| *name == sensor) {
+            self.last_success_ms[index].store(self.now_ms(), Ordering::Relaxed);
+        }
+    }
+
+    /// True once after the supervisor asked for the buses to be reopened.
+    pub fn take_reinit(&self) -> bool {
+        self.reinit_requested.swap(false, Ordering::Relaxed)
+    }
+
+    fn stage(&self) -> Stage {
+        let stage = self.stage.load(Ordering::Relaxed);
+        Stage::ALL.into_iter().find(|s| *s as u8 == stage).unwrap_or(Stage::Idle)
+    }
+
+    fn since_ms(&self, at: &AtomicU64) -> Option<u64> {
+        let at = at.load(Ordering::Relaxed);
+        (at > 0).then(|| self.now_ms().saturating_sub(at))
+    }
+}
+
+/// Starts the supervisor thread. `events` is `None` in dry-run, where the
+/// diagnostics only go to stderr.
+pub fn spawn(heartbeat: Arc<Heartbeat>, config: WatchdogConfig, events: Option<EventSender>, session_id: String) {
+    let supervisor = move || {
+        // The cycle count when the loop was found stuck and what was done.
+        let mut stuck_at: Option<(u64, Instant)> = None;
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            let interval_ms = heartbeat.interval_ms.load(Ordering::Relaxed) as f64;
+            let timeout_ms = (interval_ms * config.timeout_factor).max(config.min_timeout_secs as f64 * 1000.0);
+            let timeout = Duration::from_millis(timeout_ms as u64);
+            let cycles = heartbeat.cycles.load(Ordering::Relaxed);
+            if stuck_at.is_some_and(|(at_cycle, _)| at_cycle != cycles) {
+                eprintln!("Watchdog: il ciclo di campionamento è ripartito");
+                stuck_at = None;
+            }
+            let since_cycle = Duration::from_millis(heartbeat.since_ms(&heartbeat.last_cycle_ms).unwrap_or(0));
+            match stuck_at {
+                None if since_cycle > timeout => {
+                    report(&heartbeat, since_cycle, timeout, config.action, events.as_ref(), &session_id);
+                    if config.action == WatchdogAction::Abort {
+                        abort(events.as_ref());
+                    }
+                    heartbeat.reinit_requested.store(true, Ordering::Relaxed);
+                    stuck_at = Some((cycles, Instant::now()));
+                }
+                Some((_, at)) if at.elapsed() > timeout => {
+                    eprintln!("Watchdog: ciclo ancora fermo dopo la richiesta di riapertura dei bus");
+                    abort(events.as_ref());
+                }
+                _ => {}
+            }
+        }
+    };
+    if let Err(e) = thread::Builder::new().name("watchdog".to_string()).spawn(supervisor) {
+        println!("Impossibile avviare il watchdog: {}", e);
+    }
+}
+
+fn report(
+    heartbeat: &Heartbeat,
+    since_cycle: Duration,
+    timeout: Duration,
+    action: WatchdogAction,
+    events: Option<&EventSender>,
+    session_id: &str,
+) {
+    let stage = heartbeat.stage();
+    let last_success: serde_json::Map<_, _> = SENSORS
+        .iter()
+        .zip(&heartbeat.last_success_ms)
+        .map(|(name, at)| (name.to_string(), json!(heartbeat.since_ms(at).map(|ms| ms / 1000))))
+        .collect();
+    let message = format!(
+        "Watchdog: nessun ciclo completato da {} s (limite {} s), fermo in {}; {}",
+        since_cycle.as_secs(),
+        timeout.as_secs(),
+        stage.name(),
+        match action {
+            WatchdogAction::Abort => "interruzione del processo",
+            WatchdogAction::Reinit => "richiesta la riapertura dei bus I2C",
+        }
+    );
+    eprintln!("{}", message);
+    for (name, secs) in &last_success {
+        match secs.as_u64() {
+            Some(secs) => eprintln!("  {}: ultima lettura riuscita {} s fa", name, secs),
+            None => eprintln!("  {}: nessuna lettura riuscita", name),
+        }
+    }
+    let Some(events) = events else {
+        return;
+    };
+    let mut event = Event::new(
+        Severity::Error,
+        "watchdog",
+        message,
+        json!({
+            "since_cycle_secs": since_cycle.as_secs(),
+            "timeout_secs": timeout.as_secs(),
+            "stage": format!("{:?}", stage).to_lowercase(),
+            "cycles": heartbeat.cycles.load(Ordering::Relaxed),
+            "last_success_secs_ago": last_success,
+            "action": action,
+        }),
+    );
+    event.session_id = session_id.to_string();
+    if let Ok(text) = serde_json::to_string(&event) {
+        events.send(Output::Event(Arc::from(text)));
+    }
+}
+
+/// Gives the event sinks a moment to write the report, then aborts.
+fn abort(events: Option<&EventSender>) -> ! {
+    if let Some(events) = events {
+        events.wait_drained(Duration::from_secs(2));
+    }
+    eprintln!("Watchdog: arresto forzato del processo");
+    std::process::abort();
+}
@@ -0,0 +1,82 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::pipeline::{Output, Sink};
+
+const TAIL_BYTES: u64 = 64 * 1024;
+
+pub struct JsonlWriter {
+    path: String,
+    file: Option<BufWriter<File>>,
+}
+
+impl JsonlWriter {
+    pub fn new(path: &str) -> Self {
+        JsonlWriter { path: path.to_string(), file: None }
+    }
+
+    pub fn write_line(&mut self, line: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let result = self.append(line);
+        if result.is_err() {
+            self.file = None;
+        }
+        result
+    }
+
+    pub fn flush(&mut self) {
+        if let Some(file) = self.file.as_mut() {
+            let _ = file.flush();
+        }
+    }
+
+    fn append(&mut self, line: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if self.file.is_none() {
+            let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            self.file = Some(BufWriter::new(file));
+        }
+        if let Some(file) = self.file.as_mut() {
+            writeln!(file, "{}", line)?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for JsonlWriter {
+    fn write(&mut self, output: &Output) -> Result<(), String> {
+        self.write_line(output.text())
+            .map_err(|e| format!("{}: {}", self.path, e))
+    }
+
+    fn flush(&mut self) {
+        JsonlWriter::flush(self);
+    }
+
+    fn reopen(&mut self) {
+        JsonlWriter::flush(self);
+        self.file = None;
+    }
+}
+
+pub fn is_data_record(line: &str) -> bool {
+    match serde_json::from_str::<serde_json::Value>(line) {
+        Ok(value) => value.get("type").is_none(),
+        Err(_) => true,
+    }
+}
+
+pub fn last_line(path: &Path, predicate: impl Fn(&str) -> bool) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(TAIL_BYTES)))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    let tail = String::from_utf8_lossy(&tail);
+    Ok(tail
+        .lines()
+        .rev()
+        .filter(|line| !line.trim().is_empty())
+        .find(|line| predicate(line))
+        .map(str::to_string))
+}
@@ -0,0 +1,35 @@
+#![allow(dead_code)]
+
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// An empty directory of its own for the test `name`.
+pub fn dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sensor-program-it-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs the binary in the crate directory, so that fixture paths resolve.
+pub fn sensor_program(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_sensor-program"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap()
+}
+
+/// The data records of the `data_*.jsonl` file in `dir`, without headers.
+pub fn data_records(dir: &Path) -> Vec<Value> {
+    let path = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.file_name().unwrap().to_string_lossy().starts_with("data_"))
+        .unwrap();
+    let content = fs::read_to_string(path).unwrap();
+    let lines = content.lines().map(|line| serde_json::from_str::<Value>(line).unwrap());
+    lines.filter(|record| record.get("type").is_none()).collect()
+}
@@ -0,0 +1,32 @@
+#![cfg(all(feature = "sim-test", feature = "tokio-runtime"))]
+
+mod common;
+
+use std::fs;
+
+/// What depends on when and in which process the run happened.
+const RUN_FIELDS: [&str; 4] = ["timestamp", "session_id", "boot_id", "timing"];
+
+#[test]
+fn thread_and_tokio_runtimes_write_the_same_records() {
+    let base = common::dir("runtimes");
+    let config = base.join("config.toml");
+    fs::write(&config, "[status]\ntiming_in_records = true\n").unwrap();
+    let mut runs = Vec::new();
+    for (name, runtime) in [("thread", None), ("tokio", Some("--async"))] {
+        let dir = base.join(name);
+        let mut args = vec!["soak", "--records", "60", "--keep", "--config", config.to_str().unwrap()];
+        args.extend(["--dir", dir.to_str().unwrap()]);
+        args.extend(runtime);
+        let output = common::sensor_program(&args);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+        let mut records = common::data_records(&dir);
+        for record in &mut records {
+            record.as_object_mut().unwrap().retain(|key, _| !RUN_FIELDS.contains(&key.as_str()));
+        }
+        runs.push(records);
+    }
+    fs::remove_dir_all(&base).unwrap();
+    assert_eq!(runs[0].len(), 60);
+    assert_eq!(runs[0], runs[1]);
+}
@@ -0,0 +1,39 @@
+#![cfg(feature = "sim-test")]
+
+mod common;
+
+use std::fs;
+
+#[test]
+fn a_short_soak_passes_its_checks() {
+    let base = common::dir("soak");
+    let config = base.join("config.toml");
+    fs::write(&config, "").unwrap();
+    let dir = base.join("run");
+    let args = ["soak", "--records", "300", "--config", config.to_str().unwrap(), "--dir", dir.to_str().unwrap()];
+    let output = common::sensor_program(&args);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    for check in ["files_parse", "sequence", "schema", "timestamps", "file_set", "summary", "queues", "ring_buffer"] {
+        assert!(stdout.lines().any(|line| line.starts_with(&format!("PASS {}:", check))), "{}\n{}", check, stdout);
+    }
+    assert!(!dir.exists(), "la cartella di un soak riuscito resta");
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn a_soak_with_an_unset_clock_tags_records_until_it_is_set() {
+    let base = common::dir("soak-unset");
+    let config = base.join("config.toml");
+    fs::write(&config, "").unwrap();
+    let dir = base.join("run");
+    let mut args = vec!["soak", "--records", "100", "--unset-clock", "2m", "--keep"];
+    args.extend(["--config", config.to_str().unwrap(), "--dir", dir.to_str().unwrap()]);
+    let output = common::sensor_program(&args);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+    let records = common::data_records(&dir);
+    assert_eq!(records.len(), 100);
+    let invalid = records.iter().take_while(|record| record["clock_invalid"] == true).count();
+    assert!(invalid > 0 && records[invalid..].iter().all(|record| record.get("clock_invalid").is_none()));
+    fs::remove_dir_all(&base).unwrap();
+}
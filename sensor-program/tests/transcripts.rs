@@ -0,0 +1,33 @@
+#![cfg(feature = "sim-test")]
+
+mod common;
+
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+const FIXTURES: [&str; 2] = ["fixtures/transcripts/faults.json", "fixtures/transcripts/ms5611_ina219.json"];
+
+#[test]
+fn the_fixtures_reproduce_their_records() {
+    let output = common::sensor_program(&["transcript", FIXTURES[0], FIXTURES[1]]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn a_different_record_fails_the_transcript() {
+    let dir = common::dir("transcript");
+    let source = fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join(FIXTURES[0])).unwrap();
+    let mut transcript: Value = serde_json::from_str(&source).unwrap();
+    let altitude = transcript["records"][0]["altitude_m"].as_f64().unwrap();
+    transcript["records"][0]["altitude_m"] = (altitude + 1.0).into();
+    let path = dir.join("faults.json");
+    fs::write(&path, serde_json::to_string_pretty(&transcript).unwrap()).unwrap();
+    let output = common::sensor_program(&["transcript", path.to_str().unwrap()]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{}", stdout);
+    let failure = stdout.lines().find(|line| line.starts_with("FAIL ")).unwrap_or_default();
+    assert!(failure.contains("record 0: altitude_m"), "{}", stdout);
+    fs::remove_dir_all(&dir).unwrap();
+}